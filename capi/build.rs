@@ -0,0 +1,13 @@
+// Regenerate include/mz_parquet.h from the `extern "C"` functions in src/lib.rs
+fn main() {
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).unwrap();
+    cbindgen::Builder::new()
+        .with_crate(&dir)
+        .with_config(config)
+        .generate()
+        .expect("failed to generate C bindings")
+        .write_to_file(format!("{}/include/mz_parquet.h", dir));
+}
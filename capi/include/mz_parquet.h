@@ -0,0 +1,117 @@
+#ifndef MZ_PARQUET_H
+#define MZ_PARQUET_H
+
+/* Generated by cbindgen from capi/src/lib.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The spectra of an mzparquet file, read into memory
+ */
+typedef struct MzpReader MzpReader;
+
+/**
+ * A spectrum, borrowed from an [`MzpReader`]. Pointers are valid until the
+ * reader is freed
+ */
+typedef struct MzpSpectrum {
+  /**
+   * Native id, not NUL terminated
+   */
+  const uint8_t *id;
+  size_t id_len;
+  uint8_t ms_level;
+  /**
+   * Scan start time, in seconds
+   */
+  float scan_start_time;
+  /**
+   * m/z of the first precursor, NaN if the spectrum has none
+   */
+  double precursor_mz;
+  /**
+   * Charge of the first precursor, 0 if unknown
+   */
+  uint8_t precursor_charge;
+  /**
+   * Number of ions
+   */
+  size_t len;
+  const double *mz;
+  const double *intensity;
+} MzpSpectrum;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Message of the last error on this thread, or null if there was none. The
+ * string is owned by the library and valid until the next failing call on
+ * this thread
+ */
+const char *mzp_last_error(void);
+
+/**
+ * Convert an mzML file to a long format mzparquet file, with the default
+ * writer options. Returns 0 on success
+ *
+ * # Safety
+ * `input` and `output` must be valid, NUL terminated UTF-8 strings
+ */
+int mzp_convert(const char *input, const char *output);
+
+/**
+ * Read all spectra of a long or wide format mzparquet file. Returns null on
+ * failure. The reader must be freed with [`mzp_reader_free`]
+ *
+ * # Safety
+ * `path` must be a valid, NUL terminated UTF-8 string
+ */
+struct MzpReader *mzp_reader_open(const char *path);
+
+/**
+ * Number of spectra in the file
+ *
+ * # Safety
+ * `reader` must have been returned by [`mzp_reader_open`]
+ */
+size_t mzp_reader_len(const struct MzpReader *reader);
+
+/**
+ * Fill `spectrum` with the spectrum at `index`. Returns false if `index` is
+ * out of bounds
+ *
+ * # Safety
+ * `reader` must have been returned by [`mzp_reader_open`], and `spectrum`
+ * must point to writable memory
+ */
+bool mzp_reader_get(const struct MzpReader *reader, size_t index, struct MzpSpectrum *spectrum);
+
+/**
+ * Fill `spectrum` with the next spectrum of the file. Returns false once all
+ * spectra have been read
+ *
+ * # Safety
+ * `reader` must have been returned by [`mzp_reader_open`], and `spectrum`
+ * must point to writable memory
+ */
+bool mzp_reader_next(struct MzpReader *reader, struct MzpSpectrum *spectrum);
+
+/**
+ * Free a reader, invalidating the spectra borrowed from it
+ *
+ * # Safety
+ * `reader` must have been returned by [`mzp_reader_open`], or be null
+ */
+void mzp_reader_free(struct MzpReader *reader);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MZ_PARQUET_H */
@@ -0,0 +1,213 @@
+//! C interface to the mzparquet converter and reader.
+//!
+//! The header, `include/mz_parquet.h`, is regenerated with cbindgen whenever
+//! the crate is built. Link against `libmzparquet` (shared or static).
+//!
+//! Functions that can fail return a non-zero code (or a null pointer) on
+//! failure, and record a message retrieved with [`mzp_last_error`]. Errors
+//! are recorded per thread.
+//!
+//! ```c
+//! MzpReader *reader = mzp_reader_open("run.mzparquet");
+//! if (!reader) { fprintf(stderr, "%s\n", mzp_last_error()); return 1; }
+//! MzpSpectrum spectrum;
+//! while (mzp_reader_next(reader, &spectrum)) {
+//!     for (size_t i = 0; i < spectrum.len; i++) { /* spectrum.mz[i] ... */ }
+//! }
+//! mzp_reader_free(reader);
+//! ```
+use mz_parquet::write_long::WriterOptions;
+use mz_parquet::{MzMLReader, RawSpectrum};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(error: anyhow::Error) {
+    let message = CString::new(format!("{:#}", error).replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+unsafe fn path<'a>(path: *const c_char) -> anyhow::Result<&'a str> {
+    anyhow::ensure!(!path.is_null(), "path is null");
+    Ok(CStr::from_ptr(path).to_str()?)
+}
+
+/// Message of the last error on this thread, or null if there was none. The
+/// string is owned by the library and valid until the next failing call on
+/// this thread
+#[no_mangle]
+pub extern "C" fn mzp_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(std::ptr::null(), |e| e.as_ptr()))
+}
+
+/// Convert an mzML file to a long format mzparquet file, with the default
+/// writer options. Returns 0 on success
+///
+/// # Safety
+/// `input` and `output` must be valid, NUL terminated UTF-8 strings
+#[no_mangle]
+pub unsafe extern "C" fn mzp_convert(input: *const c_char, output: *const c_char) -> c_int {
+    let convert = || -> anyhow::Result<()> {
+        let (input, output) = (path(input)?, path(output)?);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            let file = tokio::io::BufReader::new(tokio::fs::File::open(input).await?);
+            let mut spectra = MzMLReader::default().stream(file);
+            let w = std::io::BufWriter::new(std::fs::File::create(output)?);
+            mz_parquet::write_long::serialize_stream_to_parquet(
+                w,
+                &mut spectra,
+                &WriterOptions::default(),
+            )
+            .await?;
+            Ok(())
+        })
+    };
+    match convert() {
+        Ok(()) => 0,
+        Err(error) => {
+            set_error(error);
+            -1
+        }
+    }
+}
+
+/// The spectra of an mzparquet file, read into memory
+pub struct MzpReader {
+    spectra: Vec<RawSpectrum>,
+    position: usize,
+}
+
+/// A spectrum, borrowed from an [`MzpReader`]. Pointers are valid until the
+/// reader is freed
+#[repr(C)]
+pub struct MzpSpectrum {
+    /// Native id, not NUL terminated
+    pub id: *const u8,
+    pub id_len: usize,
+    pub ms_level: u8,
+    /// Scan start time, in seconds
+    pub scan_start_time: f32,
+    /// m/z of the first precursor, NaN if the spectrum has none
+    pub precursor_mz: f64,
+    /// Charge of the first precursor, 0 if unknown
+    pub precursor_charge: u8,
+    /// Number of ions
+    pub len: usize,
+    pub mz: *const f64,
+    pub intensity: *const f64,
+}
+
+impl From<&RawSpectrum> for MzpSpectrum {
+    fn from(spectrum: &RawSpectrum) -> Self {
+        let precursor = spectrum.precursors.first();
+        MzpSpectrum {
+            id: spectrum.id.as_ptr(),
+            id_len: spectrum.id.len(),
+            ms_level: spectrum.ms_level,
+            scan_start_time: spectrum.scan_start_time,
+            precursor_mz: precursor.map_or(f64::NAN, |p| p.mz),
+            precursor_charge: precursor.and_then(|p| p.charge).unwrap_or_default(),
+            len: spectrum.mz.len(),
+            mz: spectrum.mz.as_ptr(),
+            intensity: spectrum.intensity.as_ptr(),
+        }
+    }
+}
+
+/// Read all spectra of a long or wide format mzparquet file. Returns null on
+/// failure. The reader must be freed with [`mzp_reader_free`]
+///
+/// # Safety
+/// `path` must be a valid, NUL terminated UTF-8 string
+#[no_mangle]
+pub unsafe extern "C" fn mzp_reader_open(path: *const c_char) -> *mut MzpReader {
+    let open = || -> anyhow::Result<MzpReader> {
+        let buf = bytes::Bytes::from(std::fs::read(self::path(path)?)?);
+        let (_, spectra) = mz_parquet::read_spectra(buf)?;
+        Ok(MzpReader {
+            spectra,
+            position: 0,
+        })
+    };
+    match open() {
+        Ok(reader) => Box::into_raw(Box::new(reader)),
+        Err(error) => {
+            set_error(error);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Number of spectra in the file
+///
+/// # Safety
+/// `reader` must have been returned by [`mzp_reader_open`]
+#[no_mangle]
+pub unsafe extern "C" fn mzp_reader_len(reader: *const MzpReader) -> usize {
+    reader.as_ref().map_or(0, |r| r.spectra.len())
+}
+
+/// Fill `spectrum` with the spectrum at `index`. Returns false if `index` is
+/// out of bounds
+///
+/// # Safety
+/// `reader` must have been returned by [`mzp_reader_open`], and `spectrum`
+/// must point to writable memory
+#[no_mangle]
+pub unsafe extern "C" fn mzp_reader_get(
+    reader: *const MzpReader,
+    index: usize,
+    spectrum: *mut MzpSpectrum,
+) -> bool {
+    match (reader.as_ref(), spectrum.is_null()) {
+        (Some(reader), false) => match reader.spectra.get(index) {
+            Some(s) => {
+                spectrum.write(s.into());
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Fill `spectrum` with the next spectrum of the file. Returns false once all
+/// spectra have been read
+///
+/// # Safety
+/// `reader` must have been returned by [`mzp_reader_open`], and `spectrum`
+/// must point to writable memory
+#[no_mangle]
+pub unsafe extern "C" fn mzp_reader_next(
+    reader: *mut MzpReader,
+    spectrum: *mut MzpSpectrum,
+) -> bool {
+    let Some(reader) = reader.as_mut() else {
+        return false;
+    };
+    match (reader.spectra.get(reader.position), spectrum.is_null()) {
+        (Some(s), false) => {
+            spectrum.write(s.into());
+            reader.position += 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Free a reader, invalidating the spectra borrowed from it
+///
+/// # Safety
+/// `reader` must have been returned by [`mzp_reader_open`], or be null
+#[no_mangle]
+pub unsafe extern "C" fn mzp_reader_free(reader: *mut MzpReader) {
+    if !reader.is_null() {
+        drop(Box::from_raw(reader));
+    }
+}
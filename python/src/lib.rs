@@ -0,0 +1,208 @@
+//! Python bindings for mz_parquet, built with maturin (`maturin develop` from
+//! this directory).
+//!
+//! Spectra are returned as dictionaries holding numpy arrays, and query
+//! results as `pyarrow.RecordBatch`es, which convert to pandas or polars
+//! without copying. Files are read from local paths; the GIL is released
+//! while converting and querying.
+use arrow::pyarrow::ToPyArrow;
+use mz_parquet::output::Table;
+use mz_parquet::query::{self, FragmentQuery, PrecursorQuery, Tolerance, XicQuery};
+use mz_parquet::write_long::WriterOptions;
+use mz_parquet::{MzMLReader, RawSpectrum};
+use numpy::IntoPyArray;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+fn read_file(path: &str) -> anyhow::Result<bytes::Bytes> {
+    Ok(std::fs::read(path)?.into())
+}
+
+fn tolerance(ppm: Option<f32>, da: Option<f32>) -> PyResult<Tolerance> {
+    match (ppm, da) {
+        (Some(_), Some(_)) => Err(PyValueError::new_err("only one of ppm and da can be given")),
+        (_, Some(da)) => Ok(Tolerance::Da(da)),
+        (ppm, None) => Ok(Tolerance::Ppm(ppm.unwrap_or(10.0))),
+    }
+}
+
+fn to_pyarrow(py: Python<'_>, table: Table) -> PyResult<PyObject> {
+    table
+        .to_record_batch()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?
+        .to_pyarrow(py)
+}
+
+/// Convert an mzML file to mzparquet, in the long (default) or wide format.
+/// Returns the number of spectra written
+#[pyfunction]
+#[pyo3(signature = (input, output, format = "long"))]
+fn convert(py: Python<'_>, input: &str, output: &str, format: &str) -> PyResult<usize> {
+    let wide = match format {
+        "long" => false,
+        "wide" => true,
+        _ => {
+            return Err(PyValueError::new_err(format!(
+                "unknown format `{}`, expected `long` or `wide`",
+                format
+            )))
+        }
+    };
+    let count = py.allow_threads(|| -> anyhow::Result<usize> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            let file = tokio::io::BufReader::new(tokio::fs::File::open(input).await?);
+            let mut spectra = MzMLReader::default().stream(file);
+            let w = std::io::BufWriter::new(std::fs::File::create(output)?);
+            let options = WriterOptions::default();
+            let (_, written) = match wide {
+                false => {
+                    mz_parquet::write_long::serialize_stream_to_parquet(w, &mut spectra, &options)
+                        .await?
+                }
+                true => {
+                    mz_parquet::write_wide::serialize_stream_to_parquet(w, &mut spectra, &options)
+                        .await?
+                }
+            };
+            Ok(written.spectra)
+        })
+    })?;
+    Ok(count)
+}
+
+fn spectrum_to_dict<'py>(py: Python<'py>, spectrum: RawSpectrum) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    dict.set_item("id", String::from_utf8_lossy(&spectrum.id))?;
+    dict.set_item("scan_number", spectrum.scan_number)?;
+    dict.set_item("ms_level", spectrum.ms_level)?;
+    dict.set_item("centroid", spectrum.centroid)?;
+    dict.set_item("scan_start_time", spectrum.scan_start_time)?;
+    dict.set_item("ion_injection_time", spectrum.ion_injection_time)?;
+    dict.set_item("total_ion_current", spectrum.total_ion_current)?;
+    dict.set_item("inverse_ion_mobility", spectrum.inverse_ion_mobility)?;
+    let precursors = spectrum
+        .precursors
+        .iter()
+        .map(|precursor| {
+            let p = PyDict::new_bound(py);
+            p.set_item("mz", precursor.mz)?;
+            p.set_item("intensity", precursor.intensity)?;
+            p.set_item("charge", precursor.charge)?;
+            p.set_item("isolation_window_target", precursor.isolation_window_target)?;
+            p.set_item("isolation_window_lower", precursor.isolation_window_lower)?;
+            p.set_item("isolation_window_upper", precursor.isolation_window_upper)?;
+            Ok(p)
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    dict.set_item("precursors", precursors)?;
+    dict.set_item("mz", spectrum.mz.into_pyarray_bound(py))?;
+    dict.set_item("intensity", spectrum.intensity.into_pyarray_bound(py))?;
+    if !spectrum.ion_mobility.is_empty() {
+        dict.set_item("ion_mobility", spectrum.ion_mobility.into_pyarray_bound(py))?;
+    }
+    Ok(dict)
+}
+
+/// Read all spectra of a long or wide format file, as a list of dictionaries
+/// with `mz` and `intensity` numpy arrays. Retention times are in seconds
+#[pyfunction]
+fn read_spectra<'py>(py: Python<'py>, path: &str) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let spectra = py.allow_threads(|| -> anyhow::Result<_> {
+        let (_, spectra) = mz_parquet::read_spectra(read_file(path)?)?;
+        Ok(spectra)
+    })?;
+    spectra
+        .into_iter()
+        .map(|spectrum| spectrum_to_dict(py, spectrum))
+        .collect()
+}
+
+/// Extract an ion chromatogram from a long format file. `rt` is an
+/// inclusive retention time window, in seconds
+#[pyfunction]
+#[pyo3(signature = (path, mz, ppm = None, da = None, rt = None, ms_level = 1))]
+fn xic(
+    py: Python<'_>,
+    path: &str,
+    mz: f64,
+    ppm: Option<f32>,
+    da: Option<f32>,
+    rt: Option<(f32, f32)>,
+    ms_level: u8,
+) -> PyResult<PyObject> {
+    let query = XicQuery {
+        mz,
+        tolerance: tolerance(ppm, da)?,
+        rt,
+        ms_level,
+    };
+    let points =
+        py.allow_threads(|| -> anyhow::Result<_> { Ok(query::xic(read_file(path)?, &query)?) })?;
+    to_pyarrow(py, Table::from(points.as_slice()))
+}
+
+/// Find the MS2 spectra of a long format file containing a product ion,
+/// optionally within an `rt` window in seconds
+#[pyfunction]
+#[pyo3(signature = (path, mz, ppm = None, da = None, precursor_mz = None, rt = None))]
+fn fragment(
+    py: Python<'_>,
+    path: &str,
+    mz: f64,
+    ppm: Option<f32>,
+    da: Option<f32>,
+    precursor_mz: Option<f64>,
+    rt: Option<(f32, f32)>,
+) -> PyResult<PyObject> {
+    let tolerance = tolerance(ppm, da)?;
+    let query = FragmentQuery {
+        mz,
+        tolerance,
+        precursor: precursor_mz.map(|mz| (mz, tolerance)),
+        rt,
+    };
+    let matches = py.allow_threads(|| -> anyhow::Result<_> {
+        Ok(query::fragment(read_file(path)?, &query)?)
+    })?;
+    to_pyarrow(py, Table::from(matches.as_slice()))
+}
+
+/// Find the MS2 spectra of a long format file by precursor m/z, optionally
+/// within an `rt` window in seconds
+#[pyfunction]
+#[pyo3(signature = (path, mz, ppm = None, da = None, isolation_window = false, rt = None))]
+fn precursor(
+    py: Python<'_>,
+    path: &str,
+    mz: f64,
+    ppm: Option<f32>,
+    da: Option<f32>,
+    isolation_window: bool,
+    rt: Option<(f32, f32)>,
+) -> PyResult<PyObject> {
+    let query = PrecursorQuery {
+        mz,
+        tolerance: tolerance(ppm, da)?,
+        isolation_window,
+        rt,
+    };
+    let matches = py.allow_threads(|| -> anyhow::Result<_> {
+        Ok(query::precursor(read_file(path)?, &query)?)
+    })?;
+    to_pyarrow(py, Table::from(matches.as_slice()))
+}
+
+#[pymodule]
+#[pyo3(name = "mz_parquet")]
+fn mz_parquet_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(convert, m)?)?;
+    m.add_function(wrap_pyfunction!(read_spectra, m)?)?;
+    m.add_function(wrap_pyfunction!(xic, m)?)?;
+    m.add_function(wrap_pyfunction!(fragment, m)?)?;
+    m.add_function(wrap_pyfunction!(precursor, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,230 @@
+//! Averaging of MS1 spectra over a sliding window of neighbouring scans.
+//!
+//! Each MS1 spectrum is replaced by the average of itself and the MS1
+//! spectra around it, which smooths out the noise of individual scans (a
+//! common preprocessing step for library-free DIA searches). Peaks of the
+//! spectra in the window are merged when they are within a ppm tolerance of
+//! each other, at their intensity weighted mean m/z, and their intensities
+//! are divided by the number of spectra in the window. Profile spectra are
+//! centroided before they are averaged.
+//!
+//! Averaged spectra keep their ids and scan numbers, so the MSn spectra in
+//! between still refer to them as precursors. Per-ion arrays (ion mobility,
+//! noise, baseline and charge) are dropped, as they cannot be averaged.
+//!
+//! Enabled with [`crate::write_long::WriterOptions::set_ms1_averaging`],
+//! which records the settings in the footer metadata under
+//! [`MS1_AVERAGING_KEY`].
+use crate::metadata::RunMetadata;
+use crate::mzml::{Chromatogram, MzMLError, RawSpectrum, SpectrumStream};
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Footer metadata key holding the [`Ms1Averaging`] settings, as JSON
+pub const MS1_AVERAGING_KEY: &str = "ms1_averaging";
+
+/// Settings for averaging MS1 spectra
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ms1Averaging {
+    /// Number of MS1 spectra averaged, centered on each spectrum
+    pub scans: usize,
+    /// Tolerance for merging peaks, in ppm
+    pub ppm: f32,
+}
+
+impl Ms1Averaging {
+    /// The settings used to average the MS1 spectra of a file, if they were
+    pub fn from_metadata(metadata: &ParquetMetaData) -> anyhow::Result<Option<Self>> {
+        metadata
+            .file_metadata()
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+            .find(|kv| kv.key == MS1_AVERAGING_KEY)
+            .and_then(|kv| kv.value.as_deref())
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    pub(crate) fn to_key_value(self) -> serde_json::Result<KeyValue> {
+        Ok(KeyValue {
+            key: MS1_AVERAGING_KEY.into(),
+            value: Some(serde_json::to_string(&self)?),
+        })
+    }
+
+    /// Average `spectrum` with the other MS1 spectra in its window
+    pub fn average(&self, spectrum: &RawSpectrum, window: &[&RawSpectrum]) -> RawSpectrum {
+        let centroided = window
+            .iter()
+            .map(|s| match s.centroid {
+                true => std::borrow::Cow::Borrowed(*s),
+                false => std::borrow::Cow::Owned(crate::centroid::centroid(s)),
+            })
+            .collect::<Vec<_>>();
+        let mut peaks = centroided
+            .iter()
+            .flat_map(|s| s.mz.iter().copied().zip(s.intensity.iter().copied()))
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Clusters of peaks, as (first m/z, intensity weighted m/z sum,
+        // intensity sum)
+        let mut clusters: Vec<(f64, f64, f64)> = Vec::new();
+        for (mz, intensity) in peaks {
+            match clusters.last_mut() {
+                Some((first, weighted, total)) if mz - *first <= *first * self.ppm as f64 / 1E6 => {
+                    *weighted += mz * intensity;
+                    *total += intensity;
+                }
+                _ => clusters.push((mz, mz * intensity, intensity)),
+            }
+        }
+
+        let n = window.len().max(1) as f64;
+        let (mz, intensity) = clusters
+            .into_iter()
+            .filter(|&(_, _, total)| total > 0.0)
+            .map(|(_, weighted, total)| (weighted / total, total / n))
+            .unzip();
+        RawSpectrum {
+            centroid: true,
+            mz,
+            intensity,
+            ion_mobility: Vec::new(),
+            noise: Vec::new(),
+            baseline: Vec::new(),
+            charge: Vec::new(),
+            ..spectrum.clone()
+        }
+    }
+}
+
+/// Wraps a [`SpectrumStream`], averaging its MS1 spectra. Spectra are read
+/// ahead until the window of the next MS1 spectrum is complete
+pub struct AveragedStream<'a, S> {
+    inner: &'a mut S,
+    averaging: Option<Ms1Averaging>,
+    /// Spectra read from `inner` that have not been returned yet
+    pending: VecDeque<RawSpectrum>,
+    /// The last MS1 spectra returned, before they were averaged
+    previous: VecDeque<RawSpectrum>,
+    done: bool,
+}
+
+impl<'a, S: SpectrumStream> AveragedStream<'a, S> {
+    /// Average the MS1 spectra of `inner`, or pass them through unchanged
+    /// if `averaging` is `None`
+    pub fn new(inner: &'a mut S, averaging: Option<Ms1Averaging>) -> Self {
+        Self {
+            inner,
+            averaging,
+            pending: VecDeque::new(),
+            previous: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl<S: SpectrumStream> SpectrumStream for AveragedStream<'_, S> {
+    async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+        let Some(averaging) = self.averaging.filter(|a| a.scans > 1) else {
+            return self.inner.next_spectrum().await;
+        };
+        let after = averaging.scans / 2;
+        let before = averaging.scans - 1 - after;
+
+        loop {
+            match self.pending.front() {
+                None if self.done => return Ok(None),
+                Some(front) if front.ms_level != 1 => return Ok(self.pending.pop_front()),
+                Some(_) => {
+                    let following = self.pending.iter().skip(1).filter(|s| s.ms_level == 1);
+                    if self.done || following.count() >= after {
+                        let spectrum = self.pending.pop_front().expect("front exists");
+                        let mut window = self.previous.iter().collect::<Vec<_>>();
+                        window.push(&spectrum);
+                        window.extend(self.pending.iter().filter(|s| s.ms_level == 1).take(after));
+                        let averaged = averaging.average(&spectrum, &window);
+
+                        self.previous.push_back(spectrum);
+                        if self.previous.len() > before {
+                            self.previous.pop_front();
+                        }
+                        return Ok(Some(averaged));
+                    }
+                }
+                None => {}
+            }
+            match self.inner.next_spectrum().await? {
+                Some(spectrum) => self.pending.push_back(spectrum),
+                None => self.done = true,
+            }
+        }
+    }
+
+    fn take_chromatograms(&mut self) -> Vec<Chromatogram> {
+        self.inner.take_chromatograms()
+    }
+
+    fn run_metadata(&self) -> RunMetadata {
+        self.inner.run_metadata()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Spectra(std::vec::IntoIter<RawSpectrum>);
+
+    impl SpectrumStream for Spectra {
+        async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+            Ok(self.0.next())
+        }
+    }
+
+    #[tokio::test]
+    async fn sliding_window() -> Result<(), MzMLError> {
+        let ms1 = |id: &[u8], intensity: f64| RawSpectrum {
+            id: id.to_vec(),
+            ms_level: 1,
+            centroid: true,
+            mz: vec![400.0],
+            intensity: vec![intensity],
+            ..Default::default()
+        };
+        let ms2 = RawSpectrum {
+            id: b"ms2".to_vec(),
+            ms_level: 2,
+            ..Default::default()
+        };
+        let spectra = vec![
+            ms1(b"a", 30.0),
+            ms2.clone(),
+            ms1(b"b", 60.0),
+            ms2,
+            ms1(b"c", 90.0),
+        ];
+        let mut inner = Spectra(spectra.into_iter());
+        let averaging = Ms1Averaging {
+            scans: 3,
+            ppm: 10.0,
+        };
+        let mut stream = AveragedStream::new(&mut inner, Some(averaging));
+
+        let mut read = Vec::new();
+        while let Some(spectrum) = stream.next_spectrum().await? {
+            read.push(spectrum);
+        }
+        let ids = read.iter().map(|s| s.id.as_slice()).collect::<Vec<_>>();
+        assert_eq!(ids, [&b"a"[..], b"ms2", b"b", b"ms2", b"c"]);
+        // The first and last spectra are averaged with their one neighbour
+        assert_eq!(read[0].intensity, vec![45.0]);
+        assert_eq!(read[2].intensity, vec![60.0]);
+        assert_eq!(read[4].intensity, vec![75.0]);
+        Ok(())
+    }
+}
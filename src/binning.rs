@@ -0,0 +1,127 @@
+//! Binning of peaks onto a fixed m/z grid.
+//!
+//! Every peak is assigned to a bin of constant width, in Da, or of width
+//! proportional to its m/z, in ppm (bins of constant relative width, like the
+//! resolution of most mass analyzers). Peaks falling in the same bin are
+//! summed, and written at the center of the bin, so every spectrum of a file
+//! shares the same m/z axis. Empty bins are not written.
+//!
+//! Long format files also store the index of each bin in a
+//! [`crate::write_long::MZ_BIN_COLUMN`] column, which can be used directly as
+//! the column of a (spectrum x bin) matrix. Per-ion arrays are dropped, as
+//! they cannot be summed.
+//!
+//! Enabled with [`crate::write_long::WriterOptions::set_binning`], which
+//! records the grid in the footer metadata under [`BINNING_KEY`].
+use crate::mzml::RawSpectrum;
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+use serde::{Deserialize, Serialize};
+
+/// Footer metadata key holding the [`Binning`] grid, as JSON
+pub const BINNING_KEY: &str = "binning";
+
+/// Width of the bins of an m/z grid
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Binning {
+    /// Bins of constant relative width, in ppm
+    Ppm(f64),
+    /// Bins of constant width, in Da
+    Da(f64),
+}
+
+impl Binning {
+    /// The grid the peaks of a file were binned on, if they were
+    pub fn from_metadata(metadata: &ParquetMetaData) -> anyhow::Result<Option<Self>> {
+        Self::from_key_value(metadata.file_metadata().key_value_metadata())
+    }
+
+    pub(crate) fn from_key_value(kv: Option<&Vec<KeyValue>>) -> anyhow::Result<Option<Self>> {
+        kv.into_iter()
+            .flatten()
+            .find(|kv| kv.key == BINNING_KEY)
+            .and_then(|kv| kv.value.as_deref())
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    pub(crate) fn to_key_value(self) -> serde_json::Result<KeyValue> {
+        Ok(KeyValue {
+            key: BINNING_KEY.into(),
+            value: Some(serde_json::to_string(&self)?),
+        })
+    }
+
+    /// Index of the bin holding `mz`. Bins in ppm start at m/z 1
+    pub fn index(&self, mz: f64) -> i32 {
+        match *self {
+            Binning::Ppm(ppm) => (mz.ln() / (ppm / 1E6).ln_1p()).floor() as i32,
+            Binning::Da(da) => (mz / da).floor() as i32,
+        }
+    }
+
+    /// m/z at the center of bin `index`
+    pub fn center(&self, index: i32) -> f64 {
+        match *self {
+            Binning::Ppm(ppm) => ((index as f64 + 0.5) * (ppm / 1E6).ln_1p()).exp(),
+            Binning::Da(da) => (index as f64 + 0.5) * da,
+        }
+    }
+
+    /// Sum the peaks of `spectrum` falling in the same bins
+    pub fn bin(&self, spectrum: &RawSpectrum) -> RawSpectrum {
+        let mut peaks = spectrum
+            .mz
+            .iter()
+            .zip(&spectrum.intensity)
+            .map(|(&mz, &intensity)| (self.index(mz), intensity))
+            .collect::<Vec<_>>();
+        peaks.sort_by_key(|&(index, _)| index);
+
+        let mut bins: Vec<(i32, f64)> = Vec::new();
+        for (index, intensity) in peaks {
+            match bins.last_mut() {
+                Some((last, total)) if *last == index => *total += intensity,
+                _ => bins.push((index, intensity)),
+            }
+        }
+        RawSpectrum {
+            centroid: true,
+            mz: bins.iter().map(|&(index, _)| self.center(index)).collect(),
+            intensity: bins.into_iter().map(|(_, total)| total).collect(),
+            ion_mobility: Vec::new(),
+            noise: Vec::new(),
+            baseline: Vec::new(),
+            charge: Vec::new(),
+            ..spectrum.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sum_peaks_in_bins() {
+        let spectrum = RawSpectrum {
+            mz: vec![100.02, 100.04, 100.31, 100.06],
+            intensity: vec![10.0, 20.0, 5.0, 1.0],
+            ..Default::default()
+        };
+        let binned = Binning::Da(0.1).bin(&spectrum);
+        assert_eq!(binned.intensity, vec![31.0, 5.0]);
+        assert!((binned.mz[0] - 100.05).abs() < 1E-9);
+        assert!((binned.mz[1] - 100.35).abs() < 1E-9);
+
+        // Centers fall in their own bins, whose width grows with m/z
+        let ppm = Binning::Ppm(20.0);
+        for mz in [100.0, 500.0, 2000.0] {
+            let index = ppm.index(mz);
+            assert_eq!(ppm.index(ppm.center(index)), index);
+            let width = ppm.center(index + 1) - ppm.center(index);
+            assert!((width / mz * 1E6 - 20.0).abs() < 0.01);
+        }
+    }
+}
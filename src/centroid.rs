@@ -0,0 +1,101 @@
+//! Peak picking of profile spectra, so that they can be stored as centroids.
+//!
+//! Profile spectra sample every peak at many m/z values, which makes them
+//! enormous in the long format. Each local intensity maximum is replaced by a
+//! single centroid, at the intensity weighted mean m/z of the points around
+//! the apex that are above half of its intensity (roughly the full width at
+//! half maximum), with the intensity of the apex.
+//!
+//! Enabled with [`crate::write_long::WriterOptions::set_centroid`], which
+//! records [`CENTROIDED_KEY`] in the footer metadata.
+use crate::mzml::RawSpectrum;
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+
+/// Footer metadata key, set to `true` if profile spectra were centroided
+/// during conversion
+pub const CENTROIDED_KEY: &str = "centroided";
+
+/// True if the file was written with profile spectra centroided
+pub fn is_centroided(metadata: &ParquetMetaData) -> bool {
+    from_key_value(metadata.file_metadata().key_value_metadata())
+}
+
+pub(crate) fn from_key_value(kv: Option<&Vec<KeyValue>>) -> bool {
+    kv.into_iter()
+        .flatten()
+        .any(|kv| kv.key == CENTROIDED_KEY && kv.value.as_deref() == Some("true"))
+}
+
+/// Peak pick a profile spectrum. Per-ion arrays (ion mobility, noise,
+/// baseline and charge) keep the value of each apex
+pub fn centroid(spectrum: &RawSpectrum) -> RawSpectrum {
+    let (mz, intensity) = (&spectrum.mz, &spectrum.intensity);
+    let n = mz.len().min(intensity.len());
+
+    let mut apexes = Vec::new();
+    let mut centroids = Vec::new();
+    for apex in 0..n {
+        let height = intensity[apex];
+        let left = apex.checked_sub(1).map_or(0.0, |i| intensity[i]);
+        let right = intensity.get(apex + 1).copied().unwrap_or(0.0);
+        // Flat tops are picked once, at their first point
+        if height <= 0.0 || height <= left || height < right {
+            continue;
+        }
+
+        let half = height / 2.0;
+        let mut lo = apex;
+        while lo > 0 && intensity[lo - 1] >= half && intensity[lo - 1] <= intensity[lo] {
+            lo -= 1;
+        }
+        let mut hi = apex;
+        while hi + 1 < n && intensity[hi + 1] >= half && intensity[hi + 1] <= intensity[hi] {
+            hi += 1;
+        }
+
+        let weight = intensity[lo..=hi].iter().sum::<f64>();
+        let mean = (lo..=hi).map(|i| mz[i] * intensity[i]).sum::<f64>() / weight;
+        apexes.push(apex);
+        centroids.push((mean, height));
+    }
+
+    let at_apexes = |values: &[f32]| match values.len() == n {
+        true => apexes.iter().map(|&i| values[i]).collect(),
+        false => Vec::new(),
+    };
+    RawSpectrum {
+        centroid: true,
+        mz: centroids.iter().map(|(mz, _)| *mz).collect(),
+        intensity: centroids.iter().map(|(_, int)| *int).collect(),
+        ion_mobility: at_apexes(&spectrum.ion_mobility),
+        noise: at_apexes(&spectrum.noise),
+        baseline: at_apexes(&spectrum.baseline),
+        charge: match spectrum.charge.len() == n {
+            true => apexes.iter().map(|&i| spectrum.charge[i]).collect(),
+            false => Vec::new(),
+        },
+        ..spectrum.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pick_peaks() {
+        let spectrum = RawSpectrum {
+            mz: vec![100.0, 100.1, 100.2, 100.3, 100.4, 200.0, 200.1, 200.2],
+            intensity: vec![0.0, 10.0, 20.0, 10.0, 1.0, 0.0, 5.0, 5.0],
+            noise: vec![1.0; 8],
+            ..Default::default()
+        };
+        let centroided = centroid(&spectrum);
+        assert!(centroided.centroid);
+        assert_eq!(centroided.intensity, vec![20.0, 5.0]);
+        assert!((centroided.mz[0] - 100.2).abs() < 1e-9);
+        // Flat tops are centroided over both points
+        assert!((centroided.mz[1] - 200.15).abs() < 1e-9);
+        assert_eq!(centroided.noise, vec![1.0, 1.0]);
+    }
+}
@@ -0,0 +1,1096 @@
+//! Read and write local files and objects in cloud storage uniformly, through
+//! [`object_store`].
+//!
+//! Paths are either local, or URLs with one of the schemes:
+//!
+//! * `s3://bucket/key` - Amazon S3, and S3 compatible stores
+//! * `gs://bucket/key` - Google Cloud Storage
+//! * `az://container/key` (or `abfs://`, `azure://`) - Azure Blob Storage
+//! * `http://` and `https://` - plain HTTP servers, including WebDAV for
+//!   writes. HTTPS URLs of S3 or Azure endpoints use those stores instead
+//!
+//! Credentials are discovered per backend, as by each store's builder: from
+//! `AWS_*` environment variables (or the instance metadata service and web
+//! identity tokens), from `GOOGLE_SERVICE_ACCOUNT` (or application default
+//! credentials), and from `AZURE_STORAGE_*` environment variables (or managed
+//! identities).
+//!
+//! S3 compatible stores run on-premises (MinIO, Ceph RGW) are reached through a
+//! custom endpoint, set with [`CloudOptions::set_s3_endpoint`] or
+//! `AWS_ENDPOINT_URL`. Buckets are addressed in the path of requests unless
+//! `AWS_VIRTUAL_HOSTED_STYLE_REQUEST` is set, and plain HTTP endpoints are
+//! allowed.
+//!
+//! When a named AWS profile ([`CloudOptions::set_aws_profile`] or
+//! `AWS_PROFILE`) or a role to assume is given, S3 credentials and the region
+//! are resolved by the AWS SDK instead, as by the AWS CLI.
+use crate::progress::{Callback, Progress};
+use aws_config::sts::AssumeRoleProvider;
+use aws_credential_types::provider::{ProvideCredentials, SharedCredentialsProvider};
+use memmap2::Mmap;
+use object_store::{
+    aws::{AmazonS3Builder, AmazonS3ConfigKey, AwsCredential},
+    azure::{AzureConfigKey, MicrosoftAzureBuilder},
+    buffered::BufWriter,
+    gcp::{GoogleCloudStorageBuilder, GoogleConfigKey},
+    http::HttpBuilder,
+    path::Path as ObjectPath,
+    ClientConfigKey, CredentialProvider, ObjectStore, ObjectStoreScheme, RetryConfig,
+};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncBufRead, AsyncWriteExt};
+use url::Url;
+
+/// Memory-map a local file, so that it is parsed in place rather than copied
+/// into buffers first, and pages are only kept in memory while the OS has room
+/// for them
+pub fn map_file(path: &Path) -> std::io::Result<Mmap> {
+    let file = std::fs::File::open(path)?;
+    // SAFETY: the mapping is only read, and inputs are not modified while
+    // they are converted. A file truncated in the meantime makes reads past
+    // its new end fault, as with any mapped input
+    unsafe { Mmap::map(&file) }
+}
+
+/// A local path, or the URL of an object in cloud storage
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CloudPath {
+    Local(PathBuf),
+    Remote(Url),
+}
+
+impl FromStr for CloudPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.contains("://") {
+            return Ok(CloudPath::Local(s.into()));
+        }
+        let url = Url::parse(s)?;
+        match ObjectStoreScheme::parse(&url)?.0 {
+            ObjectStoreScheme::Local => url
+                .to_file_path()
+                .map(CloudPath::Local)
+                .map_err(|_| anyhow::anyhow!("invalid file URL: {}", s)),
+            ObjectStoreScheme::Memory => anyhow::bail!("unsupported URL: {}", s),
+            _ => Ok(CloudPath::Remote(url)),
+        }
+    }
+}
+
+impl std::fmt::Display for CloudPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloudPath::Local(path) => write!(f, "{}", path.display()),
+            CloudPath::Remote(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Settings for remote stores, overriding those found in the environment
+///
+/// Failed requests (server errors, throttling, dropped connections and
+/// timeouts) are retried with exponential backoff, from 100ms up to 15s
+/// between attempts, by default up to 10 times and for at most 3 minutes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CloudOptions {
+    s3_endpoint: Option<String>,
+    s3_path_style: Option<bool>,
+    s3_requester_pays: Option<bool>,
+    aws_profile: Option<String>,
+    aws_role_arn: Option<String>,
+    aws_external_id: Option<String>,
+    max_retries: Option<usize>,
+    retry_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+}
+
+impl CloudOptions {
+    /// Endpoint of an S3 compatible store, e.g. `http://minio:9000`
+    pub fn set_s3_endpoint(&mut self, endpoint: Option<String>) -> &mut Self {
+        self.s3_endpoint = endpoint;
+        self
+    }
+
+    /// Address buckets in the path of requests (`endpoint/bucket/key`), or
+    /// in the host name (`bucket.endpoint/key`)
+    pub fn set_s3_path_style(&mut self, path_style: Option<bool>) -> &mut Self {
+        self.s3_path_style = path_style;
+        self
+    }
+
+    /// Accept the charges for requests to requester pays buckets
+    pub fn set_s3_requester_pays(&mut self, requester_pays: Option<bool>) -> &mut Self {
+        self.s3_requester_pays = requester_pays;
+        self
+    }
+
+    /// Named profile of the shared AWS config and credentials files to use.
+    /// Defaults to `AWS_PROFILE`
+    pub fn set_aws_profile(&mut self, profile: Option<String>) -> &mut Self {
+        self.aws_profile = profile;
+        self
+    }
+
+    /// Role to assume through STS, with the credentials found otherwise
+    pub fn set_aws_role_arn(&mut self, role_arn: Option<String>) -> &mut Self {
+        self.aws_role_arn = role_arn;
+        self
+    }
+
+    /// External id required by the trust policy of the assumed role
+    pub fn set_aws_external_id(&mut self, external_id: Option<String>) -> &mut Self {
+        self.aws_external_id = external_id;
+        self
+    }
+
+    /// Number of times a failed request is retried
+    pub fn set_max_retries(&mut self, max_retries: Option<usize>) -> &mut Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Time after which a failing request is no longer retried, counted from
+    /// the first attempt
+    pub fn set_retry_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.retry_timeout = timeout;
+        self
+    }
+
+    /// Time allowed for each request, including reading the response body.
+    /// Defaults to 30 seconds
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Time allowed for connecting to the store. Defaults to 5 seconds
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    fn retry(&self) -> RetryConfig {
+        let mut retry = RetryConfig::default();
+        if let Some(max_retries) = self.max_retries {
+            retry.max_retries = max_retries;
+        }
+        if let Some(timeout) = self.retry_timeout {
+            retry.retry_timeout = timeout;
+        }
+        retry
+    }
+
+    /// HTTP client settings, applied on top of those from the environment
+    fn client(&self) -> Vec<(ClientConfigKey, String)> {
+        let millis = |duration: Duration| format!("{}ms", duration.as_millis());
+        let mut config = Vec::new();
+        if let Some(timeout) = self.timeout {
+            config.push((ClientConfigKey::Timeout, millis(timeout)));
+        }
+        if let Some(timeout) = self.connect_timeout {
+            config.push((ClientConfigKey::ConnectTimeout, millis(timeout)));
+        }
+        config
+    }
+
+    fn s3(&self, url: &Url, sdk: Option<&AwsSdk>) -> AmazonS3Builder {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_url(url.as_str())
+            .with_retry(self.retry());
+        for (key, value) in self.client() {
+            builder = builder.with_config(AmazonS3ConfigKey::Client(key), value);
+        }
+        if let Some(sdk) = sdk {
+            builder = builder.with_credentials(sdk.credentials.clone());
+            if builder
+                .get_config_value(&AmazonS3ConfigKey::Region)
+                .is_none()
+            {
+                if let Some(region) = &sdk.region {
+                    builder = builder.with_region(region);
+                }
+            }
+        }
+        if let Some(requester_pays) = self.s3_requester_pays {
+            builder = builder.with_request_payer(requester_pays);
+        }
+        if let Some(endpoint) = &self.s3_endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(path_style) = self.s3_path_style {
+            builder = builder.with_virtual_hosted_style_request(!path_style);
+        }
+        // On-premises stores are often served without TLS
+        let endpoint = builder.get_config_value(&AmazonS3ConfigKey::Endpoint);
+        if endpoint.is_some_and(|endpoint| endpoint.starts_with("http://")) {
+            builder = builder.with_allow_http(true);
+        }
+        builder
+    }
+
+    fn gcs(&self, url: &Url) -> GoogleCloudStorageBuilder {
+        let mut builder = GoogleCloudStorageBuilder::from_env()
+            .with_url(url.as_str())
+            .with_retry(self.retry());
+        for (key, value) in self.client() {
+            builder = builder.with_config(GoogleConfigKey::Client(key), value);
+        }
+        builder
+    }
+
+    fn azure(&self, url: &Url) -> MicrosoftAzureBuilder {
+        let mut builder = MicrosoftAzureBuilder::from_env()
+            .with_url(url.as_str())
+            .with_retry(self.retry());
+        for (key, value) in self.client() {
+            builder = builder.with_config(AzureConfigKey::Client(key), value);
+        }
+        builder
+    }
+
+    fn http(&self, url: &Url) -> HttpBuilder {
+        let mut builder = HttpBuilder::new()
+            .with_url(&url[..url::Position::BeforePath])
+            .with_retry(self.retry())
+            .with_config(
+                ClientConfigKey::AllowHttp,
+                (url.scheme() == "http").to_string(),
+            );
+        for (key, value) in self.client() {
+            builder = builder.with_config(key, value);
+        }
+        builder
+    }
+}
+
+/// S3 credentials from the AWS SDK's provider chain, which reads named
+/// profiles (including `role_arn`, `credential_process` and SSO sessions),
+/// optionally exchanged for those of a role through STS. Credentials are
+/// cached until shortly before they expire
+#[derive(Debug)]
+struct SdkCredentials {
+    provider: SharedCredentialsProvider,
+    cached: tokio::sync::Mutex<Option<(Arc<AwsCredential>, Option<SystemTime>)>>,
+}
+
+#[async_trait::async_trait]
+impl CredentialProvider for SdkCredentials {
+    type Credential = AwsCredential;
+
+    async fn get_credential(&self) -> object_store::Result<Arc<AwsCredential>> {
+        let mut cached = self.cached.lock().await;
+        if let Some((credential, expiry)) = cached.as_ref() {
+            let refresh = SystemTime::now() + Duration::from_secs(300);
+            if expiry.is_none_or(|expiry| expiry > refresh) {
+                return Ok(credential.clone());
+            }
+        }
+        let credentials = self.provider.provide_credentials().await.map_err(|e| {
+            object_store::Error::Generic {
+                store: "S3",
+                source: Box::new(e),
+            }
+        })?;
+        let credential = Arc::new(AwsCredential {
+            key_id: credentials.access_key_id().into(),
+            secret_key: credentials.secret_access_key().into(),
+            token: credentials.session_token().map(String::from),
+        });
+        *cached = Some((credential.clone(), credentials.expiry()));
+        Ok(credential)
+    }
+}
+
+/// Credentials and region resolved by the AWS SDK
+struct AwsSdk {
+    credentials: Arc<SdkCredentials>,
+    region: Option<String>,
+}
+
+/// The AWS SDK's credentials, if a profile or role to assume is configured.
+/// Otherwise `object_store` finds credentials itself. Resolved once per
+/// process, so that credentials are shared by every store
+async fn aws_sdk(options: &CloudOptions) -> anyhow::Result<Option<&'static AwsSdk>> {
+    static SDK: tokio::sync::OnceCell<Option<AwsSdk>> = tokio::sync::OnceCell::const_new();
+
+    let sdk = SDK
+        .get_or_try_init(|| async {
+            let profile = options
+                .aws_profile
+                .clone()
+                .or_else(|| std::env::var("AWS_PROFILE").ok());
+            if profile.is_none() && options.aws_role_arn.is_none() {
+                return Ok(None);
+            }
+            let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+            if let Some(profile) = profile {
+                loader = loader.profile_name(profile);
+            }
+            let config = loader.load().await;
+            let mut provider = config
+                .credentials_provider()
+                .ok_or_else(|| anyhow::anyhow!("no AWS credentials provider"))?;
+            if let Some(role_arn) = &options.aws_role_arn {
+                let mut role = AssumeRoleProvider::builder(role_arn)
+                    .session_name("mz_parquet")
+                    .configure(&config);
+                if let Some(external_id) = &options.aws_external_id {
+                    role = role.external_id(external_id);
+                }
+                provider = SharedCredentialsProvider::new(role.build().await);
+            }
+            anyhow::Ok(Some(AwsSdk {
+                credentials: Arc::new(SdkCredentials {
+                    provider,
+                    cached: tokio::sync::Mutex::new(None),
+                }),
+                region: config.region().map(|region| region.to_string()),
+            }))
+        })
+        .await?;
+    Ok(sdk.as_ref())
+}
+
+static OPTIONS: RwLock<Option<CloudOptions>> = RwLock::new(None);
+
+/// Use `options` for every remote path opened from now on
+pub fn configure(options: CloudOptions) {
+    *OPTIONS.write().expect("cloud options lock poisoned") = Some(options);
+}
+
+fn options() -> CloudOptions {
+    let options = OPTIONS.read().expect("cloud options lock poisoned");
+    options.clone().unwrap_or_default()
+}
+
+/// The store holding a remote object, and the path of the object in it
+async fn object_store(url: &Url) -> anyhow::Result<(Arc<dyn ObjectStore>, ObjectPath)> {
+    let (scheme, path) = ObjectStoreScheme::parse(url)?;
+    let options = options();
+    let store: Arc<dyn ObjectStore> = match scheme {
+        ObjectStoreScheme::AmazonS3 => {
+            let sdk = aws_sdk(&options).await?;
+            Arc::new(options.s3(url, sdk).build()?)
+        }
+        ObjectStoreScheme::GoogleCloudStorage => Arc::new(options.gcs(url).build()?),
+        ObjectStoreScheme::MicrosoftAzure => Arc::new(options.azure(url).build()?),
+        ObjectStoreScheme::Http => Arc::new(options.http(url).build()?),
+        scheme => anyhow::bail!("unsupported store {:?} for {}", scheme, url),
+    };
+    Ok((store, path))
+}
+
+impl CloudPath {
+    /// Last component of the path, if any
+    pub fn filename(&self) -> Option<String> {
+        match self {
+            CloudPath::Local(path) => Some(path.file_name()?.to_string_lossy().into_owned()),
+            CloudPath::Remote(url) => {
+                let (_, path) = ObjectStoreScheme::parse(url).ok()?;
+                path.filename().map(String::from)
+            }
+        }
+    }
+
+    /// Append a component to the path
+    pub fn push(&mut self, name: &str) {
+        match self {
+            CloudPath::Local(path) => path.push(name),
+            CloudPath::Remote(url) => {
+                if let Ok(mut segments) = url.path_segments_mut() {
+                    segments.pop_if_empty().push(name);
+                }
+            }
+        }
+    }
+
+    /// The path of a sibling named `name`
+    pub fn with_file_name(&self, name: &str) -> CloudPath {
+        match self {
+            CloudPath::Local(path) => CloudPath::Local(path.with_file_name(name)),
+            CloudPath::Remote(url) => {
+                let mut url = url.clone();
+                if let Ok(mut segments) = url.path_segments_mut() {
+                    segments.pop().push(name);
+                }
+                CloudPath::Remote(url)
+            }
+        }
+    }
+
+    /// Create a local directory, and its parents. Object stores have no
+    /// directories, so this does nothing for remote paths
+    pub fn mkdir(&self) -> anyhow::Result<()> {
+        if let CloudPath::Local(path) = self {
+            std::fs::create_dir_all(path)?;
+        }
+        Ok(())
+    }
+
+    /// Stream the contents of the file
+    pub async fn read(&self) -> anyhow::Result<Box<dyn AsyncBufRead + Unpin + Send>> {
+        match self {
+            CloudPath::Local(path) => Ok(Box::new(std::io::Cursor::new(map_file(path)?))),
+            CloudPath::Remote(url) => {
+                use futures::TryStreamExt;
+                let (store, path) = object_store(url).await?;
+                let stream = store
+                    .get(&path)
+                    .await?
+                    .into_stream()
+                    .map_err(std::io::Error::other);
+                Ok(Box::new(tokio_util::io::StreamReader::new(stream)))
+            }
+        }
+    }
+
+    /// Read the whole file into memory
+    pub async fn read_bytes(&self) -> anyhow::Result<bytes::Bytes> {
+        match self {
+            CloudPath::Local(path) => Ok(tokio::fs::read(path).await?.into()),
+            CloudPath::Remote(url) => {
+                let (store, path) = object_store(url).await?;
+                Ok(store.get(&path).await?.bytes().await?)
+            }
+        }
+    }
+
+    /// When the file was last modified, or `None` if it does not exist
+    pub async fn modified(&self) -> anyhow::Result<Option<SystemTime>> {
+        match self {
+            CloudPath::Local(path) => match tokio::fs::metadata(path).await {
+                Ok(metadata) => Ok(Some(metadata.modified()?)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            CloudPath::Remote(url) => {
+                let (store, path) = object_store(url).await?;
+                match store.head(&path).await {
+                    Ok(meta) => Ok(Some(meta.last_modified.into())),
+                    Err(object_store::Error::NotFound { .. }) => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    }
+
+    /// Size of the file in bytes
+    pub async fn size(&self) -> anyhow::Result<u64> {
+        match self {
+            CloudPath::Local(path) => Ok(tokio::fs::metadata(path).await?.len()),
+            CloudPath::Remote(url) => {
+                let (store, path) = object_store(url).await?;
+                Ok(store.head(&path).await?.size as u64)
+            }
+        }
+    }
+
+    /// Check that the file exists and can be read, without reading it
+    pub async fn check_read(&self) -> anyhow::Result<()> {
+        match self {
+            CloudPath::Local(path) => drop(std::fs::File::open(path)?),
+            CloudPath::Remote(url) => {
+                let (store, path) = object_store(url).await?;
+                store.head(&path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the file could be written, without writing it. Local files
+    /// are checked by creating, and removing, an empty file in the nearest
+    /// existing directory. Writes to object stores cannot be checked without
+    /// making one, so only the credentials, and access to the bucket, are
+    /// checked by listing the key
+    pub async fn check_write(&self) -> anyhow::Result<()> {
+        match self {
+            CloudPath::Local(path) => {
+                let mut dir = path.parent().unwrap_or(Path::new(""));
+                while !dir.as_os_str().is_empty() && !dir.exists() {
+                    dir = dir.parent().unwrap_or(Path::new(""));
+                }
+                let name = format!(".mz_parquet_check_{}", std::process::id());
+                let probe = match dir.as_os_str().is_empty() {
+                    true => PathBuf::from(name),
+                    false => dir.join(name),
+                };
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&probe)?;
+                std::fs::remove_file(&probe)?;
+            }
+            CloudPath::Remote(url) => {
+                let (store, path) = object_store(url).await?;
+                store.list_with_delimiter(Some(&path)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Start writing the file, replacing it once it is finished. Remote files
+    /// are uploaded while they are written, in parts, rather than held in
+    /// memory
+    pub async fn create(&self) -> anyhow::Result<CloudWriter> {
+        self.create_with_progress(None).await
+    }
+
+    /// Start writing the file, as for [`CloudPath::create`], reporting the
+    /// bytes of remote files to `progress` as they are uploaded
+    pub async fn create_with_progress(
+        &self,
+        progress: Option<Arc<dyn Progress>>,
+    ) -> anyhow::Result<CloudWriter> {
+        match self {
+            CloudPath::Local(path) => {
+                create_parent(path)?;
+                Ok(CloudWriter::Local(LocalFile::create(path)?))
+            }
+            CloudPath::Remote(url) => {
+                let (store, path) = object_store(url).await?;
+                Ok(CloudWriter::upload(store, path, Callback::new(progress)))
+            }
+        }
+    }
+
+    /// Write `bytes` to the file, replacing it. Large objects are uploaded in
+    /// parts
+    pub async fn write_bytes(&self, bytes: Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            CloudPath::Local(path) => {
+                create_parent(path)?;
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || {
+                    let mut file = LocalFile::create(&path)?;
+                    std::io::Write::write_all(&mut file.writer, &bytes)?;
+                    file.finish()
+                })
+                .await??
+            }
+            CloudPath::Remote(url) => {
+                let (store, path) = object_store(url).await?;
+                let mut writer = BufWriter::new(store, path);
+                writer.write_all(&bytes).await?;
+                writer.shutdown().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create the directory a local file is written to, if it does not exist
+fn create_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+/// A local file being written. It is written to a hidden temporary file in
+/// the same directory, which replaces the file once it is finished, so that
+/// a failed conversion never leaves a truncated file at `path`. The temporary
+/// file is removed if it is dropped before then
+pub struct LocalFile {
+    writer: std::io::BufWriter<std::fs::File>,
+    temp: PathBuf,
+    path: PathBuf,
+    finished: bool,
+}
+
+impl LocalFile {
+    fn create(path: &Path) -> std::io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = path.with_file_name(format!(
+            ".{}.{}-{}.tmp",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = std::fs::File::create(&temp)?;
+        Ok(LocalFile {
+            writer: std::io::BufWriter::new(file),
+            temp,
+            path: path.into(),
+            finished: false,
+        })
+    }
+
+    /// Flush the file to disk, and move it into place
+    fn finish(mut self) -> anyhow::Result<()> {
+        std::io::Write::flush(&mut self.writer)?;
+        self.writer.get_ref().sync_all()?;
+        std::fs::rename(&self.temp, &self.path)?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for LocalFile {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(err) = std::fs::remove_file(&self.temp) {
+                log::warn!("failed to remove {}: {}", self.temp.display(), err);
+            }
+        }
+    }
+}
+
+/// Whether `path` holds glob wildcards: `*`, `?` or `[...]`
+pub fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// Wildcards do not match `/`, except for `**` components
+const MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Paths matching a glob pattern, e.g. `s3://bucket/runs/*.mzML` or
+/// `data/**/*.mzML`, in sorted order. Remote patterns are matched against a
+/// listing of the prefix before the first wildcard, so wildcards should come
+/// as late as possible in large buckets
+pub async fn glob(pattern: &str) -> anyhow::Result<Vec<CloudPath>> {
+    if !pattern.contains("://") {
+        let mut paths = glob::glob_with(pattern, MATCH_OPTIONS)?
+            .map(|path| path.map(CloudPath::Local))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+        return Ok(paths);
+    }
+
+    // The directory holding the first wildcard is listed
+    let wildcard = pattern.find(['*', '?', '[']).unwrap_or(pattern.len());
+    let split = pattern[..wildcard].rfind('/').map_or(0, |idx| idx + 1);
+    let url = match pattern[..split].parse::<CloudPath>()? {
+        CloudPath::Remote(url) => url,
+        CloudPath::Local(_) => anyhow::bail!("invalid pattern: {}", pattern),
+    };
+    let (store, prefix) = object_store(&url).await?;
+    let key = match prefix.as_ref() {
+        "" => pattern[split..].to_string(),
+        prefix => format!("{}/{}", prefix, &pattern[split..]),
+    };
+    list_matching(store.as_ref(), &url, &prefix, &glob::Pattern::new(&key)?).await
+}
+
+/// Objects under `prefix` whose keys match `pattern`, as URLs relative to
+/// `base`
+async fn list_matching(
+    store: &dyn ObjectStore,
+    base: &Url,
+    prefix: &ObjectPath,
+    pattern: &glob::Pattern,
+) -> anyhow::Result<Vec<CloudPath>> {
+    use futures::TryStreamExt;
+
+    let prefix = Some(prefix).filter(|prefix| !prefix.as_ref().is_empty());
+    let objects = store.list(prefix).try_collect::<Vec<_>>().await?;
+    let mut paths = objects
+        .into_iter()
+        .filter(|object| pattern.matches_with(object.location.as_ref(), MATCH_OPTIONS))
+        .map(|object| {
+            let mut url = base.clone();
+            if let Ok(mut segments) = url.path_segments_mut() {
+                segments.clear().extend(object.location.parts());
+            }
+            CloudPath::Remote(url)
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Size of the chunks handed from a [`CloudWriter`] to its upload task
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Chunks queued for upload before writes wait for the upload to catch up
+const QUEUED_CHUNKS: usize = 16;
+
+/// A file being written. Remote files are uploaded by a background task,
+/// which switches to a multipart upload once the first part is full, so
+/// uploads overlap with writing
+///
+/// Writes block while the upload falls behind, so remote files must be
+/// written from a blocking thread, e.g. in [`tokio::task::spawn_blocking`],
+/// rather than an async task. The file is only complete once
+/// [`CloudWriter::finish`] returns: a file dropped before then leaves
+/// nothing behind, as uploads are aborted and local files removed
+pub enum CloudWriter {
+    Local(LocalFile),
+    Upload {
+        buffer: Vec<u8>,
+        chunks: tokio::sync::mpsc::Sender<Upload>,
+        task: tokio::task::JoinHandle<anyhow::Result<()>>,
+    },
+}
+
+/// Sent by a [`CloudWriter`] to its upload task
+pub enum Upload {
+    Chunk(Vec<u8>),
+    /// Everything was written, so the upload is completed
+    Finish,
+}
+
+impl CloudWriter {
+    fn upload(store: Arc<dyn ObjectStore>, path: ObjectPath, progress: Callback) -> Self {
+        let (chunks, mut rx) = tokio::sync::mpsc::channel::<Upload>(QUEUED_CHUNKS);
+        let task = tokio::spawn(async move {
+            let mut writer = BufWriter::new(store, path);
+            loop {
+                match rx.recv().await {
+                    Some(Upload::Chunk(chunk)) => {
+                        if let Err(e) = writer.write_all(&chunk).await {
+                            writer.abort().await?;
+                            return Err(e.into());
+                        }
+                        progress.bytes_uploaded(chunk.len() as u64);
+                    }
+                    Some(Upload::Finish) => {
+                        writer.shutdown().await?;
+                        return Ok(());
+                    }
+                    // The writer was dropped, e.g. as conversion failed
+                    None => {
+                        writer.abort().await?;
+                        anyhow::bail!("upload was not finished");
+                    }
+                }
+            }
+        });
+        CloudWriter::Upload {
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            chunks,
+            task,
+        }
+    }
+
+    fn send(chunks: &tokio::sync::mpsc::Sender<Upload>, chunk: Vec<u8>) -> std::io::Result<()> {
+        chunks
+            .blocking_send(Upload::Chunk(chunk))
+            .map_err(|_| std::io::Error::other("upload failed"))
+    }
+
+    /// Flush the file to disk, or complete the upload
+    pub async fn finish(self) -> anyhow::Result<()> {
+        match self {
+            CloudWriter::Local(file) => file.finish()?,
+            CloudWriter::Upload {
+                buffer,
+                chunks,
+                task,
+            } => {
+                // An error sending the last chunk is reported by the task
+                let _ = chunks.send(Upload::Chunk(buffer)).await;
+                let _ = chunks.send(Upload::Finish).await;
+                task.await??;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::io::Write for CloudWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CloudWriter::Local(file) => file.writer.write(buf),
+            CloudWriter::Upload { buffer, chunks, .. } => {
+                buffer.extend_from_slice(buf);
+                if buffer.len() >= CHUNK_SIZE {
+                    let chunk = std::mem::replace(buffer, Vec::with_capacity(CHUNK_SIZE));
+                    Self::send(chunks, chunk)?;
+                }
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CloudWriter::Local(file) => file.writer.flush(),
+            // Parts are only uploaded once they are full
+            CloudWriter::Upload { .. } => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_paths() -> anyhow::Result<()> {
+        let path = "data/run.mzML".parse::<CloudPath>()?;
+        assert_eq!(path, CloudPath::Local("data/run.mzML".into()));
+        assert_eq!(
+            "file:///data/run.mzML".parse::<CloudPath>()?,
+            CloudPath::Local("/data/run.mzML".into())
+        );
+
+        for uri in [
+            "s3://bucket/runs/a%20run.mzML",
+            "gs://bucket/runs/a%20run.mzML",
+            "az://container/runs/a%20run.mzML",
+            "https://example.com/runs/a%20run.mzML",
+        ] {
+            let path = uri.parse::<CloudPath>()?;
+            assert_eq!(path.to_string(), uri);
+            assert_eq!(path.filename().as_deref(), Some("a run.mzML"));
+            let output = path.with_file_name("a run.mzparquet");
+            assert_eq!(output.filename().as_deref(), Some("a run.mzparquet"));
+        }
+
+        let mut dir = "s3://bucket/out/".parse::<CloudPath>()?;
+        dir.push("run.mzparquet");
+        assert_eq!(dir.to_string(), "s3://bucket/out/run.mzparquet");
+        assert!("memory:///run.mzML".parse::<CloudPath>().is_err());
+        Ok(())
+    }
+
+    // A single threaded runtime, as built by the Python and C bindings
+    #[tokio::test]
+    async fn upload_while_writing() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let store = Arc::new(object_store::memory::InMemory::new());
+        let path = ObjectPath::from("runs/run.mzparquet");
+        let data = (0..3 * CHUNK_SIZE + 17)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let mut w = CloudWriter::upload(store.clone(), path.clone(), Callback::default());
+        let w = tokio::task::spawn_blocking({
+            let data = data.clone();
+            move || {
+                for chunk in data.chunks(100_000) {
+                    w.write_all(chunk)?;
+                }
+                anyhow::Ok(w)
+            }
+        })
+        .await??;
+        w.finish().await?;
+        assert_eq!(store.get(&path).await?.bytes().await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn abort_dropped_upload() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let store = Arc::new(object_store::memory::InMemory::new());
+        let path = ObjectPath::from("runs/run.mzparquet");
+        let mut w = CloudWriter::upload(store.clone(), path.clone(), Callback::default());
+        tokio::task::spawn_blocking(move || w.write_all(&vec![0; 2 * CHUNK_SIZE])).await??;
+
+        // The upload task holds the other reference to the store until it ends
+        while Arc::strong_count(&store) > 1 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(matches!(
+            store.head(&path).await,
+            Err(object_store::Error::NotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replace_finished_files() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("mz_parquet-cloud-{}", std::process::id()));
+        let path = CloudPath::Local(dir.join("run.mzparquet"));
+        path.write_bytes(b"old".to_vec()).await?;
+
+        // Nothing is left behind by a dropped file
+        let mut w = path.create().await?;
+        w.write_all(b"partial")?;
+        drop(w);
+        assert_eq!(std::fs::read(dir.join("run.mzparquet"))?, b"old");
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        let mut w = path.create().await?;
+        w.write_all(b"new")?;
+        w.finish().await?;
+        assert_eq!(std::fs::read(dir.join("run.mzparquet"))?, b"new");
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn glob_objects() -> anyhow::Result<()> {
+        let store = object_store::memory::InMemory::new();
+        for key in [
+            "runs/a.mzML",
+            "runs/b.mzML",
+            "runs/b.mzparquet",
+            "runs/2024/c.mzML",
+            "other/d.mzML",
+        ] {
+            store.put(&ObjectPath::from(key), vec![0].into()).await?;
+        }
+        let base = Url::parse("s3://bucket/runs/")?;
+        let prefix = ObjectPath::from("runs");
+        let matches = |pattern: &str| {
+            let pattern = glob::Pattern::new(pattern).unwrap();
+            let (store, base, prefix) = (&store, &base, &prefix);
+            async move {
+                let paths = list_matching(store, base, prefix, &pattern).await?;
+                anyhow::Ok(paths.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+            }
+        };
+        assert_eq!(
+            matches("runs/*.mzML").await?,
+            ["s3://bucket/runs/a.mzML", "s3://bucket/runs/b.mzML"]
+        );
+        assert_eq!(
+            matches("runs/**/*.mzML").await?,
+            [
+                "s3://bucket/runs/2024/c.mzML",
+                "s3://bucket/runs/a.mzML",
+                "s3://bucket/runs/b.mzML"
+            ]
+        );
+        assert_eq!(matches("runs/b.*").await?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn s3_endpoint() {
+        let url = Url::parse("s3://bucket/run.mzML").unwrap();
+        let mut options = CloudOptions::default();
+        options
+            .set_s3_endpoint(Some("http://minio:9000".into()))
+            .set_s3_path_style(Some(true))
+            .set_s3_requester_pays(Some(true))
+            .set_timeout(Some(Duration::from_secs(120)));
+        let builder = options.s3(&url, None);
+        let config = |key| builder.get_config_value(&key);
+        assert_eq!(
+            config(AmazonS3ConfigKey::Endpoint).as_deref(),
+            Some("http://minio:9000")
+        );
+        assert_eq!(
+            config(AmazonS3ConfigKey::VirtualHostedStyleRequest).as_deref(),
+            Some("false")
+        );
+        assert_eq!(
+            config(AmazonS3ConfigKey::Client(
+                object_store::ClientConfigKey::AllowHttp
+            ))
+            .as_deref(),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn retries_and_timeouts() {
+        let options = CloudOptions::default();
+        assert_eq!(
+            options.retry().max_retries,
+            RetryConfig::default().max_retries
+        );
+        assert!(options.client().is_empty());
+
+        let mut options = CloudOptions::default();
+        options
+            .set_max_retries(Some(3))
+            .set_retry_timeout(Some(Duration::from_secs(60)))
+            .set_timeout(Some(Duration::from_secs(120)))
+            .set_connect_timeout(Some(Duration::from_millis(1500)));
+        let retry = options.retry();
+        assert_eq!(retry.max_retries, 3);
+        assert_eq!(retry.retry_timeout, Duration::from_secs(60));
+        assert_eq!(
+            options.client(),
+            vec![
+                (ClientConfigKey::Timeout, "120000ms".to_string()),
+                (ClientConfigKey::ConnectTimeout, "1500ms".to_string()),
+            ]
+        );
+    }
+
+    /// Hands out numbered credentials, expiring after `lifetime`
+    #[derive(Debug)]
+    struct Numbered(std::sync::atomic::AtomicUsize, Duration);
+
+    impl ProvideCredentials for Numbered {
+        fn provide_credentials<'a>(
+            &'a self,
+        ) -> aws_credential_types::provider::future::ProvideCredentials<'a>
+        where
+            Self: 'a,
+        {
+            let n = self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            aws_credential_types::provider::future::ProvideCredentials::ready(Ok(
+                aws_credential_types::Credentials::new(
+                    format!("key{n}"),
+                    "secret",
+                    Some("token".into()),
+                    Some(SystemTime::now() + self.1),
+                    "test",
+                ),
+            ))
+        }
+    }
+
+    fn numbered_sdk(lifetime: Duration) -> AwsSdk {
+        AwsSdk {
+            credentials: Arc::new(SdkCredentials {
+                provider: SharedCredentialsProvider::new(Numbered(Default::default(), lifetime)),
+                cached: tokio::sync::Mutex::new(None),
+            }),
+            region: Some("eu-west-1".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_sdk_credentials() -> anyhow::Result<()> {
+        let sdk = numbered_sdk(Duration::from_secs(3600));
+        let credential = sdk.credentials.get_credential().await?;
+        assert_eq!(credential.key_id, "key0");
+        assert_eq!(credential.secret_key, "secret");
+        assert_eq!(credential.token.as_deref(), Some("token"));
+        let credential = sdk.credentials.get_credential().await?;
+        assert_eq!(credential.key_id, "key0");
+
+        // Credentials about to expire are refreshed
+        let sdk = numbered_sdk(Duration::from_secs(60));
+        assert_eq!(sdk.credentials.get_credential().await?.key_id, "key0");
+        assert_eq!(sdk.credentials.get_credential().await?.key_id, "key1");
+        Ok(())
+    }
+
+    #[test]
+    fn s3_profile_region_and_requester_pays() {
+        let url = Url::parse("s3://bucket/run.mzML").unwrap();
+        let mut options = CloudOptions::default();
+        options.set_s3_requester_pays(Some(true));
+        let builder = options.s3(&url, Some(&numbered_sdk(Duration::from_secs(3600))));
+        let config = |key| builder.get_config_value(&key);
+        assert_eq!(
+            config(AmazonS3ConfigKey::RequestPayer).as_deref(),
+            Some("true")
+        );
+        // A region from the environment takes precedence over the profile's
+        let region = std::env::var("AWS_DEFAULT_REGION")
+            .or_else(|_| std::env::var("AWS_REGION"))
+            .unwrap_or_else(|_| "eu-west-1".into());
+        assert_eq!(config(AmazonS3ConfigKey::Region), Some(region));
+    }
+}
@@ -0,0 +1,41 @@
+//! Open long format mzparquet files as [`polars`] data frames.
+//!
+//! [`scan_mzparquet`] returns a [`LazyFrame`], so that filters built with
+//! [`ppm_window`], [`da_window`] and [`rt_window`] are pushed down into the
+//! parquet scan and row groups that cannot match are never decoded. The
+//! `intensity` column is cast to `Float64`, whichever type it was written with
+//! (see [`crate::write_long::WriterOptions::set_intensity_type`]), so that
+//! frames from different files can be concatenated. All other columns keep
+//! the types of the long format schema.
+//!
+//! Retention times are in the unit recorded in the file, see
+//! [`crate::write_long::RtUnit::from_metadata`].
+use polars::prelude::*;
+
+/// Lazily scan a long format file (or a glob of files)
+pub fn scan_mzparquet(path: &str) -> PolarsResult<LazyFrame> {
+    Ok(LazyFrame::scan_parquet(path, ScanArgsParquet::default())?
+        .with_column(col("intensity").cast(DataType::Float64)))
+}
+
+/// Read a whole long format file into memory
+pub fn to_polars(path: &str) -> PolarsResult<DataFrame> {
+    scan_mzparquet(path)?.collect()
+}
+
+/// Ions within `ppm` parts per million of `mz`
+pub fn ppm_window(mz: f64, ppm: f64) -> Expr {
+    da_window(mz, mz * ppm / 1E6)
+}
+
+/// Ions within `da` Daltons of `mz`
+pub fn da_window(mz: f64, da: f64) -> Expr {
+    col("mz")
+        .gt_eq(lit(mz - da))
+        .and(col("mz").lt_eq(lit(mz + da)))
+}
+
+/// Ions acquired between `start` and `end` (inclusive)
+pub fn rt_window(start: f32, end: f32) -> Expr {
+    col("rt").gt_eq(lit(start)).and(col("rt").lt_eq(lit(end)))
+}
@@ -0,0 +1,180 @@
+//! Deisotoping of MSn spectra, collapsing isotope clusters into single
+//! charge, monoisotopic peaks.
+//!
+//! Peaks are visited from the highest m/z down. A peak that sits one neutron
+//! (divided by a charge state) above a more intense peak is treated as an
+//! isotope of it: its intensity is added to the lighter peak, and it is
+//! removed. Peaks at the start of a cluster are then moved to the m/z of
+//! their singly charged ion. Peaks without isotopes are kept as they are.
+//!
+//! The lighter peak of a cluster must be the more intense one, which holds
+//! for the peptide fragments that dominate MS2 spectra (below ~1800 Da), so
+//! heavier ions may be left partially deisotoped.
+//!
+//! Enabled with [`crate::write_long::WriterOptions::set_deisotope`], which
+//! records the settings in the footer metadata under [`DEISOTOPE_KEY`].
+use crate::mzml::RawSpectrum;
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+use serde::{Deserialize, Serialize};
+
+/// Footer metadata key holding the [`Deisotope`] settings applied to MSn
+/// spectra, as JSON
+pub const DEISOTOPE_KEY: &str = "deisotope";
+
+/// Mass difference between the carbon 13 and carbon 12 isotopes
+const NEUTRON: f64 = 1.00335;
+const PROTON: f64 = 1.007276;
+
+/// Settings for deisotoping MSn spectra
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Deisotope {
+    /// Tolerance for the spacing of isotope peaks, in ppm
+    pub ppm: f32,
+    /// Highest fragment charge state considered
+    pub max_charge: u8,
+}
+
+impl Default for Deisotope {
+    fn default() -> Self {
+        Self {
+            ppm: 10.0,
+            max_charge: 4,
+        }
+    }
+}
+
+impl Deisotope {
+    /// The settings used to deisotope a file, if it was deisotoped
+    pub fn from_metadata(metadata: &ParquetMetaData) -> anyhow::Result<Option<Self>> {
+        Self::from_key_value(metadata.file_metadata().key_value_metadata())
+    }
+
+    pub(crate) fn from_key_value(kv: Option<&Vec<KeyValue>>) -> anyhow::Result<Option<Self>> {
+        kv.into_iter()
+            .flatten()
+            .find(|kv| kv.key == DEISOTOPE_KEY)
+            .and_then(|kv| kv.value.as_deref())
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    pub(crate) fn to_key_value(self) -> serde_json::Result<KeyValue> {
+        Ok(KeyValue {
+            key: DEISOTOPE_KEY.into(),
+            value: Some(serde_json::to_string(&self)?),
+        })
+    }
+
+    /// Deisotope a centroided MSn spectrum. Returns `None` for MS1 and profile
+    /// spectra, which are written as they are.
+    ///
+    /// Per-ion arrays keep the values of the monoisotopic peak, except for
+    /// charges, which no longer apply once every peak is singly charged
+    pub fn deisotope(&self, spectrum: &RawSpectrum) -> Option<RawSpectrum> {
+        if spectrum.ms_level < 2 || !spectrum.centroid {
+            return None;
+        }
+        let n = spectrum.mz.len().min(spectrum.intensity.len());
+        let mut order = (0..n).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| spectrum.mz[a].total_cmp(&spectrum.mz[b]));
+
+        let mut intensity = order
+            .iter()
+            .map(|&i| spectrum.intensity[i])
+            .collect::<Vec<_>>();
+        // Clusters are matched on the heights of the original peaks, rather
+        // than the intensities merged into them
+        let height = |i: usize| spectrum.intensity[order[i]];
+        // Charge state of the cluster each peak starts, and whether it has
+        // been merged into a lighter peak
+        let mut charge = vec![None; n];
+        let mut merged = vec![false; n];
+
+        for i in (0..n).rev() {
+            let mz = spectrum.mz[order[i]];
+            let charges = match charge[i] {
+                Some(z) => z..=z,
+                None => 1..=self.max_charge,
+            };
+            for z in charges.rev() {
+                let expected = mz - NEUTRON / z as f64;
+                let delta = expected * self.ppm as f64 / 1E6;
+                let parent = (0..i)
+                    .rev()
+                    .map(|j| (j, spectrum.mz[order[j]]))
+                    .take_while(|&(_, mz)| mz >= expected - delta)
+                    .filter(|&(j, mz)| mz <= expected + delta && height(j) > height(i))
+                    .map(|(j, _)| j)
+                    .find(|&j| !merged[j] && charge[j].is_none_or(|c| c == z));
+                if let Some(j) = parent {
+                    intensity[j] += intensity[i];
+                    charge[j] = Some(z);
+                    merged[i] = true;
+                    break;
+                }
+            }
+        }
+
+        let mut peaks = (0..n)
+            .filter(|&i| !merged[i])
+            .map(|i| {
+                let mz = match charge[i] {
+                    Some(z) if z > 1 => (spectrum.mz[order[i]] - PROTON) * z as f64 + PROTON,
+                    _ => spectrum.mz[order[i]],
+                };
+                (mz, intensity[i], order[i])
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let per_ion = |values: &[f32]| match values.len() == n {
+            true => peaks.iter().map(|&(_, _, i)| values[i]).collect(),
+            false => Vec::new(),
+        };
+        Some(RawSpectrum {
+            mz: peaks.iter().map(|&(mz, _, _)| mz).collect(),
+            intensity: peaks.iter().map(|&(_, int, _)| int).collect(),
+            ion_mobility: per_ion(&spectrum.ion_mobility),
+            noise: per_ion(&spectrum.noise),
+            baseline: per_ion(&spectrum.baseline),
+            charge: Vec::new(),
+            ..spectrum.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn collapse_isotopes() {
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            centroid: true,
+            // A singly charged cluster at 300, a doubly charged cluster at
+            // 500.5 (1000 Da singly charged), and a lone peak
+            mz: vec![
+                300.0,
+                300.0 + NEUTRON,
+                400.0,
+                500.5,
+                500.5 + NEUTRON / 2.0,
+                500.5 + NEUTRON,
+            ],
+            intensity: vec![100.0, 50.0, 10.0, 80.0, 60.0, 20.0],
+            ..Default::default()
+        };
+        let deisotoped = Deisotope::default().deisotope(&spectrum).unwrap();
+        assert_eq!(deisotoped.intensity, vec![150.0, 10.0, 160.0]);
+        assert_eq!(deisotoped.mz[..2], [300.0, 400.0]);
+        assert!((deisotoped.mz[2] - (2.0 * 500.5 - PROTON)).abs() < 1e-9);
+
+        let ms1 = RawSpectrum {
+            ms_level: 1,
+            ..spectrum
+        };
+        assert!(Deisotope::default().deisotope(&ms1).is_none());
+    }
+}
@@ -0,0 +1,42 @@
+//! Append converted runs to a [Delta Lake](https://delta.io/) table,
+//! partitioned by `file_id`.
+//!
+//! Each run is converted to the long format table in memory and appended in
+//! a single commit, so a run is either fully present in the table or not at
+//! all. Delta Lake has no unsigned integer types, so the unsigned columns
+//! are widened (see [`signed_record_batches`]).
+use crate::write_arrow::signed_record_batches;
+use arrow_array::RecordBatch;
+use deltalake::{protocol::SaveMode, DeltaOps, DeltaTable};
+use parquet::file::reader::ChunkReader;
+
+/// Column the table is partitioned by
+pub const PARTITION_COLUMN: &str = "file_id";
+
+/// Decode a long format mzparquet file, written with a `file_id` column,
+/// into record batches with Delta compatible types
+pub fn record_batches<R: 'static + ChunkReader>(r: R) -> anyhow::Result<Vec<RecordBatch>> {
+    let batches = signed_record_batches(r)?;
+    if let Some(batch) = batches.first() {
+        if batch.schema().field_with_name(PARTITION_COLUMN).is_err() {
+            anyhow::bail!(
+                "Delta tables are partitioned by `{}`, which the file does not have",
+                PARTITION_COLUMN
+            );
+        }
+    }
+    Ok(batches)
+}
+
+/// Append `batches` to the Delta table at `table_uri` (a local path or an
+/// `s3://` URI), creating the table if it does not exist yet
+pub async fn append(table_uri: &str, batches: Vec<RecordBatch>) -> anyhow::Result<DeltaTable> {
+    deltalake::aws::register_handlers(None);
+    let table = DeltaOps::try_from_uri(table_uri)
+        .await?
+        .write(batches)
+        .with_save_mode(SaveMode::Append)
+        .with_partition_columns([PARTITION_COLUMN])
+        .await?;
+    Ok(table)
+}
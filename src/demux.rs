@@ -0,0 +1,324 @@
+//! Demultiplexing of overlapping (staggered window) DIA acquisitions.
+//!
+//! Overlapping window schemes offset the isolation windows of alternate
+//! cycles by half a window, so that each half of a window is also covered by
+//! a window of the neighbouring cycles. Every MS2 spectrum is split into two
+//! spectra, one per half of its isolation window: the intensity of each peak
+//! is divided between the halves in proportion to the intensity of the same
+//! peak in the nearest spectra (before and after) whose windows cover only
+//! that half. Peaks missing from both neighbours are split evenly.
+//!
+//! Demultiplexed spectra get the id of the original spectrum with a `demux=0`
+//! (lower half) or `demux=1` (upper half) suffix, like ProteoWizard's, and
+//! keep its scan number. Spectra without neighbouring windows (e.g. from
+//! non-overlapping acquisitions), and MSX acquisitions with randomly
+//! multiplexed windows, are written as they are.
+//!
+//! Enabled with [`crate::write_long::WriterOptions::set_demultiplex`], which
+//! records the settings in the footer metadata under [`DEMULTIPLEX_KEY`].
+use crate::metadata::RunMetadata;
+use crate::mzml::{Chromatogram, MzMLError, RawSpectrum, SpectrumStream};
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Footer metadata key holding the [`Demultiplex`] settings, as JSON
+pub const DEMULTIPLEX_KEY: &str = "demultiplex";
+
+/// Maximum number of spectra read ahead while looking for the neighbours of
+/// a spectrum, bounding memory use for inputs without MS1 spectra
+const MAX_LOOKAHEAD: usize = 4096;
+
+/// Settings for demultiplexing overlapping DIA windows
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Demultiplex {
+    /// Tolerance for matching peaks in neighbouring spectra, in ppm
+    pub ppm: f32,
+}
+
+impl Default for Demultiplex {
+    fn default() -> Self {
+        Self { ppm: 10.0 }
+    }
+}
+
+/// Lower and upper bounds of the isolation window of an MS2 spectrum
+fn window(spectrum: &RawSpectrum) -> Option<(f64, f64)> {
+    if spectrum.ms_level != 2 {
+        return None;
+    }
+    let precursor = spectrum.precursors.first()?;
+    let target = precursor
+        .isolation_window_target
+        .map_or(precursor.mz, f64::from);
+    let lower = target - precursor.isolation_window_lower? as f64;
+    let upper = target + precursor.isolation_window_upper? as f64;
+    Some((lower, upper)).filter(|(lower, upper)| upper > lower)
+}
+
+impl Demultiplex {
+    /// The settings used to demultiplex a file, if it was demultiplexed
+    pub fn from_metadata(metadata: &ParquetMetaData) -> anyhow::Result<Option<Self>> {
+        metadata
+            .file_metadata()
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+            .find(|kv| kv.key == DEMULTIPLEX_KEY)
+            .and_then(|kv| kv.value.as_deref())
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    pub(crate) fn to_key_value(self) -> serde_json::Result<KeyValue> {
+        Ok(KeyValue {
+            key: DEMULTIPLEX_KEY.into(),
+            value: Some(serde_json::to_string(&self)?),
+        })
+    }
+
+    /// Split `spectrum` into the spectra of the two halves of its isolation
+    /// window, using the spectra acquired `before` (most recent first) and
+    /// `after` it. Returns `None` if it cannot be demultiplexed
+    pub fn demultiplex<'a, B, A>(
+        &self,
+        spectrum: &RawSpectrum,
+        before: B,
+        after: A,
+    ) -> Option<[RawSpectrum; 2]>
+    where
+        B: IntoIterator<Item = &'a RawSpectrum> + Clone,
+        A: IntoIterator<Item = &'a RawSpectrum> + Clone,
+    {
+        let (lower, upper) = window(spectrum)?;
+        let width = upper - lower;
+        let middle = (lower + upper) / 2.0;
+
+        // Neighbouring windows are centered on the bounds of this one
+        let neighbours = |center: f64| {
+            let covers = move |s: &&RawSpectrum| {
+                window(s).is_some_and(|(lo, hi)| {
+                    ((lo + hi) / 2.0 - center).abs() <= width * 0.05
+                        && ((hi - lo) - width).abs() <= width * 0.05
+                })
+            };
+            let before = before.clone().into_iter().find(covers);
+            let after = after.clone().into_iter().find(covers);
+            [before, after]
+                .into_iter()
+                .flatten()
+                .map(peaks)
+                .collect::<Vec<_>>()
+        };
+        let (low, high) = (neighbours(lower), neighbours(upper));
+        if low.is_empty() && high.is_empty() {
+            return None;
+        }
+
+        let halves = [(lower, middle), (middle, upper)];
+        let shares = spectrum
+            .mz
+            .iter()
+            .map(|&mz| {
+                // Mean intensity of the peak in the neighbours of each half
+                let [a, b] = [&low, &high].map(|neighbours| {
+                    neighbours
+                        .iter()
+                        .map(|peaks| intensity(peaks, mz, self.ppm))
+                        .sum::<f64>()
+                        / neighbours.len().max(1) as f64
+                });
+                match a + b > 0.0 {
+                    true => a / (a + b),
+                    false => 0.5,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Some(std::array::from_fn(|half| {
+            let (lo, hi) = halves[half];
+            let intensity = spectrum
+                .intensity
+                .iter()
+                .zip(&shares)
+                .map(|(&int, &share)| match half {
+                    0 => int * share,
+                    _ => int * (1.0 - share),
+                })
+                .collect::<Vec<_>>();
+            let keep = intensity.iter().map(|&int| int > 0.0).collect::<Vec<_>>();
+            let mut id = spectrum.id.clone();
+            id.extend_from_slice(format!(" demux={}", half).as_bytes());
+
+            let center = (lo + hi) / 2.0;
+            let offset = ((hi - lo) / 2.0) as f32;
+            let mut demultiplexed = crate::filter::retain(
+                &RawSpectrum {
+                    id,
+                    intensity,
+                    ..spectrum.clone()
+                },
+                &keep,
+            );
+            demultiplexed.total_ion_current = demultiplexed.intensity.iter().sum::<f64>() as f32;
+            for precursor in &mut demultiplexed.precursors {
+                precursor.mz = center;
+                precursor.isolation_window_target = Some(center as f32);
+                precursor.isolation_window_lower = Some(offset);
+                precursor.isolation_window_upper = Some(offset);
+            }
+            demultiplexed
+        }))
+    }
+}
+
+/// Peaks of `spectrum`, sorted by m/z
+fn peaks(spectrum: &RawSpectrum) -> Vec<(f64, f64)> {
+    let mut peaks = spectrum
+        .mz
+        .iter()
+        .copied()
+        .zip(spectrum.intensity.iter().copied())
+        .collect::<Vec<_>>();
+    peaks.sort_by(|a, b| a.0.total_cmp(&b.0));
+    peaks
+}
+
+/// Summed intensity of the `peaks` within `ppm` of `mz`
+fn intensity(peaks: &[(f64, f64)], mz: f64, ppm: f32) -> f64 {
+    let delta = mz * ppm as f64 / 1E6;
+    let start = peaks.partition_point(|p| p.0 < mz - delta);
+    peaks[start..]
+        .iter()
+        .take_while(|p| p.0 <= mz + delta)
+        .map(|p| p.1)
+        .sum()
+}
+
+/// Wraps a [`SpectrumStream`], demultiplexing its MS2 spectra. Spectra are
+/// read ahead until the next two MS1 spectra (the following cycles)
+pub struct DemultiplexedStream<'a, S> {
+    inner: &'a mut S,
+    demultiplex: Option<Demultiplex>,
+    /// Spectra read from `inner` that have not been demultiplexed yet
+    pending: VecDeque<RawSpectrum>,
+    /// Spectra of the previous cycles, before they were demultiplexed
+    previous: VecDeque<RawSpectrum>,
+    /// Demultiplexed spectra that have not been returned yet
+    ready: VecDeque<RawSpectrum>,
+    done: bool,
+}
+
+impl<'a, S: SpectrumStream> DemultiplexedStream<'a, S> {
+    /// Demultiplex the MS2 spectra of `inner`, or pass them through
+    /// unchanged if `demultiplex` is `None`
+    pub fn new(inner: &'a mut S, demultiplex: Option<Demultiplex>) -> Self {
+        Self {
+            inner,
+            demultiplex,
+            pending: VecDeque::new(),
+            previous: VecDeque::new(),
+            ready: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// True once the cycles following the next pending spectrum are read
+    fn lookahead_complete(&self) -> bool {
+        let ms1 = self.pending.iter().skip(1).filter(|s| s.ms_level == 1);
+        self.done || ms1.count() >= 2 || self.pending.len() >= MAX_LOOKAHEAD
+    }
+}
+
+impl<S: SpectrumStream> SpectrumStream for DemultiplexedStream<'_, S> {
+    async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+        let Some(demultiplex) = self.demultiplex else {
+            return self.inner.next_spectrum().await;
+        };
+
+        loop {
+            if let Some(spectrum) = self.ready.pop_front() {
+                return Ok(Some(spectrum));
+            }
+            if self.pending.is_empty() && self.done {
+                return Ok(None);
+            }
+            if !self.pending.is_empty() && self.lookahead_complete() {
+                let spectrum = self.pending.pop_front().expect("pending is not empty");
+                match demultiplex.demultiplex(&spectrum, self.previous.iter().rev(), &self.pending)
+                {
+                    Some(halves) => self.ready.extend(halves),
+                    None => self.ready.push_back(spectrum.clone()),
+                }
+
+                // Only the previous two cycles are kept
+                self.previous.push_back(spectrum);
+                while self.previous.iter().filter(|s| s.ms_level == 1).count() > 2
+                    || self.previous.len() > MAX_LOOKAHEAD
+                {
+                    self.previous.pop_front();
+                }
+                continue;
+            }
+            match self.inner.next_spectrum().await? {
+                Some(spectrum) => self.pending.push_back(spectrum),
+                None => self.done = true,
+            }
+        }
+    }
+
+    fn take_chromatograms(&mut self) -> Vec<Chromatogram> {
+        self.inner.take_chromatograms()
+    }
+
+    fn run_metadata(&self) -> RunMetadata {
+        self.inner.run_metadata()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::Precursor;
+
+    #[test]
+    fn split_overlapping_windows() {
+        let ms2 = |center: f64, mz: Vec<f64>, intensity: Vec<f64>| RawSpectrum {
+            id: format!("window={}", center).into_bytes(),
+            ms_level: 2,
+            precursors: vec![Precursor {
+                mz: center,
+                isolation_window_target: Some(center as f32),
+                isolation_window_lower: Some(10.0),
+                isolation_window_upper: Some(10.0),
+                ..Default::default()
+            }],
+            mz,
+            intensity,
+            ..Default::default()
+        };
+        // 500-520, overlapped by 490-510 before and 510-530 after
+        let spectrum = ms2(510.0, vec![300.0, 400.0, 450.0], vec![100.0, 80.0, 10.0]);
+        let before = ms2(500.0, vec![300.0], vec![30.0]);
+        let after = ms2(520.0, vec![300.0, 400.0], vec![10.0, 50.0]);
+
+        let [low, high] = Demultiplex::default()
+            .demultiplex(&spectrum, [&before], [&after])
+            .unwrap();
+        assert_eq!(low.id, b"window=510 demux=0");
+        assert_eq!(low.precursors[0].mz, 505.0);
+        assert_eq!(low.precursors[0].isolation_window_lower, Some(5.0));
+        assert_eq!(high.precursors[0].mz, 515.0);
+
+        assert_eq!(low.mz, vec![300.0, 450.0]);
+        assert_eq!(low.intensity, vec![75.0, 5.0]);
+        assert_eq!(high.mz, vec![300.0, 400.0, 450.0]);
+        assert_eq!(high.intensity, vec![25.0, 80.0, 5.0]);
+
+        // Without neighbouring windows, spectra are not demultiplexed
+        assert!(Demultiplex::default()
+            .demultiplex(&spectrum, [], [])
+            .is_none());
+    }
+}
@@ -0,0 +1,491 @@
+//! Spectrum and intensity filters, dropping spectra or low intensity peaks
+//! before they are written.
+//!
+//! A [`SpectrumFilter`] selects the spectra that are converted, e.g. only MS2
+//! spectra for a search engine, only MS1 spectra for feature finding, only
+//! the elution window of interest when triaging a run, a few scans to
+//! reproduce a bug report, or one polarity of a polarity switching run.
+//! Spectra that are left out still count towards the scan numbering, so
+//! precursor scans refer to the same spectra as in a full conversion.
+//!
+//! Noisy spectra (e.g. from QTOF instruments) hold many peaks that are never
+//! used in a search, and dominate the size of long format files. A
+//! [`PeakFilter`] drops peaks outside of an m/z window, or below an absolute
+//! or base peak relative intensity, and can limit MSn spectra to their most
+//! intense peaks (msconvert's "threshold count" filter), which shrinks DIA
+//! files severalfold.
+//!
+//! Filters are set with
+//! [`crate::write_long::WriterOptions::set_spectrum_filter`] and
+//! [`crate::write_long::WriterOptions::set_peak_filter`], and the filters
+//! applied to a file are recorded in its footer metadata under
+//! [`SPECTRUM_FILTER_KEY`] and [`PEAK_FILTER_KEY`].
+use crate::mzml::{Polarity, RawSpectrum};
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+use serde::{Deserialize, Serialize};
+
+/// Footer metadata key holding the [`SpectrumFilter`] applied to a file, as
+/// JSON
+pub const SPECTRUM_FILTER_KEY: &str = "spectrum_filter";
+
+/// Footer metadata key holding the [`PeakFilter`] applied to a file, as JSON
+pub const PEAK_FILTER_KEY: &str = "peak_filter";
+
+/// An inclusive range of MS levels, parsed from a single level (`2`) or a
+/// range (`1-2`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MsLevels {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl MsLevels {
+    pub fn contains(&self, level: u8) -> bool {
+        (self.min..=self.max).contains(&level)
+    }
+}
+
+impl std::str::FromStr for MsLevels {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let level = |s: &str| {
+            s.trim()
+                .parse::<u8>()
+                .map_err(|_| format!("invalid MS level `{}`", s))
+        };
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (level(min)?, level(max)?),
+            None => (level(s)?, level(s)?),
+        };
+        match min <= max {
+            true => Ok(MsLevels { min, max }),
+            false => Err(format!("empty MS level range `{}`", s)),
+        }
+    }
+}
+
+/// Selects the spectra that are written. The default filter keeps every
+/// spectrum
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpectrumFilter {
+    /// Only keep spectra at these MS levels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ms_levels: Option<MsLevels>,
+    /// Only keep spectra acquired within this (inclusive) retention time
+    /// window, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rt: Option<(f32, f32)>,
+    /// Only keep spectra within this (inclusive) range of the `scan` column:
+    /// vendor scan numbers, or the index of the spectrum in the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scans: Option<(u32, u32)>,
+    /// Only keep scans of this polarity, e.g. to split polarity switching
+    /// runs. Scans without a polarity are dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub polarity: Option<Polarity>,
+}
+
+impl SpectrumFilter {
+    /// True if the filter keeps every spectrum
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// The filter applied to a file, if any
+    pub fn from_metadata(metadata: &ParquetMetaData) -> anyhow::Result<Option<Self>> {
+        from_key_value(
+            metadata.file_metadata().key_value_metadata(),
+            SPECTRUM_FILTER_KEY,
+        )
+    }
+
+    pub(crate) fn to_key_value(self) -> serde_json::Result<KeyValue> {
+        to_key_value(&self, SPECTRUM_FILTER_KEY)
+    }
+
+    /// True if `spectrum`, the `index`th spectrum of its run, passes the
+    /// filter
+    pub fn keep(&self, index: u32, spectrum: &RawSpectrum) -> bool {
+        let scan = spectrum.scan_number.unwrap_or(index);
+        self.scans.is_none_or(|(lo, hi)| (lo..=hi).contains(&scan))
+            && self
+                .ms_levels
+                .is_none_or(|levels| levels.contains(spectrum.ms_level))
+            && self
+                .rt
+                .is_none_or(|(lo, hi)| (lo..=hi).contains(&spectrum.scan_start_time))
+            && self
+                .polarity
+                .is_none_or(|polarity| spectrum.polarity == Some(polarity))
+    }
+}
+
+/// Thresholds below which peaks are dropped, and the m/z window they are
+/// kept in. The default filter keeps every peak
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeakFilter {
+    /// Drop peaks with an intensity below this value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_intensity: Option<f32>,
+    /// Drop peaks with an intensity below this fraction (0-1) of the base
+    /// peak of their spectrum
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_relative_intensity: Option<f32>,
+    /// Keep only this many of the most intense peaks of each MSn spectrum.
+    /// MS1 spectra are not limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_n: Option<usize>,
+    /// Drop peaks outside of this (inclusive) m/z window, e.g. below the
+    /// reporter ions of untagged samples
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mz: Option<(f64, f64)>,
+}
+
+impl PeakFilter {
+    /// True if the filter keeps every peak
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// The filter applied to a file, if any
+    pub fn from_metadata(metadata: &ParquetMetaData) -> anyhow::Result<Option<Self>> {
+        Self::from_key_value(metadata.file_metadata().key_value_metadata())
+    }
+
+    pub(crate) fn from_key_value(kv: Option<&Vec<KeyValue>>) -> anyhow::Result<Option<Self>> {
+        from_key_value(kv, PEAK_FILTER_KEY)
+    }
+
+    pub(crate) fn to_key_value(self) -> serde_json::Result<KeyValue> {
+        to_key_value(&self, PEAK_FILTER_KEY)
+    }
+
+    /// Remove the peaks of `spectrum` that don't pass the filter, along with
+    /// their per-ion values. Returns `None` if every peak passes
+    pub fn filter(&self, spectrum: &RawSpectrum) -> Option<RawSpectrum> {
+        let base_peak = spectrum.intensity.iter().copied().fold(0.0, f64::max);
+        let threshold = f64::max(
+            self.min_intensity.map_or(0.0, f64::from),
+            self.min_relative_intensity
+                .map_or(0.0, |p| base_peak * f64::from(p)),
+        );
+        let mut keep = spectrum
+            .intensity
+            .iter()
+            .zip(&spectrum.mz)
+            .map(|(&int, mz)| {
+                int >= threshold && self.mz.is_none_or(|(lo, hi)| (lo..=hi).contains(mz))
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(n) = self.top_n.filter(|_| spectrum.ms_level > 1) {
+            let mut kept = (0..keep.len()).filter(|&i| keep[i]).collect::<Vec<_>>();
+            if kept.len() > n {
+                // Ties at the cutoff are broken in favor of lower m/z
+                kept.sort_by(|&a, &b| spectrum.intensity[b].total_cmp(&spectrum.intensity[a]));
+                for &i in &kept[n..] {
+                    keep[i] = false;
+                }
+            }
+        }
+        match keep.iter().all(|&keep| keep) {
+            true => None,
+            false => Some(retain(spectrum, &keep)),
+        }
+    }
+}
+
+fn from_key_value<T: serde::de::DeserializeOwned>(
+    kv: Option<&Vec<KeyValue>>,
+    key: &str,
+) -> anyhow::Result<Option<T>> {
+    kv.into_iter()
+        .flatten()
+        .find(|kv| kv.key == key)
+        .and_then(|kv| kv.value.as_deref())
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(Into::into)
+}
+
+fn to_key_value<T: Serialize>(filter: &T, key: &str) -> serde_json::Result<KeyValue> {
+    Ok(KeyValue {
+        key: key.into(),
+        value: Some(serde_json::to_string(filter)?),
+    })
+}
+
+/// Keep the peaks of `spectrum` for which `keep` is true. Per-ion arrays that
+/// don't have a value for every peak are cleared
+pub(crate) fn retain(spectrum: &RawSpectrum, keep: &[bool]) -> RawSpectrum {
+    fn select<T: Copy>(values: &[T], keep: &[bool]) -> Vec<T> {
+        match values.len() == keep.len() {
+            true => values
+                .iter()
+                .zip(keep)
+                .filter(|(_, &keep)| keep)
+                .map(|(value, _)| *value)
+                .collect(),
+            false => Vec::new(),
+        }
+    }
+    RawSpectrum {
+        mz: select(&spectrum.mz, keep),
+        intensity: select(&spectrum.intensity, keep),
+        ion_mobility: select(&spectrum.ion_mobility, keep),
+        noise: select(&spectrum.noise, keep),
+        baseline: select(&spectrum.baseline, keep),
+        charge: select(&spectrum.charge, keep),
+        ..spectrum.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn select_spectra() {
+        let levels = "1-2".parse::<MsLevels>().unwrap();
+        assert!(levels.contains(1) && levels.contains(2) && !levels.contains(3));
+        assert_eq!("2".parse(), Ok(MsLevels { min: 2, max: 2 }));
+        assert!("2-1".parse::<MsLevels>().is_err());
+        assert!("ms2".parse::<MsLevels>().is_err());
+
+        let filter = SpectrumFilter {
+            ms_levels: Some(MsLevels { min: 2, max: 2 }),
+            ..Default::default()
+        };
+        let ms1 = RawSpectrum {
+            ms_level: 1,
+            scan_start_time: 600.0,
+            ..Default::default()
+        };
+        assert!(!filter.keep(0, &ms1));
+        assert!(SpectrumFilter::default().keep(0, &ms1));
+
+        let window = SpectrumFilter {
+            rt: Some((300.0, 900.0)),
+            ..Default::default()
+        };
+        assert!(window.keep(0, &ms1));
+
+        let scans = SpectrumFilter {
+            scans: Some((10, 20)),
+            ..Default::default()
+        };
+        assert!(!scans.keep(0, &ms1));
+        assert!(scans.keep(12, &ms1));
+        let vendor = RawSpectrum {
+            scan_number: Some(15),
+            ..ms1.clone()
+        };
+        assert!(scans.keep(0, &vendor));
+
+        let negative = SpectrumFilter {
+            polarity: Some(Polarity::Negative),
+            ..Default::default()
+        };
+        assert!(!negative.keep(0, &ms1));
+        assert!(negative.keep(
+            0,
+            &RawSpectrum {
+                polarity: Some(Polarity::Negative),
+                ..ms1
+            }
+        ));
+        let kv = window.to_key_value().unwrap();
+        assert_eq!(
+            from_key_value::<SpectrumFilter>(Some(&vec![kv]), SPECTRUM_FILTER_KEY).unwrap(),
+            Some(window)
+        );
+    }
+
+    #[test]
+    fn intensity_thresholds() {
+        let spectrum = RawSpectrum {
+            mz: vec![100.0, 200.0, 300.0, 400.0],
+            intensity: vec![5.0, 1000.0, 50.0, 8.0],
+            charge: vec![1, 2, 3, 4],
+            ..Default::default()
+        };
+
+        let absolute = PeakFilter {
+            min_intensity: Some(10.0),
+            ..Default::default()
+        };
+        let filtered = absolute.filter(&spectrum).unwrap();
+        assert_eq!(filtered.mz, vec![200.0, 300.0]);
+        assert_eq!(filtered.charge, vec![2, 3]);
+        assert!(absolute.filter(&filtered).is_none());
+
+        let relative = PeakFilter {
+            min_relative_intensity: Some(0.01),
+            ..Default::default()
+        };
+        assert_eq!(
+            relative.filter(&spectrum).unwrap().intensity,
+            vec![1000.0, 50.0]
+        );
+
+        let kv = relative.to_key_value().unwrap();
+        assert_eq!(
+            PeakFilter::from_key_value(Some(&vec![kv])).unwrap(),
+            Some(relative)
+        );
+        assert!(PeakFilter::default().filter(&spectrum).is_none());
+
+        let window = PeakFilter {
+            mz: Some((150.0, 350.0)),
+            ..Default::default()
+        };
+        assert_eq!(window.filter(&spectrum).unwrap().mz, vec![200.0, 300.0]);
+
+        let top_n = PeakFilter {
+            top_n: Some(2),
+            ..Default::default()
+        };
+        // MS1 spectra keep every peak
+        assert!(top_n.filter(&spectrum).is_none());
+        let ms2 = RawSpectrum {
+            ms_level: 2,
+            ..spectrum
+        };
+        let filtered = top_n.filter(&ms2).unwrap();
+        assert_eq!(filtered.mz, vec![200.0, 300.0]);
+        assert!(top_n.filter(&filtered).is_none());
+    }
+
+    #[test]
+    fn retention_time_window() {
+        let at = |rt| RawSpectrum {
+            ms_level: 1,
+            scan_start_time: rt,
+            ..Default::default()
+        };
+        let window = SpectrumFilter {
+            rt: Some((300.0, 900.0)),
+            ..Default::default()
+        };
+        // Both ends are inclusive
+        assert!(window.keep(0, &at(300.0)));
+        assert!(window.keep(0, &at(900.0)));
+        assert!(!window.keep(0, &at(299.9)));
+        assert!(!window.keep(0, &at(900.1)));
+
+        // Windows left open at one end
+        let from = SpectrumFilter {
+            rt: Some((300.0, f32::MAX)),
+            ..Default::default()
+        };
+        assert!(from.keep(0, &at(1E6)));
+        assert!(!from.keep(0, &at(0.0)));
+        let until = SpectrumFilter {
+            rt: Some((f32::MIN, 300.0)),
+            ..Default::default()
+        };
+        assert!(until.keep(0, &at(0.0)));
+        assert!(!until.keep(0, &at(300.1)));
+    }
+
+    #[test]
+    fn scan_range() {
+        let scans = SpectrumFilter {
+            scans: Some((10, 20)),
+            ..Default::default()
+        };
+        let spectrum = RawSpectrum::default();
+        // Spectrum indices are used without vendor scan numbers, and both
+        // ends are inclusive
+        assert!(!scans.keep(9, &spectrum));
+        assert!(scans.keep(10, &spectrum));
+        assert!(scans.keep(20, &spectrum));
+        assert!(!scans.keep(21, &spectrum));
+
+        // Vendor scan numbers take precedence over the index
+        let numbered = |scan| RawSpectrum {
+            scan_number: Some(scan),
+            ..Default::default()
+        };
+        assert!(scans.keep(0, &numbered(20)));
+        assert!(!scans.keep(15, &numbered(21)));
+
+        let from = SpectrumFilter {
+            scans: Some((10, u32::MAX)),
+            ..Default::default()
+        };
+        assert!(from.keep(u32::MAX, &spectrum));
+        assert!(!from.keep(0, &spectrum));
+    }
+
+    #[test]
+    fn mz_window() {
+        let spectrum = RawSpectrum {
+            mz: vec![100.0, 200.0, 300.0, 400.0],
+            intensity: vec![5.0, 1000.0, 50.0, 8.0],
+            noise: vec![1.0, 2.0, 3.0, 4.0],
+            ..Default::default()
+        };
+        // Both ends are inclusive
+        let window = PeakFilter {
+            mz: Some((200.0, 300.0)),
+            ..Default::default()
+        };
+        let filtered = window.filter(&spectrum).unwrap();
+        assert_eq!(filtered.mz, vec![200.0, 300.0]);
+        assert_eq!(filtered.noise, vec![2.0, 3.0]);
+
+        let from = PeakFilter {
+            mz: Some((300.0, f64::MAX)),
+            ..Default::default()
+        };
+        assert_eq!(from.filter(&spectrum).unwrap().mz, vec![300.0, 400.0]);
+        let wide = PeakFilter {
+            mz: Some((0.0, 400.0)),
+            ..Default::default()
+        };
+        assert!(wide.filter(&spectrum).is_none());
+
+        // A window and a threshold both apply
+        let both = PeakFilter {
+            mz: Some((0.0, 350.0)),
+            min_intensity: Some(10.0),
+            ..Default::default()
+        };
+        assert_eq!(both.filter(&spectrum).unwrap().mz, vec![200.0, 300.0]);
+    }
+
+    #[test]
+    fn most_intense_peaks() {
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            mz: vec![100.0, 200.0, 300.0, 400.0],
+            intensity: vec![50.0, 1000.0, 50.0, 8.0],
+            charge: vec![1, 2, 3, 4],
+            ..Default::default()
+        };
+        let top = |n| PeakFilter {
+            top_n: Some(n),
+            ..Default::default()
+        };
+        // Peaks keep their m/z order, and ties at the cutoff go to the lower m/z
+        let filtered = top(2).filter(&spectrum).unwrap();
+        assert_eq!(filtered.mz, vec![100.0, 200.0]);
+        assert_eq!(filtered.charge, vec![1, 2]);
+        assert_eq!(
+            top(3).filter(&spectrum).unwrap().mz,
+            vec![100.0, 200.0, 300.0]
+        );
+        assert!(top(4).filter(&spectrum).is_none());
+        assert!(top(0).filter(&spectrum).unwrap().mz.is_empty());
+
+        // The limit applies to the peaks passing the other thresholds
+        let thresholds = PeakFilter {
+            top_n: Some(2),
+            mz: Some((150.0, 500.0)),
+            ..Default::default()
+        };
+        assert_eq!(thresholds.filter(&spectrum).unwrap().mz, vec![200.0, 300.0]);
+    }
+}
@@ -0,0 +1,210 @@
+//! Serve local mzparquet files over [Arrow Flight](https://arrow.apache.org/docs/format/Flight.html),
+//! so that remote clients can stream only the slices of a run they need.
+//!
+//! Each file is a flight, addressed by a descriptor path holding its file
+//! name. Tickets are JSON objects naming the file and, optionally, the
+//! inclusive ranges to read (see [`Slice`]):
+//!
+//! ```json
+//! {"file": "run.mzparquet", "level": [2, 2], "mz": [500.0, 500.01]}
+//! ```
+//!
+//! `ListFlights` and `GetFlightInfo` return tickets for whole files, and
+//! `DoGet` streams the matching rows, with the columns of the file. Other
+//! methods are not supported.
+use crate::slice::{files_by_name, read_slice, Slice};
+use arrow_flight::{
+    encode::FlightDataEncoderBuilder,
+    flight_service_server::{FlightService, FlightServiceServer},
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo,
+    HandshakeRequest, HandshakeResponse, PollInfo, PutResult, SchemaAsIpc, SchemaResult, Ticket,
+};
+use arrow_ipc::writer::IpcWriteOptions;
+use arrow_schema::SchemaRef;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf};
+use tonic::{Request, Response, Status, Streaming};
+
+/// A `DoGet` request: the rows of `file` within `slice`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlightTicket {
+    pub file: String,
+    #[serde(flatten)]
+    pub slice: Slice,
+}
+
+/// Flight service over a set of local files, keyed by file name
+pub struct MzParquetFlight {
+    files: BTreeMap<String, PathBuf>,
+}
+
+impl MzParquetFlight {
+    pub fn new<I: IntoIterator<Item = PathBuf>>(paths: I) -> anyhow::Result<Self> {
+        Ok(MzParquetFlight {
+            files: files_by_name(paths)?,
+        })
+    }
+
+    fn path(&self, name: &str) -> Result<&PathBuf, Status> {
+        self.files
+            .get(name)
+            .ok_or_else(|| Status::not_found(format!("no file named {}", name)))
+    }
+
+    fn schema(&self, name: &str) -> Result<SchemaRef, Status> {
+        let file = std::fs::File::open(self.path(name)?)?;
+        let builder = ParquetRecordBatchReaderBuilder::try_new(file)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(builder.schema().clone())
+    }
+
+    fn flight_info(&self, name: &str) -> Result<FlightInfo, Status> {
+        let ticket = serde_json::to_vec(&FlightTicket {
+            file: name.into(),
+            ..Default::default()
+        })
+        .map_err(|e| Status::internal(e.to_string()))?;
+        FlightInfo::new()
+            .try_with_schema(&self.schema(name)?)
+            .map_err(|e| Status::internal(e.to_string()))
+            .map(|info| {
+                info.with_descriptor(FlightDescriptor::new_path(vec![name.into()]))
+                    .with_endpoint(FlightEndpoint::new().with_ticket(Ticket::new(ticket)))
+            })
+    }
+}
+
+fn descriptor_name(descriptor: &FlightDescriptor) -> Result<&str, Status> {
+    match descriptor.path.as_slice() {
+        [name] => Ok(name),
+        _ => Err(Status::invalid_argument(
+            "descriptor path must be a single file name",
+        )),
+    }
+}
+
+type BoxedStream<T> = BoxStream<'static, Result<T, Status>>;
+
+#[tonic::async_trait]
+impl FlightService for MzParquetFlight {
+    type HandshakeStream = BoxedStream<HandshakeResponse>;
+    type ListFlightsStream = BoxedStream<FlightInfo>;
+    type DoGetStream = BoxedStream<FlightData>;
+    type DoPutStream = BoxedStream<PutResult>;
+    type DoActionStream = BoxedStream<arrow_flight::Result>;
+    type ListActionsStream = BoxedStream<ActionType>;
+    type DoExchangeStream = BoxedStream<FlightData>;
+
+    async fn handshake(
+        &self,
+        _: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("handshake is not supported"))
+    }
+
+    async fn list_flights(
+        &self,
+        _: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        let infos = self
+            .files
+            .keys()
+            .map(|name| self.flight_info(name))
+            .collect::<Vec<_>>();
+        Ok(Response::new(futures::stream::iter(infos).boxed()))
+    }
+
+    async fn get_flight_info(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let name = descriptor_name(request.get_ref())?;
+        Ok(Response::new(self.flight_info(name)?))
+    }
+
+    async fn poll_flight_info(
+        &self,
+        _: Request<FlightDescriptor>,
+    ) -> Result<Response<PollInfo>, Status> {
+        Err(Status::unimplemented("poll_flight_info is not supported"))
+    }
+
+    async fn get_schema(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        let schema = self.schema(descriptor_name(request.get_ref())?)?;
+        let result = SchemaAsIpc::new(&schema, &IpcWriteOptions::default())
+            .try_into()
+            .map_err(|e: arrow_schema::ArrowError| Status::internal(e.to_string()))?;
+        Ok(Response::new(result))
+    }
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        let ticket: FlightTicket = serde_json::from_slice(&request.get_ref().ticket)
+            .map_err(|e| Status::invalid_argument(format!("invalid ticket: {}", e)))?;
+        let schema = self.schema(&ticket.file)?;
+        let file = std::fs::File::open(self.path(&ticket.file)?)?;
+        let batches = tokio::task::spawn_blocking(move || read_slice(file, &ticket.slice))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let stream = FlightDataEncoderBuilder::new()
+            .with_schema(schema)
+            .build(futures::stream::iter(batches.into_iter().map(Ok)))
+            .map_err(Status::from);
+        Ok(Response::new(stream.boxed()))
+    }
+
+    async fn do_put(
+        &self,
+        _: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented("do_put is not supported"))
+    }
+
+    async fn do_action(
+        &self,
+        _: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("do_action is not supported"))
+    }
+
+    async fn list_actions(
+        &self,
+        _: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(futures::stream::empty().boxed()))
+    }
+
+    async fn do_exchange(
+        &self,
+        _: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("do_exchange is not supported"))
+    }
+}
+
+/// Serve `paths` over Arrow Flight on `addr`, until the process is stopped
+pub async fn serve<I: IntoIterator<Item = PathBuf>>(
+    addr: SocketAddr,
+    paths: I,
+) -> anyhow::Result<()> {
+    let service = MzParquetFlight::new(paths)?;
+    log::info!(
+        "serving {} files over Arrow Flight on {}",
+        service.files.len(),
+        addr
+    );
+    tonic::transport::Server::builder()
+        .add_service(FlightServiceServer::new(service))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
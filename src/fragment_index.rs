@@ -0,0 +1,388 @@
+//! An inverted fragment index over the MS2 spectra of many files, stored as
+//! parquet.
+//!
+//! Like the fragment index of Sage, the index maps fragment m/z values to
+//! the spectra containing them: the most intense peaks of every MS2
+//! spectrum are written as `(fragment_mz, precursor_mz, file, scan)` rows,
+//! sorted by fragment m/z and split into small row groups. A lookup only
+//! decodes the row groups whose `fragment_mz` statistics overlap the query,
+//! so searching a whole repository of runs takes a handful of reads.
+//!
+//! Files are referred to by their position in a list of paths, stored as
+//! JSON in the footer metadata under [`FILES_KEY`]. Entries are held in
+//! memory while the index is built, about 16 bytes per indexed peak.
+use crate::output::{Column, Table};
+use crate::query::{column_index, may_contain, read_column, read_required, Tolerance};
+use parquet::{
+    basic::{Compression, LogicalType, Repetition, Type as PhysicalType, ZstdLevel},
+    data_type::{FloatType, Int32Type},
+    file::{
+        metadata::KeyValue,
+        properties::WriterProperties,
+        reader::{ChunkReader, FileReader},
+        serialized_reader::SerializedFileReader,
+        writer::SerializedFileWriter,
+    },
+    schema::types::Type,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Footer metadata key holding the paths of the indexed files, as JSON
+pub const FILES_KEY: &str = "fragment_index_files";
+
+/// Rows per row group. Small row groups keep lookups cheap, as each one
+/// covers a narrow range of fragment m/z
+const ROW_GROUP_SIZE: usize = 1 << 16;
+
+/// An indexed fragment peak
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Entry {
+    fragment_mz: f32,
+    precursor_mz: Option<f32>,
+    file: u32,
+    scan: u32,
+}
+
+/// Builds a fragment index from the MS2 spectra of long format files
+#[derive(Clone, Debug, Default)]
+pub struct FragmentIndexBuilder {
+    /// Number of peaks indexed per spectrum, the most intense ones
+    top_n: usize,
+    files: Vec<String>,
+    entries: Vec<Entry>,
+}
+
+impl FragmentIndexBuilder {
+    /// Index the `top_n` most intense peaks of each MS2 spectrum
+    pub fn new(top_n: usize) -> Self {
+        Self {
+            top_n,
+            ..Default::default()
+        }
+    }
+
+    /// Add the MS2 spectra of a long format file, referred to as `path`
+    pub fn add_file<R: 'static + ChunkReader>(
+        &mut self,
+        path: &str,
+        r: R,
+    ) -> parquet::errors::Result<()> {
+        let reader = SerializedFileReader::new(r)?;
+        let scan_idx = column_index(&reader, "scan")?;
+        let level_idx = column_index(&reader, "level")?;
+        let mz_idx = column_index(&reader, "mz")?;
+        let int_idx = column_index(&reader, "intensity")?;
+        let pmz_idx = column_index(&reader, "precursor_mz")?;
+        let file = self.files.len() as u32;
+        self.files.push(path.into());
+
+        for i in 0..reader.num_row_groups() {
+            if !may_contain(reader.metadata().row_group(i), level_idx, 2.0, 2.0) {
+                continue;
+            }
+            let rg = reader.get_row_group(i)?;
+            let scan = read_required(rg.as_ref(), scan_idx)?;
+            let level = read_required(rg.as_ref(), level_idx)?;
+            let mz = read_required(rg.as_ref(), mz_idx)?;
+            let intensity = read_required(rg.as_ref(), int_idx)?;
+            let precursor_mz = read_column(rg.as_ref(), pmz_idx)?;
+
+            // Rows of each MS2 scan, which need not be contiguous
+            let mut scans = HashMap::<u32, Vec<usize>>::new();
+            for row in (0..scan.len()).filter(|&row| level[row] == 2.0) {
+                scans.entry(scan[row] as u32).or_default().push(row);
+            }
+            for (scan, mut rows) in scans {
+                rows.sort_by(|&a, &b| intensity[b].total_cmp(&intensity[a]));
+                self.entries
+                    .extend(rows.into_iter().take(self.top_n).map(|row| Entry {
+                        fragment_mz: mz[row] as f32,
+                        precursor_mz: precursor_mz[row].map(|mz| mz as f32),
+                        file,
+                        scan,
+                    }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sort the indexed peaks by fragment m/z and write the index
+    pub fn write<W: std::io::Write + Send>(mut self, w: W) -> anyhow::Result<W> {
+        self.entries
+            .sort_by(|a, b| a.fragment_mz.total_cmp(&b.fragment_mz));
+
+        let column = |name: &str, physical, repetition, logical| {
+            Type::primitive_type_builder(name, physical)
+                .with_repetition(repetition)
+                .with_logical_type(logical)
+                .build()
+                .map(Arc::new)
+        };
+        let unsigned = Some(LogicalType::Integer {
+            bit_width: 32,
+            is_signed: false,
+        });
+        let schema = Type::group_type_builder("schema")
+            .with_fields(vec![
+                column(
+                    "fragment_mz",
+                    PhysicalType::FLOAT,
+                    Repetition::REQUIRED,
+                    None,
+                )?,
+                column(
+                    "precursor_mz",
+                    PhysicalType::FLOAT,
+                    Repetition::OPTIONAL,
+                    None,
+                )?,
+                column(
+                    "file",
+                    PhysicalType::INT32,
+                    Repetition::REQUIRED,
+                    unsigned.clone(),
+                )?,
+                column("scan", PhysicalType::INT32, Repetition::REQUIRED, unsigned)?,
+            ])
+            .build()?;
+        let options = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::try_new(3)?))
+            .set_key_value_metadata(Some(vec![KeyValue {
+                key: FILES_KEY.into(),
+                value: Some(serde_json::to_string(&self.files)?),
+            }]))
+            .build();
+        let mut writer = SerializedFileWriter::new(w, schema.into(), options.into())?;
+
+        for chunk in self.entries.chunks(ROW_GROUP_SIZE) {
+            let mut rg = writer.next_row_group()?;
+            for column in 0..4 {
+                let mut col = rg
+                    .next_column()?
+                    .ok_or_else(|| anyhow::anyhow!("schema and columns are out of sync"))?;
+                match column {
+                    0 => {
+                        let values = chunk.iter().map(|e| e.fragment_mz).collect::<Vec<_>>();
+                        col.typed::<FloatType>().write_batch(&values, None, None)?;
+                    }
+                    1 => {
+                        let values = chunk
+                            .iter()
+                            .filter_map(|e| e.precursor_mz)
+                            .collect::<Vec<_>>();
+                        let def_levels = chunk
+                            .iter()
+                            .map(|e| e.precursor_mz.is_some() as i16)
+                            .collect::<Vec<_>>();
+                        col.typed::<FloatType>()
+                            .write_batch(&values, Some(&def_levels), None)?;
+                    }
+                    2 => {
+                        let values = chunk.iter().map(|e| e.file as i32).collect::<Vec<_>>();
+                        col.typed::<Int32Type>().write_batch(&values, None, None)?;
+                    }
+                    _ => {
+                        let values = chunk.iter().map(|e| e.scan as i32).collect::<Vec<_>>();
+                        col.typed::<Int32Type>().write_batch(&values, None, None)?;
+                    }
+                }
+                col.close()?;
+            }
+            rg.close()?;
+        }
+        Ok(writer.into_inner()?)
+    }
+}
+
+/// Parameters for searching a fragment index
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexQuery {
+    /// Fragment m/z values to look up
+    pub fragments: Vec<f64>,
+    pub tolerance: Tolerance,
+    /// Optionally require the precursor m/z to be within tolerance
+    pub precursor: Option<(f64, Tolerance)>,
+    /// Only report spectra matching at least this many fragments
+    pub min_matched: u32,
+}
+
+/// A spectrum matching the fragments of a query
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexHit {
+    pub file: String,
+    pub scan: u32,
+    pub precursor_mz: Option<f32>,
+    /// Number of query fragments found in the spectrum
+    pub matched: u32,
+}
+
+impl From<&[IndexHit]> for Table {
+    fn from(hits: &[IndexHit]) -> Self {
+        Table::default()
+            .with_column(
+                "file",
+                Column::Str(hits.iter().map(|h| h.file.clone()).collect()),
+            )
+            .with_column("scan", Column::UInt(hits.iter().map(|h| h.scan).collect()))
+            .with_column(
+                "precursor_mz",
+                Column::OptionalFloat(hits.iter().map(|h| h.precursor_mz).collect()),
+            )
+            .with_column(
+                "matched",
+                Column::UInt(hits.iter().map(|h| h.matched).collect()),
+            )
+    }
+}
+
+/// Find the spectra containing the fragments of `query`, ordered by the
+/// number of fragments they match (most first), then by file and scan
+pub fn search<R: 'static + ChunkReader>(r: R, query: &IndexQuery) -> anyhow::Result<Vec<IndexHit>> {
+    let reader = SerializedFileReader::new(r)?;
+    let files: Vec<String> = reader
+        .metadata()
+        .file_metadata()
+        .key_value_metadata()
+        .into_iter()
+        .flatten()
+        .find(|kv| kv.key == FILES_KEY)
+        .and_then(|kv| kv.value.as_deref())
+        .map(serde_json::from_str)
+        .transpose()?
+        .ok_or_else(|| anyhow::anyhow!("not a fragment index: missing `{}`", FILES_KEY))?;
+    let fragment_idx = column_index(&reader, "fragment_mz")?;
+    let pmz_idx = column_index(&reader, "precursor_mz")?;
+    let file_idx = column_index(&reader, "file")?;
+    let scan_idx = column_index(&reader, "scan")?;
+
+    let (p_lo, p_hi) = query
+        .precursor
+        .map(|(mz, tol)| tol.bounds(mz))
+        .unwrap_or((f64::MIN, f64::MAX));
+    let bounds = query
+        .fragments
+        .iter()
+        .map(|&mz| query.tolerance.bounds(mz))
+        .collect::<Vec<_>>();
+
+    // Fragments matched by each (file, scan), counting each query fragment
+    // once, and the precursor m/z of the spectrum
+    let mut matches = HashMap::<(u32, u32), (Vec<bool>, Option<f32>)>::new();
+    for i in 0..reader.num_row_groups() {
+        let meta = reader.metadata().row_group(i);
+        if !bounds
+            .iter()
+            .any(|&(lo, hi)| may_contain(meta, fragment_idx, lo, hi))
+        {
+            continue;
+        }
+        let rg = reader.get_row_group(i)?;
+        let fragment_mz = read_required(rg.as_ref(), fragment_idx)?;
+        let precursor_mz = read_column(rg.as_ref(), pmz_idx)?;
+        let file = read_required(rg.as_ref(), file_idx)?;
+        let scan = read_required(rg.as_ref(), scan_idx)?;
+
+        for (fragment, &(lo, hi)) in bounds.iter().enumerate() {
+            // Rows are sorted by fragment m/z
+            let start = fragment_mz.partition_point(|&mz| mz < lo);
+            let end = fragment_mz.partition_point(|&mz| mz <= hi);
+            for row in start..end {
+                if query.precursor.is_some()
+                    && !precursor_mz[row].is_some_and(|pmz| pmz >= p_lo && pmz <= p_hi)
+                {
+                    continue;
+                }
+                let (found, _) = matches
+                    .entry((file[row] as u32, scan[row] as u32))
+                    .or_insert_with(|| {
+                        (
+                            vec![false; bounds.len()],
+                            precursor_mz[row].map(|mz| mz as f32),
+                        )
+                    });
+                found[fragment] = true;
+            }
+        }
+    }
+
+    let mut hits = matches
+        .into_iter()
+        .map(|((file, scan), (found, precursor_mz))| {
+            let matched = found.iter().filter(|&&found| found).count() as u32;
+            (file, scan, precursor_mz, matched)
+        })
+        .filter(|&(_, _, _, matched)| matched >= query.min_matched)
+        .collect::<Vec<_>>();
+    hits.sort_by(|a, b| b.3.cmp(&a.3).then((a.0, a.1).cmp(&(b.0, b.1))));
+    Ok(hits
+        .into_iter()
+        .map(|(file, scan, precursor_mz, matched)| IndexHit {
+            file: files.get(file as usize).cloned().unwrap_or_default(),
+            scan,
+            precursor_mz,
+            matched,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::{Precursor, RawSpectrum};
+    use crate::write_long::serialize_to_parquet;
+
+    #[test]
+    fn build_and_search() -> anyhow::Result<()> {
+        let spectrum = |precursor: f64, mz: Vec<f64>| RawSpectrum {
+            ms_level: 2,
+            precursors: vec![Precursor {
+                mz: precursor,
+                ..Default::default()
+            }],
+            intensity: (1..=mz.len()).map(|i| i as f64).collect(),
+            mz,
+            ..Default::default()
+        };
+        let a = vec![
+            spectrum(500.0, vec![147.11, 244.17, 300.0]),
+            spectrum(600.0, vec![147.11, 400.0]),
+        ];
+        let b = vec![spectrum(500.0, vec![147.11, 244.17])];
+
+        // Only the two most intense peaks are indexed, dropping 147.11 from
+        // the first spectrum
+        let mut builder = FragmentIndexBuilder::new(2);
+        for (path, spectra) in [("a.mzparquet", &a), ("b.mzparquet", &b)] {
+            let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), spectra)?);
+            builder.add_file(path, buf)?;
+        }
+        let index = bytes::Bytes::from(builder.write(Vec::new())?);
+
+        let mut query = IndexQuery {
+            fragments: vec![147.11, 244.17],
+            tolerance: Tolerance::Ppm(10.0),
+            precursor: None,
+            min_matched: 1,
+        };
+        let hits = search(index.clone(), &query)?;
+        let found = hits
+            .iter()
+            .map(|h| (h.file.as_str(), h.scan, h.matched))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                ("b.mzparquet", 0, 2),
+                ("a.mzparquet", 0, 1),
+                ("a.mzparquet", 1, 1)
+            ]
+        );
+
+        query.precursor = Some((600.0, Tolerance::Da(0.5)));
+        let hits = search(index, &query)?;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].precursor_mz, Some(600.0));
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+//! Serve local mzparquet files over a small HTTP API, for web viewers and
+//! LIMS integrations.
+//!
+//! Files are addressed by file name:
+//!
+//! * `GET /` - names of the served files
+//! * `GET /{file}/spectra/{scan}` - a single scan, with its peaks
+//! * `GET /{file}/xic?mz=&ppm=&da=&rt=&ms_level=` - an extracted ion
+//!   chromatogram, with `rt` an inclusive `start:end` range in seconds
+//! * `GET /{file}/chromatogram/tic?ms_level=` - TIC and base peak
+//!   chromatogram
+//!
+//! Responses are JSON, or Arrow IPC streams with `?format=arrow`.
+//! Chromatograms are arrays of row objects, and spectra a single object. As
+//! Arrow, spectra are the rows of the scan in the long format.
+use crate::output::Table;
+use crate::query::{self, Tolerance, XicQuery};
+use crate::slice::{column_values, files_by_name, read_slice, Slice};
+use crate::{filter::MsLevels, tic};
+use arrow_array::RecordBatch;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, sync::Arc};
+
+/// Files served, keyed by file name
+struct Files(BTreeMap<String, PathBuf>);
+
+impl Files {
+    fn open(&self, name: &str) -> Result<std::fs::File, ApiError> {
+        let path = self
+            .0
+            .get(name)
+            .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("no file named {}", name)))?;
+        std::fs::File::open(path).map_err(ApiError::internal)
+    }
+}
+
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn internal<E: std::fmt::Display>(error: E) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+    }
+
+    fn bad_request<E: std::fmt::Display>(error: E) -> Self {
+        ApiError(StatusCode::BAD_REQUEST, error.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Json,
+    Arrow,
+}
+
+/// Run blocking parquet reads off of the async executor
+async fn blocking<T, F>(f: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> parquet::errors::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)
+}
+
+fn arrow_response(batches: &[RecordBatch]) -> Result<Response, ApiError> {
+    let schema = match batches.first() {
+        Some(batch) => batch.schema(),
+        None => Arc::new(arrow_schema::Schema::empty()),
+    };
+    let mut writer = arrow_ipc::writer::StreamWriter::try_new(Vec::new(), &schema)
+        .map_err(ApiError::internal)?;
+    for batch in batches {
+        writer.write(batch).map_err(ApiError::internal)?;
+    }
+    let body = writer.into_inner().map_err(ApiError::internal)?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/vnd.apache.arrow.stream")],
+        body,
+    )
+        .into_response())
+}
+
+fn table_response(table: Table, format: Format) -> Result<Response, ApiError> {
+    match format {
+        Format::Json => {
+            let mut body = Vec::new();
+            table.write_json(&mut body).map_err(ApiError::internal)?;
+            Ok(([(header::CONTENT_TYPE, "application/json")], body).into_response())
+        }
+        Format::Arrow => {
+            let batch = table.to_record_batch().map_err(ApiError::internal)?;
+            arrow_response(&[batch])
+        }
+    }
+}
+
+async fn list(State(files): State<Arc<Files>>) -> Json<Vec<String>> {
+    Json(files.0.keys().cloned().collect())
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FormatParams {
+    #[serde(default)]
+    format: Format,
+}
+
+async fn spectrum(
+    State(files): State<Arc<Files>>,
+    Path((name, scan)): Path<(String, u32)>,
+    Query(params): Query<FormatParams>,
+) -> Result<Response, ApiError> {
+    let file = files.open(&name)?;
+    let slice = Slice {
+        scan: Some((scan, scan)),
+        ..Default::default()
+    };
+    let batches = blocking(move || read_slice(file, &slice)).await?;
+    if batches.is_empty() {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("no scan {} in {}", scan, name),
+        ));
+    }
+    if params.format == Format::Arrow {
+        return arrow_response(&batches);
+    }
+
+    // Spectrum level columns repeat the same value on every row
+    let first = |column: &str| -> Result<Option<f64>, ApiError> {
+        Ok(column_values(&batches[0], column)
+            .map_err(ApiError::internal)?
+            .and_then(|values| values.first().copied().flatten()))
+    };
+    let peaks = |column: &str| -> Result<Vec<Option<f64>>, ApiError> {
+        let mut values = Vec::new();
+        for batch in &batches {
+            values.extend(
+                column_values(batch, column)
+                    .map_err(ApiError::internal)?
+                    .unwrap_or_default(),
+            );
+        }
+        Ok(values)
+    };
+    Ok(Json(serde_json::json!({
+        "scan": scan,
+        "level": first("level")?.map(|level| level as u8),
+        "rt": first("rt")?,
+        "precursor_mz": first("precursor_mz")?,
+        "precursor_charge": first("precursor_charge")?.map(|charge| charge as u8),
+        "mz": peaks("mz")?,
+        "intensity": peaks("intensity")?,
+    }))
+    .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct XicParams {
+    mz: f64,
+    ppm: Option<f32>,
+    da: Option<f32>,
+    /// Inclusive `start:end` retention time range, in seconds
+    rt: Option<String>,
+    #[serde(default = "default_ms_level")]
+    ms_level: u8,
+    #[serde(default)]
+    format: Format,
+}
+
+fn default_ms_level() -> u8 {
+    1
+}
+
+fn parse_rt(rt: &str) -> Result<(f32, f32), ApiError> {
+    let (start, end) = rt
+        .split_once(':')
+        .ok_or_else(|| ApiError::bad_request("rt must be a `start:end` range"))?;
+    let parse = |value: &str, default: f32| match value.trim() {
+        "" => Ok(default),
+        value => value.parse::<f32>().map_err(ApiError::bad_request),
+    };
+    Ok((parse(start, f32::MIN)?, parse(end, f32::MAX)?))
+}
+
+async fn xic(
+    State(files): State<Arc<Files>>,
+    Path(name): Path<String>,
+    Query(params): Query<XicParams>,
+) -> Result<Response, ApiError> {
+    let file = files.open(&name)?;
+    let query = XicQuery {
+        mz: params.mz,
+        tolerance: match (params.da, params.ppm) {
+            (Some(da), _) => Tolerance::Da(da),
+            (None, ppm) => Tolerance::Ppm(ppm.unwrap_or(10.0)),
+        },
+        rt: params.rt.as_deref().map(parse_rt).transpose()?,
+        ms_level: params.ms_level,
+    };
+    let points = blocking(move || query::xic(file, &query)).await?;
+    table_response(Table::from(points.as_slice()), params.format)
+}
+
+#[derive(Debug, Deserialize)]
+struct TicParams {
+    /// A single level (`2`) or a range (`1-2`)
+    ms_level: Option<String>,
+    #[serde(default)]
+    format: Format,
+}
+
+async fn chromatogram(
+    State(files): State<Arc<Files>>,
+    Path(name): Path<String>,
+    Query(params): Query<TicParams>,
+) -> Result<Response, ApiError> {
+    let file = files.open(&name)?;
+    let levels = params
+        .ms_level
+        .as_deref()
+        .map(str::parse::<MsLevels>)
+        .transpose()
+        .map_err(ApiError::bad_request)?;
+    let points = blocking(move || tic::chromatogram(file, levels)).await?;
+    table_response(Table::from(points.as_slice()), params.format)
+}
+
+/// Router serving `paths`, keyed by file name
+pub fn router<I: IntoIterator<Item = PathBuf>>(paths: I) -> anyhow::Result<Router> {
+    let files = files_by_name(paths)?;
+    Ok(Router::new()
+        .route("/", get(list))
+        .route("/:file/spectra/:scan", get(spectrum))
+        .route("/:file/xic", get(xic))
+        .route("/:file/chromatogram/tic", get(chromatogram))
+        .with_state(Arc::new(Files(files))))
+}
+
+/// Serve `paths` over HTTP on `addr`, until the process is stopped
+pub async fn serve<I: IntoIterator<Item = PathBuf>>(
+    addr: SocketAddr,
+    paths: I,
+) -> anyhow::Result<()> {
+    let router = router(paths)?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    log::info!("serving mzparquet files over HTTP on {}", addr);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Append converted runs to an [Apache Iceberg](https://iceberg.apache.org/)
+//! table, through a REST catalog.
+//!
+//! Each run is written as data files by the Iceberg writer, which records
+//! per-column statistics in the manifests, and committed as a single fast
+//! append. Tables are created on first use, with the long format columns
+//! (unsigned columns are widened, see [`signed_record_batches`]). Tables are
+//! not partitioned: every data file holds a single run, so the `file_id`
+//! bounds in the manifests already let engines prune by run.
+//!
+//! [`signed_record_batches`]: crate::write_arrow::signed_record_batches
+use ::iceberg::{
+    arrow::{arrow_schema_to_schema, schema_to_arrow_schema},
+    spec::DataFileFormat,
+    table::Table,
+    transaction::Transaction,
+    writer::{
+        base_writer::data_file_writer::DataFileWriterBuilder,
+        file_writer::{
+            location_generator::{DefaultFileNameGenerator, DefaultLocationGenerator},
+            ParquetWriterBuilder,
+        },
+        IcebergWriter, IcebergWriterBuilder,
+    },
+    Catalog, TableCreation, TableIdent,
+};
+use arrow_array::RecordBatch;
+use arrow_schema::Schema;
+use iceberg_catalog_rest::{RestCatalog, RestCatalogConfig};
+use parquet::{arrow::PARQUET_FIELD_ID_META_KEY, file::properties::WriterProperties};
+use std::{collections::HashMap, sync::Arc};
+
+/// A table in an Iceberg REST catalog
+pub struct IcebergTable {
+    catalog: RestCatalog,
+    ident: TableIdent,
+    name: String,
+}
+
+impl IcebergTable {
+    /// `table` is the dotted name of the table, including its namespace,
+    /// e.g. `proteomics.runs`
+    pub fn new(catalog_uri: &str, table: &str) -> anyhow::Result<Self> {
+        let ident = TableIdent::from_strs(table.split('.'))?;
+        let config = RestCatalogConfig::builder()
+            .uri(catalog_uri.to_string())
+            .build();
+        Ok(IcebergTable {
+            catalog: RestCatalog::new(config),
+            ident,
+            name: table.to_string(),
+        })
+    }
+
+    /// Dotted name of the table, as given to [`IcebergTable::new`]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn load_or_create(&self, schema: &Schema) -> anyhow::Result<Table> {
+        if self.catalog.table_exists(&self.ident).await? {
+            return Ok(self.catalog.load_table(&self.ident).await?);
+        }
+
+        // Iceberg identifies columns by id, rather than by name
+        let fields = schema
+            .fields()
+            .iter()
+            .enumerate()
+            .map(|(idx, field)| {
+                let id =
+                    HashMap::from([(PARQUET_FIELD_ID_META_KEY.to_string(), (idx + 1).to_string())]);
+                field.as_ref().clone().with_metadata(id)
+            })
+            .collect::<Vec<_>>();
+        let creation = TableCreation::builder()
+            .name(self.ident.name().to_string())
+            .schema(arrow_schema_to_schema(&Schema::new(fields))?)
+            .build();
+        Ok(self
+            .catalog
+            .create_table(self.ident.namespace(), creation)
+            .await?)
+    }
+
+    /// Append the batches of a single run, creating the table if it does not
+    /// exist yet. Returns the number of data files written
+    pub async fn append(&self, file_id: &str, batches: Vec<RecordBatch>) -> anyhow::Result<usize> {
+        let Some(first) = batches.first() else {
+            return Ok(0);
+        };
+        let table = self.load_or_create(first.schema().as_ref()).await?;
+        let metadata = table.metadata();
+        let schema = Arc::new(schema_to_arrow_schema(metadata.current_schema())?);
+
+        let parquet = ParquetWriterBuilder::new(
+            WriterProperties::default(),
+            metadata.current_schema().clone(),
+            table.file_io().clone(),
+            DefaultLocationGenerator::new(metadata.clone())?,
+            DefaultFileNameGenerator::new(file_id.to_string(), None, DataFileFormat::Parquet),
+        );
+        let mut writer = DataFileWriterBuilder::new(parquet, None).build().await?;
+        for batch in batches {
+            // Re-label the columns with the table schema, which carries the
+            // Iceberg field ids
+            writer
+                .write(RecordBatch::try_new(
+                    schema.clone(),
+                    batch.columns().to_vec(),
+                )?)
+                .await?;
+        }
+        let data_files = writer.close().await?;
+        let count = data_files.len();
+
+        let transaction = Transaction::new(&table);
+        let mut append = transaction.fast_append(None, Vec::new())?;
+        append.add_data_files(data_files)?;
+        append.apply().await?.commit(&self.catalog).await?;
+        Ok(count)
+    }
+}
@@ -0,0 +1,175 @@
+//! Read imaging mass spectrometry data stored as
+//! [imzML](https://ms-imaging.org/imzml/).
+//!
+//! An imzML file is an mzML document whose binary data arrays are stored in
+//! a separate `.ibd` file, next to it. The document is parsed with the mzML
+//! parser, which records the position of each spectrum in
+//! [`RawSpectrum::pixel`], and the arrays are read from the `.ibd` file by
+//! [`IbdArrays`]. Both continuous (shared m/z array) and processed files are
+//! supported, as every array is located by its own offset.
+//!
+//! Pixel positions are written to the optional `pixel_x`, `pixel_y` and
+//! `pixel_z` columns of the long format, see
+//! [`WriterOptions::set_pixel_columns`].
+//!
+//! [`RawSpectrum::pixel`]: crate::mzml::RawSpectrum::pixel
+//! [`WriterOptions::set_pixel_columns`]: crate::write_long::WriterOptions::set_pixel_columns
+use crate::mzml::{Dtype, ExternalArray, ExternalArrays, MzMLError};
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// Binary data arrays of an imzML file, read from its `.ibd` file
+pub struct IbdArrays<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read + Seek + Send> IbdArrays<R> {
+    pub fn new(reader: R) -> Self {
+        IbdArrays {
+            reader,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl IbdArrays<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: Read + Seek + Send> ExternalArrays for IbdArrays<R> {
+    fn read(&mut self, array: &ExternalArray) -> Result<Vec<f64>, MzMLError> {
+        let width = match array.dtype {
+            Dtype::F32 => 4,
+            Dtype::F64 => 8,
+        };
+        self.buf.resize(array.length * width, 0);
+        self.reader.seek(SeekFrom::Start(array.offset as u64))?;
+        self.reader.read_exact(&mut self.buf)?;
+
+        let values = match array.dtype {
+            Dtype::F32 => self
+                .buf
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().expect("4 bytes")) as f64)
+                .collect(),
+            Dtype::F64 => self
+                .buf
+                .chunks_exact(8)
+                .map(|chunk| f64::from_le_bytes(chunk.try_into().expect("8 bytes")))
+                .collect(),
+        };
+        Ok(values)
+    }
+}
+
+/// Path of the `.ibd` file holding the binary data of an imzML file
+pub fn ibd_path<P: AsRef<Path>>(imzml: P) -> PathBuf {
+    imzml.as_ref().with_extension("ibd")
+}
+
+/// Lazily parse spectra from a local imzML file and its `.ibd` file
+#[cfg(feature = "native")]
+pub async fn stream<P: AsRef<Path>>(
+    reader: &crate::mzml::MzMLReader,
+    path: P,
+) -> Result<crate::mzml::MzMLStream<tokio::io::BufReader<tokio::fs::File>>, MzMLError> {
+    let arrays = IbdArrays::open(ibd_path(&path))?;
+    let document = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+    let mut stream = reader.stream(document);
+    stream.set_external_arrays(Box::new(arrays));
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::{MzMLReader, Pixel};
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn read_pixels_and_arrays() -> Result<(), MzMLError> {
+        let document = r#"
+        <mzML>
+        <referenceableParamGroupList count="3">
+            <referenceableParamGroup id="spectrum">
+                <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1"/>
+                <cvParam cvRef="MS" accession="MS:1000127" name="centroid spectrum"/>
+            </referenceableParamGroup>
+            <referenceableParamGroup id="mzArray">
+                <cvParam cvRef="MS" accession="MS:1000514" name="m/z array"/>
+                <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float"/>
+                <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+            </referenceableParamGroup>
+            <referenceableParamGroup id="intensityArray">
+                <cvParam cvRef="MS" accession="MS:1000515" name="intensity array"/>
+                <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float"/>
+                <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+            </referenceableParamGroup>
+        </referenceableParamGroupList>
+        <run id="run">
+        <spectrumList count="2">
+            <spectrum id="Scan=1" index="0" defaultArrayLength="2">
+                <referenceableParamGroupRef ref="spectrum"/>
+                <scanList count="1">
+                    <scan>
+                        <cvParam cvRef="IMS" accession="IMS:1000050" name="position x" value="3"/>
+                        <cvParam cvRef="IMS" accession="IMS:1000051" name="position y" value="7"/>
+                    </scan>
+                </scanList>
+                <binaryDataArrayList count="2">
+                    <binaryDataArray encodedLength="0">
+                        <referenceableParamGroupRef ref="mzArray"/>
+                        <cvParam cvRef="IMS" accession="IMS:1000102" name="external offset" value="16"/>
+                        <cvParam cvRef="IMS" accession="IMS:1000103" name="external array length" value="2"/>
+                        <cvParam cvRef="IMS" accession="IMS:1000104" name="external encoded length" value="16"/>
+                        <binary/>
+                    </binaryDataArray>
+                    <binaryDataArray encodedLength="0">
+                        <referenceableParamGroupRef ref="intensityArray"/>
+                        <cvParam cvRef="IMS" accession="IMS:1000102" name="external offset" value="32"/>
+                        <cvParam cvRef="IMS" accession="IMS:1000103" name="external array length" value="2"/>
+                        <cvParam cvRef="IMS" accession="IMS:1000104" name="external encoded length" value="8"/>
+                        <binary/>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+            </spectrum>
+        </spectrumList>
+        </run>
+        </mzML>
+        "#;
+
+        // 16 byte UUID, followed by the m/z and intensity arrays
+        let mut ibd = vec![0u8; 16];
+        for mz in [400.5f64, 500.25] {
+            ibd.extend(mz.to_le_bytes());
+        }
+        for intensity in [10f32, 20.0] {
+            ibd.extend(intensity.to_le_bytes());
+        }
+
+        let reader = MzMLReader::default();
+        let mut stream = reader.stream(document.as_bytes());
+        stream.set_external_arrays(Box::new(IbdArrays::new(Cursor::new(ibd))));
+        let spectrum = stream.next_spectrum().await?.expect("one spectrum");
+        assert_eq!(spectrum.ms_level, 1);
+        assert!(spectrum.centroid);
+        assert_eq!(
+            spectrum.pixel,
+            Some(Pixel {
+                x: 3,
+                y: 7,
+                z: None
+            })
+        );
+        assert_eq!(spectrum.mz, vec![400.5, 500.25]);
+        assert_eq!(spectrum.intensity, vec![10.0, 20.0]);
+        assert!(stream.next_spectrum().await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+//! An index mapping scan numbers and retention times to row groups, stored
+//! as JSON in the footer metadata of long format files.
+//!
+//! Scans are written in acquisition order and are never split across row
+//! groups, so each row group covers a contiguous, non-overlapping range of
+//! scans. Readers can use the index to jump straight to the row group holding
+//! a scan, rather than checking the column statistics of every row group.
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+use serde::{Deserialize, Serialize};
+
+/// Footer metadata key under which the index is stored
+pub const KEY: &str = "scan_index";
+
+/// The scans and retention times covered by a single row group
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RowGroupRange {
+    /// First and last scan number (inclusive)
+    pub scan: (u32, u32),
+    /// Minimum and maximum retention time (inclusive)
+    pub rt: (f32, f32),
+}
+
+impl RowGroupRange {
+    pub(crate) fn new(scan: u32, rt: f32) -> Self {
+        RowGroupRange {
+            scan: (scan, scan),
+            rt: (rt, rt),
+        }
+    }
+
+    pub(crate) fn extend(&mut self, scan: u32, rt: f32) {
+        self.scan = (self.scan.0.min(scan), self.scan.1.max(scan));
+        self.rt = (self.rt.0.min(rt), self.rt.1.max(rt));
+    }
+}
+
+/// Scan and retention time ranges for each row group, in row group order
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanIndex {
+    pub row_groups: Vec<RowGroupRange>,
+}
+
+impl ScanIndex {
+    /// Load the index from a file's footer metadata. Returns `Ok(None)` for
+    /// files written without an index
+    pub fn from_metadata(metadata: &ParquetMetaData) -> serde_json::Result<Option<Self>> {
+        metadata
+            .file_metadata()
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+            .find(|kv| kv.key == KEY)
+            .and_then(|kv| kv.value.as_deref())
+            .map(serde_json::from_str)
+            .transpose()
+    }
+
+    pub(crate) fn to_key_value(&self) -> serde_json::Result<KeyValue> {
+        Ok(KeyValue {
+            key: KEY.into(),
+            value: Some(serde_json::to_string(self)?),
+        })
+    }
+
+    /// Ordinal of the row group containing `scan`, if any
+    pub fn row_group_for_scan(&self, scan: u32) -> Option<usize> {
+        let idx = self.row_groups.partition_point(|range| range.scan.1 < scan);
+        self.row_groups
+            .get(idx)
+            .filter(|range| range.scan.0 <= scan)
+            .map(|_| idx)
+    }
+
+    /// Ordinals of the row groups that may contain spectra acquired between
+    /// `lo` and `hi` (inclusive)
+    pub fn row_groups_for_rt(&self, lo: f32, hi: f32) -> impl Iterator<Item = usize> + '_ {
+        self.row_groups
+            .iter()
+            .enumerate()
+            .filter(move |(_, range)| range.rt.1 >= lo && range.rt.0 <= hi)
+            .map(|(idx, _)| idx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::RawSpectrum;
+    use crate::write_long::serialize_to_parquet;
+    use parquet::file::{reader::FileReader, serialized_reader::SerializedFileReader};
+
+    #[test]
+    fn index_row_groups() -> anyhow::Result<()> {
+        // Row groups are flushed after 2^18 ions, so this writes two
+        let spectra = (0..4)
+            .map(|scan| RawSpectrum {
+                ms_level: 1,
+                scan_start_time: scan as f32,
+                mz: vec![100.0; 100_000],
+                intensity: vec![1.0; 100_000],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+        let reader = SerializedFileReader::new(buf)?;
+
+        let index = ScanIndex::from_metadata(reader.metadata())?.expect("scan index");
+        assert_eq!(
+            index.row_groups,
+            vec![
+                RowGroupRange {
+                    scan: (0, 2),
+                    rt: (0.0, 2.0)
+                },
+                RowGroupRange {
+                    scan: (3, 3),
+                    rt: (3.0, 3.0)
+                },
+            ]
+        );
+        assert_eq!(index.row_groups.len(), reader.num_row_groups());
+        assert_eq!(index.row_group_for_scan(1), Some(0));
+        assert_eq!(index.row_group_for_scan(3), Some(1));
+        assert_eq!(index.row_group_for_scan(4), None);
+        assert_eq!(
+            index.row_groups_for_rt(2.5, 10.0).collect::<Vec<_>>(),
+            vec![1]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+//! Parse indexedmzML files in parallel, using their spectrum offset index.
+//!
+//! The spectra of an indexedmzML file are split into chunks of consecutive
+//! spectra, which are parsed on tokio's blocking thread pool. The file is
+//! memory-mapped, and each chunk is parsed in place, together with the
+//! document header (everything before the first spectrum), so that
+//! referenceableParamGroups are resolved as usual.
+//! Chunks are returned in file order, so writers see the same spectra, in
+//! the same order, as when parsing sequentially.
+use crate::cloud::map_file;
+use crate::metadata::{sha1_file, InputFile, RunMetadata};
+use crate::mzml::{progress_bar, Chromatogram, MzMLError, MzMLReader, RawSpectrum, SpectrumStream};
+use crate::progress::ProgressBar;
+use memmap2::Mmap;
+use quick_xml::{events::Event, Reader};
+use std::{
+    collections::VecDeque,
+    io::{Cursor, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+    sync::Arc,
+};
+use tokio::{io::AsyncReadExt, task::JoinHandle};
+
+/// Byte offsets of the spectra of an indexedmzML file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpectrumIndex {
+    /// Offset of each `<spectrum>` element, in file order
+    pub offsets: Vec<u64>,
+    /// Offset of the `<indexList>` element, where the last spectrum has ended
+    pub end: u64,
+}
+
+impl SpectrumIndex {
+    /// Read the spectrum index of an indexedmzML file. Returns `None` if the
+    /// file has no index, or if the index does not match the file (e.g. if
+    /// line endings were changed after it was written)
+    pub fn read<R: Read + Seek>(r: &mut R) -> Result<Option<Self>, MzMLError> {
+        // `<indexListOffset>` is the last element of the file
+        let len = r.seek(SeekFrom::End(0))?;
+        r.seek(SeekFrom::Start(len.saturating_sub(1024)))?;
+        let mut tail = Vec::new();
+        r.read_to_end(&mut tail)?;
+        let end = match String::from_utf8_lossy(&tail)
+            .rsplit_once("<indexListOffset>")
+            .and_then(|(_, rest)| rest.split_once("</indexListOffset>"))
+            .and_then(|(offset, _)| offset.trim().parse::<u64>().ok())
+        {
+            Some(end) if end < len => end,
+            _ => return Ok(None),
+        };
+
+        r.seek(SeekFrom::Start(end))?;
+        let mut index = Vec::new();
+        r.read_to_end(&mut index)?;
+        let offsets = Self::parse_offsets(&index)?;
+
+        let sorted = offsets.windows(2).all(|w| w[0] < w[1]);
+        if offsets.is_empty() || !sorted || offsets[offsets.len() - 1] >= end {
+            return Ok(None);
+        }
+        for &offset in [offsets[0], offsets[offsets.len() - 1]].iter() {
+            let mut tag = [0u8; 9];
+            r.seek(SeekFrom::Start(offset))?;
+            r.read_exact(&mut tag)?;
+            if &tag != b"<spectrum" {
+                return Ok(None);
+            }
+        }
+        Ok(Some(SpectrumIndex { offsets, end }))
+    }
+
+    /// Offsets listed in `<index name="spectrum">`
+    fn parse_offsets(index: &[u8]) -> Result<Vec<u64>, MzMLError> {
+        let mut reader = Reader::from_reader(index);
+        // The index starts halfway through the document
+        reader.check_end_names(false);
+        let mut buf = Vec::new();
+        let mut offsets = Vec::new();
+        let mut in_spectrum_index = false;
+        let mut in_offset = false;
+        loop {
+            match reader.read_event_into(&mut buf)? {
+                Event::Start(ev) => match ev.name().into_inner() {
+                    b"index" => {
+                        in_spectrum_index = ev
+                            .try_get_attribute(b"name")?
+                            .is_some_and(|name| name.value.as_ref() == b"spectrum");
+                    }
+                    b"offset" => in_offset = in_spectrum_index,
+                    _ => {}
+                },
+                Event::Text(text) if in_offset => {
+                    offsets.push(text.unescape()?.trim().parse()?);
+                }
+                Event::End(ev) => match ev.name().into_inner() {
+                    b"index" => in_spectrum_index = false,
+                    b"offset" => in_offset = false,
+                    _ => {}
+                },
+                Event::Eof => break,
+                _ => {}
+            }
+            buf.clear();
+        }
+        Ok(offsets)
+    }
+}
+
+/// Spectra of a chunk, the chromatograms following them (last chunk only),
+/// and the metadata from the document header
+type Chunk = JoinHandle<Result<(Vec<RawSpectrum>, Vec<Chromatogram>, RunMetadata), MzMLError>>;
+
+/// Spectra of a local indexedmzML file, parsed in parallel
+pub struct IndexedStream {
+    map: Arc<Mmap>,
+    config: MzMLReader,
+    /// Length of the document header, preceding the first spectrum
+    header: usize,
+    /// Byte ranges of the chunks that have not been started yet
+    chunks: VecDeque<Range<u64>>,
+    /// Chunks being parsed, in file order, with their size in bytes
+    pending: VecDeque<(u64, Chunk)>,
+    /// Total size of the chunks being parsed
+    pending_bytes: u64,
+    current: std::vec::IntoIter<RawSpectrum>,
+    chromatograms: Vec<Chromatogram>,
+    metadata: Option<RunMetadata>,
+    input: InputFile,
+    /// SHA-1 checksum of the file, computed alongside the spectra
+    checksum: Option<JoinHandle<std::io::Result<String>>>,
+    threads: usize,
+    pb: ProgressBar,
+}
+
+impl IndexedStream {
+    /// Number of spectra per chunk
+    pub const CHUNK_SIZE: usize = 256;
+
+    /// Start parsing an indexedmzML file with `threads` threads. Returns
+    /// `None` if the file has no usable index
+    pub fn open<P: AsRef<Path>>(
+        reader: &MzMLReader,
+        path: P,
+        threads: usize,
+    ) -> Result<Option<Self>, MzMLError> {
+        Self::with_chunk_size(reader, path, threads, Self::CHUNK_SIZE)
+    }
+
+    fn with_chunk_size<P: AsRef<Path>>(
+        reader: &MzMLReader,
+        path: P,
+        threads: usize,
+        chunk_size: usize,
+    ) -> Result<Option<Self>, MzMLError> {
+        let map = map_file(path.as_ref())?;
+        let Some(index) = SpectrumIndex::read(&mut Cursor::new(&map[..]))? else {
+            return Ok(None);
+        };
+
+        // With a read ahead limit, chunks are also cut short so that every
+        // thread can be kept busy within it
+        let threads = threads.max(1);
+        let max_chunk_bytes = reader
+            .read_ahead()
+            .map(|max| (max / (2 * threads)).max(1) as u64);
+        let offset = |i: usize| index.offsets.get(i).copied().unwrap_or(index.end);
+        let mut chunks = VecDeque::new();
+        let mut start = 0;
+        while start < index.offsets.len() {
+            let mut stop = (start + chunk_size).min(index.offsets.len());
+            if let Some(max) = max_chunk_bytes {
+                while stop > start + 1 && offset(stop) - offset(start) > max {
+                    stop -= 1;
+                }
+            }
+            chunks.push_back(offset(start)..offset(stop));
+            start = stop;
+        }
+
+        // Chunks are already parsed in parallel
+        let mut config = reader.clone();
+        config.set_parallel_decoding(false);
+
+        Ok(Some(IndexedStream {
+            map: Arc::new(map),
+            config,
+            header: index.offsets[0] as usize,
+            chunks,
+            pending: VecDeque::new(),
+            pending_bytes: 0,
+            current: Vec::new().into_iter(),
+            chromatograms: Vec::new(),
+            metadata: None,
+            input: InputFile {
+                path: path.as_ref().display().to_string(),
+                sha1: None,
+            },
+            checksum: Some(tokio::task::spawn_blocking({
+                let path = path.as_ref().to_path_buf();
+                move || sha1_file(path)
+            })),
+            threads,
+            pb: progress_bar(index.offsets.len() as u64),
+        }))
+    }
+
+    fn spawn(&self, range: Range<u64>) -> Chunk {
+        let map = self.map.clone();
+        let config = self.config.clone();
+        let header = self.header;
+        let handle = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            let spectra = &map[range.start as usize..range.end as usize];
+            let document = AsyncReadExt::chain(&map[..header], spectra);
+
+            handle.block_on(async {
+                let mut stream = config.stream(document);
+                stream.hide_progress();
+                let mut spectra = Vec::new();
+                while let Some(spectrum) = stream.next_spectrum().await? {
+                    spectra.push(spectrum);
+                }
+                let chromatograms = stream.take_chromatograms();
+                Ok((spectra, chromatograms, stream.metadata().clone()))
+            })
+        })
+    }
+}
+
+impl SpectrumStream for IndexedStream {
+    async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+        loop {
+            if let Some(spectrum) = self.current.next() {
+                self.pb.inc(1);
+                self.config.progress().spectra_parsed(1);
+                return Ok(Some(spectrum));
+            }
+            // Keep every thread busy while the current chunk is consumed, as
+            // far as the read ahead limit allows
+            while self.pending.len() < self.threads * 2 {
+                let Some(range) = self.chunks.front() else {
+                    break;
+                };
+                let bytes = range.end - range.start;
+                let fits = self
+                    .config
+                    .read_ahead()
+                    .is_none_or(|max| self.pending_bytes + bytes <= max as u64);
+                if !fits && !self.pending.is_empty() {
+                    break;
+                }
+                let range = self.chunks.pop_front().expect("chunk is queued");
+                let chunk = self.spawn(range);
+                self.pending.push_back((bytes, chunk));
+                self.pending_bytes += bytes;
+            }
+            match self.pending.pop_front() {
+                Some((bytes, chunk)) => {
+                    self.pending_bytes -= bytes;
+                    let (spectra, chromatograms, metadata) = chunk
+                        .await
+                        .map_err(|err| MzMLError::InputError(err.to_string()))??;
+                    self.current = spectra.into_iter();
+                    self.chromatograms.extend(chromatograms);
+                    // Every chunk is parsed with the same header
+                    self.metadata.get_or_insert(metadata);
+                }
+                None => {
+                    if let Some(checksum) = self.checksum.take() {
+                        let sha1 = checksum
+                            .await
+                            .map_err(|err| MzMLError::InputError(err.to_string()))??;
+                        self.input.sha1 = Some(sha1);
+                    }
+                    self.pb.finish();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn take_chromatograms(&mut self) -> Vec<Chromatogram> {
+        std::mem::take(&mut self.chromatograms)
+    }
+
+    fn run_metadata(&self) -> RunMetadata {
+        RunMetadata {
+            input: Some(self.input.clone()),
+            ..self.metadata.clone().unwrap_or_default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An indexedmzML document with `n` spectra, whose MS level is set
+    /// through a referenceableParamGroup
+    fn document(n: usize) -> Vec<u8> {
+        let mut doc = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<indexedmzML>
+<mzML>
+<referenceableParamGroupList count="1">
+  <referenceableParamGroup id="ms2">
+    <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2"/>
+  </referenceableParamGroup>
+</referenceableParamGroupList>
+<run id="run">
+<spectrumList count="{n}">
+"#
+        );
+        let mut offsets = Vec::new();
+        for i in 0..n {
+            offsets.push(doc.len());
+            doc.push_str(&format!(
+                r#"<spectrum id="scan={i}" index="{i}" defaultArrayLength="0">
+  <referenceableParamGroupRef ref="ms2"/>
+  <scanList count="1"><scan>
+    <cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="{i}"/>
+  </scan></scanList>
+</spectrum>
+"#
+            ));
+        }
+        doc.push_str("</spectrumList>\n</run>\n</mzML>\n");
+        let end = doc.len();
+        doc.push_str("<indexList count=\"1\">\n<index name=\"spectrum\">\n");
+        for (i, offset) in offsets.iter().enumerate() {
+            doc.push_str(&format!("<offset idRef=\"scan={i}\">{offset}</offset>\n"));
+        }
+        doc.push_str(&format!(
+            "</index>\n</indexList>\n<indexListOffset>{end}</indexListOffset>\n</indexedmzML>\n"
+        ));
+        doc.into_bytes()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parse_in_parallel() -> Result<(), MzMLError> {
+        let path = std::env::temp_dir().join(format!("indexed-{}.mzML", std::process::id()));
+        std::fs::write(&path, document(10))?;
+
+        let reader = MzMLReader::default();
+        let mut stream =
+            IndexedStream::with_chunk_size(&reader, &path, 2, 3)?.expect("file is indexed");
+        assert_eq!(stream.chunks.len(), 4);
+        let mut spectra = Vec::new();
+        while let Some(spectrum) = stream.next_spectrum().await? {
+            spectra.push(spectrum);
+        }
+        let expected = reader.parse(std::fs::read(&path)?.as_slice()).await?;
+        assert_eq!(spectra.len(), 10);
+        assert_eq!(spectra, expected);
+
+        // Limiting the read ahead splits chunks down to single spectra
+        let mut limited = reader.clone();
+        limited.set_read_ahead(Some(1));
+        let mut stream =
+            IndexedStream::with_chunk_size(&limited, &path, 2, 3)?.expect("file is indexed");
+        assert_eq!(stream.chunks.len(), 10);
+        let mut spectra = Vec::new();
+        while let Some(spectrum) = stream.next_spectrum().await? {
+            spectra.push(spectrum);
+        }
+        assert_eq!(spectra, expected);
+        assert!(spectra.iter().all(|spectrum| spectrum.ms_level == 2));
+        let input = stream.run_metadata().input.expect("input is recorded");
+        assert_eq!(input.sha1, Some(sha1_file(&path)?));
+
+        // Without a (valid) index, files are parsed sequentially
+        std::fs::write(&path, &document(3)[..200])?;
+        assert!(IndexedStream::open(&reader, &path, 2)?.is_none());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+//! Summarize the contents of an mzparquet file: layout, spectrum and ion
+//! counts, retention time range, row groups and footer metadata.
+//!
+//! Only the columns needed for the counts are decoded, and everything else
+//! comes from the file metadata.
+use crate::metadata::RunMetadata;
+use crate::query::{column_index, column_range, read_required};
+use crate::reader::Format;
+use crate::write_long::{RtUnit, SPECTRUM_INDEX_COLUMN};
+use parquet::{
+    basic::Compression,
+    column::reader::ColumnReader,
+    errors::ParquetError,
+    file::{
+        reader::{ChunkReader, FileReader, RowGroupReader},
+        serialized_reader::SerializedFileReader,
+    },
+};
+use std::collections::{BTreeMap, HashSet};
+
+/// Number of spectra and ions at a single MS level
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LevelCounts {
+    pub spectra: usize,
+    pub ions: usize,
+}
+
+/// Size of a single row group
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RowGroupInfo {
+    pub rows: i64,
+    pub compressed_size: i64,
+    pub uncompressed_size: i64,
+}
+
+/// Type, encoding and total size of a column across all row groups
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnInfo {
+    /// Dotted path of the column, e.g. `mz.list.element`
+    pub path: String,
+    pub physical_type: String,
+    pub compression: Compression,
+    pub compressed_size: i64,
+    pub uncompressed_size: i64,
+}
+
+/// A summary of an mzparquet file
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileInfo {
+    pub format: Format,
+    /// `version` footer key
+    pub version: Option<String>,
+    /// `writer` footer key
+    pub writer: Option<String>,
+    /// Spectrum and ion counts, by MS level
+    pub levels: BTreeMap<u8, LevelCounts>,
+    /// Minimum and maximum retention time, from the column statistics
+    pub rt: Option<(f32, f32)>,
+    /// Unit of the retention times
+    pub rt_unit: RtUnit,
+    pub row_groups: Vec<RowGroupInfo>,
+    pub columns: Vec<ColumnInfo>,
+    /// Instruments and provenance of the run, from the footer metadata
+    pub run: RunMetadata,
+    /// All footer key-value metadata
+    pub key_value: Vec<(String, Option<String>)>,
+}
+
+/// Summarize an mzparquet file
+pub fn info<R: 'static + ChunkReader>(r: R) -> parquet::errors::Result<FileInfo> {
+    let reader = SerializedFileReader::new(r)?;
+    let metadata = reader.metadata();
+    let format = Format::detect(metadata)?;
+
+    let key_value = metadata
+        .file_metadata()
+        .key_value_metadata()
+        .into_iter()
+        .flatten()
+        .map(|kv| (kv.key.clone(), kv.value.clone()))
+        .collect::<Vec<_>>();
+    let lookup = |key: &str| {
+        key_value
+            .iter()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, v)| v.clone())
+    };
+
+    let run = RunMetadata::from_metadata(metadata)
+        .map_err(|err| ParquetError::General(format!("invalid run metadata: {}", err)))?;
+
+    let (levels, rt_column) = match format {
+        Format::Long => (long_levels(&reader)?, "rt"),
+        Format::Wide => (wide_levels(&reader)?, "scan_start_time"),
+    };
+
+    let rt_idx = column_index(&reader, rt_column)?;
+    let rt = metadata
+        .row_groups()
+        .iter()
+        .map(|rg| column_range(rg, rt_idx))
+        .collect::<Option<Vec<_>>>()
+        .and_then(|ranges| {
+            ranges
+                .into_iter()
+                .reduce(|(lo, hi), (min, max)| (lo.min(min), hi.max(max)))
+        })
+        .map(|(lo, hi)| (lo as f32, hi as f32));
+
+    let row_groups = metadata
+        .row_groups()
+        .iter()
+        .map(|rg| RowGroupInfo {
+            rows: rg.num_rows(),
+            compressed_size: rg.compressed_size(),
+            uncompressed_size: rg.total_byte_size(),
+        })
+        .collect();
+
+    let mut columns = metadata
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .map(|col| ColumnInfo {
+            path: col.path().string(),
+            physical_type: col.physical_type().to_string(),
+            compression: Compression::UNCOMPRESSED,
+            compressed_size: 0,
+            uncompressed_size: 0,
+        })
+        .collect::<Vec<_>>();
+    for rg in metadata.row_groups() {
+        for (column, chunk) in columns.iter_mut().zip(rg.columns()) {
+            column.compression = chunk.compression();
+            column.compressed_size += chunk.compressed_size();
+            column.uncompressed_size += chunk.uncompressed_size();
+        }
+    }
+
+    Ok(FileInfo {
+        format,
+        version: lookup("version"),
+        writer: lookup("writer"),
+        levels,
+        rt,
+        rt_unit: RtUnit::from_metadata(metadata),
+        row_groups,
+        columns,
+        run,
+        key_value,
+    })
+}
+
+/// Each distinct spectrum index (or scan, in older files) is a spectrum, and
+/// each row an ion
+fn long_levels(reader: &dyn FileReader) -> parquet::errors::Result<BTreeMap<u8, LevelCounts>> {
+    let scan_idx =
+        column_index(reader, SPECTRUM_INDEX_COLUMN).or_else(|_| column_index(reader, "scan"))?;
+    let level_idx = column_index(reader, "level")?;
+
+    let mut levels = BTreeMap::<u8, LevelCounts>::new();
+    let mut seen = HashSet::new();
+    for i in 0..reader.num_row_groups() {
+        let rg = reader.get_row_group(i)?;
+        let scans = read_required(rg.as_ref(), scan_idx)?;
+        let level = read_required(rg.as_ref(), level_idx)?;
+        for (scan, level) in scans.into_iter().zip(level) {
+            let counts = levels.entry(level as u8).or_default();
+            counts.ions += 1;
+            if seen.insert(scan as u32) {
+                counts.spectra += 1;
+            }
+        }
+    }
+    Ok(levels)
+}
+
+/// Each row is a spectrum, and each element of its `mz` list an ion
+fn wide_levels(reader: &dyn FileReader) -> parquet::errors::Result<BTreeMap<u8, LevelCounts>> {
+    let level_idx = column_index(reader, "ms_level")?;
+    let mz_idx = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .position(|col| col.path().string() == "mz.list.element")
+        .ok_or_else(|| ParquetError::General("missing column `mz`".into()))?;
+
+    let mut levels = BTreeMap::<u8, LevelCounts>::new();
+    for i in 0..reader.num_row_groups() {
+        let rg = reader.get_row_group(i)?;
+        let level = read_required(rg.as_ref(), level_idx)?;
+        let ions = list_lengths(rg.as_ref(), mz_idx)?;
+        for (level, ions) in level.into_iter().zip(ions) {
+            let counts = levels.entry(level as u8).or_default();
+            counts.spectra += 1;
+            counts.ions += ions;
+        }
+    }
+    Ok(levels)
+}
+
+/// Number of elements in each row of a list of floats
+fn list_lengths(rg: &dyn RowGroupReader, idx: usize) -> parquet::errors::Result<Vec<usize>> {
+    let rows = rg.metadata().num_rows() as usize;
+    let max_def = rg.metadata().column(idx).column_descr().max_def_level();
+
+    let (mut def_levels, mut rep_levels, mut values) = (Vec::new(), Vec::new(), Vec::new());
+    match rg.get_column_reader(idx)? {
+        ColumnReader::FloatColumnReader(mut reader) => {
+            let mut total = 0;
+            while total < rows {
+                let (records, _, _) = reader.read_records(
+                    rows - total,
+                    Some(&mut def_levels),
+                    Some(&mut rep_levels),
+                    &mut values,
+                )?;
+                if records == 0 {
+                    break;
+                }
+                total += records;
+            }
+        }
+        _ => return Err(ParquetError::General("expected a list of floats".into())),
+    }
+
+    let mut lengths: Vec<usize> = Vec::with_capacity(rows);
+    for (def, rep) in def_levels.into_iter().zip(rep_levels) {
+        if rep == 0 {
+            lengths.push(0);
+        }
+        if def == max_def {
+            if let Some(len) = lengths.last_mut() {
+                *len += 1;
+            }
+        }
+    }
+    Ok(lengths)
+}
+
+impl std::fmt::Display for FileInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "format:    {}", self.format)?;
+        writeln!(
+            f,
+            "version:   {}",
+            self.version.as_deref().unwrap_or("unknown")
+        )?;
+        writeln!(
+            f,
+            "writer:    {}",
+            self.writer.as_deref().unwrap_or("unknown")
+        )?;
+        match self.rt {
+            Some((lo, hi)) => writeln!(f, "rt:        {} - {} {}", lo, hi, self.rt_unit)?,
+            None => writeln!(f, "rt:        unknown")?,
+        }
+
+        if let Some(start) = &self.run.start_timestamp {
+            writeln!(f, "started:   {}", start)?;
+        }
+        if let Some(input) = &self.run.input {
+            writeln!(f, "input:     {}", input.path)?;
+            if let Some(sha1) = &input.sha1 {
+                writeln!(f, "sha1:      {}", sha1)?;
+            }
+        }
+        for source in &self.run.source_files {
+            writeln!(
+                f,
+                "source:    {} ({}){}",
+                source.name,
+                source.location,
+                source
+                    .sha1
+                    .as_ref()
+                    .map(|sha1| format!(" sha1 {}", sha1))
+                    .unwrap_or_default()
+            )?;
+        }
+
+        for instrument in &self.run.instruments {
+            writeln!(
+                f,
+                "\ninstrument {}: {}",
+                instrument.id,
+                instrument.model.as_deref().unwrap_or("unknown model")
+            )?;
+            if let Some(serial_number) = &instrument.serial_number {
+                writeln!(f, "  serial number: {}", serial_number)?;
+            }
+            for component in &instrument.components {
+                writeln!(f, "  {}: {}", component.kind, component.names.join(", "))?;
+            }
+            if let Some(software) = &instrument.software {
+                writeln!(
+                    f,
+                    "  software: {} {}",
+                    software.name.as_deref().unwrap_or(&software.id),
+                    software.version.as_deref().unwrap_or("")
+                )?;
+            }
+        }
+
+        writeln!(f, "\nlevel  spectra       ions")?;
+        for (level, counts) in &self.levels {
+            writeln!(
+                f,
+                "MS{:<4} {:>7} {:>10}",
+                level, counts.spectra, counts.ions
+            )?;
+        }
+
+        writeln!(f, "\nrow group       rows   compressed uncompressed")?;
+        for (idx, rg) in self.row_groups.iter().enumerate() {
+            writeln!(
+                f,
+                "{:<9} {:>10} {:>12} {:>12}",
+                idx, rg.rows, rg.compressed_size, rg.uncompressed_size
+            )?;
+        }
+
+        writeln!(f, "\ncolumns")?;
+        for column in &self.columns {
+            writeln!(
+                f,
+                "  {:<40} {:<10} {:<12} {:>12} {:>12}",
+                column.path,
+                column.physical_type,
+                column.compression.to_string(),
+                column.compressed_size,
+                column.uncompressed_size
+            )?;
+        }
+
+        writeln!(f, "\nmetadata")?;
+        for (key, value) in &self.key_value {
+            match value.as_deref() {
+                // Large values, like the scan index, are summarized
+                Some(value) if value.len() > 80 => {
+                    writeln!(f, "  {}: <{} bytes>", key, value.len())?
+                }
+                Some(value) => writeln!(f, "  {}: {}", key, value)?,
+                None => writeln!(f, "  {}", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::RawSpectrum;
+    use crate::{write_long, write_wide};
+
+    #[test]
+    fn count_spectra_and_ions() -> anyhow::Result<()> {
+        let spectra = vec![
+            RawSpectrum {
+                id: b"0".to_vec(),
+                ms_level: 1,
+                scan_start_time: 1.0,
+                mz: vec![100.0, 200.0, 300.0],
+                intensity: vec![1.0; 3],
+                ..Default::default()
+            },
+            RawSpectrum {
+                id: b"1".to_vec(),
+                ms_level: 2,
+                scan_start_time: 2.5,
+                mz: vec![150.0],
+                intensity: vec![1.0],
+                ..Default::default()
+            },
+            RawSpectrum {
+                id: b"2".to_vec(),
+                ms_level: 2,
+                scan_start_time: 3.0,
+                mz: vec![150.0, 250.0],
+                intensity: vec![1.0; 2],
+                ..Default::default()
+            },
+        ];
+        let expected = BTreeMap::from([
+            (
+                1,
+                LevelCounts {
+                    spectra: 1,
+                    ions: 3,
+                },
+            ),
+            (
+                2,
+                LevelCounts {
+                    spectra: 2,
+                    ions: 3,
+                },
+            ),
+        ]);
+
+        let long = bytes::Bytes::from(write_long::serialize_to_parquet(Vec::new(), &spectra)?);
+        let wide = bytes::Bytes::from(write_wide::serialize_to_parquet(Vec::new(), &spectra)?);
+        for (format, buf) in [(Format::Long, long), (Format::Wide, wide)] {
+            let info = info(buf)?;
+            assert_eq!(info.format, format);
+            assert_eq!(info.levels, expected);
+            assert_eq!(info.rt, Some((1.0, 3.0)));
+            assert_eq!(
+                info.version.as_deref(),
+                Some(crate::write_long::SCHEMA_VERSION)
+            );
+            assert_eq!(info.row_groups.len(), 1);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+//! Convert mass spectrometry data to, and read it back from, mzparquet.
+//!
+//! mzparquet is a minimal, column-oriented representation of a mass
+//! spectrometry acquisition, stored in Apache Parquet. This crate provides
+//! the pieces used by the `mz_parquet` command line tool so that they can be
+//! embedded directly in other applications:
+//!
+//! * [`mzml`] - an asynchronous mzML parser producing [`RawSpectrum`]s
+//! * [`cloud`] - read and write local files, S3, Google Cloud Storage, Azure
+//!   and HTTP objects through one path type (requires the default `native`
+//!   feature)
+//! * [`indexed`] - parse indexedmzML files in parallel, using their offset
+//!   index (requires the default `native` feature)
+//! * [`numpress`] - decode MS-Numpress compressed binary data arrays
+//! * [`native_id`] - parse vendor scan numbers out of spectrum native ids
+//! * [`binning`] - sum peaks onto a fixed m/z grid during conversion
+//! * [`centroid`] - peak pick profile spectra during conversion
+//! * [`average`] - average MS1 spectra over neighbouring scans during
+//!   conversion
+//! * [`demux`] - demultiplex overlapping window DIA spectra during
+//!   conversion
+//! * [`filter`] - drop low intensity peaks during conversion
+//! * [`deisotope`] - collapse isotope clusters of MSn spectra during
+//!   conversion
+//! * [`lock_mass`] - recalibrate m/z values against a background ion during
+//!   conversion
+//! * [`purity`] - compute the precursor purity of MS2 spectra during
+//!   conversion
+//! * [`metadata`] - run metadata (instrument configurations) stored in the
+//!   file footer
+//! * [`imzml`] - read imaging data from imzML files, with pixel positions
+//! * [`mzmlb`] - read mzMLb (HDF5) files with the mzML parser (requires the
+//!   `mzmlb` feature)
+//! * [`thermo`] - read Thermo RAW files directly (requires the `thermo`
+//!   feature)
+//! * [`tdf`] - read Bruker timsTOF `.d` directories directly (requires the
+//!   `tdf` feature)
+//! * [`dataframe`] - open long format files as Polars lazy frames, with
+//!   m/z and retention time filter helpers (requires the `polars` feature)
+//! * [`mzdata_io`] - read mzparquet files through `mzdata`'s spectrum source
+//!   traits (requires the `mzdata` feature)
+//! * [`sage`] - read mzparquet files directly into Sage's spectrum types, for
+//!   in-process searches (requires the `sage` feature)
+//! * [`watch`] - poll a directory for completed mzML files to convert, with a
+//!   state file recording what has been converted
+//! * [`manifest`] - read lists of files to convert, with per-file output
+//!   directories and metadata
+//! * [`report`] - JSON summary of a conversion, for workflow managers
+//! * [`progress`] - receive progress of reading and writing, for showing it
+//!   in applications embedding the library
+//! * [`vendor`] - convert vendor formats without a native reader (Sciex
+//!   WIFF, Waters `.raw`, Agilent `.d`) with ProteoWizard's msconvert
+//! * [`write_long`] - serialize spectra to the long (one row per ion) format
+//! * [`write_wide`] - serialize spectra to the wide (one row per spectrum) format
+//! * [`write_chromatograms`] - serialize chromatograms (TIC, BPC, SRM) and non-MS
+//!   traces (UV, pressure) next to the spectra
+//! * [`write_arrow`] - write the long format table as Arrow IPC
+//! * [`index`] - scan and retention time to row group index for long format files
+//! * [`info`] - summarize the contents of an mzparquet file
+//! * [`reader`] - deserialize spectra from long or wide format mzparquet files
+//! * [`rewrite`] - re-encode, merge or split existing mzparquet files
+//! * [`stats`] - per-run quality control metrics
+//! * [`verify`] - check that spectra survive a round trip through mzparquet
+//! * [`mgf`] - export MS2 spectra to MGF
+//! * [`migrate`] - upgrade files written by older versions to the current schema
+//! * [`query`] - search long format files for ions, using row group statistics
+//!   to skip data that cannot match
+//! * [`similarity`] - score query spectra against the MS2 spectra of long
+//!   format files (cosine and modified cosine)
+//! * [`targets`] - extract XICs and peak areas for a target list across
+//!   many files
+//! * [`fragment_index`] - build and search an inverted fragment index over
+//!   the MS2 spectra of many files
+//! * [`slice`] - read the ions of a long format file within scan, MS level,
+//!   retention time and m/z ranges as Arrow record batches
+//! * [`http`] - serve spectra, XICs and TICs of mzparquet files over HTTP
+//!   (requires the `http` feature)
+//! * [`flight`] - serve mzparquet files over Arrow Flight (requires the
+//!   `flight` feature)
+//! * [`tic`] - TIC and base peak chromatograms of long format files
+//! * [`massql`] - run a subset of MassQL against long format files (requires
+//!   the `massql` feature)
+//! * [`sql`] - run SQL over mzparquet files with DataFusion (requires the
+//!   `sql` feature)
+//! * [`delta`] - append converted runs to a Delta Lake table (requires the
+//!   `delta` feature)
+//! * [`iceberg`] - append converted runs to an Apache Iceberg table (requires
+//!   the `iceberg` feature)
+//!
+//! # Example
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! let file = tokio::io::BufReader::new(tokio::fs::File::open("run.mzML").await?);
+//! let spectra = mz_parquet::MzMLReader::default().parse(file).await?;
+//!
+//! let output = std::fs::File::create("run.mzparquet")?;
+//! mz_parquet::serialize_to_parquet(output, &spectra)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # WebAssembly
+//!
+//! With default features disabled, the crate builds for `wasm32-unknown-unknown`
+//! so that spectrum viewers can open mzparquet files client-side. The
+//! command line tool, cloud storage and the parallel [`indexed`] parser are
+//! left out, and progress bars are not shown. Files are read from memory, and
+//! mzML can still be converted in memory:
+//!
+//! ```no_run
+//! # async fn run(mzml: &[u8], mzparquet: bytes::Bytes) -> anyhow::Result<()> {
+//! let (_format, spectra) = mz_parquet::read_spectra(mzparquet)?;
+//!
+//! let spectra = mz_parquet::MzMLReader::default().parse(mzml).await?;
+//! let converted = mz_parquet::serialize_to_parquet(Vec::new(), &spectra)?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod average;
+pub mod binning;
+pub mod centroid;
+#[cfg(feature = "native")]
+pub mod cloud;
+#[cfg(feature = "polars")]
+pub mod dataframe;
+pub mod deisotope;
+#[cfg(feature = "delta")]
+pub mod delta;
+pub mod demux;
+pub mod filter;
+#[cfg(feature = "flight")]
+pub mod flight;
+pub mod fragment_index;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "iceberg")]
+pub mod iceberg;
+pub mod imzml;
+pub mod index;
+#[cfg(feature = "native")]
+pub mod indexed;
+pub mod info;
+pub mod lock_mass;
+pub mod manifest;
+#[cfg(feature = "massql")]
+pub mod massql;
+pub mod metadata;
+pub mod mgf;
+pub mod migrate;
+#[cfg(feature = "mzdata")]
+pub mod mzdata_io;
+pub mod mzml;
+#[cfg(feature = "mzmlb")]
+pub mod mzmlb;
+pub mod native_id;
+pub mod numpress;
+pub mod output;
+pub mod progress;
+pub mod purity;
+pub mod query;
+pub mod reader;
+pub mod report;
+pub mod rewrite;
+#[cfg(feature = "sage")]
+pub mod sage;
+pub mod similarity;
+pub mod slice;
+#[cfg(feature = "sql")]
+pub mod sql;
+pub mod stats;
+pub mod targets;
+#[cfg(feature = "tdf")]
+pub mod tdf;
+#[cfg(feature = "native")]
+pub mod tee;
+#[cfg(feature = "thermo")]
+pub mod thermo;
+pub mod tic;
+pub mod vendor;
+pub mod verify;
+pub mod watch;
+pub mod write_arrow;
+pub mod write_chromatograms;
+pub mod write_long;
+pub mod write_wide;
+
+pub use mzml::{MzMLError, MzMLReader, Precursor, RawSpectrum};
+pub use reader::{deserialize_from_parquet, deserialize_long_from_parquet, read_spectra, Format};
+pub use write_long::serialize_to_parquet;
@@ -0,0 +1,179 @@
+//! Lock mass recalibration, correcting m/z drift with a known background ion.
+//!
+//! Ions that are always present in the background, such as the
+//! polysiloxane at m/z 445.12003, drift with the calibration of the
+//! instrument. The most intense peak within a window around the lock mass is
+//! located in each MS1 spectrum, and the m/z values of that spectrum, and of
+//! the MSn spectra that follow it, are scaled to remove its mass error. MS1
+//! spectra in which the lock mass is not found keep the correction of the
+//! previous MS1 spectrum.
+//!
+//! Enabled with [`crate::write_long::WriterOptions::set_lock_mass`], which
+//! records the settings in the footer metadata under [`LOCK_MASS_KEY`]. Long
+//! format files also store the correction applied to each spectrum in a
+//! [`crate::write_long::LOCK_MASS_COLUMN`] column.
+use crate::mzml::RawSpectrum;
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+use serde::{Deserialize, Serialize};
+
+/// Footer metadata key holding the [`LockMass`] settings, as JSON
+pub const LOCK_MASS_KEY: &str = "lock_mass";
+
+/// Polysiloxane background ion, a common lock mass in positive mode
+pub const POLYSILOXANE: f64 = 445.12003;
+
+/// Settings for lock mass recalibration
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LockMass {
+    /// Theoretical m/z of the lock mass
+    pub mz: f64,
+    /// Window searched for the lock mass, in ppm
+    pub ppm: f32,
+}
+
+impl LockMass {
+    /// The settings used to recalibrate a file, if it was recalibrated
+    pub fn from_metadata(metadata: &ParquetMetaData) -> anyhow::Result<Option<Self>> {
+        Self::from_key_value(metadata.file_metadata().key_value_metadata())
+    }
+
+    pub(crate) fn from_key_value(kv: Option<&Vec<KeyValue>>) -> anyhow::Result<Option<Self>> {
+        kv.into_iter()
+            .flatten()
+            .find(|kv| kv.key == LOCK_MASS_KEY)
+            .and_then(|kv| kv.value.as_deref())
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    pub(crate) fn to_key_value(self) -> serde_json::Result<KeyValue> {
+        Ok(KeyValue {
+            key: LOCK_MASS_KEY.into(),
+            value: Some(serde_json::to_string(&self)?),
+        })
+    }
+
+    /// Mass error of the lock mass in `spectrum`, in ppm, from the most
+    /// intense peak within the search window. `None` if there is no peak
+    pub fn error(&self, spectrum: &RawSpectrum) -> Option<f32> {
+        let delta = self.mz * self.ppm as f64 / 1E6;
+        spectrum
+            .mz
+            .iter()
+            .zip(&spectrum.intensity)
+            .filter(|(&mz, _)| (mz - self.mz).abs() <= delta)
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(&mz, _)| ((mz - self.mz) / self.mz * 1E6) as f32)
+    }
+}
+
+/// Remove a mass error of `ppm` from the m/z values of `spectrum`, and of
+/// its precursors
+pub fn recalibrate(spectrum: &RawSpectrum, ppm: f32) -> RawSpectrum {
+    let scale = 1.0 / (1.0 + ppm as f64 / 1E6);
+    let mut spectrum = spectrum.clone();
+    spectrum.mz.iter_mut().for_each(|mz| *mz *= scale);
+    for precursor in &mut spectrum.precursors {
+        precursor.mz *= scale;
+    }
+    spectrum
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn correct_drift() {
+        let lock_mass = LockMass {
+            mz: POLYSILOXANE,
+            ppm: 10.0,
+        };
+        // Lock mass observed 5 ppm high, next to a weaker peak in the window
+        let observed = POLYSILOXANE * (1.0 + 5E-6);
+        let spectrum = RawSpectrum {
+            ms_level: 1,
+            mz: vec![400.0, POLYSILOXANE * (1.0 - 8E-6), observed, 500.0],
+            intensity: vec![10.0, 5.0, 100.0, 10.0],
+            ..Default::default()
+        };
+        let error = lock_mass.error(&spectrum).unwrap();
+        assert!((error - 5.0).abs() < 1E-3);
+
+        let recalibrated = recalibrate(&spectrum, error);
+        assert!((recalibrated.mz[2] - POLYSILOXANE).abs() < 1E-6);
+
+        let empty = RawSpectrum {
+            mz: vec![400.0],
+            intensity: vec![10.0],
+            ..Default::default()
+        };
+        assert_eq!(lock_mass.error(&empty), None);
+    }
+
+    struct Spectra(std::vec::IntoIter<RawSpectrum>);
+
+    impl crate::mzml::SpectrumStream for Spectra {
+        async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, crate::mzml::MzMLError> {
+            Ok(self.0.next())
+        }
+    }
+
+    #[tokio::test]
+    async fn correct_msn_after_skipped_ms1() -> anyhow::Result<()> {
+        use crate::filter::{MsLevels, SpectrumFilter};
+        use crate::write_long::WriterOptions;
+
+        let spectra = vec![
+            RawSpectrum {
+                id: b"scan=1".to_vec(),
+                ms_level: 1,
+                centroid: true,
+                mz: vec![400.0, POLYSILOXANE * (1.0 + 5E-6)],
+                intensity: vec![10.0, 100.0],
+                ..Default::default()
+            },
+            RawSpectrum {
+                id: b"scan=2".to_vec(),
+                ms_level: 2,
+                centroid: true,
+                mz: vec![300.0],
+                intensity: vec![10.0],
+                ..Default::default()
+            },
+        ];
+        let mut options = WriterOptions::default();
+        options
+            .set_lock_mass(Some(LockMass {
+                mz: POLYSILOXANE,
+                ppm: 10.0,
+            }))
+            .set_spectrum_filter(SpectrumFilter {
+                ms_levels: Some(MsLevels { min: 2, max: 2 }),
+                ..Default::default()
+            });
+        let corrected = 300.0 * (1.0 - 5E-6);
+
+        // Only the MS2 spectrum is written, corrected by the skipped MS1
+        let mut stream = Spectra(spectra.clone().into_iter());
+        let (buf, written) =
+            crate::write_wide::serialize_stream_to_parquet(Vec::new(), &mut stream, &options)
+                .await?;
+        assert_eq!(written.spectra, 1);
+        let (_, read) = crate::reader::read_spectra(bytes::Bytes::from(buf))?;
+        assert_eq!(read.len(), 1);
+        assert!((read[0].mz[0] - corrected).abs() < 1E-4);
+
+        let file = crate::rewrite::SpectrumFile {
+            format: crate::Format::Wide,
+            sources: Vec::new(),
+            metadata: Default::default(),
+            spectra,
+        };
+        let (buf, _) = file.write_ms_level(2, Vec::new(), &options)?;
+        let (_, read) = crate::reader::read_spectra(bytes::Bytes::from(buf))?;
+        assert!((read[0].mz[0] - corrected).abs() < 1E-4);
+        Ok(())
+    }
+}
@@ -1,88 +1,3632 @@
-use anyhow::anyhow;
-use clap::{Args, Command, FromArgMatches};
-use sage_cloudpath::CloudPath;
+use anyhow::{anyhow, Context};
+use async_compression::tokio::bufread::GzipDecoder;
+use clap::{Args, Command, FromArgMatches, Subcommand, ValueEnum};
+use mz_parquet::{
+    average::Ms1Averaging,
+    binning::Binning,
+    cloud::{self, CloudPath, CloudWriter},
+    deisotope::Deisotope,
+    demux::Demultiplex,
+    filter::{MsLevels, PeakFilter, SpectrumFilter},
+    fragment_index::{self, FragmentIndexBuilder, IndexQuery},
+    info,
+    lock_mass::LockMass,
+    manifest::{self, Entry},
+    metadata::Sha1Reader,
+    mgf::{self, MgfQuery},
+    migrate,
+    mzml::{self, Polarity, SpectrumStream},
+    output::{Column, Table},
+    purity::PrecursorPurity,
+    query::{self, FragmentQuery, PrecursorQuery, Tolerance, XicQuery},
+    report::{FileReport, Report, Status},
+    rewrite,
+    similarity::{self, QuerySpectrum, SimilarityQuery},
+    stats,
+    targets::{self, TargetPoint},
+    tic, verify,
+    watch::{self, Watcher},
+    write_arrow::{self, IpcFormat},
+    write_chromatograms,
+    write_long::{
+        self, BloomFilter, IntensityType, MzPrecision, RowGroupSize, RtUnit, Source, WriterOptions,
+        Written,
+    },
+    write_wide, Format,
+};
+use parquet::{
+    basic::{BrotliLevel, Compression, GzipLevel, ZstdLevel},
+    file::reader::{ChunkReader, Length},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt};
 
-pub mod mzml;
-pub mod reader;
-pub mod write_long;
+#[derive(Args, Debug)]
+struct ConverterArgs {
+    #[arg(short, long)]
+    output_directory: Option<String>,
+
+    /// Output layout: `long` writes one row per ion, `wide` writes one row
+    /// per spectrum with nested peak lists, and `arrow`/`arrow-stream` write
+    /// the long table as an Arrow IPC file (`.arrow`) or stream (`.arrows`)
+    #[arg(long, value_enum, default_value_t = OutputFormat::Long)]
+    format: OutputFormat,
+
+    #[command(flatten)]
+    writer: WriterArgs,
+
+    /// Add a `file_id` column holding the input file name (without
+    /// extensions), so that converted runs can be queried together (long
+    /// format only)
+    #[arg(long)]
+    file_id: bool,
+
+    /// Add an `acquisition_time` column holding the wall-clock time of each
+    /// spectrum, from the run start timestamp plus the retention time (long
+    /// format only)
+    #[arg(long)]
+    acquisition_time: bool,
+
+    /// Keep cvParams and userParams of spectra that have no column of their
+    /// own (e.g. vendor trailer values) in an `extra_params` JSON column,
+    /// keyed by accession or name (long format only)
+    #[arg(long)]
+    keep_extra_params: bool,
+
+    /// Add `noise` and `baseline` columns holding the noise level of each
+    /// ion, where the input reports one. Wide format files always have them,
+    /// so the flag makes no difference there
+    #[arg(long)]
+    noise_columns: bool,
+
+    /// Add a `charge` column holding the charge state the instrument
+    /// assigned to each ion, where the input reports one. Wide format files
+    /// always have it, so the flag makes no difference there
+    #[arg(long)]
+    charge_column: bool,
+
+    /// Peak pick profile spectra before writing them, as profile data is
+    /// enormous in the long format. Recorded as `centroided` in the footer
+    #[arg(long)]
+    centroid: bool,
+
+    /// Only convert spectra at this MS level, or range of levels (e.g. `2`
+    /// or `1-2`). Scans are still numbered as in the full run
+    #[arg(long)]
+    ms_level: Option<MsLevels>,
+
+    /// Only convert spectra acquired within this retention time window
+    /// (`start:end`, either of which may be left out), in the unit given by
+    /// --rt-unit
+    #[arg(long, value_parser = parse_range::<f32>, allow_hyphen_values = true)]
+    rt_range: Option<(Option<f32>, Option<f32>)>,
+
+    /// Only convert spectra within this range of scans (`lo:hi`, inclusive,
+    /// either of which may be left out): vendor scan numbers where the
+    /// native ids have them, and otherwise spectrum indices from 0
+    #[arg(long, value_parser = parse_range::<u32>)]
+    scan_range: Option<(Option<u32>, Option<u32>)>,
+
+    /// Only convert scans of one polarity, or `split` polarity switching
+    /// runs into `run.pos.mzparquet` and `run.neg.mzparquet`
+    #[arg(long, value_enum)]
+    polarity: Option<PolarityArg>,
+
+    /// Average each MS1 spectrum with its neighbours, over a sliding window
+    /// of this many MS1 spectra
+    #[arg(long)]
+    average_ms1: Option<usize>,
+
+    /// Tolerance for merging peaks when averaging MS1 spectra, in ppm
+    #[arg(long, default_value_t = 10.0, requires = "average_ms1")]
+    average_ms1_ppm: f32,
+
+    /// Demultiplex overlapping window DIA runs, splitting each MS2 spectrum
+    /// into the two halves of its isolation window
+    #[arg(long)]
+    demultiplex: bool,
+
+    /// Tolerance for matching peaks in neighbouring spectra when
+    /// demultiplexing, in ppm
+    #[arg(long, default_value_t = 10.0, requires = "demultiplex")]
+    demultiplex_ppm: f32,
+
+    /// Recalibrate m/z values against this lock mass (e.g. 445.12003 for
+    /// polysiloxane), located in each MS1 spectrum. The correction applied to
+    /// each spectrum is stored in a `lock_mass_correction` column (long
+    /// format only)
+    #[arg(long)]
+    lock_mass: Option<f64>,
+
+    /// Window searched for the lock mass, in ppm
+    #[arg(long, default_value_t = 10.0, requires = "lock_mass")]
+    lock_mass_ppm: f32,
+
+    /// Deisotope MSn spectra: collapse isotope clusters into monoisotopic,
+    /// singly charged peaks. Recorded as `deisotope` in the footer
+    #[arg(long)]
+    deisotope: bool,
+
+    /// Tolerance for the spacing of isotope peaks when deisotoping, in ppm
+    #[arg(long, default_value_t = 10.0, requires = "deisotope")]
+    deisotope_ppm: f32,
+
+    /// Highest fragment charge state considered when deisotoping
+    #[arg(long, default_value_t = 4, requires = "deisotope")]
+    deisotope_max_charge: u8,
+
+    /// Drop peaks with an intensity below this value before writing them.
+    /// Applied filters are recorded as `peak_filter` in the footer
+    #[arg(long)]
+    min_intensity: Option<f32>,
+
+    /// Drop peaks with an intensity below this fraction (0-1) of the base
+    /// peak of their spectrum before writing them
+    #[arg(long)]
+    min_relative_intensity: Option<f32>,
+
+    /// Keep only the N most intense peaks of each MSn spectrum (MS1 spectra
+    /// are not limited)
+    #[arg(long)]
+    top_n: Option<usize>,
+
+    /// Drop peaks outside of this m/z window (`lo:hi`, inclusive, either of
+    /// which may be left out) before writing them
+    #[arg(long, value_parser = parse_range::<f64>)]
+    mz_range: Option<(Option<f64>, Option<f64>)>,
+
+    /// Sum peaks onto a fixed m/z grid with bins of this width, in Da, after
+    /// any other peak processing. The bin index of each ion is stored in an
+    /// `mz_bin` column (long format only)
+    #[arg(long, conflicts_with = "bin_ppm")]
+    bin_da: Option<f64>,
+
+    /// Sum peaks onto a fixed m/z grid with bins of this relative width, in
+    /// ppm, like --bin-da
+    #[arg(long)]
+    bin_ppm: Option<f64>,
+
+    /// Compute the fraction of the MS1 signal in the isolation window of
+    /// each MS2 spectrum that belongs to its precursor, and store it in a
+    /// `precursor_purity` column (long format only)
+    #[arg(long)]
+    precursor_purity: bool,
+
+    /// Tolerance for matching isotope peaks of the precursor when computing
+    /// precursor purity, in ppm
+    #[arg(long, default_value_t = 10.0, requires = "precursor_purity")]
+    precursor_purity_ppm: f32,
+
+    /// Append converted runs to this Delta Lake table (a local path or an
+    /// `s3://` URI), partitioned by `file_id`, instead of writing mzparquet
+    /// files. Implies --file-id
+    #[cfg(feature = "delta")]
+    #[arg(long, conflicts_with = "output_directory")]
+    delta: Option<String>,
+
+    /// URI of an Iceberg REST catalog. Converted runs are appended to
+    /// --iceberg-table instead of writing mzparquet files. Implies --file-id
+    #[cfg(feature = "iceberg")]
+    #[arg(long, requires = "iceberg_table", conflicts_with = "output_directory")]
+    iceberg_catalog: Option<String>,
+
+    /// Iceberg table to append to, including its namespace (e.g.
+    /// `proteomics.runs`). The table is created if it does not exist
+    #[cfg(feature = "iceberg")]
+    #[arg(long, requires = "iceberg_catalog")]
+    iceberg_table: Option<String>,
+
+    /// Number of files to convert concurrently. Memory use grows with each
+    /// file in flight. Appends to Delta Lake and Iceberg tables are always
+    /// made one file at a time
+    #[arg(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Approximate memory budget for conversion, shared between --jobs
+    /// (e.g. `6G` or `512M`). Bounds the spectra parsed ahead of the writer
+    /// to half of each file's share, and the size of row groups to a quarter
+    #[arg(long, value_parser = parse_size)]
+    max_memory: Option<usize>,
+
+    /// Manifest of files to convert, converted after any given as arguments:
+    /// a list of paths, one per line, or a CSV table with a `path` column and
+    /// optional `output` (output directory), `file_id` and metadata columns.
+    /// Metadata columns are stored in the footer of each output
+    #[arg(long)]
+    file_list: Option<String>,
+
+    /// Skip files whose outputs already exist, or with `--skip-existing=newer`
+    /// only those whose outputs are newer than the file. Otherwise existing
+    /// outputs are an error, unless --force is given
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "always",
+        conflicts_with = "force"
+    )]
+    skip_existing: Option<SkipExisting>,
+
+    /// Overwrite existing outputs
+    #[arg(long)]
+    force: bool,
+
+    /// Write a JSON report of the conversion to this path: the status of
+    /// each file, the spectra and peaks written, input and output sizes,
+    /// durations and warnings
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Resolve the files to convert and their outputs, check that the files
+    /// can be read and the outputs written, and print the plan without
+    /// converting anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Files to convert, or glob patterns matching them, e.g.
+    /// `'s3://bucket/cohort/*.mzML'` (quoted, so that the shell leaves them
+    /// alone)
+    #[arg(num_args(1..))]
+    files: Vec<String>,
+}
+
+/// Settings for reading and writing cloud storage, accepted by every
+/// subcommand
+#[derive(Args, Debug)]
+struct CloudArgs {
+    /// Endpoint of an S3 compatible store (MinIO, Ceph RGW, ...) used for
+    /// `s3://` paths, e.g. `http://minio:9000`. Defaults to `AWS_ENDPOINT_URL`
+    #[arg(long, global = true)]
+    s3_endpoint: Option<String>,
+
+    /// Address S3 buckets in the request path rather than the host name, as
+    /// most on-premises stores require
+    #[arg(long, global = true)]
+    s3_path_style: bool,
+
+    /// Accept the charges for reading from requester pays buckets
+    #[arg(long, global = true)]
+    s3_requester_pays: bool,
+
+    /// Named profile of `~/.aws/config` and `~/.aws/credentials` to read S3
+    /// credentials and the region from. Defaults to `AWS_PROFILE`
+    #[arg(long, global = true)]
+    aws_profile: Option<String>,
+
+    /// ARN of a role to assume through STS for S3 access, e.g. in a shared
+    /// organization account
+    #[arg(long, global = true)]
+    aws_role_arn: Option<String>,
+
+    /// External id required to assume --aws-role-arn
+    #[arg(long, global = true, requires = "aws_role_arn")]
+    aws_external_id: Option<String>,
+
+    /// Number of times failed requests to cloud storage are retried, with
+    /// exponential backoff [default: 10]
+    #[arg(long, global = true)]
+    max_retries: Option<usize>,
+
+    /// Seconds after which a failing request is no longer retried
+    /// [default: 180]
+    #[arg(long, global = true)]
+    retry_timeout: Option<u64>,
+
+    /// Seconds allowed for each request to cloud storage, including
+    /// downloading the response, so large inputs over slow links need more
+    /// [default: 30]
+    #[arg(long, global = true)]
+    timeout: Option<u64>,
+
+    /// Seconds allowed for connecting to cloud storage [default: 5]
+    #[arg(long, global = true)]
+    connect_timeout: Option<u64>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Settings for logging, accepted by every subcommand
+#[derive(Args, Debug)]
+struct LogArgs {
+    /// Format of the log written to stderr: `text`, or `json` for one JSON
+    /// object per event, with fields such as `file`, `stage` and `spectra`,
+    /// for log collectors (CloudWatch, ELK, ...)
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+}
+
+/// Write a log event as a single line JSON object (see [`json_event`])
+fn write_json_event(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let event = json_event(record, buf.timestamp_millis().to_string());
+    serde_json::to_writer(&mut *buf, &event)?;
+    writeln!(buf)
+}
+
+/// The fields of a log event: its timestamp, level, target and message,
+/// alongside its key values
+fn json_event(
+    record: &log::Record,
+    timestamp: String,
+) -> serde_json::Map<String, serde_json::Value> {
+    struct Fields(serde_json::Map<String, serde_json::Value>);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Fields {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            let value = if let Some(v) = value.to_u64() {
+                v.into()
+            } else if let Some(v) = value.to_i64() {
+                v.into()
+            } else if let Some(v) = value.to_f64() {
+                v.into()
+            } else if let Some(v) = value.to_bool() {
+                v.into()
+            } else {
+                value.to_string().into()
+            };
+            self.0.insert(key.to_string(), value);
+            Ok(())
+        }
+    }
+
+    let mut fields = Fields(serde_json::Map::new());
+    let event = [
+        ("timestamp", timestamp),
+        ("level", record.level().to_string()),
+        ("target", record.target().to_string()),
+        ("message", record.args().to_string()),
+    ];
+    for (key, value) in event {
+        fields.0.insert(key.into(), value.into());
+    }
+    // Key values cannot fail to be visited by `Fields`
+    let _ = record.key_values().visit(&mut fields);
+    fields.0
+}
+
+impl CloudArgs {
+    fn options(&self) -> mz_parquet::cloud::CloudOptions {
+        let mut options = mz_parquet::cloud::CloudOptions::default();
+        options
+            .set_s3_endpoint(self.s3_endpoint.clone())
+            .set_s3_path_style(self.s3_path_style.then_some(true))
+            .set_s3_requester_pays(self.s3_requester_pays.then_some(true))
+            .set_aws_profile(self.aws_profile.clone())
+            .set_aws_role_arn(self.aws_role_arn.clone())
+            .set_aws_external_id(self.aws_external_id.clone())
+            .set_max_retries(self.max_retries)
+            .set_retry_timeout(self.retry_timeout.map(Duration::from_secs))
+            .set_timeout(self.timeout.map(Duration::from_secs))
+            .set_connect_timeout(self.connect_timeout.map(Duration::from_secs));
+        options
+    }
+}
+
+/// Settings controlling how mzparquet files are written
+#[derive(Args, Debug)]
+struct WriterArgs {
+    /// Physical type of the `mz` and `precursor_mz` columns: `f32` or `f64`
+    /// (long format only)
+    #[arg(long, default_value_t = MzPrecision::F32)]
+    mz_precision: MzPrecision,
+
+    /// Physical type of the `intensity` column: `f32`, `f64` or `u32`
+    /// (long format only)
+    #[arg(long, default_value_t = IntensityType::F32)]
+    intensity_type: IntensityType,
+
+    /// Unit of the retention times written: `seconds` or `minutes`. Input
+    /// retention times are converted from the unit given in the file
+    #[arg(long, default_value_t = RtUnit::Seconds)]
+    rt_unit: RtUnit,
+
+    /// Compression codec
+    #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+    compression: Codec,
+
+    /// Compression level, for codecs that support one (zstd: 1-22, gzip:
+    /// 0-10, brotli: 0-11). Defaults to 3 for zstd
+    #[arg(long)]
+    compression_level: Option<u32>,
+
+    /// Maximum number of ions per row group
+    #[arg(long, default_value_t = 2usize.pow(18))]
+    row_group_size: usize,
+
+    /// Maximum number of spectra per row group
+    #[arg(long)]
+    row_group_spectra: Option<usize>,
+
+    /// Maximum (estimated, uncompressed) size of a row group in bytes
+    #[arg(long)]
+    row_group_bytes: Option<usize>,
+
+    /// Write bloom filters for the `mz` and `precursor_mz` columns, so that
+    /// exact value lookups can skip row groups
+    #[arg(long)]
+    bloom_filter: bool,
+
+    /// Bloom filter false positive probability
+    #[arg(long, default_value_t = BloomFilter::default().fpp, requires = "bloom_filter")]
+    bloom_fpp: f64,
+
+    /// Expected number of distinct values per row group, used to size bloom
+    /// filters
+    #[arg(long, default_value_t = BloomFilter::default().ndv, requires = "bloom_filter")]
+    bloom_ndv: u64,
+
+    /// Sort ions within each row group by `level` and then `mz`, instead of
+    /// acquisition order (long format only)
+    #[arg(long)]
+    sort_ions: bool,
+
+    /// Skip writing page-level statistics and the column index
+    #[arg(long)]
+    no_page_index: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Long,
+    Wide,
+    Arrow,
+    ArrowStream,
+}
+
+impl OutputFormat {
+    /// Layout of the table written, Arrow IPC output uses the long table
+    fn layout(self) -> Format {
+        match self {
+            OutputFormat::Wide => Format::Wide,
+            _ => Format::Long,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Long | OutputFormat::Wide => "mzparquet",
+            OutputFormat::Arrow => "arrow",
+            OutputFormat::ArrowStream => "arrows",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum PolarityArg {
+    Pos,
+    Neg,
+    Split,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum SkipExisting {
+    Always,
+    Newer,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Codec {
+    Zstd,
+    Snappy,
+    Lz4,
+    Gzip,
+    Brotli,
+    None,
+}
+
+impl WriterArgs {
+    fn compression(&self) -> anyhow::Result<Compression> {
+        Ok(match (self.compression, self.compression_level) {
+            (Codec::Zstd, level) => {
+                Compression::ZSTD(ZstdLevel::try_new(level.unwrap_or(3) as i32)?)
+            }
+            (Codec::Gzip, Some(level)) => Compression::GZIP(GzipLevel::try_new(level)?),
+            (Codec::Gzip, None) => Compression::GZIP(GzipLevel::default()),
+            (Codec::Brotli, Some(level)) => Compression::BROTLI(BrotliLevel::try_new(level)?),
+            (Codec::Brotli, None) => Compression::BROTLI(BrotliLevel::default()),
+            (codec, Some(_)) => {
+                let name = codec.to_possible_value().expect("no skipped variants");
+                anyhow::bail!(
+                    "--compression-level is not supported for {}",
+                    name.get_name()
+                )
+            }
+            (Codec::Snappy, None) => Compression::SNAPPY,
+            (Codec::Lz4, None) => Compression::LZ4_RAW,
+            (Codec::None, None) => Compression::UNCOMPRESSED,
+        })
+    }
+
+    /// Writer options for files in the given `format`
+    fn writer_options(&self, format: Format) -> anyhow::Result<WriterOptions> {
+        if format == Format::Wide && self.mz_precision == MzPrecision::F64 {
+            anyhow::bail!("--mz-precision f64 is only supported for the long format");
+        }
+        if format == Format::Wide && self.intensity_type != IntensityType::F32 {
+            anyhow::bail!("--intensity-type is only supported for the long format");
+        }
+
+        let mut options = WriterOptions::default();
+        options
+            .set_mz_precision(self.mz_precision)
+            .set_intensity_type(self.intensity_type)
+            .set_rt_unit(self.rt_unit)
+            .set_compression(self.compression()?)
+            .set_row_group_size(RowGroupSize {
+                ions: Some(self.row_group_size),
+                spectra: self.row_group_spectra,
+                bytes: self.row_group_bytes,
+            })
+            .set_bloom_filter(self.bloom_filter.then_some(BloomFilter {
+                fpp: self.bloom_fpp,
+                ndv: self.bloom_ndv,
+            }))
+            .set_sort_ions(self.sort_ions)
+            .set_page_index(!self.no_page_index);
+        Ok(options)
+    }
+}
+
+impl ConverterArgs {
+    fn writer_options(&self) -> anyhow::Result<WriterOptions> {
+        if self.format == OutputFormat::Wide && self.file_id {
+            anyhow::bail!("--file-id is only supported for the long format");
+        }
+        if self.format == OutputFormat::Wide && self.acquisition_time {
+            anyhow::bail!("--acquisition-time is only supported for the long format");
+        }
+        if self.format == OutputFormat::Wide && self.keep_extra_params {
+            anyhow::bail!("--keep-extra-params is only supported for the long format");
+        }
+        if self.format == OutputFormat::Wide && self.precursor_purity {
+            anyhow::bail!("--precursor-purity is only supported for the long format");
+        }
+        if self
+            .bin_da
+            .or(self.bin_ppm)
+            .is_some_and(|width| width <= 0.0)
+        {
+            anyhow::bail!("m/z bin width must be positive");
+        }
+        #[cfg(feature = "delta")]
+        if self.delta.is_some() && self.polarity == Some(PolarityArg::Split) {
+            anyhow::bail!("--polarity split is not supported with --delta");
+        }
+        #[cfg(feature = "iceberg")]
+        if self.iceberg_catalog.is_some() && self.polarity == Some(PolarityArg::Split) {
+            anyhow::bail!("--polarity split is not supported with --iceberg-catalog");
+        }
+        #[cfg(feature = "delta")]
+        if self.delta.is_some() && self.format != OutputFormat::Long {
+            anyhow::bail!("--delta only supports the long format");
+        }
+        #[cfg(feature = "iceberg")]
+        if self.iceberg_catalog.is_some() && self.format != OutputFormat::Long {
+            anyhow::bail!("--iceberg-catalog only supports the long format");
+        }
+        let mut options = self.writer.writer_options(self.format.layout())?;
+        options
+            .set_acquisition_time(self.acquisition_time)
+            .set_extra_params(self.keep_extra_params)
+            .set_noise_columns(self.noise_columns)
+            .set_charge_column(self.charge_column)
+            .set_centroid(self.centroid)
+            .set_ms1_averaging(self.average_ms1.map(|scans| Ms1Averaging {
+                scans,
+                ppm: self.average_ms1_ppm,
+            }))
+            .set_demultiplex(self.demultiplex.then_some(Demultiplex {
+                ppm: self.demultiplex_ppm,
+            }))
+            .set_lock_mass(self.lock_mass.map(|mz| LockMass {
+                mz,
+                ppm: self.lock_mass_ppm,
+            }))
+            .set_deisotope(self.deisotope.then_some(Deisotope {
+                ppm: self.deisotope_ppm,
+                max_charge: self.deisotope_max_charge,
+            }))
+            .set_spectrum_filter(self.spectrum_filter(match self.polarity {
+                Some(PolarityArg::Pos) => Some(Polarity::Positive),
+                Some(PolarityArg::Neg) => Some(Polarity::Negative),
+                Some(PolarityArg::Split) | None => None,
+            }))
+            .set_peak_filter(PeakFilter {
+                min_intensity: self.min_intensity,
+                min_relative_intensity: self.min_relative_intensity,
+                top_n: self.top_n,
+                mz: self
+                    .mz_range
+                    .map(|(lo, hi)| (lo.unwrap_or(0.0), hi.unwrap_or(f64::MAX))),
+            })
+            .set_binning(match (self.bin_da, self.bin_ppm) {
+                (Some(da), _) => Some(Binning::Da(da)),
+                (None, Some(ppm)) => Some(Binning::Ppm(ppm)),
+                (None, None) => None,
+            })
+            .set_precursor_purity(self.precursor_purity.then_some(PrecursorPurity {
+                ppm: self.precursor_purity_ppm,
+            }));
+        if let Some(budget) = self.memory_budget() {
+            let bytes = budget / 4;
+            options.set_row_group_size(RowGroupSize {
+                ions: Some(self.writer.row_group_size),
+                spectra: self.writer.row_group_spectra,
+                bytes: Some(self.writer.row_group_bytes.map_or(bytes, |b| b.min(bytes))),
+            });
+        }
+        Ok(options)
+    }
+
+    /// Memory budget for converting a single file
+    fn memory_budget(&self) -> Option<usize> {
+        self.max_memory.map(|max| max / self.jobs())
+    }
+
+    /// Spectra selected for conversion, keeping scans of `polarity` only
+    fn spectrum_filter(&self, polarity: Option<Polarity>) -> SpectrumFilter {
+        SpectrumFilter {
+            ms_levels: self.ms_level,
+            rt: self.rt_range.and_then(|(lo, hi)| {
+                let rt_unit = self.writer.rt_unit;
+                rt_window(
+                    lo.map(|lo| rt_unit.to_seconds(lo)),
+                    hi.map(|hi| rt_unit.to_seconds(hi)),
+                )
+            }),
+            scans: self
+                .scan_range
+                .map(|(lo, hi)| (lo.unwrap_or(0), hi.unwrap_or(u32::MAX))),
+            polarity,
+        }
+    }
+
+    /// Settings for parsing the input files
+    fn mzml_reader(&self) -> mzml::MzMLReader {
+        mzml_reader(
+            self.keep_extra_params,
+            self.memory_budget().map(|budget| budget / 2),
+        )
+    }
+
+    /// Files to convert, from the arguments and the manifest
+    async fn entries(&self) -> anyhow::Result<Vec<Entry>> {
+        let mut entries = expand_globs(&self.files)
+            .await?
+            .into_iter()
+            .map(Entry::new)
+            .collect::<Vec<_>>();
+        if let Some(list) = &self.file_list {
+            let buf = list.parse::<CloudPath>()?.read_bytes().await?;
+            let text = String::from_utf8(buf.to_vec())
+                .with_context(|| format!("{} is not a text file", list))?;
+            entries.extend(
+                manifest::parse(&text).with_context(|| format!("failed to read {}", list))?,
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Number of files converted concurrently
+    fn jobs(&self) -> usize {
+        #[cfg(feature = "delta")]
+        if self.delta.is_some() {
+            return 1;
+        }
+        #[cfg(feature = "iceberg")]
+        if self.iceberg_catalog.is_some() {
+            return 1;
+        }
+        self.jobs.max(1)
+    }
+
+    /// Whether rows are tagged with the `file_id` of their run
+    fn tag_file_id(&self) -> bool {
+        #[cfg(feature = "delta")]
+        if self.delta.is_some() {
+            return true;
+        }
+        #[cfg(feature = "iceberg")]
+        if self.iceberg_catalog.is_some() {
+            return true;
+        }
+        self.file_id
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Search long format mzparquet files
+    #[command(subcommand)]
+    Query(QueryCommand),
+    /// Combine mzparquet files into a single long format file, tagging each
+    /// row with the `file_id` of the run it came from
+    Merge(MergeArgs),
+    /// Split an mzparquet file into one file per MS level, e.g.
+    /// `run.ms1.mzparquet` and `run.ms2.mzparquet`
+    Split(SplitArgs),
+    /// Rewrite an mzparquet file with new writer settings (compression, row
+    /// group size, sorting, bloom filters, ...), keeping its format
+    Rewrite(RewriteArgs),
+    /// Print the layout, spectrum counts, row groups and metadata of an
+    /// mzparquet file
+    Info(InfoArgs),
+    /// Convert an mzML file in memory, read it back, and report any m/z or
+    /// intensity values that differ from the source by more than the
+    /// precision of the output types allows
+    Verify(VerifyArgs),
+    /// Compute QC metrics for a run: spectra per MS level, the MS1 TIC,
+    /// median injection times, precursor charges and peaks per spectrum
+    Stats(StatsArgs),
+    /// Upgrade a file written by an older version of mz_parquet to the
+    /// current schema, reporting anything that cannot be migrated losslessly
+    Migrate(MigrateArgs),
+    /// Export MS2 spectra to MGF, for search engines that cannot read
+    /// mzparquet
+    Mgf(MgfArgs),
+    /// Compute the TIC and base peak chromatogram of long format files, per
+    /// MS level
+    Chromatogram(ChromatogramArgs),
+    /// Build or search a fragment index over the MS2 spectra of long format
+    /// files
+    #[command(subcommand)]
+    Index(IndexCommand),
+    /// Serve local mzparquet files to remote clients, over HTTP (spectra, XICs
+    /// and TICs as JSON or Arrow) or Arrow Flight
+    Serve(ServeArgs),
+    /// Watch a directory for completed mzML files and convert them as they
+    /// appear, e.g. on an instrument PC share
+    Watch(WatchArgs),
+}
+
+#[derive(Args, Debug)]
+struct WatchArgs {
+    /// Local directory to watch
+    directory: std::path::PathBuf,
+
+    /// Directory (or `s3://` prefix) to write converted files to. Defaults
+    /// to the watched directory
+    #[arg(short, long)]
+    output_directory: Option<String>,
+
+    /// Output layout, as for conversion
+    #[arg(long, value_enum, default_value_t = OutputFormat::Long)]
+    format: OutputFormat,
+
+    #[command(flatten)]
+    writer: WriterArgs,
+
+    /// State file recording converted files, so that they are not converted
+    /// again after a restart. Defaults to `.mz_parquet_watch.json` in the
+    /// watched directory
+    #[arg(long)]
+    state: Option<std::path::PathBuf>,
+
+    /// Seconds between scans of the directory
+    #[arg(long, default_value_t = 10)]
+    interval: u64,
+
+    /// Seconds a file must be left unchanged before it is converted
+    #[arg(long, default_value_t = 30)]
+    settle: u64,
+
+    /// Attempts made at converting a file before it is skipped (until it is
+    /// modified)
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+}
+
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// mzparquet files to serve. Directories and glob patterns are expanded
+    /// to the `.mzparquet` and `.parquet` files they match
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    /// Address to listen on. Defaults to 127.0.0.1:8080 for HTTP, and
+    /// 127.0.0.1:50051 for Arrow Flight
+    #[arg(long)]
+    addr: Option<std::net::SocketAddr>,
+
+    /// Serve the files over Arrow Flight, with `DoGet` tickets selecting
+    /// scan, MS level, retention time and m/z ranges (requires the `flight`
+    /// feature)
+    #[arg(long)]
+    flight: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum IndexCommand {
+    /// Index the most intense fragments of every MS2 spectrum, writing the
+    /// index as parquet
+    Build(IndexBuildArgs),
+    /// Find the spectra containing a set of fragments
+    Search(IndexSearchArgs),
+}
+
+#[derive(Args, Debug)]
+struct IndexBuildArgs {
+    /// mzparquet files to index. Glob patterns, local directories and remote
+    /// prefixes (ending in `/`) are expanded to the `.mzparquet` and
+    /// `.parquet` files they contain
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    /// Path of the index
+    #[arg(short, long)]
+    output: String,
+
+    /// Number of peaks indexed per spectrum, the most intense ones
+    #[arg(long, default_value_t = 50)]
+    top_n: usize,
+}
+
+#[derive(Args, Debug)]
+struct IndexSearchArgs {
+    /// Fragment index built with `index build`
+    index: String,
+
+    /// Fragment m/z values to look up, comma separated
+    #[arg(long, value_delimiter = ',', required = true)]
+    fragments: Vec<f64>,
+
+    #[command(flatten)]
+    tolerance: ToleranceArgs,
+
+    /// Only report spectra with a precursor m/z within --precursor-ppm of
+    /// this value
+    #[arg(long)]
+    precursor_mz: Option<f64>,
+
+    /// Precursor mass tolerance, in ppm
+    #[arg(long, default_value_t = 10.0)]
+    precursor_ppm: f32,
+
+    /// Only report spectra matching at least this many fragments
+    #[arg(long, default_value_t = 1)]
+    min_matched: u32,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+struct ChromatogramArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Only report scans at this MS level, or range of levels (e.g. `1` or
+    /// `1-2`)
+    #[arg(long)]
+    ms_level: Option<MsLevels>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+struct MgfArgs {
+    /// mzparquet file to export
+    file: String,
+
+    /// Write the MGF file to this path. Defaults to stdout
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// First scan to export: a vendor scan number where the native ids have
+    /// them, and otherwise a spectrum index from 0, as in the `scan` column
+    #[arg(long)]
+    scan_min: Option<u32>,
+
+    /// Last scan to export
+    #[arg(long)]
+    scan_max: Option<u32>,
+
+    /// Start of the retention time window to export, in --rt-unit
+    #[arg(long)]
+    rt_min: Option<f32>,
+
+    /// End of the retention time window to export, in --rt-unit
+    #[arg(long)]
+    rt_max: Option<f32>,
+
+    /// Unit of --rt-min and --rt-max: `seconds` or `minutes`
+    #[arg(long, default_value_t = RtUnit::Seconds)]
+    rt_unit: RtUnit,
+}
+
+#[derive(Args, Debug)]
+struct MigrateArgs {
+    /// mzparquet file to migrate
+    file: String,
+
+    /// Path of the migrated file. Defaults to rewriting the input in place
+    output: Option<String>,
+
+    /// Only print the compatibility report, without writing anything
+    #[arg(long)]
+    check: bool,
+}
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    /// mzparquet file to summarize
+    file: String,
+
+    /// Write the metrics to a file instead of stdout. Paths ending in
+    /// `.parquet` are written as a parquet table, anything else as JSON
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// mzML file to check
+    file: String,
+
+    /// Output layout to check
+    #[arg(long, default_value_t = Format::Long)]
+    format: Format,
+
+    /// Maximum number of discrepancies to print
+    #[arg(long, default_value_t = 20)]
+    max_reported: usize,
+
+    #[command(flatten)]
+    writer: WriterArgs,
+}
+
+#[derive(Args, Debug)]
+struct InfoArgs {
+    /// mzparquet file to summarize
+    file: String,
+}
+
+#[derive(Args, Debug)]
+struct RewriteArgs {
+    /// mzparquet file to rewrite
+    file: String,
+
+    /// Path of the rewritten file. May be the same as the input
+    output: String,
+
+    #[command(flatten)]
+    writer: WriterArgs,
+}
+
+#[derive(Args, Debug)]
+struct SplitArgs {
+    /// mzparquet file to split
+    file: String,
+
+    /// Directory to write the split files to. Defaults to the directory of
+    /// the input file
+    #[arg(short, long)]
+    output_directory: Option<String>,
+
+    #[command(flatten)]
+    writer: WriterArgs,
+}
+
+#[derive(Args, Debug)]
+struct MergeArgs {
+    /// Path of the merged mzparquet file
+    output: String,
+
+    /// mzparquet files to merge, in order. Glob patterns, local directories
+    /// and remote prefixes (ending in `/`) are expanded to the `.mzparquet`
+    /// and `.parquet` files they contain
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    #[command(flatten)]
+    writer: WriterArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum QueryCommand {
+    /// Extract an ion chromatogram (rt, summed intensity) for a target m/z
+    Xic(XicArgs),
+    /// Find MS2 spectra containing a product ion
+    Fragment(FragmentArgs),
+    /// Find MS2 spectra by precursor m/z
+    Precursor(PrecursorArgs),
+    /// Extract the XIC and peak area of every target in a target list, in
+    /// every file
+    Targets(TargetsArgs),
+    /// Score query spectra against every MS2 spectrum (cosine or modified
+    /// cosine), reporting the best matches in each file
+    Similarity(SimilarityArgs),
+    /// Run a MassQL query, e.g. `QUERY scaninfo(MS2DATA) WHERE MS2PROD=226.18`
+    #[cfg(feature = "massql")]
+    Massql(MassQLArgs),
+    /// Run SQL over mzparquet files, e.g. `SELECT * FROM run WHERE level = 2`
+    #[cfg(feature = "sql")]
+    Sql(SqlArgs),
+}
+
+#[derive(Args, Debug)]
+struct ToleranceArgs {
+    /// Mass tolerance, in ppm
+    #[arg(long, default_value_t = 10.0)]
+    ppm: f32,
+
+    /// Mass tolerance, in Da. Overrides --ppm
+    #[arg(long, conflicts_with = "ppm")]
+    da: Option<f32>,
+}
+
+impl ToleranceArgs {
+    fn tolerance(&self) -> Tolerance {
+        match self.da {
+            Some(da) => Tolerance::Da(da),
+            None => Tolerance::Ppm(self.ppm),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct InputArgs {
+    /// mzparquet files to search. Glob patterns, local directories and remote
+    /// prefixes (ending in `/`) are expanded to the `.mzparquet` and
+    /// `.parquet` files they contain
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    /// Number of files to search concurrently. Defaults to the number of CPUs
+    #[arg(short, long)]
+    jobs: Option<usize>,
+}
+
+/// Expand glob patterns into the paths they match, failing if a pattern
+/// matches nothing
+async fn expand_globs(files: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for file in files {
+        if !cloud::is_glob(file) {
+            paths.push(file.clone());
+            continue;
+        }
+        let matches = cloud::glob(file).await?;
+        if matches.is_empty() {
+            anyhow::bail!("no files match {}", file);
+        }
+        paths.extend(matches.iter().map(CloudPath::to_string));
+    }
+    Ok(paths)
+}
+
+/// Expand glob patterns, local directories and remote prefixes (ending in
+/// `/`) into the `.mzparquet` and `.parquet` files they contain, in sorted
+/// order
+async fn expand_paths(files: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for file in expand_globs(files).await? {
+        match file.parse::<CloudPath>()? {
+            CloudPath::Local(dir) if dir.is_dir() => {
+                let mut entries = std::fs::read_dir(&dir)?
+                    .map(|entry| entry.map(|e| e.path()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                entries.retain(|path| {
+                    path.extension()
+                        .is_some_and(|ext| ext == "mzparquet" || ext == "parquet")
+                });
+                entries.sort();
+                paths.extend(entries.iter().map(|path| path.display().to_string()));
+            }
+            CloudPath::Remote(url) if url.path().ends_with('/') => {
+                let entries = cloud::glob(&format!("{}*", url)).await?;
+                paths.extend(
+                    entries
+                        .iter()
+                        .filter(|path| {
+                            matches!(
+                                input_extension(path).as_deref(),
+                                Some("mzparquet" | "parquet")
+                            )
+                        })
+                        .map(CloudPath::to_string),
+                );
+            }
+            _ => paths.push(file),
+        }
+    }
+    Ok(paths)
+}
+
+impl InputArgs {
+    async fn paths(&self) -> anyhow::Result<Vec<String>> {
+        expand_paths(&self.files).await
+    }
+
+    fn jobs(&self) -> usize {
+        self.jobs
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            })
+            .max(1)
+    }
+}
+
+/// Where to write a table of results (hits of a search, or chromatograms)
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// Write results to this path, as parquet if it ends with `.parquet` and
+    /// CSV otherwise. Defaults to CSV on stdout
+    #[arg(short = 'o', long = "output", value_name = "OUTPUT")]
+    path: Option<String>,
+}
+
+impl OutputArgs {
+    async fn write(&self, table: &Table) -> anyhow::Result<()> {
+        write_table(table, self.path.as_deref()).await
+    }
+}
+
+#[derive(Args, Debug)]
+struct SimilarityArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// MGF file holding the query spectra
+    #[arg(long, required_unless_present = "peaks", conflicts_with = "peaks")]
+    mgf: Option<String>,
+
+    /// Query spectrum, as a list of `mz:intensity` peaks (e.g.
+    /// `105.07:30,133.06:100`)
+    #[arg(long)]
+    peaks: Option<String>,
+
+    /// Precursor m/z of the --peaks query spectrum, for --modified
+    #[arg(long, requires = "peaks")]
+    precursor_mz: Option<f64>,
+
+    #[command(flatten)]
+    tolerance: ToleranceArgs,
+
+    /// Use the modified cosine, which also matches peaks shifted by the
+    /// precursor m/z difference
+    #[arg(long)]
+    modified: bool,
+
+    /// Number of matches reported for each query spectrum, in each file
+    #[arg(long, default_value_t = 10)]
+    top_k: usize,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+struct TargetsArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// CSV or tab separated target list, with `name`, `mz` and optional
+    /// `rt_min` and `rt_max` columns, in seconds
+    #[arg(long)]
+    targets: String,
+
+    #[command(flatten)]
+    tolerance: ToleranceArgs,
+
+    #[arg(long, default_value_t = 1)]
+    ms_level: u8,
+
+    /// Report every point of each XIC, rather than one row per target with
+    /// its peak area and apex
+    #[arg(long)]
+    points: bool,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+struct XicArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Target m/z
+    #[arg(long)]
+    mz: f64,
+
+    #[command(flatten)]
+    tolerance: ToleranceArgs,
+
+    /// Start of the retention time window, in seconds whatever the unit of
+    /// each file
+    #[arg(long)]
+    rt_min: Option<f32>,
+
+    /// End of the retention time window, in seconds
+    #[arg(long)]
+    rt_max: Option<f32>,
+
+    #[arg(long, default_value_t = 1)]
+    ms_level: u8,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+struct FragmentArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Product ion m/z
+    #[arg(long)]
+    mz: f64,
+
+    #[command(flatten)]
+    tolerance: ToleranceArgs,
+
+    /// Only report spectra with a precursor m/z within tolerance of this value
+    #[arg(long)]
+    precursor_mz: Option<f64>,
+
+    /// Precursor mass tolerance, in ppm
+    #[arg(long, default_value_t = 10.0)]
+    precursor_ppm: f32,
+
+    /// Precursor mass tolerance, in Da. Overrides --precursor-ppm
+    #[arg(long, conflicts_with = "precursor_ppm")]
+    precursor_da: Option<f32>,
+
+    /// Start of the retention time window, in seconds whatever the unit of
+    /// each file
+    #[arg(long)]
+    rt_min: Option<f32>,
+
+    /// End of the retention time window, in seconds
+    #[arg(long)]
+    rt_max: Option<f32>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+struct PrecursorArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Precursor m/z
+    #[arg(long)]
+    mz: f64,
+
+    #[command(flatten)]
+    tolerance: ToleranceArgs,
+
+    /// Also match spectra whose isolation window contains the target m/z
+    #[arg(long)]
+    isolation_window: bool,
+
+    /// Start of the retention time window, in seconds whatever the unit of
+    /// each file
+    #[arg(long)]
+    rt_min: Option<f32>,
+
+    /// End of the retention time window, in seconds
+    #[arg(long)]
+    rt_max: Option<f32>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[cfg(feature = "massql")]
+#[derive(Args, Debug)]
+struct MassQLArgs {
+    query: mz_parquet::massql::MassQuery,
+
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[cfg(feature = "sql")]
+#[derive(Args, Debug)]
+struct SqlArgs {
+    query: String,
+
+    /// Files or directories to register as tables, as `name=path` or a bare
+    /// path (named after the file, without extensions)
+    #[arg(required = true)]
+    tables: Vec<mz_parquet::sql::TableSource>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+/// Parse an inclusive `lo:hi` range, either end of which may be left out
+fn parse_range<T>(s: &str) -> Result<(Option<T>, Option<T>), String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let (lo, hi) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected a range `start:end`, got `{}`", s))?;
+    let bound = |b: &str| match b.trim() {
+        "" => Ok(None),
+        b => b.parse().map(Some).map_err(|e| format!("`{}`: {}", b, e)),
+    };
+    Ok((bound(lo)?, bound(hi)?))
+}
+
+/// Parse a size in bytes, with an optional `K`, `M`, `G` or `T` suffix (powers
+/// of 1024)
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let scale: u64 = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("unknown unit `{}`", unit)),
+    };
+    let number = number
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| format!("`{}`: {}", number, e))?;
+    Ok((number * scale as f64) as usize)
+}
+
+fn rt_window(lo: Option<f32>, hi: Option<f32>) -> Option<(f32, f32)> {
+    match (lo, hi) {
+        (None, None) => None,
+        (lo, hi) => Some((lo.unwrap_or(f32::MIN), hi.unwrap_or(f32::MAX))),
+    }
+}
+
+/// An mzparquet file opened for reading. Local files are read lazily, while
+/// remote files are downloaded in full
+enum ParquetFile {
+    Local(std::fs::File),
+    Remote(bytes::Bytes),
+}
+
+impl ParquetFile {
+    async fn open(path: &str) -> anyhow::Result<Self> {
+        match path.parse::<CloudPath>()? {
+            CloudPath::Local(path) => Ok(ParquetFile::Local(std::fs::File::open(path)?)),
+            remote => Ok(ParquetFile::Remote(remote.read_bytes().await?)),
+        }
+    }
+}
+
+impl Length for ParquetFile {
+    fn len(&self) -> u64 {
+        match self {
+            ParquetFile::Local(f) => f.len(),
+            ParquetFile::Remote(b) => b.len() as u64,
+        }
+    }
+}
+
+impl ChunkReader for ParquetFile {
+    type T = Box<dyn std::io::Read + Send>;
+
+    fn get_read(&self, start: u64) -> parquet::errors::Result<Self::T> {
+        match self {
+            ParquetFile::Local(f) => Ok(Box::new(f.get_read(start)?)),
+            ParquetFile::Remote(b) => Ok(Box::new(b.get_read(start)?)),
+        }
+    }
+
+    fn get_bytes(&self, start: u64, length: usize) -> parquet::errors::Result<bytes::Bytes> {
+        match self {
+            ParquetFile::Local(f) => f.get_bytes(start, length),
+            ParquetFile::Remote(b) => b.get_bytes(start, length),
+        }
+    }
+}
+
+async fn write_table(table: &Table, output: Option<&str>) -> anyhow::Result<()> {
+    match output {
+        None => table.write_csv(std::io::stdout().lock())?,
+        Some(path) => {
+            let bytes = match path.ends_with(".parquet") {
+                true => table.write_parquet(Vec::new())?,
+                false => {
+                    let mut buf = Vec::new();
+                    table.write_csv(&mut buf)?;
+                    buf
+                }
+            };
+            path.parse::<CloudPath>()?.write_bytes(bytes).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Run `query` against every input file, with up to `--jobs` files in flight
+/// at once. Hits are returned in input order, with a leading `filename` column
+async fn fan_out<T, F>(input: &InputArgs, query: F) -> anyhow::Result<Table>
+where
+    T: Send + 'static,
+    for<'a> Table: From<&'a [T]>,
+    F: Fn(ParquetFile) -> parquet::errors::Result<Vec<T>> + Clone + Send + 'static,
+{
+    let paths = input.paths().await?;
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(input.jobs()));
+    let mut tasks = tokio::task::JoinSet::new();
+    for (index, path) in paths.iter().cloned().enumerate() {
+        let semaphore = semaphore.clone();
+        let query = query.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            let file = ParquetFile::open(&path)
+                .await
+                .with_context(|| format!("failed to open {}", path))?;
+            let hits = tokio::task::spawn_blocking(move || query(file))
+                .await?
+                .with_context(|| format!("failed to search {}", path))?;
+            anyhow::Ok((index, hits))
+        });
+    }
+
+    let mut results = Vec::with_capacity(paths.len());
+    while let Some(result) = tasks.join_next().await {
+        results.push(result??);
+    }
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut filenames = Vec::new();
+    let mut hits = Vec::new();
+    for (index, file_hits) in results {
+        filenames.extend(std::iter::repeat_n(paths[index].clone(), file_hits.len()));
+        hits.extend(file_hits);
+    }
+    Ok(Table::from(hits.as_slice()).insert_column(0, "filename", Column::Str(filenames)))
+}
+
+async fn run_query(command: QueryCommand) -> anyhow::Result<()> {
+    match command {
+        QueryCommand::Xic(args) => {
+            let query = XicQuery {
+                mz: args.mz,
+                tolerance: args.tolerance.tolerance(),
+                rt: rt_window(args.rt_min, args.rt_max),
+                ms_level: args.ms_level,
+            };
+            let table = fan_out(&args.input, move |file| query::xic(file, &query)).await?;
+            args.output.write(&table).await
+        }
+        QueryCommand::Fragment(args) => {
+            let precursor_tolerance = match args.precursor_da {
+                Some(da) => Tolerance::Da(da),
+                None => Tolerance::Ppm(args.precursor_ppm),
+            };
+            let query = FragmentQuery {
+                mz: args.mz,
+                tolerance: args.tolerance.tolerance(),
+                precursor: args.precursor_mz.map(|mz| (mz, precursor_tolerance)),
+                rt: rt_window(args.rt_min, args.rt_max),
+            };
+            let table = fan_out(&args.input, move |file| query::fragment(file, &query)).await?;
+            args.output.write(&table).await
+        }
+        QueryCommand::Precursor(args) => {
+            let query = PrecursorQuery {
+                mz: args.mz,
+                tolerance: args.tolerance.tolerance(),
+                isolation_window: args.isolation_window,
+                rt: rt_window(args.rt_min, args.rt_max),
+            };
+            let table = fan_out(&args.input, move |file| query::precursor(file, &query)).await?;
+            args.output.write(&table).await
+        }
+        QueryCommand::Similarity(args) => {
+            let spectra = match (&args.mgf, &args.peaks) {
+                (Some(mgf), _) => {
+                    let mut text = String::new();
+                    let path = mgf.parse::<CloudPath>()?;
+                    path.read().await?.read_to_string(&mut text).await?;
+                    similarity::parse_mgf(&text)?
+                }
+                (None, Some(peaks)) => {
+                    let mut spectrum = QuerySpectrum::from_peak_list("query", peaks)?;
+                    spectrum.precursor_mz = args.precursor_mz;
+                    vec![spectrum]
+                }
+                (None, None) => anyhow::bail!("either --mgf or --peaks is required"),
+            };
+            let query = SimilarityQuery {
+                spectra,
+                tolerance: args.tolerance.tolerance(),
+                modified: args.modified,
+                top_k: args.top_k,
+            };
+            let table = fan_out(&args.input, move |file| similarity::search(file, &query)).await?;
+            args.output.write(&table).await
+        }
+        QueryCommand::Targets(args) => {
+            let mut text = String::new();
+            let path = args.targets.parse::<CloudPath>()?;
+            path.read().await?.read_to_string(&mut text).await?;
+            let targets = Arc::new(targets::parse_targets(&text)?);
+            let (tolerance, ms_level) = (args.tolerance.tolerance(), args.ms_level);
+            let extract = move |file| targets::extract(file, &targets, tolerance, ms_level);
+            let table = match args.points {
+                true => {
+                    fan_out(&args.input, move |file| {
+                        let points = extract(file)?
+                            .into_iter()
+                            .flat_map(|xic| {
+                                let target = xic.target.name;
+                                xic.points.into_iter().map(move |point| TargetPoint {
+                                    target: target.clone(),
+                                    point,
+                                })
+                            })
+                            .collect();
+                        Ok(points)
+                    })
+                    .await?
+                }
+                false => fan_out(&args.input, extract).await?,
+            };
+            args.output.write(&table).await
+        }
+        #[cfg(feature = "massql")]
+        QueryCommand::Massql(args) => {
+            use mz_parquet::massql::{self, Output};
+            let query = args.query;
+            let table = match query.output {
+                Output::ScanInfo => {
+                    fan_out(&args.input, move |file| massql::scan_info(file, &query)).await?
+                }
+                Output::Data => {
+                    fan_out(&args.input, move |file| massql::data(file, &query)).await?
+                }
+            };
+            args.output.write(&table).await
+        }
+        #[cfg(feature = "sql")]
+        QueryCommand::Sql(args) => {
+            use mz_parquet::sql;
+            let (schema, batches) = sql::query(&args.query, &args.tables).await?;
+            match args.output.path.as_deref() {
+                None => sql::write_csv(&batches, std::io::stdout().lock())?,
+                Some(path) => {
+                    let bytes = match path.ends_with(".parquet") {
+                        true => sql::write_parquet(schema, &batches, Vec::new())?,
+                        false => {
+                            let mut buf = Vec::new();
+                            sql::write_csv(&batches, &mut buf)?;
+                            buf
+                        }
+                    };
+                    path.parse::<CloudPath>()?.write_bytes(bytes).await?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn serialize<W, S>(
+    format: OutputFormat,
+    w: W,
+    stream: &mut S,
+    options: &WriterOptions,
+) -> anyhow::Result<(W, Written)>
+where
+    W: std::io::Write + Send,
+    S: SpectrumStream,
+{
+    match format {
+        OutputFormat::Long => write_long::serialize_stream_to_parquet(w, stream, options).await,
+        OutputFormat::Wide => write_wide::serialize_stream_to_parquet(w, stream, options).await,
+        OutputFormat::Arrow => {
+            write_arrow::serialize_stream_to_ipc(w, stream, options, IpcFormat::File).await
+        }
+        OutputFormat::ArrowStream => {
+            write_arrow::serialize_stream_to_ipc(w, stream, options, IpcFormat::Stream).await
+        }
+    }
+}
+
+/// File name of `path`, with all extensions removed
+fn file_stem(path: &CloudPath) -> anyhow::Result<String> {
+    path.filename()
+        .and_then(|f| f.split_once('.').map(|(f, _)| f.to_string()))
+        .ok_or_else(|| anyhow!("no filename!"))
+}
+
+/// Path of an output file named `filename`, placed in `output_directory` if
+/// given, and otherwise alongside `input`
+fn output_path(
+    input: &CloudPath,
+    output_directory: Option<&str>,
+    filename: String,
+) -> anyhow::Result<CloudPath> {
+    Ok(match output_directory {
+        Some(dir) => {
+            let mut dir = dir.parse::<CloudPath>()?;
+            dir.push(&filename);
+            dir
+        }
+        None => input.with_file_name(&filename),
+    })
+}
+
+/// Spectra of an input file
+enum Input {
+    MzML(Box<mzml::MzMLStream<Box<dyn AsyncBufRead + Unpin + Send>>>),
+    /// mzML written by msconvert, removed once the input is dropped
+    Converted {
+        stream: Box<mzml::MzMLStream<Box<dyn AsyncBufRead + Unpin + Send>>>,
+        _mzml: mz_parquet::vendor::TempMzML,
+    },
+    /// Local indexedmzML, parsed in parallel
+    Indexed(Box<mz_parquet::indexed::IndexedStream>),
+    #[cfg(feature = "thermo")]
+    Thermo(mz_parquet::thermo::RawFileStream),
+    #[cfg(feature = "tdf")]
+    Tdf(mz_parquet::tdf::TdfStream),
+}
+
+impl SpectrumStream for Input {
+    async fn next_spectrum(&mut self) -> Result<Option<mz_parquet::RawSpectrum>, mzml::MzMLError> {
+        match self {
+            Input::MzML(stream) | Input::Converted { stream, .. } => stream.next_spectrum().await,
+            Input::Indexed(stream) => SpectrumStream::next_spectrum(stream.as_mut()).await,
+            #[cfg(feature = "thermo")]
+            Input::Thermo(stream) => SpectrumStream::next_spectrum(stream).await,
+            #[cfg(feature = "tdf")]
+            Input::Tdf(stream) => SpectrumStream::next_spectrum(stream).await,
+        }
+    }
+
+    fn take_chromatograms(&mut self) -> Vec<mzml::Chromatogram> {
+        match self {
+            Input::MzML(stream) | Input::Converted { stream, .. } => stream.take_chromatograms(),
+            Input::Indexed(stream) => SpectrumStream::take_chromatograms(stream.as_mut()),
+            #[cfg(feature = "thermo")]
+            Input::Thermo(_) => Vec::new(),
+            #[cfg(feature = "tdf")]
+            Input::Tdf(_) => Vec::new(),
+        }
+    }
+
+    fn run_metadata(&self) -> mz_parquet::metadata::RunMetadata {
+        match self {
+            Input::MzML(stream) | Input::Converted { stream, .. } => stream.metadata().clone(),
+            Input::Indexed(stream) => SpectrumStream::run_metadata(stream.as_ref()),
+            #[cfg(feature = "thermo")]
+            Input::Thermo(_) => Default::default(),
+            #[cfg(feature = "tdf")]
+            Input::Tdf(_) => Default::default(),
+        }
+    }
+}
+
+/// Lowercase extension of an input file, e.g. `mzml` or `imzml`
+fn input_extension(path: &CloudPath) -> Option<String> {
+    path.filename()
+        .and_then(|f| f.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()))
+}
+
+/// Start reading an mzML or imzML file, or an mzMLb file, Thermo RAW file or
+/// timsTOF `.d` directory when built with the `mzmlb`, `thermo` or `tdf`
+/// features. Sciex WIFF files, Waters `.raw` and Agilent `.d` directories are
+/// converted with msconvert first. Gzipped (`.mzML.gz`) files are decompressed
+/// while they are parsed, and local indexedmzML files are parsed in parallel.
+async fn open_input(cloudpath: &CloudPath, reader: &mzml::MzMLReader) -> anyhow::Result<Input> {
+    match (input_extension(cloudpath).as_deref(), cloudpath) {
+        (Some("imzml"), CloudPath::Local(path)) => {
+            let arrays = mz_parquet::imzml::IbdArrays::open(mz_parquet::imzml::ibd_path(path))?;
+            let mut stream = mzml_stream(reader, cloudpath.to_string(), cloudpath.read().await?);
+            stream.set_external_arrays(Box::new(arrays));
+            return Ok(Input::MzML(Box::new(stream)));
+        }
+        #[cfg(feature = "mzmlb")]
+        (Some("mzmlb"), CloudPath::Local(path)) => {
+            let (document, arrays) = mz_parquet::mzmlb::open(path)?;
+            let mut stream = mzml_stream(
+                reader,
+                cloudpath.to_string(),
+                std::io::Cursor::new(document),
+            );
+            stream.set_external_arrays(Box::new(arrays));
+            return Ok(Input::MzML(Box::new(stream)));
+        }
+        (Some("raw"), CloudPath::Local(path)) if mz_parquet::vendor::is_waters_raw(path) => {
+            return msconvert(path, mz_parquet::vendor::Msconvert::waters(), reader).await;
+        }
+        (Some("d"), CloudPath::Local(path)) if mz_parquet::vendor::is_agilent_d(path) => {
+            return msconvert(path, mz_parquet::vendor::Msconvert::agilent(), reader).await;
+        }
+        #[cfg(feature = "thermo")]
+        (Some("raw"), CloudPath::Local(path)) => {
+            let stream = mz_parquet::thermo::RawFileStream::open(path)?;
+            return Ok(Input::Thermo(stream));
+        }
+        #[cfg(feature = "tdf")]
+        (Some("d"), CloudPath::Local(path)) => {
+            let stream = mz_parquet::tdf::TdfStream::open(path)?;
+            return Ok(Input::Tdf(stream));
+        }
+        (Some(ext), CloudPath::Local(path)) if mz_parquet::vendor::EXTENSIONS.contains(&ext) => {
+            return msconvert(path, mz_parquet::vendor::Msconvert::default(), reader).await;
+        }
+        (Some("imzml" | "mzmlb" | "raw" | "d" | "wiff" | "wiff2"), _) => {
+            anyhow::bail!("{} can only be read from a local path", cloudpath)
+        }
+        (Some("gz"), _) => {
+            let mut decoder = GzipDecoder::new(cloudpath.read().await?);
+            // Files compressed in parallel (pigz, bgzip) have several members
+            decoder.multiple_members(true);
+            let stream = mzml_stream(reader, cloudpath.to_string(), decoder);
+            return Ok(Input::MzML(Box::new(stream)));
+        }
+        (Some("mzml"), CloudPath::Local(path)) => {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            // Parsing is faster sequentially on a single thread
+            if threads > 1 {
+                let stream = mz_parquet::indexed::IndexedStream::open(reader, path, threads)?;
+                if let Some(stream) = stream {
+                    return Ok(Input::Indexed(Box::new(stream)));
+                }
+            }
+        }
+        _ => {}
+    }
+    let stream = mzml_stream(reader, cloudpath.to_string(), cloudpath.read().await?);
+    Ok(Input::MzML(Box::new(stream)))
+}
+
+/// Settings for parsing files to convert, keeping extra params if asked to
+/// and reading at most `read_ahead` bytes ahead of the writer
+fn mzml_reader(extra_params: bool, read_ahead: Option<usize>) -> mzml::MzMLReader {
+    let mut reader = mzml::MzMLReader::default();
+    reader
+        .set_extra_params(extra_params)
+        .set_parallel_decoding(true)
+        .set_read_ahead(read_ahead);
+    reader
+}
+
+/// Start parsing an mzML document read from `path`, recording the path and
+/// the checksum of the document in the run metadata
+fn mzml_stream<R>(
+    config: &mzml::MzMLReader,
+    path: String,
+    r: R,
+) -> mzml::MzMLStream<Box<dyn AsyncBufRead + Unpin + Send>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (reader, checksum) = Sha1Reader::new(r);
+    let reader: Box<dyn AsyncBufRead + Unpin + Send> = Box::new(tokio::io::BufReader::new(reader));
+    let mut stream = config.stream(reader);
+    stream.set_input(path, Some(checksum));
+    stream
+}
+
+/// Convert a vendor file to a temporary mzML file, and start reading it
+async fn msconvert(
+    path: &std::path::Path,
+    msconvert: mz_parquet::vendor::Msconvert,
+    reader: &mzml::MzMLReader,
+) -> anyhow::Result<Input> {
+    let vendor_path = path.display().to_string();
+    let path = path.to_path_buf();
+    let mzml = tokio::task::spawn_blocking(move || msconvert.convert(&path)).await??;
+    let file = tokio::fs::File::open(mzml.path()).await?;
+    let stream = mzml_stream(reader, vendor_path, file);
+    Ok(Input::Converted {
+        stream: Box::new(stream),
+        _mzml: mzml,
+    })
+}
+
+/// Path of the spectra written for `input`, `run.mzparquet` or
+/// `run.{suffix}.mzparquet`
+fn spectra_path(
+    input: &CloudPath,
+    output_directory: Option<&str>,
+    suffix: Option<&str>,
+    format: OutputFormat,
+) -> anyhow::Result<CloudPath> {
+    let stem = match suffix {
+        Some(suffix) => format!("{}.{}", file_stem(input)?, suffix),
+        None => file_stem(input)?,
+    };
+    let filename = format!("{}.{}", stem, format.extension());
+    output_path(input, output_directory, filename)
+}
+
+/// Start writing the spectra of `stream` to `pqt_path` on a blocking thread,
+/// returning the writer (still to be finished), what was written and the
+/// stream once done. Row groups are flushed to disk, or uploaded, as they are
+/// completed, and writes wait for uploads to catch up
+async fn write_spectra<S>(
+    pqt_path: &CloudPath,
+    format: OutputFormat,
+    mut stream: S,
+    options: &WriterOptions,
+) -> anyhow::Result<tokio::task::JoinHandle<anyhow::Result<(CloudWriter, Written, S)>>>
+where
+    S: SpectrumStream + Send + 'static,
+{
+    let w = pqt_path.create().await?;
+    let handle = tokio::runtime::Handle::current();
+    let options = options.clone();
+    Ok(tokio::task::spawn_blocking(move || {
+        let (w, written) = handle.block_on(serialize(format, w, &mut stream, &options))?;
+        anyhow::Ok((w, written, stream))
+    }))
+}
+
+/// Convert `path`, writing `run.mzparquet`. With `split` outputs, the input is
+/// instead read once and written to a `run.{suffix}.mzparquet` file for each,
+/// keeping the spectra selected by its filter. What was written is added to
+/// `report`
+async fn convert_mzml(
+    path: &str,
+    output_directory: Option<&str>,
+    split: &[(&str, SpectrumFilter)],
+    format: OutputFormat,
+    options: &WriterOptions,
+    reader: &mzml::MzMLReader,
+    report: &mut FileReport,
+) -> anyhow::Result<()> {
+    let cloudpath = path.parse::<CloudPath>()?;
+    let stream = open_input(&cloudpath, reader).await?;
+
+    let mut outputs = Vec::new();
+    let mut stream = if split.is_empty() {
+        let pqt_path = spectra_path(&cloudpath, output_directory, None, format)?;
+        let (w, written, stream) = write_spectra(&pqt_path, format, stream, options)
+            .await?
+            .await??;
+        w.finish().await?;
+        outputs.push((pqt_path, written));
+        stream
+    } else {
+        let (streams, input) = mz_parquet::tee::tee(stream, split.len());
+        let mut writes = Vec::new();
+        for ((suffix, filter), stream) in split.iter().zip(streams) {
+            let pqt_path = spectra_path(&cloudpath, output_directory, Some(suffix), format)?;
+            let mut options = options.clone();
+            options.set_spectrum_filter(*filter);
+            let write = write_spectra(&pqt_path, format, stream, &options).await?;
+            writes.push((pqt_path, write));
+        }
+        // Writers fail with the input if it cannot be read, so are awaited
+        // first for any error of their own
+        for (pqt_path, write) in writes {
+            let (w, written, _) = write.await??;
+            w.finish().await?;
+            outputs.push((pqt_path, written));
+        }
+        input.await??
+    };
+
+    for (pqt_path, written) in outputs {
+        log::info!(
+            file:% = cloudpath,
+            stage = "write",
+            output:% = pqt_path,
+            spectra = written.spectra,
+            peaks = written.peaks;
+            "copied {} spectra from {} to {}",
+            written.spectra,
+            cloudpath,
+            pqt_path,
+        );
+        report.spectra += written.spectra;
+        report.peaks += written.peaks;
+        report.push_output(pqt_path.to_string(), pqt_path.size().await?);
+        if written.spectra == 0 {
+            report
+                .warnings
+                .push(format!("no spectra were written to {}", pqt_path));
+        }
+    }
+
+    // Chromatograms follow the spectra in mzML, so they are only available
+    // once every spectrum has been written
+    let chromatograms = stream.take_chromatograms();
+    if !chromatograms.is_empty() {
+        let filename = format!("{}.chromatograms.mzparquet", file_stem(&cloudpath)?);
+        let chrom_path = output_path(&cloudpath, output_directory, filename)?;
+        let buffer =
+            write_chromatograms::serialize_to_parquet(Vec::new(), &chromatograms, options)?;
+        let bytes = buffer.len() as u64;
+        chrom_path.write_bytes(buffer).await?;
+        report.push_output(chrom_path.to_string(), bytes);
+        log::info!(
+            file:% = cloudpath,
+            stage = "chromatograms",
+            output:% = chrom_path,
+            chromatograms = chromatograms.len();
+            "copied {} chromatograms from {} to {}",
+            chromatograms.len(),
+            cloudpath,
+            chrom_path,
+        );
+    }
+    Ok(())
+}
+
+/// Convert an mzML file to an in-memory long format mzparquet file, for the
+/// table format outputs. Returns the file and the number of spectra and peaks
+#[cfg(any(feature = "delta", feature = "iceberg"))]
+async fn convert_to_buffer(
+    cloudpath: &CloudPath,
+    options: &WriterOptions,
+    reader: &mzml::MzMLReader,
+) -> anyhow::Result<(Vec<u8>, Written)> {
+    let mut stream = open_input(cloudpath, reader).await?;
+    write_long::serialize_stream_to_parquet(Vec::new(), &mut stream, options).await
+}
+
+#[cfg(feature = "delta")]
+async fn append_to_delta(
+    path: &str,
+    table: &str,
+    options: &WriterOptions,
+    reader: &mzml::MzMLReader,
+    report: &mut FileReport,
+) -> anyhow::Result<()> {
+    use mz_parquet::delta;
+
+    let cloudpath = path.parse::<CloudPath>()?;
+    let (buffer, written) = convert_to_buffer(&cloudpath, options, reader).await?;
+    report.spectra = written.spectra;
+    report.peaks = written.peaks;
+    report.push_output(table, buffer.len() as u64);
+    let batches =
+        tokio::task::spawn_blocking(move || delta::record_batches(bytes::Bytes::from(buffer)))
+            .await??;
+
+    let version = delta::append(table, batches).await?.version();
+    log::info!(
+        file:% = cloudpath,
+        stage = "append",
+        output = table,
+        spectra = written.spectra,
+        peaks = written.peaks;
+        "appended {} spectra from {} to {} (version {})",
+        written.spectra,
+        cloudpath,
+        table,
+        version
+    );
+    Ok(())
+}
 
-#[derive(Args, Debug)]
-struct ConverterArgs {
-    #[arg(short, long)]
-    output_directory: Option<String>,
+#[cfg(feature = "iceberg")]
+async fn append_to_iceberg(
+    path: &str,
+    table: &mz_parquet::iceberg::IcebergTable,
+    file_id: &str,
+    options: &WriterOptions,
+    reader: &mzml::MzMLReader,
+    report: &mut FileReport,
+) -> anyhow::Result<()> {
+    let cloudpath = path.parse::<CloudPath>()?;
+    let (buffer, written) = convert_to_buffer(&cloudpath, options, reader).await?;
+    report.spectra = written.spectra;
+    report.peaks = written.peaks;
+    report.push_output(table.name(), buffer.len() as u64);
+    let batches = tokio::task::spawn_blocking(move || {
+        write_arrow::signed_record_batches(bytes::Bytes::from(buffer))
+    })
+    .await??;
 
-    #[arg(num_args(1..))]
-    files: Vec<String>,
+    let files = table.append(file_id, batches).await?;
+    log::info!(
+        file:% = cloudpath,
+        stage = "append",
+        output = table.name(),
+        spectra = written.spectra,
+        peaks = written.peaks;
+        "appended {} spectra from {} to the Iceberg table ({} data files)",
+        written.spectra,
+        cloudpath,
+        files
+    );
+    Ok(())
 }
 
-async fn convert_mzml(path: &str, output_directory: Option<&str>) -> anyhow::Result<()> {
-    let cloudpath = path.parse::<CloudPath>()?;
-    let pqt_path = match output_directory {
-        Some(dir) => {
-            let mut dir = dir.parse::<CloudPath>()?;
-            dir.mkdir()?;
-
-            let filename = cloudpath
-                .filename()
-                .and_then(|f| f.split_once('.'))
-                .and_then(|(f, _)| Some(format!("{}.mzparquet", f)))
-                .ok_or_else(|| anyhow!("no filename!"))?;
-            dir.push(filename);
-            dir
+/// Write an output file on a blocking thread. Remote files are uploaded while
+/// they are written. `write` returns the number of spectra written
+async fn write_output<F>(path: &CloudPath, write: F) -> anyhow::Result<usize>
+where
+    F: FnOnce(&mut (dyn std::io::Write + Send)) -> anyhow::Result<usize> + Send + 'static,
+{
+    let mut w = path.create().await?;
+    let (w, count) = tokio::task::spawn_blocking(move || {
+        let count = write(&mut w)?;
+        anyhow::Ok((w, count))
+    })
+    .await??;
+    w.finish().await?;
+    Ok(count)
+}
+
+/// Read an mzparquet file back into spectra
+async fn read_spectrum_file(path: &str) -> anyhow::Result<Arc<rewrite::SpectrumFile>> {
+    let file = ParquetFile::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path))?;
+    let file = tokio::task::spawn_blocking(move || rewrite::SpectrumFile::read(file))
+        .await?
+        .with_context(|| format!("failed to read {}", path))?;
+    Ok(Arc::new(file))
+}
+
+async fn merge(args: MergeArgs) -> anyhow::Result<()> {
+    let options = args.writer.writer_options(Format::Long)?;
+    let paths = expand_paths(&args.files).await?;
+    let output = args.output.parse::<CloudPath>()?;
+
+    // Inputs are opened one at a time as they are merged, so that at most one
+    // remote file is held in memory
+    let handle = tokio::runtime::Handle::current();
+    let inputs = paths.clone();
+    let count = write_output(&output, move |w| {
+        let inputs = inputs.iter().map(|path| {
+            let file = handle
+                .block_on(ParquetFile::open(path))
+                .with_context(|| format!("failed to open {}", path))?;
+            let source = Source {
+                file_id: file_stem(&path.parse()?)?,
+                path: Some(path.clone()),
+            };
+            Ok((source, file))
+        });
+        Ok(rewrite::merge(w, inputs, &options)?.1)
+    })
+    .await?;
+
+    log::info!(
+        "merged {} spectra from {} files into {}",
+        count,
+        paths.len(),
+        output
+    );
+    Ok(())
+}
+
+async fn split(args: SplitArgs) -> anyhow::Result<()> {
+    let input = args.file.parse::<CloudPath>()?;
+    let stem = file_stem(&input)?;
+    let file = read_spectrum_file(&args.file).await?;
+    let options = args.writer.writer_options(file.format)?;
+
+    for level in file.ms_levels() {
+        let filename = format!("{}.ms{}.mzparquet", stem, level);
+        let path = output_path(&input, args.output_directory.as_deref(), filename)?;
+        let (file, options) = (file.clone(), options.clone());
+        let count = write_output(&path, move |w| {
+            Ok(file.write_ms_level(level, w, &options)?.1)
+        })
+        .await?;
+        log::info!("wrote {} MS{} spectra to {}", count, level, path);
+    }
+    Ok(())
+}
+
+async fn rewrite(args: RewriteArgs) -> anyhow::Result<()> {
+    // The input is read in full (and closed) before the output is created,
+    // so that files can be rewritten in place
+    let file = read_spectrum_file(&args.file).await?;
+    let options = args.writer.writer_options(file.format)?;
+
+    let output = args.output.parse::<CloudPath>()?;
+    let count = write_output(&output, move |w| Ok(file.write(w, &options)?.1)).await?;
+    log::info!("rewrote {} spectra from {} to {}", count, args.file, output);
+    Ok(())
+}
+
+/// Expand `files` into local paths, for subcommands that cannot read from
+/// cloud storage
+#[cfg(any(feature = "flight", feature = "http"))]
+async fn local_paths(files: &[String]) -> anyhow::Result<Vec<std::path::PathBuf>> {
+    expand_paths(files)
+        .await?
+        .into_iter()
+        .map(|path| match path.parse::<CloudPath>()? {
+            CloudPath::Local(path) => Ok(path),
+            _ => anyhow::bail!("only local files are supported, not {}", path),
+        })
+        .collect()
+}
+
+async fn serve(args: ServeArgs) -> anyhow::Result<()> {
+    match args.flight {
+        #[cfg(feature = "flight")]
+        true => {
+            let addr = args.addr.unwrap_or(([127, 0, 0, 1], 50051).into());
+            mz_parquet::flight::serve(addr, local_paths(&args.files).await?).await
         }
-        None => {
-            let filename = cloudpath
-                .filename()
-                .and_then(|f| f.split_once('.'))
-                .and_then(|(f, _)| Some(format!("{}.mzparquet", f)))
-                .ok_or_else(|| anyhow!("no filename!"))?;
-            match cloudpath.clone() {
-                CloudPath::S3 { bucket, .. } => CloudPath::S3 {
-                    bucket,
-                    key: filename,
-                },
-                CloudPath::Local(path) => CloudPath::Local(path.with_file_name(filename)),
+        #[cfg(not(feature = "flight"))]
+        true => anyhow::bail!("Arrow Flight support requires building with the `flight` feature"),
+        #[cfg(feature = "http")]
+        false => {
+            let addr = args.addr.unwrap_or(([127, 0, 0, 1], 8080).into());
+            mz_parquet::http::serve(addr, local_paths(&args.files).await?).await
+        }
+        #[cfg(not(feature = "http"))]
+        false => anyhow::bail!("the HTTP server requires building with the `http` feature"),
+    }
+}
+
+async fn watch(args: WatchArgs) -> anyhow::Result<()> {
+    let options = args.writer.writer_options(args.format.layout())?;
+    let reader = mzml_reader(false, None);
+    let state = args
+        .state
+        .unwrap_or_else(|| args.directory.join(watch::STATE_FILE));
+    let mut watcher = Watcher::new(&args.directory, state)?;
+    watcher
+        .set_settle(Duration::from_secs(args.settle))
+        .set_retries(args.retries);
+
+    log::info!("watching {} for mzML files", args.directory.display());
+    let mut interval = tokio::time::interval(Duration::from_secs(args.interval));
+    loop {
+        interval.tick().await;
+        // The daemon keeps running through errors (e.g. a network share going
+        // away), trying again at the next scan
+        let paths = match watcher.poll() {
+            Ok(paths) => paths,
+            Err(e) => {
+                log::error!(
+                    stage = "watch",
+                    error:% = format!("{:#}", e);
+                    "failed to scan {}: {:#}", args.directory.display(), e
+                );
+                continue;
+            }
+        };
+        for path in paths {
+            let file = path.display().to_string();
+            let output = args.output_directory.as_deref();
+            let mut report = FileReport::new(&file);
+            let result = convert_mzml(
+                &file,
+                output,
+                &[],
+                args.format,
+                &options,
+                &reader,
+                &mut report,
+            )
+            .await
+            .map_err(|e| {
+                log::error!(
+                    file = file.as_str(),
+                    stage = "convert",
+                    error:% = format!("{:#}", e);
+                    "failed to convert {}: {:#}", file, e
+                );
+                format!("{:#}", e)
+            });
+            if let Err(e) = watcher.record(&path, result) {
+                log::error!(
+                    file = file.as_str(),
+                    stage = "watch",
+                    error:% = format!("{:#}", e);
+                    "failed to record {}: {:#}", file, e
+                );
+            }
+        }
+    }
+}
+
+async fn fragment_index(command: IndexCommand) -> anyhow::Result<()> {
+    match command {
+        IndexCommand::Build(args) => {
+            let mut builder = FragmentIndexBuilder::new(args.top_n);
+            let paths = expand_paths(&args.files).await?;
+            for path in &paths {
+                let file = ParquetFile::open(path)
+                    .await
+                    .with_context(|| format!("failed to open {}", path))?;
+                builder
+                    .add_file(path, file)
+                    .with_context(|| format!("failed to index {}", path))?;
             }
+            let output = args.output.parse::<CloudPath>()?;
+            write_output(&output, move |w| {
+                builder.write(w)?;
+                Ok(0)
+            })
+            .await?;
+            log::info!("indexed {} files into {}", paths.len(), args.output);
+            Ok(())
         }
+        IndexCommand::Search(args) => {
+            let query = IndexQuery {
+                fragments: args.fragments,
+                tolerance: args.tolerance.tolerance(),
+                precursor: args
+                    .precursor_mz
+                    .map(|mz| (mz, Tolerance::Ppm(args.precursor_ppm))),
+                min_matched: args.min_matched,
+            };
+            let file = ParquetFile::open(&args.index)
+                .await
+                .with_context(|| format!("failed to open {}", args.index))?;
+            let hits =
+                tokio::task::spawn_blocking(move || fragment_index::search(file, &query)).await??;
+            args.output.write(&Table::from(hits.as_slice())).await
+        }
+    }
+}
+
+async fn export_mgf(args: MgfArgs) -> anyhow::Result<()> {
+    let file = read_spectrum_file(&args.file).await?;
+    let query = MgfQuery {
+        scans: match (args.scan_min, args.scan_max) {
+            (None, None) => None,
+            (lo, hi) => Some((lo.unwrap_or(0), hi.unwrap_or(u32::MAX))),
+        },
+        rt: rt_window(args.rt_min, args.rt_max),
+        rt_unit: args.rt_unit,
     };
 
-    let mzml_spectra = mzml::MzMLReader::default()
-        .parse(cloudpath.read().await?)
-        .await?;
+    let count = match args.output.as_deref() {
+        None => {
+            let stdout = std::io::BufWriter::new(std::io::stdout().lock());
+            mgf::write_mgf(stdout, &file.spectra, &query)?
+        }
+        Some(output) => {
+            let path = output.parse::<CloudPath>()?;
+            write_output(&path, move |w| {
+                Ok(mgf::write_mgf(w, &file.spectra, &query)?)
+            })
+            .await?
+        }
+    };
+    log::info!("exported {} MS2 spectra from {}", count, args.file);
+    Ok(())
+}
 
-    let mut buffer = Vec::new();
-    write_long::serialize_to_parquet(&mut buffer, &mzml_spectra)?;
+async fn migrate(args: MigrateArgs) -> anyhow::Result<()> {
+    // As with `rewrite`, the input is read in full before the output is
+    // created, so that files can be migrated in place
+    let file = ParquetFile::open(&args.file)
+        .await
+        .with_context(|| format!("failed to open {}", args.file))?;
+    let migration = tokio::task::spawn_blocking(move || migrate::Migration::read(file))
+        .await?
+        .with_context(|| format!("failed to read {}", args.file))?;
 
-    pqt_path.write_bytes(buffer).await?;
+    println!("{}", migration.compatibility);
+    if args.check || (migration.compatibility.is_current() && args.output.is_none()) {
+        return Ok(());
+    }
 
-    log::info!(
-        "copied {} spectra from {} to {}",
-        mzml_spectra.len(),
-        cloudpath,
-        pqt_path,
-    );
+    let output = args.output.as_deref().unwrap_or(&args.file);
+    let path = output.parse::<CloudPath>()?;
+    let count = write_output(&path, move |w| Ok(migration.write(w)?.1)).await?;
+    log::info!("migrated {} spectra from {} to {}", count, args.file, path);
+    Ok(())
+}
+
+async fn stats(args: StatsArgs) -> anyhow::Result<()> {
+    let file = read_spectrum_file(&args.file).await?;
+    let stats = stats::RunStats::new(&file.spectra);
+    match args.output.as_deref() {
+        None => println!("{}", serde_json::to_string_pretty(&stats)?),
+        Some(path) => {
+            let bytes = match path.ends_with(".parquet") {
+                true => Table::from(&stats).write_parquet(Vec::new())?,
+                false => serde_json::to_vec_pretty(&stats)?,
+            };
+            path.parse::<CloudPath>()?.write_bytes(bytes).await?;
+        }
+    }
     Ok(())
 }
 
+async fn verify(args: VerifyArgs) -> anyhow::Result<()> {
+    let options = args.writer.writer_options(args.format)?;
+    let cloudpath = args.file.parse::<CloudPath>()?;
+    let mut stream = open_input(&cloudpath, &mzml::MzMLReader::default()).await?;
+    let mut spectra = Vec::new();
+    while let Some(spectrum) = stream
+        .next_spectrum()
+        .await
+        .with_context(|| format!("failed to parse {}", args.file))?
+    {
+        spectra.push(spectrum);
+    }
+
+    let format = args.format;
+    let report = tokio::task::spawn_blocking(move || verify::round_trip(spectra, format, &options))
+        .await??;
+
+    println!("{}", report);
+    for discrepancy in report.discrepancies.iter().take(args.max_reported) {
+        println!("  {}", discrepancy);
+    }
+    if report.discrepancies.len() > args.max_reported {
+        println!(
+            "  ... and {} more",
+            report.discrepancies.len() - args.max_reported
+        );
+    }
+    match report.is_ok() {
+        true => Ok(()),
+        false => Err(anyhow!("{} did not round trip within tolerance", args.file)),
+    }
+}
+
+/// Converts input files with the settings given on the command line
+struct Converter {
+    args: ConverterArgs,
+    options: WriterOptions,
+    reader: mzml::MzMLReader,
+    #[cfg(feature = "iceberg")]
+    iceberg: Option<mz_parquet::iceberg::IcebergTable>,
+}
+
+impl Converter {
+    fn new(args: ConverterArgs) -> anyhow::Result<Self> {
+        Ok(Converter {
+            options: args.writer_options()?,
+            reader: args.mzml_reader(),
+            #[cfg(feature = "iceberg")]
+            iceberg: match (&args.iceberg_catalog, &args.iceberg_table) {
+                (Some(catalog), Some(table)) => {
+                    Some(mz_parquet::iceberg::IcebergTable::new(catalog, table)?)
+                }
+                _ => None,
+            },
+            args,
+        })
+    }
+
+    /// Spectra files written for `input`
+    fn outputs(&self, input: &CloudPath, output: Option<&str>) -> anyhow::Result<Vec<CloudPath>> {
+        let suffixes = match self.args.polarity {
+            Some(PolarityArg::Split) => vec![Some("pos"), Some("neg")],
+            _ => vec![None],
+        };
+        suffixes
+            .into_iter()
+            .map(|suffix| spectra_path(input, output, suffix, self.args.format))
+            .collect()
+    }
+
+    /// Errors for inputs, by index, whose outputs are also written for an
+    /// earlier input. `x.rep1.mzML` and `x.rep2.mzML` are both converted to
+    /// `x.mzparquet`, so would overwrite each other
+    fn duplicate_outputs(&self, entries: &[Entry]) -> BTreeMap<usize, anyhow::Error> {
+        let mut duplicates = BTreeMap::new();
+        #[cfg(feature = "delta")]
+        if self.args.delta.is_some() {
+            return duplicates;
+        }
+        #[cfg(feature = "iceberg")]
+        if self.args.iceberg_table.is_some() {
+            return duplicates;
+        }
+        let mut written = HashMap::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let output = entry
+                .output
+                .as_deref()
+                .or(self.args.output_directory.as_deref());
+            // Invalid paths are reported when the input is converted
+            let Ok(outputs) = entry
+                .path
+                .parse::<CloudPath>()
+                .and_then(|input| self.outputs(&input, output))
+            else {
+                continue;
+            };
+            for path in outputs {
+                match written.get(&path) {
+                    Some(other) => {
+                        duplicates.entry(index).or_insert_with(|| {
+                            anyhow!("{} would also be written for {}", path, other)
+                        });
+                    }
+                    None => {
+                        written.insert(path, &entry.path);
+                    }
+                }
+            }
+        }
+        duplicates
+    }
+
+    /// The existing output that `file` is skipped for. Existing outputs are
+    /// an error unless --skip-existing or --force is given
+    async fn skip(&self, file: &str, output: Option<&str>) -> anyhow::Result<Option<CloudPath>> {
+        let args = &self.args;
+        if args.force {
+            return Ok(None);
+        }
+        let input = file.parse::<CloudPath>()?;
+        let outputs = self.outputs(&input, output)?;
+        let mut existing = Vec::new();
+        for path in &outputs {
+            if let Some(modified) = path.modified().await? {
+                existing.push((path, modified));
+            }
+        }
+        let Some(&(path, _)) = existing.first() else {
+            return Ok(None);
+        };
+        let skip = match args.skip_existing {
+            None => anyhow::bail!(
+                "{} already exists, pass --force to overwrite it or --skip-existing to skip {}",
+                path,
+                file
+            ),
+            // Outputs of a run that was cut short are written again
+            Some(_) if existing.len() < outputs.len() => false,
+            Some(SkipExisting::Always) => true,
+            Some(SkipExisting::Newer) => match input.modified().await? {
+                Some(input) => existing.iter().all(|(_, modified)| *modified > input),
+                None => false,
+            },
+        };
+        Ok(Some(path.clone()).filter(|_| skip))
+    }
+
+    /// Check that `entry` can be read, and its outputs written, and describe
+    /// what converting it would do
+    async fn plan(&self, entry: &Entry) -> anyhow::Result<String> {
+        let args = &self.args;
+        let input = entry.path.parse::<CloudPath>()?;
+        input
+            .check_read()
+            .await
+            .with_context(|| format!("cannot read {}", input))?;
+        #[cfg(feature = "delta")]
+        if let Some(table) = &args.delta {
+            let table = table.parse::<CloudPath>()?;
+            table
+                .check_write()
+                .await
+                .with_context(|| format!("cannot write to {}", table))?;
+            return Ok(format!("append  {} to {}", input, table));
+        }
+        #[cfg(feature = "iceberg")]
+        if let Some(table) = &args.iceberg_table {
+            return Ok(format!("append  {} to {}", input, table));
+        }
+        let output = entry.output.as_deref().or(args.output_directory.as_deref());
+        if let Some(path) = self.skip(&entry.path, output).await? {
+            return Ok(format!("skip    {}, {} already exists", input, path));
+        }
+        let outputs = self.outputs(&input, output)?;
+        for path in &outputs {
+            path.check_write()
+                .await
+                .with_context(|| format!("cannot write {}", path))?;
+        }
+        let outputs = outputs
+            .iter()
+            .map(|path| path.to_string())
+            .collect::<Vec<_>>();
+        Ok(format!("convert {} to {}", input, outputs.join(", ")))
+    }
+
+    /// Convert `entry`, recording the outcome and how long it took
+    async fn run(&self, entry: Entry) -> (FileReport, anyhow::Result<()>) {
+        let started = Instant::now();
+        let path = entry.path.clone();
+        let mut report = FileReport::new(&path);
+        let result = self.convert(entry, &mut report).await;
+        report.seconds = started.elapsed().as_secs_f64();
+        if let Err(e) = &result {
+            report.status = Status::Failed;
+            report.error = Some(format!("{:#}", e));
+        }
+        let result = result.with_context(|| format!("failed to convert {}", path));
+        (report, result)
+    }
+
+    async fn convert(&self, entry: Entry, report: &mut FileReport) -> anyhow::Result<()> {
+        let args = &self.args;
+        let reader = &self.reader;
+        let file = &entry.path;
+        let output = entry.output.or_else(|| args.output_directory.clone());
+        let file_id = match entry.file_id {
+            Some(file_id) => file_id,
+            None => file_stem(&file.parse()?)?,
+        };
+        let mut options = self.options.clone();
+        options.set_metadata(entry.metadata);
+        if args.tag_file_id() {
+            options.set_source(Some(Source {
+                file_id: file_id.clone(),
+                path: Some(file.clone()),
+            }));
+        }
+        if input_extension(&file.parse()?).as_deref() == Some("imzml") {
+            options.set_pixel_columns(true);
+        }
+        // Vendor `.d` inputs are directories, with no size of their own
+        report.input_bytes = file.parse::<CloudPath>()?.size().await.ok();
+        #[cfg(feature = "delta")]
+        if let Some(table) = &args.delta {
+            return append_to_delta(file, table, &options, reader, report).await;
+        }
+        #[cfg(feature = "iceberg")]
+        if let Some(table) = &self.iceberg {
+            return append_to_iceberg(file, table, &file_id, &options, reader, report).await;
+        }
+        if let Some(path) = self.skip(file, output.as_deref()).await? {
+            log::info!(
+                file = file.as_str(),
+                stage = "skip",
+                output:% = path;
+                "skipping {}, {} already exists", file, path
+            );
+            report.status = Status::Skipped;
+            return Ok(());
+        }
+        let split = match args.polarity {
+            Some(PolarityArg::Split) => vec![
+                ("pos", args.spectrum_filter(Some(Polarity::Positive))),
+                ("neg", args.spectrum_filter(Some(Polarity::Negative))),
+            ],
+            _ => Vec::new(),
+        };
+        let output = output.as_deref();
+        convert_mzml(file, output, &split, args.format, &options, reader, report).await
+    }
+}
+
+/// Convert `entries` on the runtime's worker threads, with up to `jobs` files
+/// in flight, adding each file to `report`. A failure does not stop the other
+/// conversions: every file is converted (or fails) before the failures are
+/// returned together
+async fn convert_all(
+    converter: Arc<Converter>,
+    entries: Vec<Entry>,
+    jobs: usize,
+    report: &mut Report,
+) -> anyhow::Result<()> {
+    let total = entries.len();
+    let mut failures = Vec::new();
+    let mut record = |(file, result): (FileReport, anyhow::Result<()>)| {
+        if total > 1 {
+            let done = report.files.len() + 1;
+            match &result {
+                Ok(()) => log::info!(
+                    file = file.input.as_str(),
+                    stage = "done",
+                    done = done,
+                    total = total;
+                    "finished {} ({} of {} files)", file.input, done, total
+                ),
+                Err(e) => log::error!(
+                    file = file.input.as_str(),
+                    stage = "convert",
+                    error:% = format!("{:#}", e);
+                    "{:#}", e
+                ),
+            }
+        }
+        report.push(file);
+        if let Err(e) = result {
+            failures.push(e);
+        }
+    };
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(jobs));
+    let mut tasks = tokio::task::JoinSet::new();
+    for entry in entries {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let converter = converter.clone();
+        tasks.spawn(async move {
+            let _permit = permit;
+            converter.run(entry).await
+        });
+        while let Some(result) = tasks.try_join_next() {
+            record(result?);
+        }
+    }
+    while let Some(result) = tasks.join_next().await {
+        record(result?);
+    }
+
+    match failures.len() {
+        0 => Ok(()),
+        1 if total == 1 => Err(failures.remove(0)),
+        n => {
+            let reasons = failures
+                .iter()
+                .map(|e| format!("\n  {:#}", e))
+                .collect::<String>();
+            Err(anyhow!(
+                "{} of {} files failed to convert:{}",
+                n,
+                total,
+                reasons
+            ))
+        }
+    }
+}
+
+/// Print what converting `entries` would do, checking that every input can be
+/// read and every output written. Fails after printing the plan if any check
+/// failed
+async fn dry_run_plan(converter: Arc<Converter>, entries: Vec<Entry>) -> anyhow::Result<()> {
+    let mut duplicates = converter.duplicate_outputs(&entries);
+    // Checks are mostly waiting on object stores, so many are made at once
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(16));
+    let mut tasks = tokio::task::JoinSet::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let semaphore = semaphore.clone();
+        let converter = converter.clone();
+        let duplicate = duplicates.remove(&index);
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            let plan = match duplicate {
+                Some(e) => Err(e),
+                None => converter.plan(&entry).await,
+            };
+            anyhow::Ok((index, entry.path, plan))
+        });
+    }
+    let mut plans = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        plans.push(result??);
+    }
+    plans.sort_by_key(|(index, ..)| *index);
+
+    let mut failed = 0;
+    for (_, path, plan) in &plans {
+        match plan {
+            Ok(plan) => println!("{}", plan),
+            Err(e) => {
+                failed += 1;
+                println!("error   {}: {:#}", path, e);
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(anyhow!(
+            "{} of {} files cannot be converted",
+            failed,
+            plans.len()
+        )),
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::Builder::default()
-        .filter_level(log::LevelFilter::Error)
-        .parse_env(env_logger::Env::default().filter_or("LOG", "error,mz_parquet=info"))
-        .init();
-
     let cli = Command::new("mz_parquet")
         .version(clap::crate_version!())
         .author("Michael Lazear <michaellazear92@gmail.com>")
-        .about("Convert mzML to mzparquet");
+        .args_conflicts_with_subcommands(true);
+
+    let cli = Commands::augment_subcommands(ConverterArgs::augment_args(cli));
+    let cli = CloudArgs::augment_args(cli);
+    let cli = LogArgs::augment_args(cli);
+    // Set last, as augmenting with documented argument structs replaces it
+    let matches = cli.about("Convert mzML to mzparquet").get_matches();
+
+    let log_format = LogArgs::from_arg_matches(&matches)?.log_format;
+    let mut logger = env_logger::Builder::default();
+    logger
+        .filter_level(log::LevelFilter::Error)
+        .parse_env(env_logger::Env::default().filter_or("LOG", "error,mz_parquet=info"));
+    if log_format == LogFormat::Json {
+        logger.format(write_json_event);
+    }
+    logger.init();
+
+    match run(matches).await {
+        // Log collectors only see the log, so the error is logged as an event
+        // rather than printed
+        Err(e) if log_format == LogFormat::Json => {
+            log::error!(error:% = format!("{:#}", e); "{}", e);
+            std::process::exit(1)
+        }
+        result => result,
+    }
+}
+
+async fn run(matches: clap::ArgMatches) -> anyhow::Result<()> {
+    mz_parquet::cloud::configure(CloudArgs::from_arg_matches(&matches)?.options());
+
+    if matches.subcommand().is_some() {
+        return match Commands::from_arg_matches(&matches)? {
+            Commands::Query(query) => run_query(query).await,
+            Commands::Merge(args) => merge(args).await,
+            Commands::Split(args) => split(args).await,
+            Commands::Rewrite(args) => rewrite(args).await,
+            Commands::Verify(args) => verify(args).await,
+            Commands::Stats(args) => stats(args).await,
+            Commands::Migrate(args) => migrate(args).await,
+            Commands::Mgf(args) => export_mgf(args).await,
+            Commands::Chromatogram(args) => {
+                let levels = args.ms_level;
+                let table =
+                    fan_out(&args.input, move |file| tic::chromatogram(file, levels)).await?;
+                args.output.write(&table).await
+            }
+            Commands::Index(command) => fragment_index(command).await,
+            Commands::Serve(args) => serve(args).await,
+            Commands::Watch(args) => watch(args).await,
+            Commands::Info(args) => {
+                let file = ParquetFile::open(&args.file)
+                    .await
+                    .with_context(|| format!("failed to open {}", args.file))?;
+                let info = tokio::task::spawn_blocking(move || info::info(file))
+                    .await?
+                    .with_context(|| format!("failed to read {}", args.file))?;
+                print!("{}", info);
+                Ok(())
+            }
+        };
+    }
 
-    let matches = ConverterArgs::augment_args(cli).get_matches();
     let args = ConverterArgs::from_arg_matches(&matches)?;
+    let entries = args.entries().await?;
+    let jobs = args.jobs();
+    let dry_run = args.dry_run;
+    let report_path = args.report.clone();
+    let converter = Arc::new(Converter::new(args)?);
+    if dry_run {
+        return dry_run_plan(converter, entries).await;
+    }
+    if let Some((index, e)) = converter.duplicate_outputs(&entries).pop_first() {
+        return Err(e.context(format!("cannot convert {}", entries[index].path)));
+    }
 
-    for file in args.files {
-        let output = args.output_directory.clone();
-        convert_mzml(&file, output.as_deref()).await?
+    let started = Instant::now();
+    let mut report = Report::default();
+    let result = convert_all(converter, entries, jobs, &mut report).await;
+    // The report is written even if a file failed, so that workflow managers
+    // can tell which one
+    if let Some(path) = report_path {
+        report.finish(started.elapsed());
+        path.parse::<CloudPath>()?
+            .write_bytes(report.to_json()?)
+            .await
+            .with_context(|| format!("failed to write {}", path))?;
     }
+    result
+}
 
-    Ok(())
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression as GzCompression};
+    use parquet::file::{reader::FileReader, serialized_reader::SerializedFileReader};
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    async fn read_all(path: &std::path::Path) -> anyhow::Result<Vec<mzml::RawSpectrum>> {
+        let mut input = open_input(&CloudPath::Local(path.into()), &Default::default()).await?;
+        let mut spectra = Vec::new();
+        while let Some(spectrum) = input.next_spectrum().await? {
+            spectra.push(spectrum);
+        }
+        Ok(spectra)
+    }
+
+    /// An mzML document of `n` MS1 spectra of two peaks, each also given the
+    /// cvParams returned by `params`
+    fn document(n: usize, params: impl Fn(usize) -> &'static str) -> String {
+        let mut document = format!(r#"<mzML><run><spectrumList count="{n}">"#);
+        for scan in 0..n {
+            let params = params(scan);
+            document.push_str(&format!(
+                r#"<spectrum id="scan={scan}" index="{scan}">
+                <cvParam accession="MS:1000511" name="ms level" value="1" />
+                {params}
+                <binaryDataArrayList count="2">
+                    <binaryDataArray>
+                        <cvParam accession="MS:1000514" name="m/z array" />
+                        <cvParam accession="MS:1000523" name="64-bit float" />
+                        <binary>AAAAAAAAWUAAAAAAAABpQA==</binary>
+                    </binaryDataArray>
+                    <binaryDataArray>
+                        <cvParam accession="MS:1000515" name="intensity array" />
+                        <cvParam accession="MS:1000521" name="32-bit float" />
+                        <binary>AACAPwAAAEA=</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+                </spectrum>"#
+            ));
+        }
+        document.push_str("</spectrumList></run></mzML>");
+        document
+    }
+
+    /// A converter configured by command line `args`
+    fn converter(args: &[&str]) -> anyhow::Result<Converter> {
+        let cli = ConverterArgs::augment_args(Command::new("mz_parquet"));
+        let matches =
+            cli.try_get_matches_from(std::iter::once("mz_parquet").chain(args.iter().copied()))?;
+        Converter::new(ConverterArgs::from_arg_matches(&matches)?)
+    }
+
+    #[tokio::test]
+    async fn read_gzipped_mzml() -> anyhow::Result<()> {
+        let mut document = String::from(r#"<mzML><run><spectrumList count="4">"#);
+        for scan in 0..4 {
+            document.push_str(&format!(
+                r#"<spectrum id="scan={scan}" index="{scan}">
+                <cvParam accession="MS:1000511" name="ms level" value="1" />
+                <binaryDataArrayList count="2">
+                    <binaryDataArray>
+                        <cvParam accession="MS:1000514" name="m/z array" />
+                        <cvParam accession="MS:1000523" name="64-bit float" />
+                        <binary>AAAAAAAAWUAAAAAAAABpQA==</binary>
+                    </binaryDataArray>
+                    <binaryDataArray>
+                        <cvParam accession="MS:1000515" name="intensity array" />
+                        <cvParam accession="MS:1000521" name="32-bit float" />
+                        <binary>AACAPwAAAEA=</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+                </spectrum>"#
+            ));
+        }
+        document.push_str("</spectrumList></run></mzML>");
+
+        let dir = std::env::temp_dir().join(format!("mz_parquet-gzip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let plain = dir.join("run.mzML");
+        std::fs::write(&plain, &document)?;
+
+        // Two members, split in the middle of a spectrum, as pigz would write
+        let (head, tail) = document.as_bytes().split_at(document.len() / 2);
+        let mut members = gzip(head);
+        members.extend(gzip(tail));
+        let gzipped = dir.join("run.mzML.gz");
+        std::fs::write(&gzipped, &members)?;
+
+        // A damaged second member
+        let len = members.len();
+        members[len - 20..len - 8].fill(0xff);
+        let corrupt = dir.join("corrupt.mzML.gz");
+        std::fs::write(&corrupt, &members)?;
+
+        let expected = read_all(&plain).await?;
+        let spectra = read_all(&gzipped).await;
+        let corrupted = read_all(&corrupt).await;
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(expected.len(), 4);
+        assert_eq!(spectra?, expected);
+        assert!(corrupted.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn split_polarity_in_one_pass() -> anyhow::Result<()> {
+        let document = document(5, |scan| match scan % 2 {
+            0 => r#"<cvParam accession="MS:1000130" name="positive scan" />"#,
+            _ => r#"<cvParam accession="MS:1000129" name="negative scan" />"#,
+        });
+
+        let dir = std::env::temp_dir().join(format!("mz_parquet-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let input = dir.join("run.mzML");
+        std::fs::write(&input, &document)?;
+
+        let filter = |polarity| SpectrumFilter {
+            polarity: Some(polarity),
+            ..Default::default()
+        };
+        let split = [
+            ("pos", filter(Polarity::Positive)),
+            ("neg", filter(Polarity::Negative)),
+        ];
+        let file = input.display().to_string();
+        let mut report = FileReport::new(&file);
+        let result = convert_mzml(
+            &file,
+            None,
+            &split,
+            OutputFormat::Long,
+            &WriterOptions::default(),
+            &mzml_reader(false, None),
+            &mut report,
+        )
+        .await;
+        let read = |name: &str| -> anyhow::Result<Vec<String>> {
+            let bytes = std::fs::read(dir.join(name))?;
+            let (_, spectra) = mz_parquet::reader::read_spectra(bytes::Bytes::from(bytes))?;
+            Ok(spectra
+                .iter()
+                .map(|s| String::from_utf8_lossy(&s.id).into_owned())
+                .collect())
+        };
+        let positive = read("run.pos.mzparquet");
+        let negative = read("run.neg.mzparquet");
+        std::fs::remove_dir_all(&dir)?;
+
+        result?;
+        assert_eq!(positive?, ["scan=0", "scan=2", "scan=4"]);
+        assert_eq!(negative?, ["scan=1", "scan=3"]);
+        assert_eq!(report.spectra, 5);
+        assert_eq!(report.outputs.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn convert_every_file_despite_failures() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mz_parquet-all-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let document = document(2, |_| "");
+        let mut entries = Vec::new();
+        for name in ["a.mzML", "missing.mzML", "b.mzML", "c.mzML"] {
+            if name != "missing.mzML" {
+                std::fs::write(dir.join(name), &document)?;
+            }
+            entries.push(Entry::new(dir.join(name).display().to_string()));
+        }
+
+        let converter = Arc::new(converter(&["--jobs", "2"])?);
+        let mut report = Report::default();
+        let result = convert_all(converter, entries, 2, &mut report).await;
+        let converted =
+            ["a", "b", "c"].map(|stem| dir.join(format!("{}.mzparquet", stem)).exists());
+        std::fs::remove_dir_all(&dir)?;
+
+        // The failure is reported once every other file has been converted
+        let e = result.expect_err("a file is missing");
+        assert!(format!("{:#}", e).starts_with("1 of 4 files failed to convert:"));
+        assert!(format!("{:#}", e).contains("missing.mzML"));
+        assert_eq!(converted, [true; 3]);
+        assert_eq!(report.files.len(), 4);
+        let failed = report
+            .files
+            .iter()
+            .filter(|file| file.status == Status::Failed)
+            .map(|file| file.input.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].ends_with("missing.mzML"));
+        Ok(())
+    }
+
+    #[test]
+    fn wide_format_always_has_ion_columns() -> anyhow::Result<()> {
+        converter(&["--format", "wide", "--noise-columns"])?;
+        converter(&["--format", "wide", "--charge-column"])?;
+        Ok(())
+    }
+
+    #[test]
+    fn parse_rt_range() -> anyhow::Result<()> {
+        assert_eq!(parse_range::<f32>("5:10"), Ok((Some(5.0), Some(10.0))));
+        assert_eq!(parse_range::<f32>(":10"), Ok((None, Some(10.0))));
+        assert_eq!(parse_range::<f32>("-1:"), Ok((Some(-1.0), None)));
+        assert!(parse_range::<f32>("5-10").is_err());
+        assert!(parse_range::<f32>("five:10").is_err());
+
+        // Ranges are given in the output unit, and filter in seconds
+        let args = converter(&["--rt-range", "5:", "--rt-unit", "minutes"])?.args;
+        assert_eq!(args.spectrum_filter(None).rt, Some((300.0, f32::MAX)));
+        let args = converter(&["--rt-range", ":90"])?.args;
+        assert_eq!(args.spectrum_filter(None).rt, Some((f32::MIN, 90.0)));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_scan_range() -> anyhow::Result<()> {
+        assert_eq!(parse_range::<u32>("10:20"), Ok((Some(10), Some(20))));
+        assert!(parse_range::<u32>("-1:20").is_err());
+
+        let args = converter(&["--scan-range", "10:20"])?.args;
+        assert_eq!(args.spectrum_filter(None).scans, Some((10, 20)));
+        let args = converter(&["--scan-range", "10:"])?.args;
+        assert_eq!(args.spectrum_filter(None).scans, Some((10, u32::MAX)));
+        let args = converter(&["--scan-range", ":20"])?.args;
+        assert_eq!(args.spectrum_filter(None).scans, Some((0, 20)));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_mz_range() -> anyhow::Result<()> {
+        let peak_filter = |mz| {
+            let mut options = converter(&[])?.options;
+            options.set_peak_filter(PeakFilter {
+                mz,
+                ..Default::default()
+            });
+            anyhow::Ok(options)
+        };
+        let options = converter(&["--mz-range", "150:2000"])?.options;
+        assert_eq!(options, peak_filter(Some((150.0, 2000.0)))?);
+        let options = converter(&["--mz-range", "150:"])?.options;
+        assert_eq!(options, peak_filter(Some((150.0, f64::MAX)))?);
+        let options = converter(&["--mz-range", ":2000"])?.options;
+        assert_eq!(options, peak_filter(Some((0.0, 2000.0)))?);
+        assert!(converter(&["--mz-range", "150"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_top_n() -> anyhow::Result<()> {
+        let mut expected = converter(&[])?.options;
+        expected.set_peak_filter(PeakFilter {
+            top_n: Some(150),
+            ..Default::default()
+        });
+        assert_eq!(converter(&["--top-n", "150"])?.options, expected);
+        assert!(converter(&["--top-n", "-1"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn memory_budget() -> anyhow::Result<()> {
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("1.5 GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert!(parse_size("6X").is_err());
+
+        let row_groups = |args: &[&str], bytes| {
+            let mut options = converter(args)?.options;
+            options.set_row_group_size(RowGroupSize {
+                ions: Some(2usize.pow(18)),
+                spectra: None,
+                bytes,
+            });
+            anyhow::Ok(options)
+        };
+        // Each of two files gets 2G, half of which may be parsed ahead, and
+        // a quarter of which bounds the size of row groups
+        let budget = converter(&["--max-memory", "4G", "--jobs", "2"])?;
+        assert_eq!(budget.reader.read_ahead(), Some(1 << 30));
+        assert_eq!(
+            budget.options,
+            row_groups(&["--jobs", "2"], Some(512 << 20))?
+        );
+
+        // Smaller row groups are kept
+        let args = ["--max-memory", "4G", "--row-group-bytes", "1000"];
+        let budget = converter(&args)?;
+        assert_eq!(budget.options, row_groups(&[], Some(1000))?);
+        let args = ["--max-memory", "1M", "--row-group-bytes", "1000000000"];
+        let budget = converter(&args)?;
+        assert_eq!(budget.options, row_groups(&[], Some(256 << 10))?);
+
+        assert_eq!(converter(&[])?.reader.read_ahead(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_existing_outputs() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mz_parquet-skip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let input = dir.join("run.mzML");
+        std::fs::write(&input, "")?;
+        let file = input.display().to_string();
+        let output = dir.join("run.mzparquet");
+        // Set the modification time of `path` to `secs` after the epoch
+        let touch = |path: &std::path::Path, secs| {
+            let time = std::time::UNIX_EPOCH + Duration::from_secs(secs);
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(time)
+        };
+
+        let result = async {
+            let skip = |args: &[&str]| {
+                let converter = converter(args);
+                let file = file.clone();
+                async move { converter?.skip(&file, None).await }
+            };
+            assert!(skip(&[]).await?.is_none());
+
+            std::fs::write(&output, "")?;
+            let e = skip(&[]).await.expect_err("output exists");
+            assert!(e.to_string().contains("pass --force to overwrite it"));
+            assert!(skip(&["--force"]).await?.is_none());
+            assert!(skip(&["--skip-existing"]).await?.is_some());
+
+            // Outputs older than their input are written again
+            touch(&input, 2000)?;
+            touch(&output, 1000)?;
+            assert!(skip(&["--skip-existing=newer"]).await?.is_none());
+            assert!(skip(&["--skip-existing=always"]).await?.is_some());
+            touch(&output, 3000)?;
+            assert!(skip(&["--skip-existing=newer"]).await?.is_some());
+
+            // As are the outputs of a run that was cut short
+            std::fs::rename(&output, dir.join("run.pos.mzparquet"))?;
+            let split = ["--polarity", "split", "--skip-existing"];
+            assert!(skip(&split).await?.is_none());
+            std::fs::write(dir.join("run.neg.mzparquet"), "")?;
+            assert!(skip(&split).await?.is_some());
+            anyhow::Ok(())
+        }
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        result
+    }
+
+    #[tokio::test]
+    async fn dry_run_with_duplicate_outputs() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mz_parquet-plan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let result = async {
+            let mut entries = Vec::new();
+            for name in ["x.rep1.mzML", "x.rep2.mzML", "y.mzML", "missing.mzML"] {
+                if name != "missing.mzML" {
+                    std::fs::write(dir.join(name), "")?;
+                }
+                entries.push(Entry::new(dir.join(name).display().to_string()));
+            }
+            let output = dir.join("out").display().to_string();
+            let converter = Arc::new(converter(&["--output-directory", &output])?);
+
+            // Both replicates would be written to x.mzparquet
+            let duplicates = converter.duplicate_outputs(&entries);
+            assert_eq!(duplicates.keys().collect::<Vec<_>>(), [&1]);
+            let e = duplicates[&1].to_string();
+            assert!(e.ends_with(&format!(
+                "x.mzparquet would also be written for {}",
+                entries[0].path
+            )));
+
+            let plan = converter.plan(&entries[2]).await?;
+            let y = dir.join("out").join("y.mzparquet");
+            assert_eq!(
+                plan,
+                format!("convert {} to {}", entries[2].path, y.display())
+            );
+
+            let e = dry_run_plan(converter, entries)
+                .await
+                .expect_err("two inputs fail");
+            assert_eq!(e.to_string(), "2 of 4 files cannot be converted");
+            // Nothing is written
+            assert!(!y.exists());
+            anyhow::Ok(())
+        }
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        result
+    }
+
+    #[test]
+    fn json_log_events() {
+        use log::kv::Value;
+
+        let kvs = [
+            ("file", Value::from("run.mzML")),
+            ("spectra", Value::from(12u64)),
+            ("seconds", Value::from(1.5)),
+        ];
+        let event = json_event(
+            &log::Record::builder()
+                .args(format_args!("copied {} spectra", 12))
+                .level(log::Level::Info)
+                .target("mz_parquet")
+                .key_values(&kvs)
+                .build(),
+            "2024-01-01T00:00:00.000Z".into(),
+        );
+        assert_eq!(
+            serde_json::Value::Object(event),
+            serde_json::json!({
+                "timestamp": "2024-01-01T00:00:00.000Z",
+                "level": "INFO",
+                "target": "mz_parquet",
+                "message": "copied 12 spectra",
+                "file": "run.mzML",
+                "spectra": 12,
+                "seconds": 1.5,
+            })
+        );
+    }
+
+    /// Cloud storage options configured by command line `args`
+    fn cloud_options(args: &[&str]) -> anyhow::Result<mz_parquet::cloud::CloudOptions> {
+        let cli = CloudArgs::augment_args(Command::new("mz_parquet"));
+        let matches =
+            cli.try_get_matches_from(std::iter::once("mz_parquet").chain(args.iter().copied()))?;
+        Ok(CloudArgs::from_arg_matches(&matches)?.options())
+    }
+
+    #[test]
+    fn cloud_retries_and_timeouts() -> anyhow::Result<()> {
+        assert_eq!(cloud_options(&[])?, Default::default());
+
+        let options = cloud_options(&[
+            "--max-retries",
+            "3",
+            "--retry-timeout",
+            "60",
+            "--timeout",
+            "120",
+            "--connect-timeout",
+            "10",
+        ])?;
+        let mut expected = mz_parquet::cloud::CloudOptions::default();
+        expected
+            .set_max_retries(Some(3))
+            .set_retry_timeout(Some(Duration::from_secs(60)))
+            .set_timeout(Some(Duration::from_secs(120)))
+            .set_connect_timeout(Some(Duration::from_secs(10)));
+        assert_eq!(options, expected);
+
+        assert!(cloud_options(&["--timeout", "30s"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn aws_credentials_and_requester_pays() -> anyhow::Result<()> {
+        let role_arn = "arn:aws:iam::123456789012:role/mz_parquet";
+        let options = cloud_options(&[
+            "--aws-profile",
+            "lab",
+            "--aws-role-arn",
+            role_arn,
+            "--aws-external-id",
+            "mz",
+            "--s3-requester-pays",
+        ])?;
+        let mut expected = mz_parquet::cloud::CloudOptions::default();
+        expected
+            .set_aws_profile(Some("lab".into()))
+            .set_aws_role_arn(Some(role_arn.into()))
+            .set_aws_external_id(Some("mz".into()))
+            .set_s3_requester_pays(Some(true));
+        assert_eq!(options, expected);
+
+        let err = cloud_options(&["--aws-external-id", "mz"]).unwrap_err();
+        assert!(err.to_string().contains("--aws-role-arn"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn row_group_size_flags() -> anyhow::Result<()> {
+        let args = [
+            "--row-group-size",
+            "1000",
+            "--row-group-spectra",
+            "50",
+            "--row-group-bytes",
+            "65536",
+        ];
+        let mut expected = converter(&[])?.options;
+        expected.set_row_group_size(RowGroupSize {
+            ions: Some(1000),
+            spectra: Some(50),
+            bytes: Some(65536),
+        });
+        assert_eq!(converter(&args)?.options, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn compression_flags() -> anyhow::Result<()> {
+        let compression = |args: &[&str], compression| {
+            let mut expected = converter(&[])?.options;
+            expected.set_compression(compression);
+            assert_eq!(converter(args)?.options, expected, "{:?}", args);
+            anyhow::Ok(())
+        };
+        compression(&[], Compression::ZSTD(ZstdLevel::try_new(3)?))?;
+        compression(
+            &["--compression-level", "9"],
+            Compression::ZSTD(ZstdLevel::try_new(9)?),
+        )?;
+        compression(
+            &["--compression", "gzip", "--compression-level", "6"],
+            Compression::GZIP(GzipLevel::try_new(6)?),
+        )?;
+        compression(
+            &["--compression", "brotli"],
+            Compression::BROTLI(BrotliLevel::default()),
+        )?;
+        compression(&["--compression", "snappy"], Compression::SNAPPY)?;
+        compression(&["--compression", "lz4"], Compression::LZ4_RAW)?;
+        compression(&["--compression", "none"], Compression::UNCOMPRESSED)?;
+
+        // Out of range levels, and levels for codecs without them
+        assert!(converter(&["--compression-level", "30"]).is_err());
+        assert!(converter(&["--compression", "gzip", "--compression-level", "11"]).is_err());
+        let err = converter(&["--compression", "lz4", "--compression-level", "1"])
+            .err()
+            .expect("lz4 has no levels");
+        assert!(err.to_string().contains("lz4"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rewrite_in_place() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mz_parquet-rewrite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let result = async {
+            let spectra = (0..4)
+                .map(|scan| mz_parquet::RawSpectrum {
+                    id: format!("scan={}", scan).into_bytes(),
+                    ms_level: 1,
+                    scan_start_time: scan as f32,
+                    mz: vec![100.0, 200.0],
+                    intensity: vec![1.0, 2.0],
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
+            let metadata = mz_parquet::metadata::RunMetadata {
+                start_timestamp: Some("2021-03-04T10:15:00Z".into()),
+                ..Default::default()
+            };
+            for format in [Format::Long, Format::Wide] {
+                let path = dir.join(format!("{:?}.mzparquet", format));
+                let file = rewrite::SpectrumFile {
+                    format,
+                    sources: Vec::new(),
+                    metadata: metadata.clone(),
+                    spectra: spectra.clone(),
+                };
+                let options = WriterOptions::default();
+                file.write(std::fs::File::create(&path)?, &options)?;
+                let before = mz_parquet::reader::read_spectra(std::fs::File::open(&path)?)?;
+
+                let path = path.to_str().expect("utf-8 path");
+                let cli = RewriteArgs::augment_args(Command::new("mz_parquet"));
+                let matches = cli.try_get_matches_from([
+                    "mz_parquet",
+                    path,
+                    path,
+                    "--compression",
+                    "snappy",
+                    "--row-group-spectra",
+                    "1",
+                ])?;
+                rewrite(RewriteArgs::from_arg_matches(&matches)?).await?;
+
+                // The same spectra and run, with the new settings
+                let reader = SerializedFileReader::new(std::fs::File::open(path)?)?;
+                assert_eq!(reader.num_row_groups(), 4, "{:?}", format);
+                let codec = reader.metadata().row_group(0).column(0).compression();
+                assert_eq!(codec, Compression::SNAPPY);
+                assert_eq!(
+                    mz_parquet::metadata::RunMetadata::from_metadata(reader.metadata())?,
+                    metadata
+                );
+                let after = mz_parquet::reader::read_spectra(std::fs::File::open(path)?)?;
+                assert_eq!(after, before);
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        result
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn record_provenance() -> anyhow::Result<()> {
+        let source_files = r#"<fileDescription><sourceFileList count="1">
+            <sourceFile id="RAW1" name="run.raw" location="file:///C:/data">
+                <cvParam accession="MS:1000569" name="SHA-1" value="9d5ed678fe57bcca610140957afab571b4b2ec27"/>
+            </sourceFile>
+        </sourceFileList></fileDescription><run>"#;
+        let document = document(4, |scan| match scan % 2 {
+            0 => r#"<cvParam accession="MS:1000130" name="positive scan" />"#,
+            _ => r#"<cvParam accession="MS:1000129" name="negative scan" />"#,
+        })
+        .replacen("<run>", source_files, 1);
+
+        let dir =
+            std::env::temp_dir().join(format!("mz_parquet-provenance-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let result = async {
+            // The checksum is of the decompressed mzML
+            std::fs::write(dir.join("plain.mzML"), &document)?;
+            let sha1 = mz_parquet::metadata::sha1_file(dir.join("plain.mzML"))?;
+            let input = dir.join("run.mzML.gz");
+            std::fs::write(&input, gzip(document.as_bytes()))?;
+
+            let filter = |polarity| SpectrumFilter {
+                polarity: Some(polarity),
+                ..Default::default()
+            };
+            let split = [
+                ("pos", filter(Polarity::Positive)),
+                ("neg", filter(Polarity::Negative)),
+            ];
+            let file = input.display().to_string();
+            convert_mzml(
+                &file,
+                None,
+                &split,
+                OutputFormat::Long,
+                &WriterOptions::default(),
+                &mzml_reader(false, None),
+                &mut FileReport::new(&file),
+            )
+            .await?;
+
+            // Every output records where its spectra came from
+            for name in ["run.pos.mzparquet", "run.neg.mzparquet"] {
+                let reader = SerializedFileReader::new(std::fs::File::open(dir.join(name))?)?;
+                let metadata = mz_parquet::metadata::RunMetadata::from_metadata(reader.metadata())?;
+                assert_eq!(
+                    metadata.source_files,
+                    vec![mz_parquet::metadata::SourceFile {
+                        id: "RAW1".into(),
+                        name: "run.raw".into(),
+                        location: "file:///C:/data".into(),
+                        sha1: Some("9d5ed678fe57bcca610140957afab571b4b2ec27".into()),
+                    }]
+                );
+                assert_eq!(
+                    metadata.input,
+                    Some(mz_parquet::metadata::InputFile {
+                        path: file.clone(),
+                        sha1: Some(sha1.clone()),
+                    }),
+                    "{}",
+                    name
+                );
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        result
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn acquisition_time_column() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mz_parquet-start-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let result = async {
+            use parquet::record::Field;
+
+            let args = converter(&["--acquisition-time"])?;
+            let mut acquisition_times = Vec::new();
+            for (name, run) in [
+                ("started", r#"<run startTimeStamp="2021-03-04T10:15:00Z">"#),
+                ("unknown", "<run>"),
+            ] {
+                let input = dir.join(format!("{name}.mzML"));
+                std::fs::write(&input, document(2, |_| "").replacen("<run>", run, 1))?;
+                let file = input.display().to_string();
+                convert_mzml(
+                    &file,
+                    None,
+                    &[],
+                    OutputFormat::Long,
+                    &args.options,
+                    &args.reader,
+                    &mut FileReport::new(&file),
+                )
+                .await?;
+
+                let output = dir.join(format!("{name}.mzparquet"));
+                let reader = SerializedFileReader::new(std::fs::File::open(output)?)?;
+                let metadata = mz_parquet::metadata::RunMetadata::from_metadata(reader.metadata())?;
+                let times = reader
+                    .get_row_iter(None)?
+                    .map(|row| {
+                        let row = row?;
+                        let (_, time) = row
+                            .get_column_iter()
+                            .find(|(name, _)| *name == "acquisition_time")
+                            .expect("acquisition time column");
+                        anyhow::Ok(time.clone())
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                acquisition_times.push((metadata.start_timestamp, times));
+            }
+
+            let start = Field::TimestampMillis(1_614_852_900_000);
+            assert_eq!(
+                acquisition_times,
+                vec![
+                    (Some("2021-03-04T10:15:00Z".into()), vec![start; 4]),
+                    (None, vec![Field::Null; 4]),
+                ]
+            );
+            anyhow::Ok(())
+        }
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        result?;
+
+        assert!(converter(&["--acquisition-time", "--format", "wide"]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn query_many_files() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mz_parquet-fan-out-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let result = async {
+            for (name, intensities) in [("a", vec![10.0]), ("b", vec![20.0, 30.0])] {
+                let spectra = intensities
+                    .into_iter()
+                    .map(|intensity| mz_parquet::RawSpectrum {
+                        ms_level: 1,
+                        mz: vec![500.0],
+                        intensity: vec![intensity],
+                        ..Default::default()
+                    })
+                    .collect::<Vec<_>>();
+                let file = std::fs::File::create(dir.join(format!("{name}.mzparquet")))?;
+                mz_parquet::write_long::serialize_to_parquet(file, &spectra)?;
+            }
+            let path = |name: &str| dir.join(name).display().to_string();
+            let query = XicQuery {
+                mz: 500.0,
+                tolerance: Tolerance::Ppm(10.0),
+                rt: None,
+                ms_level: 1,
+            };
+            let search = |files: Vec<String>| {
+                let query = query.clone();
+                async move {
+                    let cli = InputArgs::augment_args(Command::new("mz_parquet"));
+                    let args = ["mz_parquet", "--jobs", "2"].map(String::from);
+                    let matches = cli.try_get_matches_from(args.into_iter().chain(files))?;
+                    let input = InputArgs::from_arg_matches(&matches)?;
+                    let table = fan_out(&input, move |file| query::xic(file, &query)).await?;
+                    let mut csv = Vec::new();
+                    table.write_csv(&mut csv)?;
+                    anyhow::Ok(String::from_utf8(csv)?)
+                }
+            };
+
+            // Hits are in input order, whichever file finishes first
+            let (a, b) = (path("a.mzparquet"), path("b.mzparquet"));
+            assert_eq!(
+                search(vec![b.clone(), a.clone()]).await?,
+                format!("filename,scan,rt,intensity\n{b},0,0,20\n{b},1,0,30\n{a},0,0,10\n")
+            );
+            // Directories are expanded in sorted order
+            let csv = search(vec![dir.display().to_string()]).await?;
+            assert!(csv.starts_with(&format!("filename,scan,rt,intensity\n{a},")));
+
+            let err = search(vec![a, path("missing.mzparquet")])
+                .await
+                .unwrap_err();
+            assert!(format!("{err:#}").contains("missing.mzparquet"), "{err:#}");
+            anyhow::Ok(())
+        }
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        result
+    }
 }
@@ -0,0 +1,152 @@
+//! Read manifests listing the files to convert, so that workflow managers
+//! can hand over thousands of files in a single invocation.
+//!
+//! A manifest is either a plain list of paths, one per line, or a CSV (or
+//! tab separated) table with a header row. Tables must have a `path` column,
+//! and may have:
+//!
+//! * `output` - directory the outputs of the file are written to, instead of
+//!   the output directory given on the command line
+//! * `file_id` - identifier of the run, used instead of the file name when
+//!   rows are tagged with the run they came from
+//!
+//! Any other columns are metadata of the run (e.g. `sample`, `condition`),
+//! stored in the footer of its output. Blank lines, and lines starting with
+//! `#`, are ignored. Empty values are treated as missing.
+use std::collections::BTreeMap;
+
+/// A file listed in a manifest
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub path: String,
+    pub output: Option<String>,
+    pub file_id: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Entry {
+    pub fn new<S: Into<String>>(path: S) -> Self {
+        Entry {
+            path: path.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Split a line of a CSV file into fields, unquoting fields that are
+/// enclosed in double quotes
+fn split_csv(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        anyhow::bail!("unterminated quote in `{}`", line);
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+/// Parse the contents of a manifest
+pub fn parse(text: &str) -> anyhow::Result<Vec<Entry>> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .peekable();
+
+    let split = |line: &str| -> anyhow::Result<Vec<String>> {
+        let fields = match line.contains('\t') {
+            true => line.split('\t').map(str::to_string).collect(),
+            false => split_csv(line)?,
+        };
+        Ok(fields.into_iter().map(|f| f.trim().to_string()).collect())
+    };
+
+    let header = match lines.peek() {
+        Some(&(_, line)) => split(line)?,
+        None => return Ok(Vec::new()),
+    };
+    if !header.iter().any(|column| column == "path") {
+        return Ok(lines.map(|(_, line)| Entry::new(line)).collect());
+    }
+    lines.next();
+
+    let mut entries = Vec::new();
+    for (number, line) in lines {
+        let fields = split(line)?;
+        if fields.len() != header.len() {
+            anyhow::bail!(
+                "line {} has {} fields, but the header has {}",
+                number,
+                fields.len(),
+                header.len()
+            );
+        }
+        let mut entry = Entry::default();
+        for (column, value) in header.iter().zip(fields) {
+            if value.is_empty() {
+                continue;
+            }
+            match column.as_str() {
+                "path" => entry.path = value,
+                "output" => entry.output = Some(value),
+                "file_id" => entry.file_id = Some(value),
+                _ => {
+                    entry.metadata.insert(column.clone(), value);
+                }
+            }
+        }
+        if entry.path.is_empty() {
+            anyhow::bail!("line {} has no path", number);
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_manifests() -> anyhow::Result<()> {
+        let list = "# cohort\n/data/a.mzML\n\ns3://bucket/b.mzML\n";
+        assert_eq!(
+            parse(list)?,
+            vec![Entry::new("/data/a.mzML"), Entry::new("s3://bucket/b.mzML")]
+        );
+
+        let csv = "path,output,file_id,sample\n\
+                   /data/a.mzML,s3://bucket/out/,,\"QC, pooled\"\n\
+                   /data/b.mzML,,b1,\"plasma \"\"A\"\"\"\n";
+        let entries = parse(csv)?;
+        assert_eq!(entries[0].output.as_deref(), Some("s3://bucket/out/"));
+        assert_eq!(entries[0].file_id, None);
+        assert_eq!(entries[0].metadata["sample"], "QC, pooled");
+        assert_eq!(entries[1].output, None);
+        assert_eq!(entries[1].file_id.as_deref(), Some("b1"));
+        assert_eq!(entries[1].metadata["sample"], "plasma \"A\"");
+
+        let tsv = "sample\tpath\nQC\t/data/a.mzML\n";
+        assert_eq!(parse(tsv)?[0].path, "/data/a.mzML");
+        assert!(parse("path,sample\n/data/a.mzML\n").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,571 @@
+//! A subset of the [MassQL](https://mwang87.github.io/MassQueryLanguage_Documentation/)
+//! query language, lowered to scans over long format mzparquet files.
+//!
+//! Supported queries have the form
+//!
+//! ```text
+//! QUERY scaninfo(MS2DATA) WHERE MS2PROD=226.18:TOLERANCEPPM=5 AND MS2PREC=500:TOLERANCEMZ=0.5
+//! ```
+//!
+//! where the data type is either `MS1DATA` or `MS2DATA`, optionally wrapped in
+//! `scaninfo(..)` to return one row per scan rather than every peak of each
+//! matching scan. Conditions are joined with `AND`:
+//!
+//! * `MS1MZ` - an MS1 peak (requires `MS1DATA`)
+//! * `MS2PROD` - an MS2 product ion (requires `MS2DATA`)
+//! * `MS2PREC` - the MS2 precursor m/z (requires `MS2DATA`)
+//! * `RTMIN`, `RTMAX` (in minutes, as in MassQL), `SCANMIN`, `SCANMAX`
+//!
+//! Peak conditions accept the `TOLERANCEMZ`, `TOLERANCEPPM`,
+//! `INTENSITYVALUE>` and `INTENSITYPERCENT>` qualifiers. Anything else
+//! (variables, `OR`, `FILTER`, neutral losses, ...) is rejected as unsupported.
+use crate::output::{Column, Table};
+use crate::query::{column_index, may_contain, read_column, read_required, Tolerance};
+use crate::write_long::RtUnit;
+use parquet::file::{
+    reader::{ChunkReader, FileReader},
+    serialized_reader::SerializedFileReader,
+};
+use std::str::FromStr;
+
+/// MassQL's default peak tolerance
+const DEFAULT_TOLERANCE: Tolerance = Tolerance::Da(0.1);
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum MassQLError {
+    #[error("invalid MassQL query: {0}")]
+    Syntax(String),
+    #[error("unsupported MassQL: {0}")]
+    Unsupported(String),
+}
+
+/// What a query returns for each matching scan
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// `scaninfo(..)`: one row per scan
+    ScanInfo,
+    /// Every peak of the scan
+    Data,
+}
+
+/// A peak that must be present in a scan
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PeakCondition {
+    pub mz: f64,
+    pub tolerance: Tolerance,
+    /// Minimum absolute intensity of the matching peak
+    pub min_intensity: Option<f32>,
+    /// Minimum intensity of the matching peak, as a percentage of the base peak
+    pub min_intensity_percent: Option<f32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Condition {
+    Ms1Mz(PeakCondition),
+    Ms2Prod(PeakCondition),
+    Ms2Prec { mz: f64, tolerance: Tolerance },
+    RtMin(f32),
+    RtMax(f32),
+    ScanMin(u32),
+    ScanMax(u32),
+}
+
+/// A parsed MassQL query
+#[derive(Clone, Debug, PartialEq)]
+pub struct MassQuery {
+    pub output: Output,
+    pub ms_level: u8,
+    pub conditions: Vec<Condition>,
+}
+
+/// Strip a case-insensitive keyword from the start of `s`
+fn strip_keyword<'a>(s: &'a str, keyword: &str) -> Option<&'a str> {
+    match s.get(..keyword.len()) {
+        Some(head) if head.eq_ignore_ascii_case(keyword) => Some(&s[keyword.len()..]),
+        _ => None,
+    }
+}
+
+/// Split `s` on a case-insensitive, whitespace-delimited keyword
+fn split_keyword<'a>(s: &'a str, keyword: &str) -> Vec<&'a str> {
+    let upper = s.to_ascii_uppercase();
+    let pattern = format!(" {} ", keyword);
+    let mut parts = Vec::new();
+    let mut start = 0;
+    while let Some(offset) = upper[start..].find(&pattern) {
+        parts.push(s[start..start + offset].trim());
+        start += offset + pattern.len();
+    }
+    parts.push(s[start..].trim());
+    parts
+}
+
+fn number<T: FromStr>(value: &str) -> Result<T, MassQLError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| MassQLError::Unsupported(format!("expected a number, found `{}`", value)))
+}
+
+fn parse_peak(mz: f64, qualifiers: &[&str]) -> Result<PeakCondition, MassQLError> {
+    let mut peak = PeakCondition {
+        mz,
+        tolerance: DEFAULT_TOLERANCE,
+        min_intensity: None,
+        min_intensity_percent: None,
+    };
+    for qualifier in qualifiers {
+        let qualifier = qualifier.trim().to_ascii_uppercase();
+        if let Some(v) = qualifier.strip_prefix("TOLERANCEMZ=") {
+            peak.tolerance = Tolerance::Da(number(v)?);
+        } else if let Some(v) = qualifier.strip_prefix("TOLERANCEPPM=") {
+            peak.tolerance = Tolerance::Ppm(number(v)?);
+        } else if let Some(v) = qualifier.strip_prefix("INTENSITYVALUE>") {
+            peak.min_intensity = Some(number(v)?);
+        } else if let Some(v) = qualifier.strip_prefix("INTENSITYPERCENT>") {
+            peak.min_intensity_percent = Some(number(v)?);
+        } else {
+            return Err(MassQLError::Unsupported(format!(
+                "qualifier `{}`",
+                qualifier
+            )));
+        }
+    }
+    Ok(peak)
+}
+
+fn parse_condition(s: &str) -> Result<Condition, MassQLError> {
+    let mut parts = s.split(':');
+    let head = parts.next().unwrap_or_default();
+    let qualifiers = parts.collect::<Vec<_>>();
+    let (name, value) = head
+        .split_once('=')
+        .ok_or_else(|| MassQLError::Syntax(format!("expected `NAME=value`, found `{}`", s)))?;
+    let name = name.trim().to_ascii_uppercase();
+
+    if !qualifiers.is_empty() && !matches!(name.as_str(), "MS1MZ" | "MS2PROD" | "MS2PREC") {
+        return Err(MassQLError::Unsupported(format!(
+            "qualifiers on `{}`",
+            name
+        )));
+    }
+
+    Ok(match name.as_str() {
+        "MS1MZ" => Condition::Ms1Mz(parse_peak(number(value)?, &qualifiers)?),
+        "MS2PROD" => Condition::Ms2Prod(parse_peak(number(value)?, &qualifiers)?),
+        "MS2PREC" => {
+            let peak = parse_peak(number(value)?, &qualifiers)?;
+            if peak.min_intensity.is_some() || peak.min_intensity_percent.is_some() {
+                return Err(MassQLError::Unsupported(
+                    "intensity qualifiers on `MS2PREC`".into(),
+                ));
+            }
+            Condition::Ms2Prec {
+                mz: peak.mz,
+                tolerance: peak.tolerance,
+            }
+        }
+        "RTMIN" => Condition::RtMin(number(value)?),
+        "RTMAX" => Condition::RtMax(number(value)?),
+        "SCANMIN" => Condition::ScanMin(number(value)?),
+        "SCANMAX" => Condition::ScanMax(number(value)?),
+        _ => return Err(MassQLError::Unsupported(format!("condition `{}`", name))),
+    })
+}
+
+impl FromStr for MassQuery {
+    type Err = MassQLError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+        let body = strip_keyword(&s, "QUERY ")
+            .ok_or_else(|| MassQLError::Syntax("query must start with `QUERY`".into()))?;
+
+        let mut clauses = split_keyword(body, "WHERE").into_iter();
+        let select = clauses.next().unwrap_or_default().replace(' ', "");
+        let filter = clauses.next();
+        if clauses.next().is_some() {
+            return Err(MassQLError::Syntax("multiple `WHERE` clauses".into()));
+        }
+        if filter.is_some_and(|f| split_keyword(f, "FILTER").len() > 1) {
+            return Err(MassQLError::Unsupported("`FILTER` clauses".into()));
+        }
+
+        let (output, data) = match strip_keyword(&select, "scaninfo(") {
+            Some(inner) => (
+                Output::ScanInfo,
+                inner
+                    .strip_suffix(')')
+                    .ok_or_else(|| MassQLError::Syntax("unbalanced parentheses".into()))?,
+            ),
+            None => (Output::Data, select.as_str()),
+        };
+        let ms_level = match data.to_ascii_uppercase().as_str() {
+            "MS1DATA" => 1,
+            "MS2DATA" => 2,
+            other => return Err(MassQLError::Unsupported(format!("data type `{}`", other))),
+        };
+
+        let conditions = match filter {
+            Some(filter) => {
+                if split_keyword(filter, "OR").len() > 1 {
+                    return Err(MassQLError::Unsupported("`OR`".into()));
+                }
+                split_keyword(filter, "AND")
+                    .into_iter()
+                    .map(parse_condition)
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            None => Vec::new(),
+        };
+
+        for condition in &conditions {
+            match (condition, ms_level) {
+                (Condition::Ms1Mz(_), 2) => {
+                    return Err(MassQLError::Unsupported(
+                        "`MS1MZ` can only be used with `MS1DATA`".into(),
+                    ))
+                }
+                (Condition::Ms2Prod(_) | Condition::Ms2Prec { .. }, 1) => {
+                    return Err(MassQLError::Unsupported(
+                        "`MS2PROD` and `MS2PREC` can only be used with `MS2DATA`".into(),
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(MassQuery {
+            output,
+            ms_level,
+            conditions,
+        })
+    }
+}
+
+/// One row of a `scaninfo(..)` query
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ScanInfo {
+    pub scan: u32,
+    pub rt: f32,
+    pub ms_level: u32,
+    pub precursor_mz: Option<f32>,
+    /// Summed intensity of the peaks satisfying the query's peak conditions
+    pub intensity: f32,
+}
+
+/// One peak of a scan matching a query
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Peak {
+    pub scan: u32,
+    pub rt: f32,
+    pub ms_level: u32,
+    pub precursor_mz: Option<f32>,
+    pub mz: f32,
+    pub intensity: f32,
+}
+
+impl From<&[ScanInfo]> for Table {
+    fn from(rows: &[ScanInfo]) -> Self {
+        Table::default()
+            .with_column("scan", Column::UInt(rows.iter().map(|r| r.scan).collect()))
+            .with_column("rt", Column::Float(rows.iter().map(|r| r.rt).collect()))
+            .with_column(
+                "ms_level",
+                Column::UInt(rows.iter().map(|r| r.ms_level).collect()),
+            )
+            .with_column(
+                "precursor_mz",
+                Column::OptionalFloat(rows.iter().map(|r| r.precursor_mz).collect()),
+            )
+            .with_column(
+                "intensity",
+                Column::Float(rows.iter().map(|r| r.intensity).collect()),
+            )
+    }
+}
+
+impl From<&[Peak]> for Table {
+    fn from(rows: &[Peak]) -> Self {
+        Table::default()
+            .with_column("scan", Column::UInt(rows.iter().map(|r| r.scan).collect()))
+            .with_column("rt", Column::Float(rows.iter().map(|r| r.rt).collect()))
+            .with_column(
+                "ms_level",
+                Column::UInt(rows.iter().map(|r| r.ms_level).collect()),
+            )
+            .with_column(
+                "precursor_mz",
+                Column::OptionalFloat(rows.iter().map(|r| r.precursor_mz).collect()),
+            )
+            .with_column("mz", Column::Float(rows.iter().map(|r| r.mz).collect()))
+            .with_column(
+                "intensity",
+                Column::Float(rows.iter().map(|r| r.intensity).collect()),
+            )
+    }
+}
+
+/// Run a query, returning one row per matching scan
+pub fn scan_info<R: 'static + ChunkReader>(
+    r: R,
+    query: &MassQuery,
+) -> parquet::errors::Result<Vec<ScanInfo>> {
+    let reader = SerializedFileReader::new(r)?;
+    let mut rows = Vec::new();
+    for_each_scan(&reader, query, |scan, matched| {
+        rows.push(ScanInfo {
+            scan: scan.scan,
+            rt: scan.rt,
+            ms_level: query.ms_level as u32,
+            precursor_mz: scan.precursor_mz,
+            intensity: matched,
+        })
+    })?;
+    Ok(rows)
+}
+
+/// Run a query, returning every peak of each matching scan
+pub fn data<R: 'static + ChunkReader>(
+    r: R,
+    query: &MassQuery,
+) -> parquet::errors::Result<Vec<Peak>> {
+    let reader = SerializedFileReader::new(r)?;
+    let mut rows = Vec::new();
+    for_each_scan(&reader, query, |scan, _| {
+        rows.extend(
+            scan.mz
+                .iter()
+                .zip(scan.intensity)
+                .map(|(mz, intensity)| Peak {
+                    scan: scan.scan,
+                    rt: scan.rt,
+                    ms_level: query.ms_level as u32,
+                    precursor_mz: scan.precursor_mz,
+                    mz: *mz as f32,
+                    intensity: *intensity as f32,
+                }),
+        )
+    })?;
+    Ok(rows)
+}
+
+/// The decoded rows of a single scan
+struct Scan<'a> {
+    scan: u32,
+    rt: f32,
+    precursor_mz: Option<f32>,
+    mz: &'a [f64],
+    intensity: &'a [f64],
+}
+
+/// Summed intensity of the peaks matching `peak`, or `None` if no peak does
+fn match_peak(scan: &Scan, peak: &PeakCondition) -> Option<f64> {
+    let (lo, hi) = peak.tolerance.bounds(peak.mz);
+    let base_peak = scan.intensity.iter().copied().fold(0.0, f64::max);
+    let min_intensity = f64::max(
+        peak.min_intensity.unwrap_or_default() as f64,
+        base_peak * peak.min_intensity_percent.unwrap_or_default() as f64 / 100.0,
+    );
+
+    scan.mz
+        .iter()
+        .zip(scan.intensity)
+        .filter(|(mz, intensity)| **mz >= lo && **mz <= hi && **intensity >= min_intensity)
+        .map(|(_, intensity)| *intensity)
+        .reduce(|a, b| a + b)
+}
+
+/// Call `f` with every scan satisfying all of the query's conditions, along
+/// with the summed intensity of the peaks that satisfied its peak conditions
+fn for_each_scan<F: FnMut(&Scan, f32)>(
+    reader: &dyn FileReader,
+    query: &MassQuery,
+    mut f: F,
+) -> parquet::errors::Result<()> {
+    let scan_idx = column_index(reader, "scan")?;
+    let level_idx = column_index(reader, "level")?;
+    let rt_idx = column_index(reader, "rt")?;
+    let mz_idx = column_index(reader, "mz")?;
+    let int_idx = column_index(reader, "intensity")?;
+    let pmz_idx = column_index(reader, "precursor_mz")?;
+
+    // MassQL retention times are in minutes, whatever the unit of the file
+    let rt_unit = RtUnit::from_metadata(reader.metadata());
+    let rt = |minutes: f32| rt_unit.from_seconds(minutes * 60.0) as f64;
+    let (mut rt_lo, mut rt_hi) = (f64::MIN, f64::MAX);
+    let (mut scan_lo, mut scan_hi) = (f64::MIN, f64::MAX);
+    let mut peaks = Vec::new();
+    let mut precursors = Vec::new();
+    for condition in &query.conditions {
+        match condition {
+            Condition::Ms1Mz(peak) | Condition::Ms2Prod(peak) => peaks.push(*peak),
+            Condition::Ms2Prec { mz, tolerance } => precursors.push(tolerance.bounds(*mz)),
+            Condition::RtMin(minutes) => rt_lo = rt_lo.max(rt(*minutes)),
+            Condition::RtMax(minutes) => rt_hi = rt_hi.min(rt(*minutes)),
+            Condition::ScanMin(scan) => scan_lo = scan_lo.max(*scan as f64),
+            Condition::ScanMax(scan) => scan_hi = scan_hi.min(*scan as f64),
+        }
+    }
+    let level = query.ms_level as f64;
+
+    for i in 0..reader.num_row_groups() {
+        let meta = reader.metadata().row_group(i);
+        let prune = !may_contain(meta, level_idx, level, level)
+            || !may_contain(meta, rt_idx, rt_lo, rt_hi)
+            || !may_contain(meta, scan_idx, scan_lo, scan_hi)
+            || peaks.iter().any(|peak| {
+                let (lo, hi) = peak.tolerance.bounds(peak.mz);
+                !may_contain(meta, mz_idx, lo, hi)
+            })
+            || precursors
+                .iter()
+                .any(|(lo, hi)| !may_contain(meta, pmz_idx, *lo, *hi));
+        if prune {
+            continue;
+        }
+
+        let rg = reader.get_row_group(i)?;
+        let mut scans = read_required(rg.as_ref(), scan_idx)?;
+        let mut levels = read_required(rg.as_ref(), level_idx)?;
+        let mut rt = read_required(rg.as_ref(), rt_idx)?;
+        let mut mz = read_required(rg.as_ref(), mz_idx)?;
+        let mut intensity = read_required(rg.as_ref(), int_idx)?;
+        let mut precursor_mz = read_column(rg.as_ref(), pmz_idx)?;
+
+        // Spectra are never split across row groups, but the ions of a row
+        // group may have been sorted by m/z - regroup them by scan
+        if !scans.windows(2).all(|w| w[0] <= w[1]) {
+            let mut order = (0..scans.len()).collect::<Vec<_>>();
+            order.sort_by(|&a, &b| scans[a].total_cmp(&scans[b]));
+            fn permute<T: Copy>(values: &mut Vec<T>, order: &[usize]) {
+                *values = order.iter().map(|&i| values[i]).collect();
+            }
+            permute(&mut scans, &order);
+            permute(&mut levels, &order);
+            permute(&mut rt, &order);
+            permute(&mut mz, &order);
+            permute(&mut intensity, &order);
+            permute(&mut precursor_mz, &order);
+        }
+        let mut start = 0;
+        while start < scans.len() {
+            let end = start
+                + scans[start..]
+                    .iter()
+                    .position(|s| *s != scans[start])
+                    .unwrap_or(scans.len() - start);
+            let row = start;
+            start = end;
+
+            if levels[row] != level
+                || rt[row] < rt_lo
+                || rt[row] > rt_hi
+                || scans[row] < scan_lo
+                || scans[row] > scan_hi
+            {
+                continue;
+            }
+            let pmz = precursor_mz[row];
+            if !precursors
+                .iter()
+                .all(|(lo, hi)| pmz.is_some_and(|mz| mz >= *lo && mz <= *hi))
+            {
+                continue;
+            }
+
+            let scan = Scan {
+                scan: scans[row] as u32,
+                rt: rt[row] as f32,
+                precursor_mz: pmz.map(|mz| mz as f32),
+                mz: &mz[row..end],
+                intensity: &intensity[row..end],
+            };
+            let matched = peaks
+                .iter()
+                .try_fold(0.0, |acc, peak| Some(acc + match_peak(&scan, peak)?));
+            if let Some(matched) = matched {
+                f(&scan, matched as f32);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::{Precursor, RawSpectrum};
+    use crate::write_long::serialize_to_parquet;
+
+    #[test]
+    fn parse_query() {
+        let query =
+            "QUERY scaninfo(MS2DATA) WHERE MS2PROD=226.18:TOLERANCEPPM=5:INTENSITYPERCENT>10 \
+                     and ms2prec=500 AND RTMIN=1.5";
+        assert_eq!(
+            query.parse::<MassQuery>(),
+            Ok(MassQuery {
+                output: Output::ScanInfo,
+                ms_level: 2,
+                conditions: vec![
+                    Condition::Ms2Prod(PeakCondition {
+                        mz: 226.18,
+                        tolerance: Tolerance::Ppm(5.0),
+                        min_intensity: None,
+                        min_intensity_percent: Some(10.0),
+                    }),
+                    Condition::Ms2Prec {
+                        mz: 500.0,
+                        tolerance: Tolerance::Da(0.1)
+                    },
+                    Condition::RtMin(1.5),
+                ]
+            })
+        );
+
+        assert!(matches!(
+            "QUERY MS1DATA WHERE MS2PROD=100".parse::<MassQuery>(),
+            Err(MassQLError::Unsupported(_))
+        ));
+        assert!(matches!(
+            "QUERY MS1DATA WHERE MS1MZ=X-2".parse::<MassQuery>(),
+            Err(MassQLError::Unsupported(_))
+        ));
+        assert!(matches!(
+            "SELECT * FROM spectra".parse::<MassQuery>(),
+            Err(MassQLError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn run_query() -> anyhow::Result<()> {
+        let spectrum = |ms_level, precursor: f64, mz: Vec<f64>, intensity: Vec<f64>| RawSpectrum {
+            ms_level,
+            precursors: vec![Precursor {
+                mz: precursor,
+                ..Default::default()
+            }],
+            mz,
+            intensity,
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum(2, 500.0, vec![226.18, 300.0], vec![50.0, 100.0]),
+            spectrum(2, 500.0, vec![226.18, 300.0], vec![5.0, 100.0]),
+            spectrum(2, 600.0, vec![226.18, 300.0], vec![50.0, 100.0]),
+        ];
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+
+        let query =
+            "QUERY scaninfo(MS2DATA) WHERE MS2PROD=226.18:INTENSITYPERCENT>10 AND MS2PREC=500"
+                .parse::<MassQuery>()?;
+        let rows = scan_info(buf.clone(), &query)?;
+        assert_eq!(rows.iter().map(|r| r.scan).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(rows[0].intensity, 50.0);
+
+        let query = "QUERY MS2DATA WHERE MS2PROD=226.18".parse::<MassQuery>()?;
+        assert_eq!(data(buf, &query)?.len(), 6);
+        Ok(())
+    }
+}
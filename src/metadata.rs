@@ -0,0 +1,407 @@
+//! Run-level metadata parsed from the mzML header, and stored as JSON in the
+//! footer key-value metadata of converted files.
+//!
+//! * `instruments` - the `<instrumentConfigurationList>`: instrument model,
+//!   serial number, ion source/analyzer/detector components, and the
+//!   acquisition software with its version
+//! * `source_files` - the `<sourceFileList>`: the vendor files the mzML was
+//!   converted from, with their SHA-1 checksums
+//! * `input` - the file that was converted, and the SHA-1 checksum of the mzML
+//!   document that was actually parsed
+//! * `start_timestamp` - the `startTimeStamp` of the `<run>`, as written in
+//!   the mzML file (e.g. `2021-03-04T10:15:00Z`)
+//!
+//! Together, these link a parquet file back to the raw data it came from.
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Footer metadata key holding the [`InstrumentConfiguration`]s of a run
+pub const INSTRUMENTS_KEY: &str = "instruments";
+
+/// Footer metadata key holding the [`SourceFile`]s of a run
+pub const SOURCE_FILES_KEY: &str = "source_files";
+
+/// Footer metadata key holding the [`InputFile`] of a run
+pub const INPUT_KEY: &str = "input";
+
+/// Footer metadata key holding the start timestamp of a run
+pub const START_TIMESTAMP_KEY: &str = "start_timestamp";
+
+/// Software listed in the `<softwareList>` of an mzML file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Software {
+    pub id: String,
+    /// Name of the software, e.g. `Xcalibur`
+    pub name: Option<String>,
+    pub version: Option<String>,
+}
+
+/// An ion source, mass analyzer or detector of an instrument configuration
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Component {
+    /// `source`, `analyzer` or `detector`
+    pub kind: String,
+    /// Position of the component in the instrument
+    pub order: Option<u32>,
+    /// cvParam names describing the component, e.g. `orbitrap`
+    pub names: Vec<String>,
+}
+
+/// An `<instrumentConfiguration>` of an mzML file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstrumentConfiguration {
+    pub id: String,
+    /// Instrument model, e.g. `Q Exactive HF`
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    pub components: Vec<Component>,
+    /// Software that acquired the data on this instrument
+    pub software: Option<Software>,
+}
+
+/// A `<sourceFile>` of an mzML file, usually the vendor file it was
+/// converted from
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub id: String,
+    /// File name, e.g. `run.raw`
+    pub name: String,
+    /// Directory or URI of the file, e.g. `file:///C:/data`
+    pub location: String,
+    /// SHA-1 checksum of the file, as recorded by the converter
+    pub sha1: Option<String>,
+}
+
+/// The file that spectra were read from
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputFile {
+    /// Path or URI of the converted file
+    pub path: String,
+    /// SHA-1 checksum of the (decompressed) mzML document that was parsed.
+    /// For vendor files converted with msconvert, this is the checksum of
+    /// the intermediate mzML
+    pub sha1: Option<String>,
+}
+
+/// Metadata describing the run that spectra were read from
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunMetadata {
+    pub instruments: Vec<InstrumentConfiguration>,
+    pub source_files: Vec<SourceFile>,
+    pub input: Option<InputFile>,
+    /// When acquisition of the run started, as an xs:dateTime
+    pub start_timestamp: Option<String>,
+}
+
+impl RunMetadata {
+    /// Footer key-value metadata for a file holding this run. Empty fields
+    /// are left out
+    pub fn to_key_value(&self) -> serde_json::Result<Vec<KeyValue>> {
+        let mut kv = Vec::new();
+        let mut push = |key: &str, value: String| {
+            kv.push(KeyValue {
+                key: key.into(),
+                value: Some(value),
+            })
+        };
+        if !self.instruments.is_empty() {
+            push(INSTRUMENTS_KEY, serde_json::to_string(&self.instruments)?);
+        }
+        if !self.source_files.is_empty() {
+            push(SOURCE_FILES_KEY, serde_json::to_string(&self.source_files)?);
+        }
+        if let Some(input) = &self.input {
+            push(INPUT_KEY, serde_json::to_string(input)?);
+        }
+        // Stored as a plain string, rather than JSON
+        if let Some(start_timestamp) = &self.start_timestamp {
+            push(START_TIMESTAMP_KEY, start_timestamp.clone());
+        }
+        Ok(kv)
+    }
+
+    /// Start of the run, in milliseconds since the Unix epoch. Timestamps
+    /// without a timezone are assumed to be UTC
+    pub fn start_time_millis(&self) -> Option<i64> {
+        let timestamp = self.start_timestamp.as_deref()?.trim();
+        match chrono::DateTime::parse_from_rfc3339(timestamp) {
+            Ok(time) => Some(time.timestamp_millis()),
+            Err(_) => timestamp
+                .parse::<chrono::NaiveDateTime>()
+                .ok()
+                .map(|time| time.and_utc().timestamp_millis()),
+        }
+    }
+
+    /// Load the run metadata recorded in a file's footer metadata
+    pub fn from_metadata(metadata: &ParquetMetaData) -> serde_json::Result<Self> {
+        let value = |key: &str| {
+            metadata
+                .file_metadata()
+                .key_value_metadata()
+                .into_iter()
+                .flatten()
+                .find(|kv| kv.key == key)
+                .and_then(|kv| kv.value.as_deref())
+        };
+        fn json<T: DeserializeOwned>(value: Option<&str>) -> serde_json::Result<Option<T>> {
+            value.map(serde_json::from_str).transpose()
+        }
+        Ok(RunMetadata {
+            instruments: json(value(INSTRUMENTS_KEY))?.unwrap_or_default(),
+            source_files: json(value(SOURCE_FILES_KEY))?.unwrap_or_default(),
+            input: json(value(INPUT_KEY))?,
+            start_timestamp: value(START_TIMESTAMP_KEY).map(String::from),
+        })
+    }
+}
+
+/// Running SHA-1 checksum of the bytes read through a [`Sha1Reader`]
+#[derive(Clone, Default)]
+pub struct Checksum(Arc<Mutex<Sha1>>);
+
+impl Checksum {
+    /// Lowercase hex digest of everything read so far
+    pub fn hex(&self) -> String {
+        hex(self.0.lock().expect("poisoned lock").clone())
+    }
+
+    fn update(&self, bytes: &[u8]) {
+        self.0.lock().expect("poisoned lock").update(bytes);
+    }
+}
+
+fn hex(hasher: Sha1) -> String {
+    let digest = hasher.finalize();
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-1 checksum of a local file, as lowercase hex
+pub fn sha1_file<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
+    let mut hasher = Sha1::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hex(hasher))
+}
+
+/// Computes the SHA-1 checksum of a reader as it is consumed. Wrap it in a
+/// [`tokio::io::BufReader`] to parse it
+pub struct Sha1Reader<R> {
+    inner: R,
+    checksum: Checksum,
+}
+
+impl<R> Sha1Reader<R> {
+    /// Wrap `inner`, returning the reader and a handle to its checksum
+    pub fn new(inner: R) -> (Self, Checksum) {
+        let checksum = Checksum::default();
+        let reader = Sha1Reader {
+            inner,
+            checksum: checksum.clone(),
+        };
+        (reader, checksum)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Sha1Reader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.checksum.update(&buf.filled()[filled..]);
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::MzMLReader;
+    use crate::write_long::{serialize_stream_to_parquet, WriterOptions, ACQUISITION_TIME_COLUMN};
+    use parquet::{
+        file::{reader::FileReader, serialized_reader::SerializedFileReader},
+        record::Field,
+    };
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn run_metadata_round_trip() -> anyhow::Result<()> {
+        let document = r#"
+        <mzML>
+        <fileDescription>
+            <sourceFileList count="1">
+                <sourceFile id="RAW1" name="run.raw" location="file:///C:/data">
+                    <cvParam cvRef="MS" accession="MS:1000768" name="Thermo nativeID format" value=""/>
+                    <cvParam cvRef="MS" accession="MS:1000563" name="Thermo RAW format" value=""/>
+                    <cvParam cvRef="MS" accession="MS:1000569" name="SHA-1" value="9d5ed678fe57bcca610140957afab571b4b2ec27"/>
+                </sourceFile>
+            </sourceFileList>
+        </fileDescription>
+        <referenceableParamGroupList count="1">
+            <referenceableParamGroup id="CommonInstrumentParams">
+                <cvParam cvRef="MS" accession="MS:1002523" name="Q Exactive HF" value=""/>
+                <cvParam cvRef="MS" accession="MS:1000529" name="instrument serial number" value="Exactive Series slot #1"/>
+            </referenceableParamGroup>
+        </referenceableParamGroupList>
+        <softwareList count="2">
+            <software id="Xcalibur" version="4.1.31.9">
+                <cvParam cvRef="MS" accession="MS:1000532" name="Xcalibur" value=""/>
+            </software>
+            <software id="pwiz" version="3.0.19">
+                <cvParam cvRef="MS" accession="MS:1000615" name="ProteoWizard software" value=""/>
+            </software>
+        </softwareList>
+        <instrumentConfigurationList count="1">
+            <instrumentConfiguration id="IC1">
+                <referenceableParamGroupRef ref="CommonInstrumentParams"/>
+                <componentList count="3">
+                    <source order="1">
+                        <cvParam cvRef="MS" accession="MS:1000073" name="electrospray ionization" value=""/>
+                        <cvParam cvRef="MS" accession="MS:1000057" name="electrospray inlet" value=""/>
+                    </source>
+                    <analyzer order="2">
+                        <cvParam cvRef="MS" accession="MS:1000484" name="orbitrap" value=""/>
+                    </analyzer>
+                    <detector order="3">
+                        <cvParam cvRef="MS" accession="MS:1000624" name="inductive detector" value=""/>
+                    </detector>
+                </componentList>
+                <softwareRef ref="Xcalibur"/>
+            </instrumentConfiguration>
+        </instrumentConfigurationList>
+        <run id="run" defaultInstrumentConfigurationRef="IC1" startTimeStamp="2021-03-04T10:15:00Z">
+        <spectrumList count="1">
+            <spectrum id="scan=1" index="0" defaultArrayLength="1">
+                <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1"/>
+                <scanList count="1">
+                    <scan>
+                        <cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="0.5" unitAccession="UO:0000031" unitName="minute"/>
+                    </scan>
+                </scanList>
+                <binaryDataArrayList count="2">
+                    <binaryDataArray encodedLength="12">
+                        <cvParam cvRef="MS" accession="MS:1000514" name="m/z array"/>
+                        <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float"/>
+                        <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                        <binary>AAAAAAAAWUA=</binary>
+                    </binaryDataArray>
+                    <binaryDataArray encodedLength="8">
+                        <cvParam cvRef="MS" accession="MS:1000515" name="intensity array"/>
+                        <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float"/>
+                        <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                        <binary>AACAPw==</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+            </spectrum>
+        </spectrumList>
+        </run>
+        </mzML>
+        "#;
+
+        let expected = InstrumentConfiguration {
+            id: "IC1".into(),
+            model: Some("Q Exactive HF".into()),
+            serial_number: Some("Exactive Series slot #1".into()),
+            components: vec![
+                Component {
+                    kind: "source".into(),
+                    order: Some(1),
+                    names: vec![
+                        "electrospray ionization".into(),
+                        "electrospray inlet".into(),
+                    ],
+                },
+                Component {
+                    kind: "analyzer".into(),
+                    order: Some(2),
+                    names: vec!["orbitrap".into()],
+                },
+                Component {
+                    kind: "detector".into(),
+                    order: Some(3),
+                    names: vec!["inductive detector".into()],
+                },
+            ],
+            software: Some(Software {
+                id: "Xcalibur".into(),
+                name: Some("Xcalibur".into()),
+                version: Some("4.1.31.9".into()),
+            }),
+        };
+
+        let (reader, checksum) = Sha1Reader::new(document.as_bytes());
+        let mut stream = MzMLReader::default().stream(BufReader::new(reader));
+        stream.set_input("run.mzML".into(), Some(checksum));
+        let mut options = WriterOptions::default();
+        options.set_acquisition_time(true);
+        let (buf, count) = serialize_stream_to_parquet(Vec::new(), &mut stream, &options).await?;
+        assert_eq!(count.spectra, 1);
+        assert_eq!(stream.metadata().instruments, vec![expected]);
+        assert_eq!(
+            stream.metadata().source_files,
+            vec![SourceFile {
+                id: "RAW1".into(),
+                name: "run.raw".into(),
+                location: "file:///C:/data".into(),
+                sha1: Some("9d5ed678fe57bcca610140957afab571b4b2ec27".into()),
+            }]
+        );
+        let mut hasher = Sha1::new();
+        hasher.update(document.as_bytes());
+        assert_eq!(
+            stream.metadata().input,
+            Some(InputFile {
+                path: "run.mzML".into(),
+                sha1: Some(hex(hasher)),
+            })
+        );
+
+        assert_eq!(
+            stream.metadata().start_time_millis(),
+            Some(1_614_852_900_000)
+        );
+
+        let reader = SerializedFileReader::new(bytes::Bytes::from(buf))?;
+        let metadata = RunMetadata::from_metadata(reader.metadata())?;
+        assert_eq!(&metadata, stream.metadata());
+
+        // Half a minute after the start of the run
+        let row = reader.get_row_iter(None)?.next().expect("one ion")?;
+        let (_, time) = row
+            .get_column_iter()
+            .find(|(name, _)| *name == ACQUISITION_TIME_COLUMN)
+            .expect("acquisition time column");
+        assert_eq!(time, &Field::TimestampMillis(1_614_852_930_000));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_start_timestamps() {
+        let millis = |timestamp: &str| {
+            RunMetadata {
+                start_timestamp: Some(timestamp.into()),
+                ..Default::default()
+            }
+            .start_time_millis()
+        };
+        assert_eq!(millis("2021-03-04T10:15:00Z"), Some(1_614_852_900_000));
+        assert_eq!(millis("2021-03-04T11:15:00+01:00"), Some(1_614_852_900_000));
+        assert_eq!(millis("2021-03-04T10:15:00.250Z"), Some(1_614_852_900_250));
+        // Timestamps without a timezone are taken as UTC
+        assert_eq!(millis(" 2021-03-04T10:15:00 "), Some(1_614_852_900_000));
+        assert_eq!(millis("4 March 2021"), None);
+        assert_eq!(RunMetadata::default().start_time_millis(), None);
+    }
+}
@@ -0,0 +1,145 @@
+//! Export MS2 spectra to Mascot Generic Format (MGF), for search engines
+//! that cannot read mzparquet directly
+use crate::mzml::RawSpectrum;
+use crate::write_long::RtUnit;
+use std::io::Write;
+
+/// Which MS2 spectra to export
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MgfQuery {
+    /// Optional (inclusive) scan range, of the numbers in the `scan` column of
+    /// long format files: vendor scan numbers where the native ids have
+    /// them, and otherwise spectrum indices from 0
+    pub scans: Option<(u32, u32)>,
+    /// Optional (inclusive) retention time window, in `rt_unit`
+    pub rt: Option<(f32, f32)>,
+    /// Unit of the retention time window
+    pub rt_unit: RtUnit,
+}
+
+impl MgfQuery {
+    fn matches(&self, scan: u32, spectrum: &RawSpectrum) -> bool {
+        spectrum.ms_level == 2
+            && self.scans.is_none_or(|(lo, hi)| (lo..=hi).contains(&scan))
+            && self.rt.is_none_or(|(lo, hi)| {
+                (lo..=hi).contains(&self.rt_unit.from_seconds(spectrum.scan_start_time))
+            })
+    }
+}
+
+/// Write the MS2 spectra matching `query` as MGF, returning the number of
+/// spectra written.
+///
+/// Each spectrum is titled with its native id, and `PEPMASS` and `CHARGE`
+/// are taken from the first precursor (if any). m/z values are written with
+/// 6 decimal places, well within 0.01 ppm above m/z 100
+pub fn write_mgf<W: Write>(
+    mut w: W,
+    spectra: &[RawSpectrum],
+    query: &MgfQuery,
+) -> std::io::Result<usize> {
+    let mut count = 0;
+    for (index, spectrum) in spectra.iter().enumerate() {
+        let scan = spectrum.scan_number.unwrap_or(index as u32);
+        if !query.matches(scan, spectrum) {
+            continue;
+        }
+
+        writeln!(w, "BEGIN IONS")?;
+        writeln!(w, "TITLE={}", String::from_utf8_lossy(&spectrum.id))?;
+        if let Some(precursor) = spectrum.precursors.first() {
+            match precursor.intensity {
+                Some(intensity) => writeln!(w, "PEPMASS={:.6} {:.3}", precursor.mz, intensity)?,
+                None => writeln!(w, "PEPMASS={:.6}", precursor.mz)?,
+            }
+            if let Some(charge) = precursor.charge {
+                writeln!(w, "CHARGE={}+", charge)?;
+            }
+        }
+        writeln!(w, "RTINSECONDS={}", spectrum.scan_start_time)?;
+        writeln!(w, "SCANS={}", scan)?;
+        for (mz, intensity) in spectrum.mz.iter().zip(&spectrum.intensity) {
+            writeln!(w, "{:.6} {:.3}", mz, intensity)?;
+        }
+        writeln!(w, "END IONS\n")?;
+        count += 1;
+    }
+    w.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::Precursor;
+
+    #[test]
+    fn write_ms2_spectra() -> std::io::Result<()> {
+        let ms2 = |id: &[u8], rt: f32| RawSpectrum {
+            id: id.to_vec(),
+            ms_level: 2,
+            scan_start_time: rt,
+            precursors: vec![Precursor {
+                mz: 500.25,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            mz: vec![150.5, 250.125],
+            intensity: vec![10.0, 20.0],
+            ..Default::default()
+        };
+        let spectra = vec![
+            RawSpectrum {
+                id: b"scan=1".to_vec(),
+                ms_level: 1,
+                mz: vec![500.25],
+                intensity: vec![100.0],
+                ..Default::default()
+            },
+            ms2(b"scan=2", 90.0),
+            ms2(b"scan=3", 120.0),
+        ];
+
+        let mut buf = Vec::new();
+        let query = MgfQuery {
+            rt: Some((1.0, 1.75)),
+            rt_unit: RtUnit::Minutes,
+            ..Default::default()
+        };
+        assert_eq!(write_mgf(&mut buf, &spectra, &query)?, 1);
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            "BEGIN IONS\nTITLE=scan=2\nPEPMASS=500.250000\nCHARGE=2+\nRTINSECONDS=90\nSCANS=1\n\
+             150.500000 10.000\n250.125000 20.000\nEND IONS\n\n"
+        );
+
+        let query = MgfQuery {
+            scans: Some((2, 10)),
+            ..Default::default()
+        };
+        assert_eq!(write_mgf(std::io::sink(), &spectra, &query)?, 1);
+        assert_eq!(
+            write_mgf(std::io::sink(), &spectra, &MgfQuery::default())?,
+            2
+        );
+
+        // Vendor scan numbers are used where spectra have them
+        let spectra = spectra
+            .into_iter()
+            .enumerate()
+            .map(|(index, spectrum)| RawSpectrum {
+                scan_number: Some(index as u32 + 1),
+                ..spectrum
+            })
+            .collect::<Vec<_>>();
+        let mut buf = Vec::new();
+        let query = MgfQuery {
+            scans: Some((3, 3)),
+            ..Default::default()
+        };
+        assert_eq!(write_mgf(&mut buf, &spectra, &query)?, 1);
+        assert!(String::from_utf8_lossy(&buf).contains("TITLE=scan=3\n"));
+        assert!(String::from_utf8_lossy(&buf).contains("SCANS=3\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+//! Upgrade files written by older versions of mz_parquet to the current
+//! schema (see [`SCHEMA_VERSION`]).
+//!
+//! Older files are read with the same reader used for current files, which
+//! tolerates the trailing columns added since, and written out again. The
+//! physical types of the `mz` and `intensity` columns are kept, so that e.g.
+//! version 0.2 files with `u32` intensities are not converted to `f32`.
+//! Columns that are missing from the source have to be filled in, and a
+//! [`Compatibility`] report records what that means for each of them.
+use crate::reader::Format;
+use crate::rewrite::SpectrumFile;
+use crate::write_long::{
+    self, IntensityType, MzPrecision, WriterOptions, PIXEL_COLUMNS, SCHEMA_VERSION,
+};
+use crate::write_wide;
+use parquet::{
+    basic::Type as PhysicalType,
+    file::{
+        metadata::ParquetMetaData,
+        reader::{ChunkReader, FileReader},
+        serialized_reader::SerializedFileReader,
+    },
+};
+use std::io::Write;
+
+/// A column of the current schema that is not present in the source file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingColumn {
+    pub column: String,
+    /// How the column is filled in when migrating
+    pub effect: &'static str,
+    /// False if the filled in values could be mistaken for real data
+    pub lossless: bool,
+}
+
+/// How a file differs from the current schema
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compatibility {
+    pub format: Format,
+    /// `version` footer key of the source file
+    pub version: Option<String>,
+    pub mz_precision: MzPrecision,
+    pub intensity_type: IntensityType,
+    pub missing: Vec<MissingColumn>,
+    /// Columns of the source file that the current schema does not have,
+    /// and which are dropped when migrating
+    pub dropped: Vec<String>,
+}
+
+impl Compatibility {
+    pub fn check(metadata: &ParquetMetaData) -> anyhow::Result<Self> {
+        let format = Format::detect(metadata)?;
+        let file = metadata.file_metadata();
+        let version = file
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+            .find(|kv| kv.key == "version")
+            .and_then(|kv| kv.value.clone());
+
+        let schema = file.schema_descr();
+        let physical_type = |name: &str| {
+            schema
+                .columns()
+                .iter()
+                .find(|col| col.path().string() == name)
+                .map(|col| col.physical_type())
+        };
+        let (mz_precision, intensity_type) = match format {
+            Format::Long => (
+                match physical_type("mz") {
+                    Some(PhysicalType::DOUBLE) => MzPrecision::F64,
+                    _ => MzPrecision::F32,
+                },
+                match physical_type("intensity") {
+                    Some(PhysicalType::DOUBLE) => IntensityType::F64,
+                    Some(PhysicalType::INT32) => IntensityType::U32,
+                    _ => IntensityType::F32,
+                },
+            ),
+            Format::Wide => (MzPrecision::F32, IntensityType::F32),
+        };
+
+        let current = match format {
+            Format::Long => write_long::build_schema(&WriterOptions::default())?,
+            Format::Wide => write_wide::build_schema()?,
+        };
+        let current = current
+            .get_fields()
+            .iter()
+            .map(|f| f.name())
+            .collect::<Vec<_>>();
+        let source = file
+            .schema()
+            .get_fields()
+            .iter()
+            .map(|f| f.name())
+            .collect::<Vec<_>>();
+
+        let missing = current
+            .iter()
+            .filter(|name| !source.contains(name))
+            .map(|name| {
+                let (effect, lossless) = match *name {
+                    "total_ion_current" | "ion_injection_time" => ("written as 0", false),
+                    "native_id" => ("spectrum ids are replaced by scan numbers", false),
+                    "filter_string" => ("written as null", true),
+                    "spectrum_index" => ("numbered from the scan column", true),
+                    "ms1_scan" => ("resolved from the precursor scans", true),
+                    "sps_mz" | "ion_mobility" | "noise" | "baseline" | "charge" => {
+                        ("written as null", true)
+                    }
+                    _ => ("cannot be filled in", false),
+                };
+                MissingColumn {
+                    column: name.to_string(),
+                    effect,
+                    lossless,
+                }
+            })
+            .collect();
+        let dropped = source
+            .iter()
+            // `file_id` and the pixel columns are optional, and kept
+            .filter(|name| {
+                !current.contains(name) && **name != "file_id" && !PIXEL_COLUMNS.contains(name)
+            })
+            .map(|name| name.to_string())
+            .collect();
+
+        Ok(Compatibility {
+            format,
+            version,
+            mz_precision,
+            intensity_type,
+            missing,
+            dropped,
+        })
+    }
+
+    /// True if the file was written with the current schema
+    pub fn is_current(&self) -> bool {
+        self.version.as_deref() == Some(SCHEMA_VERSION)
+            && self.missing.is_empty()
+            && self.dropped.is_empty()
+    }
+
+    /// True if migrating the file does not drop data, or fill in values that
+    /// could be mistaken for real data
+    pub fn is_lossless(&self) -> bool {
+        self.missing.iter().all(|m| m.lossless) && self.dropped.is_empty()
+    }
+
+    /// Writer options preserving the physical types of the source file
+    pub fn writer_options(&self) -> WriterOptions {
+        let mut options = WriterOptions::default();
+        options
+            .set_mz_precision(self.mz_precision)
+            .set_intensity_type(self.intensity_type);
+        options
+    }
+}
+
+impl std::fmt::Display for Compatibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "format:    {}", self.format)?;
+        writeln!(
+            f,
+            "version:   {} (current {})",
+            self.version.as_deref().unwrap_or("unknown"),
+            SCHEMA_VERSION
+        )?;
+        writeln!(f, "m/z:       {}", self.mz_precision)?;
+        writeln!(f, "intensity: {}", self.intensity_type)?;
+        for missing in &self.missing {
+            writeln!(f, "missing column `{}`: {}", missing.column, missing.effect)?;
+        }
+        for dropped in &self.dropped {
+            writeln!(
+                f,
+                "column `{}` is not part of the current schema, and is dropped",
+                dropped
+            )?;
+        }
+        match (self.is_current(), self.is_lossless()) {
+            (true, _) => write!(f, "file is up to date"),
+            (false, true) => write!(f, "migration is lossless"),
+            (false, false) => write!(f, "migration is NOT lossless"),
+        }
+    }
+}
+
+/// A file loaded for migration
+pub struct Migration {
+    pub compatibility: Compatibility,
+    pub file: SpectrumFile,
+}
+
+impl Migration {
+    pub fn read<R: 'static + ChunkReader>(r: R) -> anyhow::Result<Self> {
+        let reader = SerializedFileReader::new(r)?;
+        let compatibility = Compatibility::check(reader.metadata())?;
+        let file = SpectrumFile::read_from(&reader)?;
+        Ok(Migration {
+            compatibility,
+            file,
+        })
+    }
+
+    /// Write the file with the current schema, returning the writer and the
+    /// number of spectra written
+    pub fn write<W: Write + Send>(&self, w: W) -> anyhow::Result<(W, usize)> {
+        self.file.write(w, &self.compatibility.writer_options())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parquet::{
+        data_type::{FloatType, Int32Type},
+        file::{properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+    use std::sync::Arc;
+
+    /// A long format file, as written by version 0.2
+    fn version_2() -> anyhow::Result<Vec<u8>> {
+        let schema = Arc::new(parse_message_type(
+            "message schema {
+                required int32 scan (INTEGER(32, false));
+                required int32 level (INTEGER(32, false));
+                required float rt;
+                required float mz;
+                required int32 intensity (INTEGER(32, false));
+                optional float ion_mobility;
+                optional float isolation_lower;
+                optional float isolation_upper;
+                optional int32 precursor_scan (INTEGER(32, false));
+                optional float precursor_mz;
+                optional int32 precursor_charge (INTEGER(32, false));
+            }",
+        )?);
+        let properties = WriterProperties::builder()
+            .set_key_value_metadata(Some(vec![parquet::file::metadata::KeyValue {
+                key: "version".into(),
+                value: Some("0.2".into()),
+            }]))
+            .build();
+        let mut writer =
+            SerializedFileWriter::new(Vec::new(), schema.clone(), Arc::new(properties))?;
+        let mut rg = writer.next_row_group()?;
+        let nulls = [0i16; 3];
+        for field in schema.get_fields() {
+            let mut col = rg.next_column()?.expect("column");
+            match field.name() {
+                "scan" => col
+                    .typed::<Int32Type>()
+                    .write_batch(&[0, 0, 1], None, None)?,
+                "level" => col
+                    .typed::<Int32Type>()
+                    .write_batch(&[1, 1, 2], None, None)?,
+                "intensity" => col
+                    .typed::<Int32Type>()
+                    .write_batch(&[10, 20, 5], None, None)?,
+                "rt" => col
+                    .typed::<FloatType>()
+                    .write_batch(&[1.0, 1.0, 1.5], None, None)?,
+                "mz" => col
+                    .typed::<FloatType>()
+                    .write_batch(&[400.0, 500.0, 150.0], None, None)?,
+                "precursor_scan" | "precursor_charge" => {
+                    col.typed::<Int32Type>()
+                        .write_batch(&[], Some(&nulls), None)?
+                }
+                _ => col
+                    .typed::<FloatType>()
+                    .write_batch(&[], Some(&nulls), None)?,
+            };
+            col.close()?;
+        }
+        rg.close()?;
+        Ok(writer.into_inner()?)
+    }
+
+    #[test]
+    fn migrate_version_2() -> anyhow::Result<()> {
+        let migration = Migration::read(bytes::Bytes::from(version_2()?))?;
+        let compatibility = &migration.compatibility;
+        assert_eq!(compatibility.format, Format::Long);
+        assert_eq!(compatibility.version.as_deref(), Some("0.2"));
+        assert_eq!(compatibility.intensity_type, IntensityType::U32);
+        assert_eq!(
+            compatibility
+                .missing
+                .iter()
+                .map(|m| m.column.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "total_ion_current",
+                "ion_injection_time",
+                "filter_string",
+                "native_id",
+                "spectrum_index",
+                "ms1_scan",
+                "sps_mz"
+            ]
+        );
+        assert!(!compatibility.is_current());
+        assert!(!compatibility.is_lossless());
+
+        let (buf, count) = migration.write(Vec::new())?;
+        assert_eq!(count, 2);
+        let migrated = Migration::read(bytes::Bytes::from(buf))?;
+        assert!(migrated.compatibility.is_current());
+        assert_eq!(migrated.compatibility.intensity_type, IntensityType::U32);
+        // The old synthetic scans are kept as the scan numbers
+        let mut spectra = migrated.file.spectra.clone();
+        for (scan, spectrum) in spectra.iter_mut().enumerate() {
+            assert_eq!(spectrum.scan_number.take(), Some(scan as u32));
+        }
+        assert_eq!(spectra, migration.file.spectra);
+        assert_eq!(migrated.file.spectra[1].id, b"1");
+        assert_eq!(migrated.file.spectra[0].intensity, vec![10.0, 20.0]);
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+//! Expose mzparquet files through [`mzdata`]'s reader traits, so that tools
+//! built on mzdata can read them like any other backend.
+//!
+//! [`MzParquetSource`] implements [`SpectrumSource`] and
+//! [`RandomAccessSpectrumIterator`]. Spectra are read with
+//! [`crate::reader::read_spectra`] (long or wide format) when the source is
+//! opened, and converted to [`MultiLayerSpectrum`]s as they are requested.
+//! Spectra can be looked up by id or by their position in the file, which is
+//! also the offset recorded in the [`OffsetIndex`].
+//!
+//! mzdata reports retention times in minutes, while mzparquet stores seconds.
+//! Writing through mzdata's `SpectrumWriter` is not supported: convert to
+//! mzML with mzdata and then to mzparquet instead.
+use crate::mzml::{Polarity, Precursor, RawSpectrum};
+use crate::reader::read_spectra;
+use mzdata::io::{OffsetIndex, RandomAccessSpectrumIterator, SpectrumAccessError, SpectrumSource};
+use mzdata::spectrum::{
+    bindata::{ArrayType, BinaryArrayMap, BinaryDataArrayType, DataArray},
+    IsolationWindow, IsolationWindowState, MultiLayerSpectrum, ScanEvent, ScanPolarity,
+    SelectedIon, SignalContinuity, SpectrumDescription,
+};
+use parquet::file::reader::ChunkReader;
+
+/// An mzparquet file, read through mzdata's traits
+pub struct MzParquetSource {
+    spectra: Vec<RawSpectrum>,
+    index: OffsetIndex,
+    position: usize,
+}
+
+impl MzParquetSource {
+    /// Read all spectra of an mzparquet file
+    pub fn new<R: 'static + ChunkReader>(r: R) -> parquet::errors::Result<Self> {
+        let (_, spectra) = read_spectra(r)?;
+        let mut index = OffsetIndex::new("spectrum".into());
+        for (offset, spectrum) in spectra.iter().enumerate() {
+            index.insert(String::from_utf8_lossy(&spectrum.id), offset as u64);
+        }
+        index.init = true;
+        Ok(MzParquetSource {
+            spectra,
+            index,
+            position: 0,
+        })
+    }
+
+    fn convert(&self, index: usize) -> Option<MultiLayerSpectrum> {
+        self.spectra
+            .get(index)
+            .map(|spectrum| convert_spectrum(spectrum, index))
+    }
+}
+
+fn convert_precursor(precursor: &Precursor) -> mzdata::spectrum::Precursor {
+    let target = precursor
+        .isolation_window_target
+        .unwrap_or(precursor.mz as f32);
+    let isolation_window = match (
+        precursor.isolation_window_lower,
+        precursor.isolation_window_upper,
+    ) {
+        (Some(lower), Some(upper)) => IsolationWindow {
+            target,
+            lower_bound: target - lower,
+            upper_bound: target + upper,
+            flags: IsolationWindowState::Complete,
+        },
+        _ => IsolationWindow::default(),
+    };
+    mzdata::spectrum::Precursor {
+        ions: vec![SelectedIon {
+            mz: precursor.mz,
+            intensity: precursor.intensity.unwrap_or_default(),
+            charge: precursor.charge.map(i32::from),
+            ..Default::default()
+        }],
+        isolation_window,
+        precursor_id: precursor
+            .spectrum_ref
+            .as_deref()
+            .map(|r| String::from_utf8_lossy(r).into_owned()),
+        ..Default::default()
+    }
+}
+
+fn data_array(name: ArrayType, values: &[f64]) -> DataArray {
+    let bytes = values
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect::<Vec<_>>();
+    DataArray::wrap(&name, BinaryDataArrayType::Float64, bytes)
+}
+
+/// Convert a spectrum to mzdata's representation. `index` is the position of
+/// the spectrum in its file
+pub fn convert_spectrum(spectrum: &RawSpectrum, index: usize) -> MultiLayerSpectrum {
+    let mut description = SpectrumDescription {
+        id: String::from_utf8_lossy(&spectrum.id).into_owned(),
+        index,
+        ms_level: spectrum.ms_level,
+        polarity: match spectrum.polarity {
+            Some(Polarity::Positive) => ScanPolarity::Positive,
+            Some(Polarity::Negative) => ScanPolarity::Negative,
+            None => ScanPolarity::Unknown,
+        },
+        signal_continuity: match spectrum.centroid {
+            true => SignalContinuity::Centroid,
+            false => SignalContinuity::Profile,
+        },
+        precursor: spectrum.precursors.first().map(convert_precursor),
+        ..Default::default()
+    };
+    description.acquisition.scans.push(ScanEvent {
+        start_time: spectrum.scan_start_time as f64 / 60.0,
+        injection_time: spectrum.ion_injection_time,
+        ..Default::default()
+    });
+
+    let mut arrays = BinaryArrayMap::new();
+    arrays.add(data_array(ArrayType::MZArray, &spectrum.mz));
+    arrays.add(data_array(ArrayType::IntensityArray, &spectrum.intensity));
+    MultiLayerSpectrum::from_arrays_and_description(arrays, description)
+}
+
+impl Iterator for MzParquetSource {
+    type Item = MultiLayerSpectrum;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let spectrum = self.convert(self.position)?;
+        self.position += 1;
+        Some(spectrum)
+    }
+}
+
+impl SpectrumSource for MzParquetSource {
+    fn reset(&mut self) {
+        self.position = 0;
+    }
+
+    fn get_spectrum_by_id(&mut self, id: &str) -> Option<MultiLayerSpectrum> {
+        let index = self.index.get(id)?;
+        self.convert(index as usize)
+    }
+
+    fn get_spectrum_by_index(&mut self, index: usize) -> Option<MultiLayerSpectrum> {
+        self.convert(index)
+    }
+
+    fn get_index(&self) -> &OffsetIndex {
+        &self.index
+    }
+
+    fn set_index(&mut self, index: OffsetIndex) {
+        self.index = index;
+    }
+}
+
+impl RandomAccessSpectrumIterator for MzParquetSource {
+    fn start_from_id(&mut self, id: &str) -> Result<&mut Self, SpectrumAccessError> {
+        match self.index.get(id) {
+            Some(index) => {
+                self.position = index as usize;
+                Ok(self)
+            }
+            None => Err(SpectrumAccessError::SpectrumIdNotFound(id.to_string())),
+        }
+    }
+
+    fn start_from_index(&mut self, index: usize) -> Result<&mut Self, SpectrumAccessError> {
+        match index < self.spectra.len() {
+            true => {
+                self.position = index;
+                Ok(self)
+            }
+            false => Err(SpectrumAccessError::SpectrumIndexNotFound(index)),
+        }
+    }
+
+    /// Start from the first spectrum acquired at or after `time`, in minutes
+    fn start_from_time(&mut self, time: f64) -> Result<&mut Self, SpectrumAccessError> {
+        let seconds = (time * 60.0) as f32;
+        match self
+            .spectra
+            .iter()
+            .position(|spectrum| spectrum.scan_start_time >= seconds)
+        {
+            Some(index) => {
+                self.position = index;
+                Ok(self)
+            }
+            None => Err(SpectrumAccessError::SpectrumNotFound),
+        }
+    }
+}
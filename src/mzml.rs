@@ -1,12 +1,22 @@
-use async_compression::tokio::bufread::ZlibDecoder;
-use indicatif::ProgressStyle;
-use quick_xml::events::Event;
+use crate::metadata::{
+    Checksum, Component, InputFile, InstrumentConfiguration, RunMetadata, Software, SourceFile,
+};
+use crate::native_id::NativeIdFormat;
+use crate::numpress::Numpress;
+use crate::progress::{Callback, Progress, ProgressBar};
+use quick_xml::events::{BytesStart, Event};
 use quick_xml::Reader;
-use tokio::io::{AsyncBufRead, AsyncReadExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+use tokio::io::AsyncBufRead;
+#[cfg(feature = "native")]
+use {std::collections::VecDeque, tokio::sync::oneshot};
 
 #[derive(Default, Debug, Clone, PartialEq, PartialOrd)]
 pub struct Precursor {
-    pub mz: f32,
+    pub mz: f64,
     pub intensity: Option<f32>,
     pub charge: Option<u8>,
     pub spectrum_ref: Option<Vec<u8>>,
@@ -22,24 +32,99 @@ pub struct RawSpectrum {
     pub ms_level: u8,
     /// Spectrum identifier
     pub id: Vec<u8>,
+    /// Scan number assigned by the instrument, parsed from the native id (see
+    /// [`crate::native_id`])
+    pub scan_number: Option<u32>,
     /// Vector of precursors associated with this spectrum
     pub precursors: Vec<Precursor>,
     /// Profile or Centroided data
     pub centroid: bool,
-    /// Scan start time
+    /// Scan start time, in seconds
     pub scan_start_time: f32,
     /// Ion injection time
     pub ion_injection_time: f32,
     /// Total ion current
     pub total_ion_current: f32,
-    /// Ion mobility
+    /// Ion mobility of the scan, as reported by the instrument: inverse
+    /// reduced ion mobility (Bruker, Vs/cm²) or drift time (Waters, ms)
     pub inverse_ion_mobility: Option<f32>,
-    /// M/z array
-    pub mz: Vec<f32>,
+    /// Position of the spectrum, for imaging (imzML) acquisitions
+    pub pixel: Option<Pixel>,
+    /// Ion polarity of the scan, if reported
+    pub polarity: Option<Polarity>,
+    /// Per-ion ion mobility, for spectra spanning several mobility scans
+    /// (e.g. timsTOF frames, or Waters drift scans combined by msconvert).
+    /// Empty if every ion has the mobility of the spectrum
+    pub ion_mobility: Vec<f32>,
+    /// Instrument filter string (e.g. Thermo `FTMS + p NSI Full ms [...]`),
+    /// or a generic scan description
+    pub filter_string: Option<String>,
+    /// M/z array. Kept at full precision, as downcasting to `f32` loses
+    /// around 1 ppm of mass accuracy at high m/z
+    pub mz: Vec<f64>,
     /// Intensity array
-    pub intensity: Vec<f32>,
-    /// Noise array
+    pub intensity: Vec<f64>,
+    /// Per-ion noise level, e.g. from Thermo centroid streams. Empty unless
+    /// there is one value per ion
     pub noise: Vec<f32>,
+    /// Per-ion baseline, alongside [`RawSpectrum::noise`]
+    pub baseline: Vec<f32>,
+    /// Per-ion charge state assigned by the instrument, with 0 for ions
+    /// without one. Empty unless there is one value per ion
+    pub charge: Vec<u8>,
+    /// cvParams (by accession) and userParams (by name) of the spectrum and
+    /// its scans that don't map to any of the fields above, along with their
+    /// values. Only collected if enabled with
+    /// [`MzMLReader::set_extra_params`]
+    pub extra_params: Vec<(String, String)>,
+}
+
+/// Position of an imaging spectrum on the sample, in pixels
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pixel {
+    pub x: u32,
+    pub y: u32,
+    /// Only set for 3D acquisitions
+    pub z: Option<u32>,
+}
+
+/// Ion polarity of a scan. Polarity switching runs interleave positive and
+/// negative scans
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Polarity {
+    Positive,
+    Negative,
+}
+
+impl std::str::FromStr for Polarity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pos" | "positive" | "+" => Ok(Polarity::Positive),
+            "neg" | "negative" | "-" => Ok(Polarity::Negative),
+            _ => Err(format!("unknown polarity `{}`, expected `pos` or `neg`", s)),
+        }
+    }
+}
+
+/// A chromatogram (TIC, BPC, SRM transition, ...), as returned by a parser
+#[derive(Default, Debug, Clone, PartialEq, PartialOrd)]
+pub struct Chromatogram {
+    /// Chromatogram identifier
+    pub id: Vec<u8>,
+    /// Type of the chromatogram, e.g. `TIC` or `SRM`
+    pub kind: Option<&'static str>,
+    /// Precursor (Q1) m/z, for SRM/SIM chromatograms
+    pub precursor_mz: Option<f64>,
+    /// Product (Q3) m/z, for SRM chromatograms
+    pub product_mz: Option<f64>,
+    /// Time array, in seconds (like spectrum retention times)
+    pub time: Vec<f64>,
+    /// Intensity array, or the values of a non-MS trace (absorbance,
+    /// pressure, flow rate, ...)
+    pub intensity: Vec<f64>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -51,6 +136,12 @@ enum State {
     Binary,
     Precursor,
     SelectedIon,
+    Chromatogram,
+    Product,
+    Software,
+    Instrument,
+    Component,
+    SourceFile,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -58,22 +149,124 @@ enum BinaryKind {
     Intensity,
     Mz,
     Noise,
+    Baseline,
+    Charge,
+    IonMobility,
+    Time,
+    Trace,
 }
 
-#[derive(Copy, Clone, Debug)]
-enum Dtype {
+/// Data type of a binary data array
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Dtype {
     F32,
+    #[default]
     F64,
 }
 
+/// How the text of a `<binary>` element is encoded, from the cvParams of its
+/// binaryDataArray
+#[derive(Copy, Clone, Debug)]
+struct Encoding {
+    compression: bool,
+    numpress: Option<Numpress>,
+    dtype: Dtype,
+}
+
+/// Decode the base64 text of a `<binary>` element
+fn decode_binary(text: &[u8], encoding: Encoding) -> Result<Vec<f64>, MzMLError> {
+    let decoded = base64::decode(text)?;
+    let bytes = match encoding.compression {
+        false => decoded,
+        true => {
+            let mut buf = Vec::with_capacity(decoded.len() * 2);
+            flate2::read::ZlibDecoder::new(decoded.as_slice()).read_to_end(&mut buf)?;
+            buf
+        }
+    };
+
+    Ok(match (encoding.numpress, encoding.dtype) {
+        (Some(numpress), _) => numpress.decode(&bytes)?,
+        (None, Dtype::F32) => {
+            let mut le: [u8; 4] = [0; 4];
+            bytes
+                .chunks(4)
+                .filter(|chunk| chunk.len() == 4)
+                .map(|chunk| {
+                    le.copy_from_slice(chunk);
+                    f32::from_le_bytes(le) as f64
+                })
+                .collect::<Vec<f64>>()
+        }
+        (None, Dtype::F64) => {
+            let mut le: [u8; 8] = [0; 8];
+            bytes
+                .chunks(8)
+                .filter(|chunk| chunk.len() == 8)
+                .map(|chunk| {
+                    le.copy_from_slice(chunk);
+                    f64::from_le_bytes(le)
+                })
+                .collect::<Vec<f64>>()
+        }
+    })
+}
+
+/// Store a decoded binary data array in `spectrum`
+fn store_spectrum_array(spectrum: &mut RawSpectrum, kind: BinaryKind, array: Vec<f64>) {
+    match kind {
+        BinaryKind::Intensity => {
+            spectrum.intensity = array;
+        }
+        BinaryKind::Mz => {
+            spectrum.mz = array;
+        }
+        BinaryKind::Noise => {
+            spectrum.noise = array.into_iter().map(|n| n as f32).collect();
+        }
+        BinaryKind::Baseline => {
+            spectrum.baseline = array.into_iter().map(|b| b as f32).collect();
+        }
+        BinaryKind::Charge => {
+            spectrum.charge = array.into_iter().map(|z| z as u8).collect();
+        }
+        BinaryKind::IonMobility => {
+            spectrum.ion_mobility = array.into_iter().map(|im| im as f32).collect();
+        }
+        BinaryKind::Time | BinaryKind::Trace => {}
+    }
+}
+
 // MUST supply only one of the following
 const ZLIB_COMPRESSION: &[u8] = b"MS:1000574";
 const NO_COMPRESSION: &[u8] = b"MS:1000576";
+const NUMPRESS_LINEAR: &[u8] = b"MS:1002312";
+const NUMPRESS_PIC: &[u8] = b"MS:1002313";
+const NUMPRESS_SLOF: &[u8] = b"MS:1002314";
+const NUMPRESS_LINEAR_ZLIB: &[u8] = b"MS:1002746";
+const NUMPRESS_PIC_ZLIB: &[u8] = b"MS:1002747";
+const NUMPRESS_SLOF_ZLIB: &[u8] = b"MS:1002748";
 
 // MUST supply only one of the following
 const INTENSITY_ARRAY: &[u8] = b"MS:1000515";
 const MZ_ARRAY: &[u8] = b"MS:1000514";
 const NOISE_ARRAY: &[u8] = b"MS:1002744";
+const BASELINE_ARRAY: &[u8] = b"MS:1002745";
+const CHARGE_ARRAY: &[u8] = b"MS:1000516";
+const TIME_ARRAY: &[u8] = b"MS:1000595";
+// mean/raw drift time, inverse reduced ion mobility and ion mobility arrays,
+// written when msconvert combines ion mobility scans into one spectrum
+const ION_MOBILITY_ARRAYS: [&[u8]; 6] = [
+    b"MS:1002477",
+    b"MS:1002816",
+    b"MS:1003006",
+    b"MS:1003007",
+    b"MS:1003008",
+    b"MS:1003153",
+];
+// flow rate, pressure, temperature and non-standard data arrays, holding the
+// values of non-MS chromatograms
+const TRACE_ARRAYS: [&[u8]; 4] = [b"MS:1000820", b"MS:1000821", b"MS:1000822", b"MS:1000786"];
 
 // MUST supply only one of the following
 const FLOAT_64: &[u8] = b"MS:1000523";
@@ -83,9 +276,20 @@ const MS_LEVEL: &[u8] = b"MS:1000511";
 const PROFILE: &[u8] = b"MS:1000128";
 const CENTROID: &[u8] = b"MS:1000127";
 const TOTAL_ION_CURRENT: &[u8] = b"MS:1000285";
+const POSITIVE_SCAN: &[u8] = b"MS:1000130";
+const NEGATIVE_SCAN: &[u8] = b"MS:1000129";
 
 const SCAN_START_TIME: &[u8] = b"MS:1000016";
+const UNIT_SECOND: &[u8] = b"UO:0000010";
+const UNIT_MILLISECOND: &[u8] = b"UO:0000028";
+const UNIT_MINUTE: &[u8] = b"UO:0000031";
 const ION_INJECTION_TIME: &[u8] = b"MS:1000927";
+const FILTER_STRING: &[u8] = b"MS:1000512";
+const DRIFT_TIME: &[u8] = b"MS:1002476";
+const INVERSE_REDUCED_ION_MOBILITY: &[u8] = b"MS:1002815";
+
+// Older converters write the filter string as a userParam
+const FILTER_STRING_PARAMS: [&[u8]; 2] = [b"filter string", b"scan description"];
 
 const SELECTED_ION_MZ: &[u8] = b"MS:1000744";
 const SELECTED_ION_INT: &[u8] = b"MS:1000042";
@@ -95,12 +299,114 @@ const ISO_WINDOW_LOWER: &[u8] = b"MS:1000828";
 const ISO_WINDOW_UPPER: &[u8] = b"MS:1000829";
 const ISO_WINDOW_TARGET: &[u8] = b"MS:1000827";
 
-#[derive(Default)]
+const INSTRUMENT_SERIAL_NUMBER: &[u8] = b"MS:1000529";
+const SHA1: &[u8] = b"MS:1000569";
+
+// Chromatogram types, and the label stored for them
+const CHROMATOGRAM_TYPES: [(&[u8], &str); 12] = [
+    (b"MS:1000235", "TIC"),
+    (b"MS:1000628", "BPC"),
+    (b"MS:1000627", "SIC"),
+    (b"MS:1001472", "SIM"),
+    (b"MS:1001473", "SRM"),
+    (b"MS:1001474", "CRM"),
+    (b"MS:1000810", "mass"),
+    // Non-MS traces, e.g. UV/PDA detectors and LC pump readbacks
+    (b"MS:1000811", "EMR"),
+    (b"MS:1000812", "absorption"),
+    (b"MS:1000813", "emission"),
+    (b"MS:1003019", "pressure"),
+    (b"MS:1003020", "flow rate"),
+];
+
+// mzMLb stores binary data arrays outside of the XML document
+const EXTERNAL_DATASET: &[u8] = b"MS:1002841";
+const EXTERNAL_OFFSET: &[u8] = b"MS:1002842";
+const EXTERNAL_LENGTH: &[u8] = b"MS:1002843";
+
+// ... and so does imzML, in a separate binary (.ibd) file
+const IBD_OFFSET: &[u8] = b"IMS:1000102";
+const IBD_LENGTH: &[u8] = b"IMS:1000103";
+const IBD_ENCODED_LENGTH: &[u8] = b"IMS:1000104";
+
+const POSITION_X: &[u8] = b"IMS:1000050";
+const POSITION_Y: &[u8] = b"IMS:1000051";
+const POSITION_Z: &[u8] = b"IMS:1000052";
+
+/// Factor converting the value of a time cvParam (scan start time, time
+/// array) to seconds, from its unit. Times without a unit are assumed to be in
+/// seconds
+fn time_scale(ev: &BytesStart) -> Result<f32, MzMLError> {
+    let unit = ev.try_get_attribute(b"unitAccession")?;
+    match unit.as_ref().map(|attr| attr.value.as_ref()) {
+        Some(UNIT_MINUTE) => Ok(60.0),
+        Some(UNIT_MILLISECOND) => Ok(0.001),
+        Some(UNIT_SECOND) | None => Ok(1.0),
+        Some(unit) => Err(MzMLError::UnsupportedCV(
+            String::from_utf8_lossy(unit).into_owned(),
+        )),
+    }
+}
+
+/// Key and value of a cvParam or userParam kept in
+/// [`RawSpectrum::extra_params`]. cvParams without a value (e.g. `positive
+/// scan`) are stored with their name instead
+fn extra_param(ev: &BytesStart) -> Result<(String, String), MzMLError> {
+    let attr = |key: &[u8]| -> Result<Option<String>, MzMLError> {
+        Ok(ev
+            .try_get_attribute(key)?
+            .map(|attr| attr.unescape_value().map(|v| v.into_owned()))
+            .transpose()?)
+    };
+    let name = attr(b"name")?.unwrap_or_default();
+    let value = attr(b"value")?.filter(|value| !value.is_empty());
+    match attr(b"accession")? {
+        Some(accession) => Ok((accession, value.unwrap_or(name))),
+        None => Ok((name, value.unwrap_or_default())),
+    }
+}
+
+fn numpress(accession: &[u8]) -> Option<Numpress> {
+    match accession {
+        NUMPRESS_LINEAR | NUMPRESS_LINEAR_ZLIB => Some(Numpress::Linear),
+        NUMPRESS_PIC | NUMPRESS_PIC_ZLIB => Some(Numpress::Pic),
+        NUMPRESS_SLOF | NUMPRESS_SLOF_ZLIB => Some(Numpress::Slof),
+        _ => None,
+    }
+}
+
+/// Source of binary data arrays that are stored outside of the XML document,
+/// as in mzMLb files, where each array is a slice of an HDF5 dataset, or
+/// imzML files, where arrays are stored in a separate binary file
+pub trait ExternalArrays: Send {
+    fn read(&mut self, array: &ExternalArray) -> Result<Vec<f64>, MzMLError>;
+}
+
+/// Location of an external binary data array
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExternalArray {
+    /// HDF5 dataset holding the array (mzMLb only)
+    pub dataset: String,
+    /// Offset of the first value: an index into the dataset for mzMLb, and a
+    /// byte offset into the binary file for imzML
+    pub offset: usize,
+    /// Number of values
+    pub length: usize,
+    pub dtype: Dtype,
+}
+
+#[derive(Default, Clone)]
 pub struct MzMLReader {
     ms_level: Option<u8>,
     // If set to Some(level) and noise intensities are present in the MzML file,
     // divide intensities at this MS-level by noise to calculate S/N
     signal_to_noise: Option<u8>,
+    extra_params: bool,
+    #[cfg(feature = "native")]
+    parallel_decoding: bool,
+    #[cfg(feature = "native")]
+    read_ahead: Option<usize>,
+    progress: Callback,
 }
 
 impl MzMLReader {
@@ -112,7 +418,7 @@ impl MzMLReader {
     pub fn with_level_filter(ms_level: u8) -> Self {
         Self {
             ms_level: Some(ms_level),
-            signal_to_noise: None,
+            ..Default::default()
         }
     }
 
@@ -121,28 +427,448 @@ impl MzMLReader {
         self
     }
 
-    /// Here be dragons -
-    /// Seriously, this kinda sucks because it's a giant imperative, stateful loop.
-    /// But I also don't want to spend any more time working on an mzML parser...
+    /// Keep the cvParams and userParams of spectra that aren't parsed into a
+    /// field of [`RawSpectrum`] (e.g. vendor trailer values), in
+    /// [`RawSpectrum::extra_params`]. Otherwise they are dropped
+    pub fn set_extra_params(&mut self, extra_params: bool) -> &mut Self {
+        self.extra_params = extra_params;
+        self
+    }
+
+    /// Decode the binary data arrays of spectra on rayon's thread pool while
+    /// parsing continues, rather than as they are read. Spectra are still
+    /// returned in file order. Base64 and zlib decoding dominate parse time,
+    /// so this speeds up parsing on multicore machines, at the cost of
+    /// holding a few spectra per thread in memory
+    #[cfg(feature = "native")]
+    pub fn set_parallel_decoding(&mut self, parallel_decoding: bool) -> &mut Self {
+        self.parallel_decoding = parallel_decoding;
+        self
+    }
+
+    /// Limit the memory held by spectra parsed ahead of the one being
+    /// returned, with parallel decoding or by an
+    /// [`IndexedStream`](crate::indexed::IndexedStream), to about this many
+    /// bytes. Sizes are estimated from the XML of the spectra. Unlimited by
+    /// default, other than by the number of threads
+    #[cfg(feature = "native")]
+    pub fn set_read_ahead(&mut self, bytes: Option<usize>) -> &mut Self {
+        self.read_ahead = bytes;
+        self
+    }
+
+    /// Limit on the memory held by spectra parsed ahead, as set with
+    /// [`Self::set_read_ahead`]
+    #[cfg(feature = "native")]
+    pub fn read_ahead(&self) -> Option<usize> {
+        self.read_ahead
+    }
+
+    /// Report spectra to `progress` as they are parsed
+    pub fn set_progress(&mut self, progress: Option<Arc<dyn Progress>>) -> &mut Self {
+        self.progress = Callback::new(progress);
+        self
+    }
+
+    #[cfg(feature = "native")]
+    pub(crate) fn progress(&self) -> &Callback {
+        &self.progress
+    }
+
+    /// Drop per-ion arrays that don't match the peaks, and compute S/N, once
+    /// all binary data arrays of a spectrum have been decoded
+    fn finish(&self, mut spectrum: RawSpectrum) -> RawSpectrum {
+        if spectrum.ion_mobility.len() != spectrum.mz.len() {
+            spectrum.ion_mobility.clear();
+        }
+        // Noise may be sampled at fewer points than the peaks, in which case
+        // it isn't kept per ion
+        if spectrum.noise.len() != spectrum.mz.len() {
+            spectrum.noise.clear();
+        }
+        if spectrum.baseline.len() != spectrum.mz.len() {
+            spectrum.baseline.clear();
+        }
+        if spectrum.charge.len() != spectrum.mz.len() {
+            spectrum.charge.clear();
+        }
+        match self.signal_to_noise {
+            Some(level) if level == spectrum.ms_level && !spectrum.noise.is_empty() => {
+                spectrum
+                    .intensity
+                    .iter_mut()
+                    .zip(spectrum.noise.iter())
+                    .for_each(|(int, noise)| *int /= *noise as f64);
+            }
+            _ => {}
+        }
+        spectrum
+    }
+
+    /// Whether the binary data arrays of `spectrum` are skipped, because of
+    /// the MS level filter. Chromatograms are always read
+    fn skip_arrays(&self, spectrum: &RawSpectrum, chromatogram: bool) -> bool {
+        match self.ms_level {
+            Some(filter) if !chromatogram => spectrum.ms_level != filter,
+            _ => false,
+        }
+    }
+
+    /// Parse all spectra from an mzML file into memory
     pub async fn parse<B: AsyncBufRead + Unpin>(
         &self,
         b: B,
     ) -> Result<Vec<RawSpectrum>, MzMLError> {
-        let mut reader = Reader::from_reader(b);
-        let mut buf = Vec::new();
+        let mut stream = self.stream(b);
+        let mut spectra = Vec::new();
+        while let Some(spectrum) = stream.next_spectrum().await? {
+            spectra.push(spectrum);
+        }
+        Ok(spectra)
+    }
 
-        let mut state = None;
-        let mut compression = false;
-        let mut output_buffer = Vec::with_capacity(4096);
-        let mut binary_dtype = Dtype::F64;
-        let mut binary_array = None;
+    /// Lazily parse spectra from an mzML file. Spectra are only decoded as
+    /// they are requested, so memory usage is bounded by the largest spectrum
+    /// rather than the size of the file.
+    pub fn stream<B: AsyncBufRead + Unpin>(&self, b: B) -> MzMLStream<B> {
+        let pb = progress_bar(1);
+        MzMLStream {
+            config: self.clone(),
+            reader: Reader::from_reader(b),
+            buf: Vec::new(),
+            state: None,
+            compression: false,
+            numpress: None,
+            binary_dtype: Dtype::F64,
+            binary_array: None,
+            time_scale: 1.0,
+            spectrum: RawSpectrum::default(),
+            precursor: Precursor::default(),
+            #[cfg(feature = "native")]
+            encoded: Vec::new(),
+            #[cfg(feature = "native")]
+            decoding: VecDeque::new(),
+            #[cfg(feature = "native")]
+            decoding_bytes: 0,
+            external: None,
+            external_array: None,
+            param_groups: HashMap::new(),
+            param_group: None,
+            chromatogram: None,
+            chromatograms: Vec::new(),
+            software: Vec::new(),
+            native_id_format: None,
+            metadata: RunMetadata::default(),
+            checksum: None,
+            pb,
+        }
+    }
+}
 
-        let mut spectrum = RawSpectrum::default();
-        let mut precursor = Precursor::default();
-        let mut spectra = Vec::new();
+/// A source of spectra that are read one at a time, such as an
+/// [`MzMLStream`]. The streaming writers accept any implementation
+pub trait SpectrumStream {
+    /// Returns the next spectrum, or `None` once all spectra have been read
+    fn next_spectrum(
+        &mut self,
+    ) -> impl std::future::Future<Output = Result<Option<RawSpectrum>, MzMLError>>;
+
+    /// Chromatograms read alongside the spectra, if the input has any. Only
+    /// complete once all spectra have been read
+    fn take_chromatograms(&mut self) -> Vec<Chromatogram> {
+        Vec::new()
+    }
+
+    /// Metadata describing the run, if the input has any. Complete once the
+    /// first spectrum has been read
+    fn run_metadata(&self) -> RunMetadata {
+        RunMetadata::default()
+    }
+}
+
+impl<B: AsyncBufRead + Unpin> SpectrumStream for MzMLStream<B> {
+    async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+        MzMLStream::next_spectrum(self).await
+    }
+
+    fn take_chromatograms(&mut self) -> Vec<Chromatogram> {
+        MzMLStream::take_chromatograms(self)
+    }
+
+    fn run_metadata(&self) -> RunMetadata {
+        self.metadata().clone()
+    }
+}
+
+/// Progress bar shown while spectra are read
+pub(crate) fn progress_bar(len: u64) -> ProgressBar {
+    crate::progress::progress_bar(len, "Reading mzML")
+}
+
+/// cvParams (accession, value and name) of a referenceableParamGroup
+type ParamGroup = Vec<(Vec<u8>, Option<String>, String)>;
+
+/// An in-progress parse of an mzML file, created by [`MzMLReader::stream`]
+pub struct MzMLStream<B> {
+    config: MzMLReader,
+    reader: Reader<B>,
+    buf: Vec<u8>,
+    state: Option<State>,
+    compression: bool,
+    numpress: Option<Numpress>,
+    binary_dtype: Dtype,
+    binary_array: Option<BinaryKind>,
+    /// Converts the time array of the current binaryDataArray to seconds
+    time_scale: f32,
+    spectrum: RawSpectrum,
+    precursor: Precursor,
+    /// Binary data arrays of the current spectrum, left to be decoded in
+    /// parallel
+    #[cfg(feature = "native")]
+    encoded: Vec<(BinaryKind, String, Encoding)>,
+    /// Spectra being decoded in parallel, in file order, with the size of
+    /// their encoded arrays
+    #[cfg(feature = "native")]
+    decoding: VecDeque<(usize, oneshot::Receiver<Result<RawSpectrum, MzMLError>>)>,
+    /// Total size of the encoded arrays of the spectra being decoded
+    #[cfg(feature = "native")]
+    decoding_bytes: usize,
+    external: Option<Box<dyn ExternalArrays>>,
+    external_array: Option<ExternalArray>,
+    param_groups: HashMap<Vec<u8>, ParamGroup>,
+    /// The referenceableParamGroup currently being read
+    param_group: Option<Vec<u8>>,
+    /// The chromatogram currently being read
+    chromatogram: Option<Chromatogram>,
+    chromatograms: Vec<Chromatogram>,
+    /// The `<softwareList>`, referenced by instrument configurations
+    software: Vec<Software>,
+    /// Native id format of the first source file with scan numbers
+    native_id_format: Option<NativeIdFormat>,
+    metadata: RunMetadata,
+    /// Checksum of the input, recorded in the metadata once it has been read
+    checksum: Option<Checksum>,
+    pb: ProgressBar,
+}
+
+impl<B: AsyncBufRead + Unpin> MzMLStream<B> {
+    /// Resolve binary data arrays that reference external datasets (mzMLb)
+    /// with `arrays`
+    pub fn set_external_arrays(&mut self, arrays: Box<dyn ExternalArrays>) -> &mut Self {
+        self.external = Some(arrays);
+        self
+    }
+
+    /// Don't report progress, for streams over part of a file
+    #[cfg(feature = "native")]
+    pub(crate) fn hide_progress(&mut self) -> &mut Self {
+        self.pb = ProgressBar::hidden();
+        self.config.progress = Callback::default();
+        self
+    }
+
+    /// Chromatograms parsed so far. mzML files list chromatograms after all
+    /// of the spectra, so they are only complete once the stream is exhausted
+    pub fn take_chromatograms(&mut self) -> Vec<Chromatogram> {
+        std::mem::take(&mut self.chromatograms)
+    }
+
+    /// Metadata from the header of the mzML file. The header precedes the
+    /// spectra, so this is complete once the first spectrum has been read
+    pub fn metadata(&self) -> &RunMetadata {
+        &self.metadata
+    }
+
+    /// Record `path` as the input of this stream in the run metadata, along
+    /// with the checksum of the document once it has been fully read
+    pub fn set_input(&mut self, path: String, checksum: Option<Checksum>) -> &mut Self {
+        self.metadata.input = Some(InputFile { path, sha1: None });
+        self.checksum = checksum;
+        self
+    }
+
+    /// Apply a cvParam of an instrumentConfiguration, given either directly or
+    /// through a referenceableParamGroup
+    fn instrument_param(&mut self, accession: &[u8], value: Option<&str>, name: &str) {
+        let Some(instrument) = self.metadata.instruments.last_mut() else {
+            return;
+        };
+        match accession {
+            INSTRUMENT_SERIAL_NUMBER => instrument.serial_number = value.map(String::from),
+            // Any other term names the instrument model, e.g. `Q Exactive`
+            _ if instrument.model.is_none() => {
+                instrument.model = Some(match value {
+                    Some(value) if !value.is_empty() => value.to_string(),
+                    _ => name.to_string(),
+                })
+            }
+            _ => {}
+        }
+    }
+
+    /// Store a decoded binary data array in the current spectrum or
+    /// chromatogram
+    fn store_array(&mut self, array: Vec<f64>) {
+        if let Some(chromatogram) = self.chromatogram.as_mut() {
+            match self.binary_array.take() {
+                Some(BinaryKind::Time) => {
+                    let scale = self.time_scale as f64;
+                    chromatogram.time = array.into_iter().map(|t| t * scale).collect()
+                }
+                Some(BinaryKind::Intensity | BinaryKind::Trace) => chromatogram.intensity = array,
+                _ => {}
+            }
+            return;
+        }
+        if let Some(kind) = self.binary_array.take() {
+            store_spectrum_array(&mut self.spectrum, kind, array);
+        }
+    }
+
+    /// Return a spectrum once all of its binary data arrays have been
+    /// decoded. With parallel decoding, the spectrum is queued and the
+    /// earliest queued spectrum is returned once enough are in flight, or
+    /// once they hold as much memory as the reader may read ahead
+    async fn finish_spectrum(
+        &mut self,
+        spectrum: RawSpectrum,
+    ) -> Result<Option<RawSpectrum>, MzMLError> {
+        #[cfg(feature = "native")]
+        if self.config.parallel_decoding {
+            let encoded = std::mem::take(&mut self.encoded);
+            let bytes = encoded.iter().map(|(_, text, _)| text.len()).sum::<usize>();
+            let config = self.config.clone();
+            let (tx, rx) = oneshot::channel();
+            rayon::spawn(move || {
+                let mut spectrum = spectrum;
+                let result = encoded
+                    .into_iter()
+                    .try_for_each(|(kind, text, encoding)| {
+                        let array = decode_binary(text.as_bytes(), encoding)?;
+                        store_spectrum_array(&mut spectrum, kind, array);
+                        Ok(())
+                    })
+                    .map(|()| config.finish(spectrum));
+                let _ = tx.send(result);
+            });
+            self.decoding.push_back((bytes, rx));
+            self.decoding_bytes += bytes;
+            let full = self.decoding.len() >= 2 * rayon::current_num_threads()
+                || self
+                    .config
+                    .read_ahead
+                    .is_some_and(|max| self.decoding_bytes > max);
+            if !full {
+                return Ok(None);
+            }
+            return self.next_decoded().await;
+        }
+        Ok(Some(self.config.finish(spectrum)))
+    }
+
+    /// Wait for the earliest spectrum being decoded in parallel
+    #[cfg(feature = "native")]
+    async fn next_decoded(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+        match self.decoding.pop_front() {
+            Some((bytes, rx)) => {
+                self.decoding_bytes -= bytes;
+                rx.await
+                    .map_err(|_| {
+                        MzMLError::InputError("binary data array decoder panicked".into())
+                    })?
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Apply a cvParam of a binaryDataArray, given either directly or through
+    /// a referenceableParamGroup
+    fn binary_param(&mut self, accession: &[u8], value: Option<&str>) -> Result<(), MzMLError> {
+        let value = || value.ok_or(MzMLError::Malformed);
+        match accession {
+            ZLIB_COMPRESSION => self.compression = true,
+            NO_COMPRESSION => self.compression = false,
+            NUMPRESS_LINEAR | NUMPRESS_PIC | NUMPRESS_SLOF => {
+                self.compression = false;
+                self.numpress = numpress(accession);
+            }
+            NUMPRESS_LINEAR_ZLIB | NUMPRESS_PIC_ZLIB | NUMPRESS_SLOF_ZLIB => {
+                self.compression = true;
+                self.numpress = numpress(accession);
+            }
+            FLOAT_64 => self.binary_dtype = Dtype::F64,
+            FLOAT_32 => self.binary_dtype = Dtype::F32,
+            INTENSITY_ARRAY => self.binary_array = Some(BinaryKind::Intensity),
+            MZ_ARRAY => self.binary_array = Some(BinaryKind::Mz),
+            NOISE_ARRAY => self.binary_array = Some(BinaryKind::Noise),
+            BASELINE_ARRAY => self.binary_array = Some(BinaryKind::Baseline),
+            CHARGE_ARRAY => self.binary_array = Some(BinaryKind::Charge),
+            TIME_ARRAY => self.binary_array = Some(BinaryKind::Time),
+            kind if ION_MOBILITY_ARRAYS.contains(&kind) => {
+                self.binary_array = Some(BinaryKind::IonMobility)
+            }
+            kind if TRACE_ARRAYS.contains(&kind) => self.binary_array = Some(BinaryKind::Trace),
+            EXTERNAL_DATASET => {
+                self.external_array
+                    .get_or_insert_with(Default::default)
+                    .dataset = value()?.to_string()
+            }
+            EXTERNAL_OFFSET | IBD_OFFSET => {
+                self.external_array
+                    .get_or_insert_with(Default::default)
+                    .offset = value()?.parse()?
+            }
+            EXTERNAL_LENGTH | IBD_LENGTH => {
+                self.external_array
+                    .get_or_insert_with(Default::default)
+                    .length = value()?.parse()?
+            }
+            // Implied by the length and data type
+            IBD_ENCODED_LENGTH => {}
+            _ => {
+                // Unknown CV - perhaps noise
+                self.binary_array = None;
+            }
+        }
+        Ok(())
+    }
 
-        let mut noise_array = Vec::new();
+    /// Read a binary data array stored in an external dataset
+    fn read_external(&mut self, mut location: ExternalArray) -> Result<(), MzMLError> {
+        if self
+            .config
+            .skip_arrays(&self.spectrum, self.chromatogram.is_some())
+        {
+            return Ok(());
+        }
+        if self.binary_array.is_none() || location.length == 0 {
+            return Ok(());
+        }
+        let external = self.external.as_mut().ok_or_else(|| {
+            MzMLError::ExternalArrayError(format!(
+                "binary data array `{}` is stored outside of the mzML document",
+                location.dataset
+            ))
+        })?;
+        if self.numpress.is_some() {
+            return Err(MzMLError::ExternalArrayError(
+                "MS-Numpress compressed external arrays are not supported".into(),
+            ));
+        }
+        location.dtype = self.binary_dtype;
+        let array = external.read(&location)?;
+        self.store_array(array);
+        Ok(())
+    }
 
+    /// Here be dragons -
+    /// Seriously, this kinda sucks because it's a giant imperative, stateful loop.
+    /// But I also don't want to spend any more time working on an mzML parser...
+    ///
+    /// Returns the next spectrum in the file, or `None` once the end of the
+    /// file has been reached
+    pub async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
         macro_rules! extract {
             ($ev:expr, $key:expr) => {
                 $ev.try_get_attribute($key)?
@@ -161,62 +887,260 @@ impl MzMLReader {
             }};
         }
 
-        let pb = indicatif::ProgressBar::new(1)
-            .with_message("Reading mzML")
-            .with_style(
-                ProgressStyle::default_bar()
-                    .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-                    .unwrap(),
-            );
+        macro_rules! extract_string {
+            ($ev:expr) => {
+                $ev.try_get_attribute(b"value")?
+                    .ok_or(MzMLError::Malformed)?
+                    .unescape_value()?
+                    .into_owned()
+            };
+        }
 
+        let mut emit = None;
         loop {
-            match reader.read_event_into_async(&mut buf).await {
+            match self.reader.read_event_into_async(&mut self.buf).await {
                 Ok(Event::Start(ref ev)) => {
                     // State transition into child tag
-                    state = match (ev.name().into_inner(), state) {
+                    self.state = match (ev.name().into_inner(), self.state) {
                         (b"spectrumList", _) => {
                             let ex = extract!(ev, b"count");
                             let count = std::str::from_utf8(&ex)?.parse::<u64>()?;
-                            pb.set_length(count);
+                            self.pb.set_length(count);
                             None
                         }
                         (b"spectrum", _) => Some(State::Spectrum),
                         (b"scan", Some(State::Spectrum)) => Some(State::Scan),
-                        (b"binaryDataArray", Some(State::Spectrum)) => Some(State::BinaryDataArray),
+                        (b"chromatogram", _) => Some(State::Chromatogram),
+                        (b"binaryDataArray", Some(State::Spectrum | State::Chromatogram)) => {
+                            Some(State::BinaryDataArray)
+                        }
                         (b"binary", Some(State::BinaryDataArray)) => Some(State::Binary),
-                        (b"precursor", Some(State::Spectrum)) => Some(State::Precursor),
+                        (b"precursor", Some(State::Spectrum | State::Chromatogram)) => {
+                            Some(State::Precursor)
+                        }
+                        (b"product", Some(State::Chromatogram)) => Some(State::Product),
                         (b"selectedIon", Some(State::Precursor)) => Some(State::SelectedIon),
-                        _ => state,
+                        (b"software", None) => Some(State::Software),
+                        (b"instrumentConfiguration", None) => Some(State::Instrument),
+                        (b"sourceFile", None) => Some(State::SourceFile),
+                        (b"source" | b"analyzer" | b"detector", Some(State::Instrument)) => {
+                            Some(State::Component)
+                        }
+                        _ => self.state,
                     };
                     match ev.name().into_inner() {
+                        b"referenceableParamGroup" => {
+                            self.param_group = Some(extract!(ev, b"id").to_vec());
+                        }
+                        b"binaryDataArray" => {
+                            self.numpress = None;
+                            self.time_scale = 1.0;
+                        }
                         b"spectrum" => {
                             let id = extract!(ev, b"id");
-                            spectrum.id = id.to_vec();
+                            self.spectrum.scan_number = self
+                                .native_id_format
+                                .and_then(|format| format.scan_number(&id));
+                            self.spectrum.id = id.to_vec();
+                        }
+                        b"run" => {
+                            if let Some(start) = ev.try_get_attribute(b"startTimeStamp")? {
+                                self.metadata.start_timestamp =
+                                    Some(start.unescape_value()?.into_owned());
+                            }
+                        }
+                        b"software" => {
+                            let version = ev
+                                .try_get_attribute(b"version")?
+                                .map(|attr| attr.unescape_value().map(|v| v.into_owned()))
+                                .transpose()?;
+                            self.software.push(Software {
+                                id: String::from_utf8_lossy(&extract!(ev, b"id")).into_owned(),
+                                name: None,
+                                version,
+                            });
+                        }
+                        b"sourceFile" if self.state == Some(State::SourceFile) => {
+                            let attr = |key: &[u8]| -> Result<String, MzMLError> {
+                                Ok(ev
+                                    .try_get_attribute(key)?
+                                    .map(|attr| attr.unescape_value().map(|v| v.into_owned()))
+                                    .transpose()?
+                                    .unwrap_or_default())
+                            };
+                            self.metadata.source_files.push(SourceFile {
+                                id: attr(b"id")?,
+                                name: attr(b"name")?,
+                                location: attr(b"location")?,
+                                sha1: None,
+                            });
+                        }
+                        b"instrumentConfiguration" => {
+                            self.metadata.instruments.push(InstrumentConfiguration {
+                                id: String::from_utf8_lossy(&extract!(ev, b"id")).into_owned(),
+                                ..Default::default()
+                            });
+                        }
+                        kind @ (b"source" | b"analyzer" | b"detector")
+                            if self.state == Some(State::Component) =>
+                        {
+                            let order = match ev.try_get_attribute(b"order")? {
+                                Some(order) => Some(std::str::from_utf8(&order.value)?.parse()?),
+                                None => None,
+                            };
+                            if let Some(instrument) = self.metadata.instruments.last_mut() {
+                                instrument.components.push(Component {
+                                    kind: String::from_utf8_lossy(kind).into_owned(),
+                                    order,
+                                    names: Vec::new(),
+                                });
+                            }
+                        }
+                        b"chromatogram" => {
+                            self.chromatogram = Some(Chromatogram {
+                                id: extract!(ev, b"id").to_vec(),
+                                ..Default::default()
+                            });
                         }
                         b"precursor" => {
                             // Not all precursor fields have a spectrumRef
                             if let Some(scan) = ev.try_get_attribute(b"spectrumRef")? {
                                 // let scan = std::str::from_utf8(&scan.value)?;
-                                precursor.spectrum_ref = Some(scan.value.to_vec())
+                                self.precursor.spectrum_ref = Some(scan.value.to_vec())
                             }
                         }
                         _ => {}
                     }
                 }
-                Ok(Event::Empty(ref ev)) => match (state, ev.name().into_inner()) {
+                Ok(Event::Empty(ref ev)) => match (self.state, ev.name().into_inner()) {
                     (Some(State::BinaryDataArray), b"cvParam") => {
+                        let accession = extract!(ev, b"accession").into_owned();
+                        let value = ev
+                            .try_get_attribute(b"value")?
+                            .map(|attr| attr.unescape_value().map(|v| v.into_owned()))
+                            .transpose()?;
+                        if accession == TIME_ARRAY {
+                            self.time_scale = time_scale(ev)?;
+                        }
+                        self.binary_param(&accession, value.as_deref())?;
+                    }
+                    (Some(State::BinaryDataArray), b"referenceableParamGroupRef") => {
+                        let group = extract!(ev, b"ref");
+                        let params = self.param_groups.get(group.as_ref()).cloned();
+                        for (accession, value, _) in params.into_iter().flatten() {
+                            self.binary_param(&accession, value.as_deref())?;
+                        }
+                    }
+                    (Some(State::Spectrum), b"referenceableParamGroupRef") => {
+                        // imzML files describe spectra through shared groups
+                        let group = extract!(ev, b"ref");
+                        for (accession, value, _) in
+                            self.param_groups.get(group.as_ref()).into_iter().flatten()
+                        {
+                            match (accession.as_slice(), value) {
+                                (MS_LEVEL, Some(level)) => {
+                                    self.spectrum.ms_level = level.parse()?
+                                }
+                                (PROFILE, _) => self.spectrum.centroid = false,
+                                (CENTROID, _) => self.spectrum.centroid = true,
+                                (POSITIVE_SCAN, _) => {
+                                    self.spectrum.polarity = Some(Polarity::Positive)
+                                }
+                                (NEGATIVE_SCAN, _) => {
+                                    self.spectrum.polarity = Some(Polarity::Negative)
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    (None, b"cvParam") if self.param_group.is_some() => {
+                        let accession = extract!(ev, b"accession").to_vec();
+                        let value = ev
+                            .try_get_attribute(b"value")?
+                            .map(|attr| attr.unescape_value().map(|v| v.into_owned()))
+                            .transpose()?;
+                        let name = ev
+                            .try_get_attribute(b"name")?
+                            .map(|attr| attr.unescape_value().map(|v| v.into_owned()))
+                            .transpose()?
+                            .unwrap_or_default();
+                        let group = self.param_group.clone().unwrap_or_default();
+                        self.param_groups
+                            .entry(group)
+                            .or_default()
+                            .push((accession, value, name));
+                    }
+                    (Some(State::Chromatogram), b"cvParam") => {
                         let accession = extract!(ev, b"accession");
-                        match accession.as_ref() {
-                            ZLIB_COMPRESSION => compression = true,
-                            NO_COMPRESSION => compression = false,
-                            FLOAT_64 => binary_dtype = Dtype::F64,
-                            FLOAT_32 => binary_dtype = Dtype::F32,
-                            INTENSITY_ARRAY => binary_array = Some(BinaryKind::Intensity),
-                            MZ_ARRAY => binary_array = Some(BinaryKind::Mz),
-                            NOISE_ARRAY => binary_array = Some(BinaryKind::Noise),
-                            _ => {
-                                // Unknown CV - perhaps noise
-                                binary_array = None;
+                        if let Some((_, kind)) = CHROMATOGRAM_TYPES
+                            .iter()
+                            .find(|(acc, _)| *acc == accession.as_ref())
+                        {
+                            if let Some(chromatogram) = self.chromatogram.as_mut() {
+                                chromatogram.kind = Some(*kind);
+                            }
+                        }
+                    }
+                    (Some(State::Software), b"cvParam" | b"userParam") => {
+                        let name = extract!(ev, b"name");
+                        if let Some(software) = self.software.last_mut() {
+                            software
+                                .name
+                                .get_or_insert_with(|| String::from_utf8_lossy(&name).into_owned());
+                        }
+                    }
+                    (Some(State::SourceFile), b"cvParam") => {
+                        let accession = extract!(ev, b"accession");
+                        if accession.as_ref() == SHA1 {
+                            if let Some(source_file) = self.metadata.source_files.last_mut() {
+                                source_file.sha1 = Some(extract_string!(ev));
+                            }
+                        } else if self.native_id_format.is_none() {
+                            self.native_id_format = NativeIdFormat::from_accession(&accession);
+                        }
+                    }
+                    (Some(State::Instrument), b"cvParam") => {
+                        let accession = extract!(ev, b"accession").into_owned();
+                        let name = String::from_utf8_lossy(&extract!(ev, b"name")).into_owned();
+                        let value = ev
+                            .try_get_attribute(b"value")?
+                            .map(|attr| attr.unescape_value().map(|v| v.into_owned()))
+                            .transpose()?;
+                        self.instrument_param(&accession, value.as_deref(), &name);
+                    }
+                    (Some(State::Instrument), b"referenceableParamGroupRef") => {
+                        let group = extract!(ev, b"ref");
+                        let params = self.param_groups.get(group.as_ref()).cloned();
+                        for (accession, value, name) in params.into_iter().flatten() {
+                            self.instrument_param(&accession, value.as_deref(), &name);
+                        }
+                    }
+                    (Some(State::Instrument), b"softwareRef") => {
+                        let id = String::from_utf8_lossy(&extract!(ev, b"ref")).into_owned();
+                        let software = self.software.iter().find(|s| s.id == id).cloned();
+                        if let Some(instrument) = self.metadata.instruments.last_mut() {
+                            instrument.software = Some(software.unwrap_or(Software {
+                                id,
+                                ..Default::default()
+                            }));
+                        }
+                    }
+                    (Some(State::Component), b"cvParam") => {
+                        let name = String::from_utf8_lossy(&extract!(ev, b"name")).into_owned();
+                        let component = self
+                            .metadata
+                            .instruments
+                            .last_mut()
+                            .and_then(|instrument| instrument.components.last_mut());
+                        if let Some(component) = component {
+                            component.names.push(name);
+                        }
+                    }
+                    (Some(State::Product), b"cvParam") => {
+                        let accession = extract!(ev, b"accession");
+                        if accession.as_ref() == ISO_WINDOW_TARGET {
+                            if let Some(chromatogram) = self.chromatogram.as_mut() {
+                                chromatogram.product_mz = Some(extract_value!(ev));
                             }
                         }
                     }
@@ -225,26 +1149,34 @@ impl MzMLReader {
                         match accession.as_ref() {
                             MS_LEVEL => {
                                 let level = extract_value!(ev);
-                                if let Some(filter) = self.ms_level {
+                                if let Some(filter) = self.config.ms_level {
                                     if level != filter {
-                                        spectrum = RawSpectrum::default();
-                                        state = None;
+                                        self.spectrum = RawSpectrum::default();
+                                        self.state = None;
                                     }
                                 }
-                                spectrum.ms_level = level;
+                                self.spectrum.ms_level = level;
+                            }
+                            FILTER_STRING => {
+                                self.spectrum.filter_string = Some(extract_string!(ev));
                             }
-                            PROFILE => spectrum.centroid = false,
-                            CENTROID => spectrum.centroid = true,
+                            PROFILE => self.spectrum.centroid = false,
+                            CENTROID => self.spectrum.centroid = true,
+                            POSITIVE_SCAN => self.spectrum.polarity = Some(Polarity::Positive),
+                            NEGATIVE_SCAN => self.spectrum.polarity = Some(Polarity::Negative),
                             TOTAL_ION_CURRENT => {
                                 let value = extract_value!(ev);
                                 if value == 0.0 {
                                     // No ion current, break out of current state
-                                    spectrum = RawSpectrum::default();
-                                    state = None;
+                                    self.spectrum = RawSpectrum::default();
+                                    self.state = None;
                                 } else {
-                                    spectrum.total_ion_current = value;
+                                    self.spectrum.total_ion_current = value;
                                 }
                             }
+                            _ if self.config.extra_params => {
+                                self.spectrum.extra_params.push(extra_param(ev)?);
+                            }
                             _ => {}
                         }
                     }
@@ -252,14 +1184,21 @@ impl MzMLReader {
                         let accession = extract!(ev, b"accession");
                         match accession.as_ref() {
                             ISO_WINDOW_LOWER => {
-                                precursor.isolation_window_lower = Some(extract_value!(ev))
+                                self.precursor.isolation_window_lower = Some(extract_value!(ev))
                             }
                             ISO_WINDOW_UPPER => {
-                                precursor.isolation_window_upper = Some(extract_value!(ev))
-                            }
-                            ISO_WINDOW_TARGET => {
-                                precursor.isolation_window_target = Some(extract_value!(ev))
+                                self.precursor.isolation_window_upper = Some(extract_value!(ev))
                             }
+                            ISO_WINDOW_TARGET => match self.chromatogram.as_mut() {
+                                // Transitions are identified by their full precision m/z
+                                Some(chromatogram) => {
+                                    chromatogram.precursor_mz = Some(extract_value!(ev))
+                                }
+                                None => {
+                                    self.precursor.isolation_window_target =
+                                        Some(extract_value!(ev))
+                                }
+                            },
                             _ => {}
                         }
                     }
@@ -267,13 +1206,13 @@ impl MzMLReader {
                         let accession = extract!(ev, b"accession");
                         match accession.as_ref() {
                             SELECTED_ION_CHARGE => {
-                                precursor.charge = Some(extract_value!(ev));
+                                self.precursor.charge = Some(extract_value!(ev));
                             }
                             SELECTED_ION_MZ => {
-                                precursor.mz = extract_value!(ev);
+                                self.precursor.mz = extract_value!(ev);
                             }
                             SELECTED_ION_INT => {
-                                precursor.intensity = Some(extract_value!(ev));
+                                self.precursor.intensity = Some(extract_value!(ev));
                             }
                             _ => {}
                         }
@@ -282,142 +1221,178 @@ impl MzMLReader {
                         let accession = extract!(ev, b"accession");
                         match accession.as_ref() {
                             SCAN_START_TIME => {
-                                spectrum.scan_start_time = extract_value!(ev);
+                                let time: f32 = extract_value!(ev);
+                                self.spectrum.scan_start_time = time * time_scale(ev)?;
                             }
                             ION_INJECTION_TIME => {
-                                spectrum.ion_injection_time = extract_value!(ev);
+                                self.spectrum.ion_injection_time = extract_value!(ev);
+                            }
+                            FILTER_STRING => {
+                                self.spectrum.filter_string = Some(extract_string!(ev));
+                            }
+                            DRIFT_TIME | INVERSE_REDUCED_ION_MOBILITY => {
+                                self.spectrum.inverse_ion_mobility = Some(extract_value!(ev));
+                            }
+                            POSITION_X => {
+                                self.spectrum.pixel.get_or_insert_with(Default::default).x =
+                                    extract_value!(ev);
+                            }
+                            POSITION_Y => {
+                                self.spectrum.pixel.get_or_insert_with(Default::default).y =
+                                    extract_value!(ev);
+                            }
+                            POSITION_Z => {
+                                self.spectrum.pixel.get_or_insert_with(Default::default).z =
+                                    Some(extract_value!(ev));
+                            }
+                            _ if self.config.extra_params => {
+                                self.spectrum.extra_params.push(extra_param(ev)?);
                             }
                             _ => {}
                         }
                     }
+                    (Some(State::Spectrum | State::Scan), b"userParam") => {
+                        let name = extract!(ev, b"name");
+                        if FILTER_STRING_PARAMS.contains(&name.as_ref()) {
+                            if self.spectrum.filter_string.is_none() {
+                                self.spectrum.filter_string = Some(extract_string!(ev));
+                            }
+                        } else if self.config.extra_params {
+                            self.spectrum.extra_params.push(extra_param(ev)?);
+                        }
+                    }
 
                     _ => {}
                 },
                 Ok(Event::Text(text)) => {
-                    if let Some(State::Binary) = state {
-                        if let Some(filter) = self.ms_level {
-                            if spectrum.ms_level != filter {
-                                continue;
-                            }
+                    if let Some(State::Binary) = self.state {
+                        if self
+                            .config
+                            .skip_arrays(&self.spectrum, self.chromatogram.is_some())
+                        {
+                            continue;
                         }
                         let raw = text.unescape()?;
                         // There are occasionally empty binary data arrays, or unknown CVs
-                        if raw.is_empty() || binary_array.is_none() {
+                        if raw.is_empty() || self.binary_array.is_none() {
                             continue;
                         }
-                        let decoded = base64::decode(raw.as_bytes())?;
-                        let bytes = match compression {
-                            false => &decoded,
-                            true => {
-                                let mut r = ZlibDecoder::new(decoded.as_slice());
-                                let n = r.read_to_end(&mut output_buffer).await?;
-                                &output_buffer[..n]
-                            }
+                        let encoding = Encoding {
+                            compression: self.compression,
+                            numpress: self.numpress,
+                            dtype: self.binary_dtype,
                         };
-
-                        let array = match binary_dtype {
-                            Dtype::F32 => {
-                                let mut buf: [u8; 4] = [0; 4];
-                                bytes
-                                    .chunks(4)
-                                    .filter(|chunk| chunk.len() == 4)
-                                    .map(|chunk| {
-                                        buf.copy_from_slice(chunk);
-                                        f32::from_le_bytes(buf)
-                                    })
-                                    .collect::<Vec<f32>>()
-                            }
-                            Dtype::F64 => {
-                                let mut buf: [u8; 8] = [0; 8];
-                                bytes
-                                    .chunks(8)
-                                    .map(|chunk| {
-                                        buf.copy_from_slice(chunk);
-                                        f64::from_le_bytes(buf) as f32
-                                    })
-                                    .collect::<Vec<f32>>()
+                        #[cfg(feature = "native")]
+                        if self.config.parallel_decoding && self.chromatogram.is_none() {
+                            if let Some(kind) = self.binary_array.take() {
+                                self.encoded.push((kind, raw.into_owned(), encoding));
                             }
-                        };
-                        output_buffer.clear();
-
-                        match binary_array {
-                            Some(BinaryKind::Intensity) => {
-                                spectrum.intensity = array;
-                            }
-                            Some(BinaryKind::Mz) => {
-                                spectrum.mz = array;
-                            }
-                            Some(BinaryKind::Noise) => {
-                                noise_array = array;
-                            }
-                            None => {}
+                            self.buf.clear();
+                            continue;
                         }
-
-                        binary_array = None;
+                        let array = decode_binary(raw.as_bytes(), encoding)?;
+                        self.store_array(array);
                     }
                 }
                 Ok(Event::End(ev)) => {
-                    state = match (state, ev.name().into_inner()) {
+                    self.state = match (self.state, ev.name().into_inner()) {
                         (Some(State::Binary), b"binary") => Some(State::BinaryDataArray),
-                        (Some(State::BinaryDataArray), b"binaryDataArray") => Some(State::Spectrum),
+                        (Some(State::BinaryDataArray), b"binaryDataArray") => {
+                            if let Some(location) = self.external_array.take() {
+                                self.read_external(location)?;
+                            }
+                            match self.chromatogram {
+                                Some(_) => Some(State::Chromatogram),
+                                None => Some(State::Spectrum),
+                            }
+                        }
                         (Some(State::SelectedIon), b"selectedIon") => Some(State::Precursor),
                         (Some(State::Precursor), b"precursor") => {
-                            if precursor.mz == 0.0 {
-                                precursor.mz =
-                                    precursor.isolation_window_target.unwrap_or_default();
+                            if self.precursor.mz == 0.0 {
+                                self.precursor.mz = self
+                                    .precursor
+                                    .isolation_window_target
+                                    .map(f64::from)
+                                    .unwrap_or_default();
+                            }
+                            let precursor = std::mem::take(&mut self.precursor);
+                            if let Some(chromatogram) = self.chromatogram.as_mut() {
+                                if precursor.mz != 0.0 {
+                                    chromatogram.precursor_mz = Some(precursor.mz);
+                                }
+                                Some(State::Chromatogram)
+                            } else {
+                                if precursor.mz != 0.0 {
+                                    self.spectrum.precursors.push(precursor);
+                                }
+                                Some(State::Spectrum)
                             }
-                            if precursor.mz != 0.0 {
-                                spectrum.precursors.push(precursor);
-                                precursor = Precursor::default();
+                        }
+                        (Some(State::Product), b"product") => Some(State::Chromatogram),
+                        (Some(State::Software), b"software") => None,
+                        (Some(State::Instrument), b"instrumentConfiguration") => None,
+                        (Some(State::SourceFile), b"sourceFile") => None,
+                        (Some(State::Component), b"source" | b"analyzer" | b"detector") => {
+                            Some(State::Instrument)
+                        }
+                        (_, b"chromatogram") => {
+                            if let Some(chromatogram) = self.chromatogram.take() {
+                                self.chromatograms.push(chromatogram);
                             }
-                            Some(State::Spectrum)
+                            None
                         }
                         (Some(State::Scan), b"scan") => Some(State::Spectrum),
+                        (_, b"referenceableParamGroup") => {
+                            self.param_group = None;
+                            self.state
+                        }
                         (_, b"spectrum") => {
                             let allow = self
+                                .config
                                 .ms_level
                                 .as_ref()
-                                .map(|&level| level == spectrum.ms_level)
+                                .map(|&level| level == self.spectrum.ms_level)
                                 .unwrap_or(true);
 
-                            if spectrum.ms_level == 0 {
+                            if self.spectrum.ms_level == 0 {
                                 continue;
                             }
 
-                            match (allow, self.signal_to_noise) {
-                                (true, Some(level))
-                                    if level == spectrum.ms_level && !noise_array.is_empty() =>
-                                {
-                                    spectrum
-                                        .intensity
-                                        .iter_mut()
-                                        .zip(noise_array.iter())
-                                        .for_each(|(int, noise)| *int /= noise);
-                                    noise_array.clear();
-                                    spectra.push(spectrum);
-                                }
-                                (true, _) => {
-                                    spectra.push(spectrum);
-                                }
-                                (false, _) => {}
+                            let spectrum = std::mem::take(&mut self.spectrum);
+                            self.pb.inc(1);
+                            self.config.progress.spectra_parsed(1);
+                            if allow {
+                                emit = self.finish_spectrum(spectrum).await?;
                             }
-                            spectrum = RawSpectrum::default();
-                            pb.inc(1);
 
                             None
                         }
-                        _ => state,
+                        _ => self.state,
                     };
                 }
-                Ok(Event::Eof) => break,
-                Ok(_) => {}
-                Err(err) => {
-                    log::error!("unhandled XML error while parsing mzML: {}", err)
+                Ok(Event::Eof) => {
+                    #[cfg(feature = "native")]
+                    if !self.decoding.is_empty() {
+                        return self.next_decoded().await;
+                    }
+                    if let (Some(input), Some(checksum)) =
+                        (self.metadata.input.as_mut(), &self.checksum)
+                    {
+                        input.sha1 = Some(checksum.hex());
+                    }
+                    self.pb.finish();
+                    return Ok(None);
                 }
+                Ok(_) => {}
+                // Includes I/O errors, e.g. from a corrupt gzip member or a
+                // dropped connection, which must not end the file early
+                Err(err) => return Err(err.into()),
+            }
+            self.buf.clear();
+            if emit.is_some() {
+                return Ok(emit);
             }
-            buf.clear();
         }
-        Ok(spectra)
     }
 }
 
@@ -439,13 +1414,20 @@ pub enum MzMLError {
     IntError(#[from] std::num::ParseIntError),
     #[error("error decoding base64: {0}")]
     Base64Error(#[from] base64::DecodeError),
+    #[error("error reading external binary data array: {0}")]
+    ExternalArrayError(String),
+    #[error("error reading input: {0}")]
+    InputError(String),
+    #[error("error decoding MS-Numpress array: {0}")]
+    NumpressError(String),
 }
 
 #[cfg(test)]
 mod test {
-    use super::{MzMLError, MzMLReader};
+    use super::{ExternalArray, ExternalArrays, MzMLError, MzMLReader, Polarity};
 
     #[tokio::test]
+    #[allow(clippy::excessive_precision)]
     async fn parse_spectrum_issue_78() -> Result<(), MzMLError> {
         let s = r#"
         <spectrum id="spectrum=2442" index="286" defaultArrayLength="102" dataProcessingRef="dp_sp_1">
@@ -515,7 +1497,8 @@ mod test {
 
         assert_eq!(s.id, b"spectrum=2442");
         assert_eq!(s.ms_level, 2);
-        assert_eq!(s.centroid, true);
+        assert!(s.centroid);
+        assert_eq!(s.polarity, Some(Polarity::Positive));
         assert_eq!(s.precursors.len(), 1);
         assert_eq!(s.precursors[0].charge, Some(2));
         assert!((s.precursors[0].mz - 457.723968) < 0.0001);
@@ -523,7 +1506,330 @@ mod test {
         assert_eq!(s.precursors[0].isolation_window_lower, Some(1.5),);
         assert!((s.scan_start_time - 1503.96166992188) < 0.0001);
         assert_eq!(s.ion_injection_time, 0.0);
+        assert_eq!(
+            s.filter_string.as_deref(),
+            Some("ITMS + c NSI d w Full ms2 457.72@cid35.00 [115.00-930.00]")
+        );
         assert_eq!(s.intensity.len(), s.mz.len());
         Ok(())
     }
+
+    struct Arrays;
+
+    impl ExternalArrays for Arrays {
+        fn read(&mut self, array: &ExternalArray) -> Result<Vec<f64>, MzMLError> {
+            let scale = match array.dataset.as_str() {
+                "spectrum_MS_1000514_float64" => 100.0,
+                _ => 1.0,
+            };
+            Ok((array.offset..array.offset + array.length)
+                .map(|i| i as f64 * scale)
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_external_arrays() -> Result<(), MzMLError> {
+        let s = r#"
+        <spectrum id="scan=1" index="0" defaultArrayLength="3">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <binaryDataArrayList count="2">
+                <binaryDataArray encodedLength="0">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" />
+                    <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <cvParam cvRef="MS" accession="MS:1002841" name="external HDF5 dataset" value="spectrum_MS_1000514_float64" />
+                    <cvParam cvRef="MS" accession="MS:1002842" name="external offset" value="4" />
+                    <cvParam cvRef="MS" accession="MS:1002843" name="external array length" value="3" />
+                    <binary />
+                </binaryDataArray>
+                <binaryDataArray encodedLength="0">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <cvParam cvRef="MS" accession="MS:1002841" name="external HDF5 dataset" value="spectrum_MS_1000515_float32" />
+                    <cvParam cvRef="MS" accession="MS:1002842" name="external offset" value="0" />
+                    <cvParam cvRef="MS" accession="MS:1002843" name="external array length" value="3" />
+                    <binary />
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>
+        "#;
+
+        let reader = MzMLReader::default();
+        let mut stream = reader.stream(s.as_bytes());
+        stream.set_external_arrays(Box::new(Arrays));
+        let spectrum = stream.next_spectrum().await?.expect("one spectrum");
+        assert_eq!(spectrum.mz, vec![400.0, 500.0, 600.0]);
+        assert_eq!(spectrum.intensity, vec![0.0, 1.0, 2.0]);
+
+        // Without a source for the arrays, the spectrum cannot be read
+        assert!(reader.parse(s.as_bytes()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_drift_times() -> Result<(), MzMLError> {
+        // Two drift scans of a Waters acquisition, combined by msconvert
+        let s = r#"
+        <spectrum id="function=1 process=0 scan=1" index="0" defaultArrayLength="2">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <cvParam cvRef="MS" accession="MS:1000127" name="centroid spectrum" />
+            <scanList count="1">
+                <scan>
+                    <cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="0.5" unitAccession="UO:0000031" unitName="minute" unitCvRef="UO" />
+                </scan>
+            </scanList>
+            <binaryDataArrayList count="3">
+                <binaryDataArray encodedLength="24">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" />
+                    <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AAAAAAAAWUAAAAAAAABpQA==</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AACAPwAAAEA=</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1002477" name="mean drift time array" unitAccession="UO:0000028" unitName="millisecond" unitCvRef="UO" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AABgQAAAiEA=</binary>
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>
+        <spectrum id="function=1 process=0 scan=2" index="1" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <scanList count="1">
+                <scan>
+                    <cvParam cvRef="MS" accession="MS:1002476" name="ion mobility drift time" value="3.25" unitAccession="UO:0000028" unitName="millisecond" unitCvRef="UO" />
+                </scan>
+            </scanList>
+        </spectrum>
+        "#;
+        let spectra = MzMLReader::default().parse(s.as_bytes()).await?;
+        assert_eq!(spectra.len(), 2);
+        assert_eq!(spectra[0].mz, vec![100.0, 200.0]);
+        assert_eq!(spectra[0].intensity, vec![1.0, 2.0]);
+        assert_eq!(spectra[0].ion_mobility, vec![3.5, 4.25]);
+        assert_eq!(spectra[0].inverse_ion_mobility, None);
+        assert_eq!(spectra[1].inverse_ion_mobility, Some(3.25));
+        assert!(spectra[1].ion_mobility.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_filter_strings() -> Result<(), MzMLError> {
+        let s = r#"
+        <spectrum id="scan=1" index="0" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <scanList count="1">
+                <scan>
+                    <cvParam cvRef="MS" accession="MS:1000512" name="filter string" value="FTMS + p NSI Full ms [350.00-1500.00]" />
+                </scan>
+            </scanList>
+        </spectrum>
+        <spectrum id="scan=2" index="1" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <userParam name="scan description" type="xsd:string" value="TOF MS ^ 100-1500" />
+        </spectrum>
+        <spectrum id="scan=3" index="2" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <userParam name="scan description" type="xsd:string" value="generic" />
+            <scanList count="1">
+                <scan>
+                    <cvParam cvRef="MS" accession="MS:1000512" name="filter string" value="ITMS + c NSI Full ms" />
+                </scan>
+            </scanList>
+        </spectrum>
+        <spectrum id="scan=4" index="3" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+        </spectrum>
+        "#;
+        let spectra = MzMLReader::default().parse(s.as_bytes()).await?;
+        let filters = spectra
+            .iter()
+            .map(|s| s.filter_string.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            filters,
+            vec![
+                Some("FTMS + p NSI Full ms [350.00-1500.00]"),
+                Some("TOF MS ^ 100-1500"),
+                // The filter string cvParam is preferred to a scan description
+                Some("ITMS + c NSI Full ms"),
+                None,
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_polarity() -> Result<(), MzMLError> {
+        let s = r#"<mzML>
+        <referenceableParamGroupList count="1">
+            <referenceableParamGroup id="negative">
+                <cvParam cvRef="MS" accession="MS:1000129" name="negative scan" />
+            </referenceableParamGroup>
+        </referenceableParamGroupList>
+        <run id="run"><spectrumList count="4">
+        <spectrum id="scan=1" index="0" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <cvParam cvRef="MS" accession="MS:1000130" name="positive scan" />
+        </spectrum>
+        <spectrum id="scan=2" index="1" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <cvParam cvRef="MS" accession="MS:1000129" name="negative scan" />
+        </spectrum>
+        <spectrum id="scan=3" index="2" defaultArrayLength="0">
+            <referenceableParamGroupRef ref="negative" />
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+        </spectrum>
+        <spectrum id="scan=4" index="3" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+        </spectrum>
+        </spectrumList></run></mzML>"#;
+        let spectra = MzMLReader::default().parse(s.as_bytes()).await?;
+        let polarities = spectra.iter().map(|s| s.polarity).collect::<Vec<_>>();
+        assert_eq!(
+            polarities,
+            vec![
+                Some(Polarity::Positive),
+                Some(Polarity::Negative),
+                // Set through a referenceableParamGroup
+                Some(Polarity::Negative),
+                None,
+            ]
+        );
+
+        assert_eq!("pos".parse(), Ok(Polarity::Positive));
+        assert_eq!("-".parse(), Ok(Polarity::Negative));
+        assert!("both".parse::<Polarity>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_charge_arrays() -> Result<(), MzMLError> {
+        let spectrum = |id: &str, charges: &str| {
+            format!(
+                r#"<spectrum id="{id}" index="0" defaultArrayLength="2">
+                <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+                <binaryDataArrayList count="3">
+                    <binaryDataArray>
+                        <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" />
+                        <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" />
+                        <binary>AAAAAAAAWUAAAAAAAABpQA==</binary>
+                    </binaryDataArray>
+                    <binaryDataArray>
+                        <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" />
+                        <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                        <binary>AACAPwAAAEA=</binary>
+                    </binaryDataArray>
+                    <binaryDataArray>
+                        <cvParam cvRef="MS" accession="MS:1000516" name="charge array" />
+                        <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                        <binary>{charges}</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+                </spectrum>"#
+            )
+        };
+        let s = [
+            spectrum("scan=1", "AAAAQAAAQEA="),
+            // A single charge for two peaks
+            spectrum("scan=2", "AAAAQA=="),
+        ]
+        .concat();
+        let spectra = MzMLReader::default().parse(s.as_bytes()).await?;
+        assert_eq!(spectra[0].charge, vec![2, 3]);
+        // Charges that don't match the peaks one to one are dropped
+        assert!(spectra[1].charge.is_empty());
+        assert_eq!(spectra[1].mz, vec![100.0, 200.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_pressure_trace() -> Result<(), MzMLError> {
+        let s = r#"
+        <chromatogramList count="1">
+            <chromatogram index="0" id="pump pressure" defaultArrayLength="2">
+                <cvParam cvRef="MS" accession="MS:1003019" name="pressure chromatogram" />
+                <binaryDataArrayList count="2">
+                    <binaryDataArray encodedLength="24">
+                        <cvParam cvRef="MS" accession="MS:1000595" name="time array" unitAccession="UO:0000031" unitName="minute" unitCvRef="UO" />
+                        <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" />
+                        <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                        <binary>AAAAAAAA4D8AAAAAAADwPw==</binary>
+                    </binaryDataArray>
+                    <binaryDataArray encodedLength="24">
+                        <cvParam cvRef="MS" accession="MS:1000821" name="pressure array" unitAccession="UO:0000110" unitName="pascal" unitCvRef="UO" />
+                        <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" />
+                        <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                        <binary>AAAAAAAAWUAAAAAAAABpQA==</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+            </chromatogram>
+        </chromatogramList>
+        "#;
+        let mut stream = MzMLReader::default().stream(s.as_bytes());
+        assert!(stream.next_spectrum().await?.is_none());
+        let chromatograms = stream.take_chromatograms();
+        assert_eq!(chromatograms.len(), 1);
+        assert_eq!(chromatograms[0].kind, Some("pressure"));
+        assert_eq!(chromatograms[0].time, vec![30.0, 60.0]);
+        assert_eq!(chromatograms[0].intensity, vec![100.0, 200.0]);
+        Ok(())
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn parallel_decoding() -> Result<(), MzMLError> {
+        use std::io::Write;
+
+        let mut s = String::from(r#"<mzML><run><spectrumList count="50">"#);
+        for scan in 0..50 {
+            let mz = (0..scan).map(|i| 100.0 + i as f64).collect::<Vec<_>>();
+            let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+            for mz in &mz {
+                zlib.write_all(&mz.to_le_bytes())?;
+            }
+            let intensity = mz.iter().flat_map(|&mz| (mz as f32).to_le_bytes());
+            s.push_str(&format!(
+                r#"<spectrum id="scan={scan}" index="{scan}">
+                <cvParam accession="MS:1000511" name="ms level" value="{level}" />
+                <binaryDataArrayList count="2">
+                    <binaryDataArray>
+                        <cvParam accession="MS:1000514" name="m/z array" />
+                        <cvParam accession="MS:1000523" name="64-bit float" />
+                        <cvParam accession="MS:1000574" name="zlib compression" />
+                        <binary>{mz}</binary>
+                    </binaryDataArray>
+                    <binaryDataArray>
+                        <cvParam accession="MS:1000515" name="intensity array" />
+                        <cvParam accession="MS:1000521" name="32-bit float" />
+                        <cvParam accession="MS:1000576" name="no compression" />
+                        <binary>{intensity}</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+                </spectrum>"#,
+                level = 1 + scan % 2,
+                mz = base64::encode(zlib.finish()?),
+                intensity = base64::encode(intensity.collect::<Vec<u8>>()),
+            ));
+        }
+        s.push_str("</spectrumList></run></mzML>");
+
+        let mut reader = MzMLReader::with_level_filter(2);
+        let sequential = reader.parse(s.as_bytes()).await?;
+        reader.set_parallel_decoding(true);
+        let parallel = reader.parse(s.as_bytes()).await?;
+        assert_eq!(sequential.len(), 25);
+        assert_eq!(parallel, sequential);
+        assert_eq!(parallel[2].mz.len(), 5);
+        assert_eq!(parallel[2].intensity[4], 104.0);
+        Ok(())
+    }
 }
@@ -0,0 +1,80 @@
+//! Read [mzMLb](https://doi.org/10.1021/acs.jproteome.0c00192) files, which
+//! store an mzML document in an HDF5 container.
+//!
+//! The document is held in the `mzML` dataset, with binary data arrays moved
+//! out into typed (and usually compressed) HDF5 datasets. Each
+//! `binaryDataArray` references a slice of one of these datasets, which is
+//! resolved by [`Hdf5Arrays`] as the document is parsed, so the rest of the
+//! conversion is exactly the same as for mzML.
+//!
+//! HDF5 files are read through the HDF5 C library, and must be local.
+use crate::mzml::{ExternalArray, ExternalArrays, MzMLError, MzMLReader, MzMLStream};
+use hdf5::{types::TypeDescriptor, Dataset, File};
+use std::{collections::HashMap, io::Cursor, path::Path};
+
+/// Dataset holding the mzML document
+const DOCUMENT: &str = "mzML";
+
+fn hdf5_error(err: hdf5::Error) -> MzMLError {
+    MzMLError::ExternalArrayError(err.to_string())
+}
+
+/// Binary data arrays of an mzMLb file
+pub struct Hdf5Arrays {
+    file: File,
+    datasets: HashMap<String, Dataset>,
+}
+
+impl ExternalArrays for Hdf5Arrays {
+    fn read(&mut self, array: &ExternalArray) -> Result<Vec<f64>, MzMLError> {
+        let name = array.dataset.as_str();
+        if !self.datasets.contains_key(name) {
+            let ds = self.file.dataset(name).map_err(hdf5_error)?;
+            self.datasets.insert(name.to_string(), ds);
+        }
+        // HDF5 converts `f32` datasets to `f64` as they are read
+        let values = self.datasets[name]
+            .read_slice_1d::<f64, _>(array.offset..array.offset + array.length)
+            .map_err(hdf5_error)?;
+        Ok(values.to_vec())
+    }
+}
+
+/// Open an mzMLb file, returning its mzML document and the binary data
+/// arrays that the document references
+pub fn open<P: AsRef<Path>>(path: P) -> Result<(Vec<u8>, Hdf5Arrays), MzMLError> {
+    let file = File::open(path).map_err(hdf5_error)?;
+    let dataset = file.dataset(DOCUMENT).map_err(hdf5_error)?;
+    // The document is written as either signed or unsigned 8-bit characters
+    let document = match dataset
+        .dtype()
+        .and_then(|dtype| dtype.to_descriptor())
+        .map_err(hdf5_error)?
+    {
+        TypeDescriptor::Integer(_) => dataset
+            .read_raw::<i8>()
+            .map_err(hdf5_error)?
+            .into_iter()
+            .map(|c| c as u8)
+            .collect(),
+        _ => dataset.read_raw::<u8>().map_err(hdf5_error)?,
+    };
+    let arrays = Hdf5Arrays {
+        file,
+        datasets: HashMap::new(),
+    };
+    Ok((document, arrays))
+}
+
+/// Lazily parse spectra from an mzMLb file. The mzML document is read into
+/// memory up front, but it holds no peak data, so is small compared to the
+/// file
+pub fn stream<P: AsRef<Path>>(
+    reader: &MzMLReader,
+    path: P,
+) -> Result<MzMLStream<Cursor<Vec<u8>>>, MzMLError> {
+    let (document, arrays) = open(path)?;
+    let mut stream = reader.stream(Cursor::new(document));
+    stream.set_external_arrays(Box::new(arrays));
+    Ok(stream)
+}
@@ -0,0 +1,80 @@
+//! Vendor scan numbers, parsed from spectrum native ids.
+//!
+//! mzML files declare the format of their native ids with a `nativeID
+//! format` cvParam on each `<sourceFile>`. Formats that number spectra
+//! uniquely within a run are supported:
+//!
+//! * Thermo (`MS:1000768`) - `controllerType=0 controllerNumber=1 scan=42`
+//! * Bruker/Agilent YEP (`MS:1000771`), Bruker BAF (`MS:1000772`), Bruker U2
+//!   (`MS:1000823`) and scan number only (`MS:1000776`) - `scan=42`
+//! * Agilent MassHunter (`MS:1001508`) - `scanId=42`
+//!
+//! Waters (`function=2 process=0 scan=42`) and SCIEX WIFF (`sample=1
+//! period=1 cycle=42 experiment=2`) ids number scans within each function or
+//! experiment, so the scan alone does not identify a spectrum, and no scan
+//! number is parsed. Nor is it for Bruker TDF (`frame=42 scanStart=0
+//! scanEnd=917`): the PASEF MS2 spectra of a frame, one per precursor, share
+//! its frame number. Spectra in these formats are numbered by their index.
+
+/// A native id format with a scan number
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NativeIdFormat {
+    Thermo,
+    /// Bruker YEP/BAF/U2, or a plain scan number
+    Scan,
+    Agilent,
+}
+
+impl NativeIdFormat {
+    /// The format declared by a `nativeID format` cvParam, if it has scan
+    /// numbers
+    pub fn from_accession(accession: &[u8]) -> Option<Self> {
+        match accession {
+            b"MS:1000768" => Some(NativeIdFormat::Thermo),
+            b"MS:1000771" | b"MS:1000772" | b"MS:1000776" | b"MS:1000823" => {
+                Some(NativeIdFormat::Scan)
+            }
+            b"MS:1001508" => Some(NativeIdFormat::Agilent),
+            _ => None,
+        }
+    }
+
+    /// Parse the scan number out of a native id. Returns `None` if the id
+    /// doesn't match the format
+    pub fn scan_number(self, id: &[u8]) -> Option<u32> {
+        let id = std::str::from_utf8(id).ok()?;
+        let value = |key: &str| {
+            id.split_ascii_whitespace()
+                .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+                .and_then(|value| value.parse().ok())
+        };
+        match self {
+            NativeIdFormat::Thermo | NativeIdFormat::Scan => value("scan"),
+            NativeIdFormat::Agilent => value("scanId"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_scan_numbers() {
+        let thermo = NativeIdFormat::from_accession(b"MS:1000768").unwrap();
+        assert_eq!(
+            thermo.scan_number(b"controllerType=0 controllerNumber=1 scan=8713"),
+            Some(8713)
+        );
+        assert_eq!(thermo.scan_number(b"index=3"), None);
+
+        assert_eq!(
+            NativeIdFormat::Agilent.scan_number(b"scanId=2101"),
+            Some(2101)
+        );
+        // Waters ids restart their scan numbers in each function, and
+        // Bruker TDF frames hold several PASEF spectra
+        assert_eq!(NativeIdFormat::from_accession(b"MS:1000769"), None);
+        assert_eq!(NativeIdFormat::from_accession(b"MS:1002818"), None);
+    }
+}
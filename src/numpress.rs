@@ -0,0 +1,175 @@
+//! Decoding of [MS-Numpress](https://github.com/ms-numpress/ms-numpress)
+//! compressed binary data arrays, as written by `msconvert --numpressAll`.
+//!
+//! * linear prediction (`MS:1002312`) - m/z and retention time arrays
+//! * positive integer (`MS:1002313`) - intensities, rounded to integers
+//! * short logged float (`MS:1002314`) - intensities, stored as `ln(x + 1)`
+//!
+//! Each may be followed by zlib compression, which is undone by the mzML
+//! parser before the array is decoded here.
+use crate::mzml::MzMLError;
+
+/// MS-Numpress compression of a binary data array
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Numpress {
+    Linear,
+    Pic,
+    Slof,
+}
+
+impl Numpress {
+    /// Decode a numpress compressed array
+    pub fn decode(self, data: &[u8]) -> Result<Vec<f64>, MzMLError> {
+        match self {
+            Numpress::Linear => decode_linear(data),
+            Numpress::Pic => decode_pic(data),
+            Numpress::Slof => decode_slof(data),
+        }
+    }
+}
+
+fn corrupt() -> MzMLError {
+    MzMLError::NumpressError("corrupt input data".into())
+}
+
+/// Reads the half bytes (nibbles) of numpress encoded integers, high nibble
+/// first
+struct HalfBytes<'a> {
+    data: &'a [u8],
+    index: usize,
+    high: bool,
+}
+
+impl<'a> HalfBytes<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        HalfBytes {
+            data,
+            index: 0,
+            high: true,
+        }
+    }
+
+    fn next(&mut self) -> Result<u32, MzMLError> {
+        let byte = *self.data.get(self.index).ok_or_else(corrupt)?;
+        let hb = match self.high {
+            true => byte >> 4,
+            false => {
+                self.index += 1;
+                byte & 0xf
+            }
+        };
+        self.high = !self.high;
+        Ok(hb as u32)
+    }
+
+    /// Whether there are no further integers. An odd number of half bytes is
+    /// padded with a trailing zero half byte
+    fn is_done(&self) -> bool {
+        self.index >= self.data.len()
+            || (self.index == self.data.len() - 1 && !self.high && self.data[self.index] & 0xf == 0)
+    }
+
+    /// Decode one integer: the first half byte holds the number of leading
+    /// zero (0-8) or 0xf (9-15, minus 8) half bytes, followed by the remaining
+    /// half bytes, least significant first
+    fn decode_int(&mut self) -> Result<u32, MzMLError> {
+        let head = self.next()?;
+        let (n, mut value) = match head {
+            0..=8 => (head, 0u32),
+            _ => {
+                let n = head - 8;
+                (n, !(u32::MAX >> (4 * n)))
+            }
+        };
+        for i in n..8 {
+            value |= self.next()? << ((i - n) * 4);
+        }
+        Ok(value)
+    }
+}
+
+/// Fixed point (scaling factor), stored as a big endian f64
+fn fixed_point(data: &[u8]) -> Result<f64, MzMLError> {
+    let bytes = data.get(..8).ok_or_else(corrupt)?;
+    Ok(f64::from_be_bytes(bytes.try_into().expect("8 bytes")))
+}
+
+fn decode_linear(data: &[u8]) -> Result<Vec<f64>, MzMLError> {
+    let fixed_point = fixed_point(data)?;
+    let mut values = Vec::new();
+    let mut ints = [0i64; 3];
+    for (i, chunk) in data[8..].chunks(4).take(2).enumerate() {
+        let chunk: [u8; 4] = chunk.try_into().map_err(|_| corrupt())?;
+        ints[i + 1] = u32::from_le_bytes(chunk) as i64;
+        values.push(ints[i + 1] as f64 / fixed_point);
+    }
+    if values.len() < 2 {
+        return Ok(values);
+    }
+
+    // Each further value is the difference from a linear extrapolation of
+    // the previous two
+    let mut half_bytes = HalfBytes::new(data.get(16..).unwrap_or_default());
+    while !half_bytes.is_done() {
+        let diff = half_bytes.decode_int()? as i32 as i64;
+        let extrapolated = ints[2] + (ints[2] - ints[1]);
+        let y = extrapolated + diff;
+        values.push(y as f64 / fixed_point);
+        ints = [ints[1], ints[2], y];
+    }
+    Ok(values)
+}
+
+fn decode_pic(data: &[u8]) -> Result<Vec<f64>, MzMLError> {
+    let mut values = Vec::new();
+    let mut half_bytes = HalfBytes::new(data);
+    while !half_bytes.is_done() {
+        values.push(half_bytes.decode_int()? as f64);
+    }
+    Ok(values)
+}
+
+fn decode_slof(data: &[u8]) -> Result<Vec<f64>, MzMLError> {
+    let fixed_point = fixed_point(data)?;
+    if !data.len().is_multiple_of(2) {
+        return Err(corrupt());
+    }
+    Ok(data[8..]
+        .chunks_exact(2)
+        .map(|chunk| (u16::from_le_bytes([chunk[0], chunk[1]]) as f64 / fixed_point).exp() - 1.0)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode() -> Result<(), MzMLError> {
+        // Encoded following the reference implementation (MSNumpress.cpp)
+        let linear = [
+            64, 195, 136, 0, 0, 0, 0, 0, 64, 66, 15, 0, 8, 152, 30, 0, 196, 178, 227, 32, 106, 122,
+            9, 95, 142,
+        ];
+        assert_eq!(
+            Numpress::Linear.decode(&linear)?,
+            vec![100.0, 200.5, 300.25, 450.125, 449.0]
+        );
+
+        let pic = [113, 114, 100, 104, 21, 29, 203, 87];
+        assert_eq!(
+            Numpress::Pic.decode(&pic)?,
+            vec![1.0, 2.0, 100.0, 0.0, 123456789.0]
+        );
+
+        let slof = [64, 143, 64, 0, 0, 0, 0, 0, 0, 0, 94, 9, 253, 26];
+        let decoded = Numpress::Slof.decode(&slof)?;
+        assert_eq!(decoded.len(), 3);
+        for (decoded, expected) in decoded.iter().zip([0.0, 10.0, 1000.5]) {
+            assert!((decoded - expected).abs() <= expected * 1e-3);
+        }
+
+        assert!(Numpress::Linear.decode(&linear[..20]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! Tabular query results, written as CSV, JSON or parquet, or converted to
+//! Arrow
+use arrow_array::{ArrayRef, Float32Array, RecordBatch, StringArray, UInt32Array};
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use parquet::{
+    basic::{Compression, LogicalType, Repetition, Type as PhysicalType, ZstdLevel},
+    column::writer::ColumnWriter,
+    data_type::ByteArray,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::types::Type,
+};
+use std::{io::Write, sync::Arc};
+
+/// A single named column of results
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    UInt(Vec<u32>),
+    OptionalUInt(Vec<Option<u32>>),
+    Float(Vec<f32>),
+    OptionalFloat(Vec<Option<f32>>),
+    Str(Vec<String>),
+}
+
+impl Column {
+    pub fn len(&self) -> usize {
+        match self {
+            Column::UInt(v) => v.len(),
+            Column::OptionalUInt(v) => v.len(),
+            Column::Float(v) => v.len(),
+            Column::OptionalFloat(v) => v.len(),
+            Column::Str(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn fmt_row(&self, row: usize) -> String {
+        match self {
+            Column::UInt(v) => v[row].to_string(),
+            Column::OptionalUInt(v) => v[row].map(|v| v.to_string()).unwrap_or_default(),
+            Column::Float(v) => v[row].to_string(),
+            Column::OptionalFloat(v) => v[row].map(|v| v.to_string()).unwrap_or_default(),
+            Column::Str(v) if v[row].contains([',', '"', '\n']) => {
+                format!("\"{}\"", v[row].replace('"', "\"\""))
+            }
+            Column::Str(v) => v[row].clone(),
+        }
+    }
+
+    fn json_value(&self, row: usize) -> serde_json::Value {
+        match self {
+            Column::UInt(v) => v[row].into(),
+            Column::OptionalUInt(v) => v[row].into(),
+            Column::Float(v) => v[row].into(),
+            Column::OptionalFloat(v) => v[row].into(),
+            Column::Str(v) => v[row].as_str().into(),
+        }
+    }
+
+    fn arrow_array(&self) -> ArrayRef {
+        match self {
+            Column::UInt(v) => Arc::new(UInt32Array::from(v.clone())),
+            Column::OptionalUInt(v) => Arc::new(UInt32Array::from(v.clone())),
+            Column::Float(v) => Arc::new(Float32Array::from(v.clone())),
+            Column::OptionalFloat(v) => Arc::new(Float32Array::from(v.clone())),
+            Column::Str(v) => Arc::new(StringArray::from(v.clone())),
+        }
+    }
+
+    fn arrow_field(&self, name: &str) -> Field {
+        let (data_type, nullable) = match self {
+            Column::UInt(_) => (DataType::UInt32, false),
+            Column::OptionalUInt(_) => (DataType::UInt32, true),
+            Column::Float(_) => (DataType::Float32, false),
+            Column::OptionalFloat(_) => (DataType::Float32, true),
+            Column::Str(_) => (DataType::Utf8, false),
+        };
+        Field::new(name, data_type, nullable)
+    }
+
+    fn parquet_type(&self, name: &str) -> parquet::errors::Result<Type> {
+        let (physical, repetition, logical) = match self {
+            Column::UInt(_) => (
+                PhysicalType::INT32,
+                Repetition::REQUIRED,
+                Some(LogicalType::Integer {
+                    bit_width: 32,
+                    is_signed: false,
+                }),
+            ),
+            Column::OptionalUInt(_) => (
+                PhysicalType::INT32,
+                Repetition::OPTIONAL,
+                Some(LogicalType::Integer {
+                    bit_width: 32,
+                    is_signed: false,
+                }),
+            ),
+            Column::Float(_) => (PhysicalType::FLOAT, Repetition::REQUIRED, None),
+            Column::OptionalFloat(_) => (PhysicalType::FLOAT, Repetition::OPTIONAL, None),
+            Column::Str(_) => (
+                PhysicalType::BYTE_ARRAY,
+                Repetition::REQUIRED,
+                Some(LogicalType::String),
+            ),
+        };
+        Type::primitive_type_builder(name, physical)
+            .with_repetition(repetition)
+            .with_logical_type(logical)
+            .build()
+    }
+}
+
+/// A set of equal-length, named columns
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Table {
+    columns: Vec<(String, Column)>,
+}
+
+impl Table {
+    /// Add a column to the table. All columns must have the same length
+    pub fn with_column<S: Into<String>>(mut self, name: S, column: Column) -> Self {
+        if let Some((_, first)) = self.columns.first() {
+            assert_eq!(first.len(), column.len(), "columns must be the same length");
+        }
+        self.columns.push((name.into(), column));
+        self
+    }
+
+    /// Insert a column at `index`, shifting later columns to the right
+    pub fn insert_column<S: Into<String>>(mut self, index: usize, name: S, column: Column) -> Self {
+        if let Some((_, first)) = self.columns.first() {
+            assert_eq!(first.len(), column.len(), "columns must be the same length");
+        }
+        self.columns.insert(index, (name.into(), column));
+        self
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.columns
+            .first()
+            .map(|(_, c)| c.len())
+            .unwrap_or_default()
+    }
+
+    pub fn write_csv<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        let header = self
+            .columns
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        writeln!(w, "{}", header.join(","))?;
+        for row in 0..self.num_rows() {
+            let line = self
+                .columns
+                .iter()
+                .map(|(_, column)| column.fmt_row(row))
+                .collect::<Vec<_>>();
+            writeln!(w, "{}", line.join(","))?;
+        }
+        w.flush()
+    }
+
+    /// Write the table as a JSON array with one object per row, keeping the
+    /// column order. Non-finite floats are written as `null`
+    pub fn write_json<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        w.write_all(b"[")?;
+        for row in 0..self.num_rows() {
+            if row > 0 {
+                w.write_all(b",")?;
+            }
+            w.write_all(b"{")?;
+            for (idx, (name, column)) in self.columns.iter().enumerate() {
+                if idx > 0 {
+                    w.write_all(b",")?;
+                }
+                serde_json::to_writer(&mut w, name)?;
+                w.write_all(b":")?;
+                serde_json::to_writer(&mut w, &column.json_value(row))?;
+            }
+            w.write_all(b"}")?;
+        }
+        w.write_all(b"]")?;
+        w.flush()
+    }
+
+    /// Convert the table to an Arrow record batch, with the same column types
+    /// as [`Table::write_parquet`]
+    pub fn to_record_batch(&self) -> Result<RecordBatch, ArrowError> {
+        let schema = Schema::new(
+            self.columns
+                .iter()
+                .map(|(name, column)| column.arrow_field(name))
+                .collect::<Vec<_>>(),
+        );
+        let arrays = self
+            .columns
+            .iter()
+            .map(|(_, column)| column.arrow_array())
+            .collect();
+        RecordBatch::try_new(Arc::new(schema), arrays)
+    }
+
+    pub fn write_parquet<W: Write + Send>(&self, w: W) -> anyhow::Result<W> {
+        let fields = self
+            .columns
+            .iter()
+            .map(|(name, column)| column.parquet_type(name).map(Arc::new))
+            .collect::<parquet::errors::Result<Vec<_>>>()?;
+        let schema = Type::group_type_builder("schema")
+            .with_fields(fields)
+            .build()?;
+
+        let options = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::try_new(3)?))
+            .build();
+        let mut writer = SerializedFileWriter::new(w, schema.into(), options.into())?;
+        let mut rg = writer.next_row_group()?;
+
+        for (_, column) in &self.columns {
+            let mut col = rg
+                .next_column()?
+                .ok_or_else(|| anyhow::anyhow!("schema and columns are out of sync"))?;
+            match (col.untyped(), column) {
+                (ColumnWriter::Int32ColumnWriter(c), Column::UInt(v)) => {
+                    let v = v.iter().map(|v| *v as i32).collect::<Vec<_>>();
+                    c.write_batch(&v, None, None)?;
+                }
+                (ColumnWriter::Int32ColumnWriter(c), Column::OptionalUInt(v)) => {
+                    let values = v.iter().flatten().map(|v| *v as i32).collect::<Vec<_>>();
+                    let def_levels = v.iter().map(|v| v.is_some() as i16).collect::<Vec<_>>();
+                    c.write_batch(&values, Some(&def_levels), None)?;
+                }
+                (ColumnWriter::FloatColumnWriter(c), Column::Float(v)) => {
+                    c.write_batch(v, None, None)?;
+                }
+                (ColumnWriter::FloatColumnWriter(c), Column::OptionalFloat(v)) => {
+                    let values = v.iter().flatten().copied().collect::<Vec<_>>();
+                    let def_levels = v.iter().map(|v| v.is_some() as i16).collect::<Vec<_>>();
+                    c.write_batch(&values, Some(&def_levels), None)?;
+                }
+                (ColumnWriter::ByteArrayColumnWriter(c), Column::Str(v)) => {
+                    let v = v
+                        .iter()
+                        .map(|s| ByteArray::from(s.as_str()))
+                        .collect::<Vec<_>>();
+                    c.write_batch(&v, None, None)?;
+                }
+                _ => anyhow::bail!("schema and columns are out of sync"),
+            }
+            col.close()?;
+        }
+        rg.close()?;
+        Ok(writer.into_inner()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_batch_and_json() -> anyhow::Result<()> {
+        let table = Table::default()
+            .with_column("scan", Column::UInt(vec![1, 2]))
+            .with_column("mz", Column::OptionalFloat(vec![Some(100.0), None]))
+            .with_column("name", Column::Str(vec!["a".into(), "b".into()]));
+        let batch = table.to_record_batch()?;
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.schema().field(0).data_type(), &DataType::UInt32);
+        assert!(batch.schema().field(1).is_nullable());
+        assert_eq!(batch.column(1).null_count(), 1);
+
+        let mut json = Vec::new();
+        table.write_json(&mut json)?;
+        assert_eq!(
+            String::from_utf8(json)?,
+            r#"[{"scan":1,"mz":100.0,"name":"a"},{"scan":2,"mz":null,"name":"b"}]"#
+        );
+        Ok(())
+    }
+}
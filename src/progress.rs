@@ -0,0 +1,180 @@
+//! Progress of reading and writing files. Applications embedding the library
+//! (e.g. GUIs, or servers tracking conversion jobs) receive it through the
+//! [`Progress`] trait, set on [`MzMLReader`], [`WriterOptions`] and
+//! [`CloudPath::create_with_progress`].
+//!
+//! The command line tool shows progress bars instead. Without the `native`
+//! feature (e.g. when compiling to WebAssembly) they are not shown.
+//!
+//! [`MzMLReader`]: crate::mzml::MzMLReader
+//! [`WriterOptions`]: crate::write_long::WriterOptions
+//! [`CloudPath::create_with_progress`]: crate::cloud::CloudPath::create_with_progress
+use std::sync::Arc;
+
+#[cfg(feature = "native")]
+pub(crate) use indicatif::ProgressBar;
+
+/// Receives progress as files are read and written. Each method is given the
+/// increase since it was last called, and does nothing by default. Methods
+/// are called on whichever thread does the work, so should return quickly
+pub trait Progress: Send + Sync {
+    /// `count` more spectra were parsed from the input
+    fn spectra_parsed(&self, count: usize) {
+        let _ = count;
+    }
+
+    /// `count` more rows were written to the output, as a row group was
+    /// flushed. Rows are ions in the long format, and spectra in the wide
+    /// format
+    fn rows_written(&self, count: usize) {
+        let _ = count;
+    }
+
+    /// `bytes` more of the output were handed to an object store upload
+    fn bytes_uploaded(&self, bytes: u64) {
+        let _ = bytes;
+    }
+}
+
+/// The [`Progress`] set on a reader or writer, if any
+#[derive(Clone, Default)]
+pub(crate) struct Callback(Option<Arc<dyn Progress>>);
+
+impl Callback {
+    pub(crate) fn new(progress: Option<Arc<dyn Progress>>) -> Self {
+        Callback(progress)
+    }
+
+    pub(crate) fn spectra_parsed(&self, count: usize) {
+        if let Some(progress) = &self.0 {
+            progress.spectra_parsed(count);
+        }
+    }
+
+    pub(crate) fn rows_written(&self, count: usize) {
+        if let Some(progress) = &self.0 {
+            progress.rows_written(count);
+        }
+    }
+
+    #[cfg(feature = "native")]
+    pub(crate) fn bytes_uploaded(&self, bytes: u64) {
+        if let Some(progress) = &self.0 {
+            progress.bytes_uploaded(bytes);
+        }
+    }
+}
+
+impl std::fmt::Debug for Callback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Callback")
+            .field(&self.0.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// Callbacks are equal if they are the same receiver
+impl PartialEq for Callback {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
+}
+
+/// A progress bar over `len` items, in the style shared by all readers
+#[cfg(feature = "native")]
+pub(crate) fn progress_bar(len: u64, message: &'static str) -> ProgressBar {
+    ProgressBar::new(len).with_message(message).with_style(
+        indicatif::ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
+            .unwrap(),
+    )
+}
+
+#[cfg(not(feature = "native"))]
+pub(crate) fn progress_bar(_: u64, _: &'static str) -> ProgressBar {
+    ProgressBar
+}
+
+/// Stand-in for `indicatif::ProgressBar` that doesn't draw anything
+#[cfg(not(feature = "native"))]
+pub(crate) struct ProgressBar;
+
+#[cfg(not(feature = "native"))]
+impl ProgressBar {
+    pub(crate) fn set_length(&self, _: u64) {}
+
+    pub(crate) fn inc(&self, _: u64) {}
+
+    pub(crate) fn finish(&self) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::MzMLReader;
+    use crate::write_long::{serialize_stream_to_parquet, RowGroupSize, WriterOptions};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counts {
+        spectra: AtomicUsize,
+        rows: AtomicUsize,
+    }
+
+    impl Progress for Counts {
+        fn spectra_parsed(&self, count: usize) {
+            self.spectra.fetch_add(count, Ordering::Relaxed);
+        }
+
+        fn rows_written(&self, count: usize) {
+            self.rows.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn report_progress() -> anyhow::Result<()> {
+        let mut document = String::from(r#"<mzML><run><spectrumList count="3">"#);
+        for scan in 0..3 {
+            document.push_str(&format!(
+                r#"<spectrum id="scan={scan}" index="{scan}">
+                <cvParam accession="MS:1000511" name="ms level" value="1" />
+                <binaryDataArrayList count="2">
+                    <binaryDataArray>
+                        <cvParam accession="MS:1000514" name="m/z array" />
+                        <cvParam accession="MS:1000523" name="64-bit float" />
+                        <binary>AAAAAAAAWUAAAAAAAABpQA==</binary>
+                    </binaryDataArray>
+                    <binaryDataArray>
+                        <cvParam accession="MS:1000515" name="intensity array" />
+                        <cvParam accession="MS:1000521" name="32-bit float" />
+                        <binary>AACAPwAAAEA=</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+                </spectrum>"#
+            ));
+        }
+        document.push_str("</spectrumList></run></mzML>");
+
+        let counts = Arc::new(Counts::default());
+        let mut reader = MzMLReader::default();
+        reader.set_progress(Some(counts.clone()));
+        let mut options = WriterOptions::default();
+        options
+            .set_row_group_size(RowGroupSize {
+                ions: None,
+                spectra: Some(2),
+                bytes: None,
+            })
+            .set_progress(Some(counts.clone()));
+
+        let mut stream = reader.stream(document.as_bytes());
+        let (_, written) = serialize_stream_to_parquet(Vec::new(), &mut stream, &options).await?;
+        assert_eq!(written.peaks, 6);
+        assert_eq!(counts.spectra.load(Ordering::Relaxed), 3);
+        assert_eq!(counts.rows.load(Ordering::Relaxed), 6);
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Precursor purity, the fraction of the MS1 signal isolated for an MS2
+//! spectrum that belongs to its precursor.
+//!
+//! Co-isolated ions contaminate the fragment (and reporter ion) intensities
+//! of an MS2 spectrum, so isobaric labelling (TMT) workflows commonly discard
+//! spectra with a low purity. Peaks of the MS1 spectrum preceding an MS2
+//! spectrum are summed within its isolation window; those that fall on the
+//! isotope envelope of the precursor (its m/z plus or minus a whole number of
+//! neutrons, divided by its charge) count towards the precursor.
+//!
+//! Purity is only computed for MS2 spectra with a precursor charge and an
+//! isolation window. MS3 spectra isolate fragments, so their purity is that of
+//! the MS2 spectrum they were acquired from.
+//!
+//! Enabled with [`crate::write_long::WriterOptions::set_precursor_purity`],
+//! which records the settings in the footer metadata under
+//! [`PRECURSOR_PURITY_KEY`], and stores the purity of each spectrum in a
+//! [`crate::write_long::PRECURSOR_PURITY_COLUMN`] column.
+use crate::mzml::{Precursor, RawSpectrum};
+use parquet::file::metadata::{KeyValue, ParquetMetaData};
+use serde::{Deserialize, Serialize};
+
+/// Footer metadata key holding the [`PrecursorPurity`] settings, as JSON
+pub const PRECURSOR_PURITY_KEY: &str = "precursor_purity";
+
+/// Mass difference between the carbon 13 and carbon 12 isotopes
+const NEUTRON: f64 = 1.00335;
+
+/// Settings for computing precursor purity
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrecursorPurity {
+    /// Tolerance for matching isotope peaks of the precursor, in ppm
+    pub ppm: f32,
+}
+
+impl Default for PrecursorPurity {
+    fn default() -> Self {
+        Self { ppm: 10.0 }
+    }
+}
+
+impl PrecursorPurity {
+    /// The settings used to compute precursor purity, if it was computed
+    pub fn from_metadata(metadata: &ParquetMetaData) -> anyhow::Result<Option<Self>> {
+        Self::from_key_value(metadata.file_metadata().key_value_metadata())
+    }
+
+    pub(crate) fn from_key_value(kv: Option<&Vec<KeyValue>>) -> anyhow::Result<Option<Self>> {
+        kv.into_iter()
+            .flatten()
+            .find(|kv| kv.key == PRECURSOR_PURITY_KEY)
+            .and_then(|kv| kv.value.as_deref())
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    pub(crate) fn to_key_value(self) -> serde_json::Result<KeyValue> {
+        Ok(KeyValue {
+            key: PRECURSOR_PURITY_KEY.into(),
+            value: Some(serde_json::to_string(&self)?),
+        })
+    }
+
+    /// Fraction of the intensity of the centroided `ms1` spectrum, within the
+    /// isolation window of `precursor`, that belongs to its isotope envelope.
+    /// `None` if the precursor has no charge or isolation window, or if the
+    /// window is empty
+    pub fn purity(&self, ms1: &RawSpectrum, precursor: &Precursor) -> Option<f32> {
+        let charge = precursor.charge.filter(|&z| z > 0)? as f64;
+        let target = precursor
+            .isolation_window_target
+            .map_or(precursor.mz, f64::from);
+        let lower = target - precursor.isolation_window_lower? as f64;
+        let upper = target + precursor.isolation_window_upper? as f64;
+
+        let (mut matched, mut total) = (0.0, 0.0);
+        for (&mz, &intensity) in ms1.mz.iter().zip(&ms1.intensity) {
+            if mz < lower || mz > upper {
+                continue;
+            }
+            total += intensity;
+            let isotope = ((mz - precursor.mz) * charge / NEUTRON).round();
+            let expected = precursor.mz + isotope * NEUTRON / charge;
+            if (mz - expected).abs() <= expected * self.ppm as f64 / 1E6 {
+                matched += intensity;
+            }
+        }
+        (total > 0.0).then(|| (matched / total) as f32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn isolation_interference() {
+        let ms1 = RawSpectrum {
+            ms_level: 1,
+            centroid: true,
+            // A doubly charged envelope at 600, interfering ions at 599.7 and
+            // 600.3, and a peak outside of the isolation window
+            mz: vec![599.7, 600.0, 600.0 + NEUTRON / 2.0, 600.3, 603.0],
+            intensity: vec![10.0, 100.0, 60.0, 30.0, 500.0],
+            ..Default::default()
+        };
+        let mut precursor = Precursor {
+            mz: 600.0,
+            charge: Some(2),
+            isolation_window_target: Some(600.0),
+            isolation_window_lower: Some(0.7),
+            isolation_window_upper: Some(0.7),
+            ..Default::default()
+        };
+        let purity = PrecursorPurity::default().purity(&ms1, &precursor).unwrap();
+        assert!((purity - 160.0 / 200.0).abs() < 1E-6);
+
+        precursor.charge = None;
+        assert_eq!(PrecursorPurity::default().purity(&ms1, &precursor), None);
+    }
+}
@@ -0,0 +1,917 @@
+//! Queries over long format mzparquet files.
+//!
+//! Each query walks the row groups of a file, skipping any row group whose
+//! column statistics show that it cannot contain a match, and then decodes
+//! only the columns required to answer the query. Rows for a scan never span
+//! row groups, but need not be contiguous within one (see
+//! [`crate::write_long::WriterOptions::set_sort_ions`]).
+use crate::output::{Column, Table};
+use crate::write_long::RtUnit;
+use parquet::{
+    basic::ConvertedType,
+    column::reader::ColumnReader,
+    data_type::ByteArray,
+    errors::ParquetError,
+    file::{
+        metadata::RowGroupMetaData,
+        reader::{ChunkReader, FileReader, RowGroupReader},
+        serialized_reader::SerializedFileReader,
+        statistics::Statistics,
+    },
+};
+use std::collections::BTreeMap;
+
+/// Mass tolerance used when matching m/z values
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Tolerance {
+    /// Parts-per-million of the target m/z
+    Ppm(f32),
+    /// Absolute tolerance, in Daltons
+    Da(f32),
+}
+
+impl Tolerance {
+    /// Return the (inclusive) lower and upper m/z bounds around `mz`
+    pub fn bounds(&self, mz: f64) -> (f64, f64) {
+        let delta = match self {
+            Tolerance::Ppm(ppm) => mz * *ppm as f64 / 1E6,
+            Tolerance::Da(da) => *da as f64,
+        };
+        (mz - delta, mz + delta)
+    }
+}
+
+/// Parameters for extracting an ion chromatogram
+#[derive(Clone, Debug, PartialEq)]
+pub struct XicQuery {
+    /// Target m/z
+    pub mz: f64,
+    pub tolerance: Tolerance,
+    /// Optional (inclusive) retention time window, in seconds
+    pub rt: Option<(f32, f32)>,
+    /// MS level to extract ions from
+    pub ms_level: u8,
+}
+
+/// A single point of an extracted ion chromatogram
+#[derive(Clone, Debug, PartialEq)]
+pub struct XicPoint {
+    /// Run the scan belongs to, in files holding several runs (see
+    /// [`crate::rewrite::merge`])
+    pub file_id: Option<String>,
+    pub scan: u32,
+    pub rt: f32,
+    /// Summed intensity of all matching ions in this scan
+    pub intensity: f32,
+}
+
+impl From<&[XicPoint]> for Table {
+    fn from(points: &[XicPoint]) -> Self {
+        let mut table = Table::default();
+        // Files holding several runs
+        if points.iter().any(|p| p.file_id.is_some()) {
+            let file_ids = points
+                .iter()
+                .map(|p| p.file_id.clone().unwrap_or_default())
+                .collect();
+            table = table.with_column("file_id", Column::Str(file_ids));
+        }
+        table
+            .with_column(
+                "scan",
+                Column::UInt(points.iter().map(|p| p.scan).collect()),
+            )
+            .with_column("rt", Column::Float(points.iter().map(|p| p.rt).collect()))
+            .with_column(
+                "intensity",
+                Column::Float(points.iter().map(|p| p.intensity).collect()),
+            )
+    }
+}
+
+/// Look up the index of a (top-level) column in the schema
+pub(crate) fn column_index(reader: &dyn FileReader, name: &str) -> parquet::errors::Result<usize> {
+    reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .position(|col| col.name() == name)
+        .ok_or_else(|| ParquetError::General(format!("missing column `{}`", name)))
+}
+
+/// Return the min and max value of a column chunk, if statistics were written
+pub(crate) fn column_range(rg: &RowGroupMetaData, idx: usize) -> Option<(f64, f64)> {
+    let unsigned = rg.column(idx).column_descr().converted_type() == ConvertedType::UINT_32;
+    match rg.column(idx).statistics()? {
+        Statistics::Int32(s) if unsigned => {
+            Some((*s.min_opt()? as u32 as f64, *s.max_opt()? as u32 as f64))
+        }
+        Statistics::Int32(s) => Some((*s.min_opt()? as f64, *s.max_opt()? as f64)),
+        Statistics::Int64(s) => Some((*s.min_opt()? as f64, *s.max_opt()? as f64)),
+        Statistics::Float(s) => Some((*s.min_opt()? as f64, *s.max_opt()? as f64)),
+        Statistics::Double(s) => Some((*s.min_opt()?, *s.max_opt()?)),
+        _ => None,
+    }
+}
+
+/// Could the column chunk contain a value within `lo..=hi`? Column chunks
+/// without statistics are always assumed to overlap.
+pub(crate) fn may_contain(rg: &RowGroupMetaData, idx: usize, lo: f64, hi: f64) -> bool {
+    match column_range(rg, idx) {
+        Some((min, max)) => max >= lo && min <= hi,
+        None => true,
+    }
+}
+
+/// Bounds of an optional retention time window, given in seconds, in the
+/// unit of the file's `rt` column (see [`RtUnit::from_metadata`])
+pub(crate) fn rt_bounds(reader: &dyn FileReader, window: Option<(f32, f32)>) -> (f64, f64) {
+    let unit = RtUnit::from_metadata(reader.metadata());
+    match window {
+        Some((lo, hi)) => (unit.from_seconds(lo) as f64, unit.from_seconds(hi) as f64),
+        None => (f64::MIN, f64::MAX),
+    }
+}
+
+/// Decode all `$rows` records of a column chunk with `$reader`, converting
+/// values with `$convert`. Values are `None` where their definition level is
+/// below `$max_def`
+macro_rules! read_chunk {
+    ($reader:expr, $rows:expr, $max_def:expr, $convert:expr) => {{
+        let mut reader = $reader;
+        let mut values = Vec::with_capacity($rows);
+        let mut def_levels = Vec::with_capacity($rows);
+        let mut total = 0;
+        while total < $rows {
+            let (records, _, _) =
+                reader.read_records($rows - total, Some(&mut def_levels), None, &mut values)?;
+            if records == 0 {
+                break;
+            }
+            total += records;
+        }
+
+        let mut values = values.into_iter().map($convert);
+        if $max_def == 0 {
+            values.map(Some).collect()
+        } else {
+            def_levels
+                .into_iter()
+                .map(|def| if def == $max_def { values.next() } else { None })
+                .collect()
+        }
+    }};
+}
+
+/// Decode an entire numeric column chunk, widening values to `f64`. Null
+/// values are returned as `None`
+pub(crate) fn read_column(
+    rg: &dyn RowGroupReader,
+    idx: usize,
+) -> parquet::errors::Result<Vec<Option<f64>>> {
+    let rows = rg.metadata().num_rows() as usize;
+    let descr = rg.metadata().column(idx).column_descr_ptr();
+    let max_def = descr.max_def_level();
+    let unsigned = descr.converted_type() == ConvertedType::UINT_32;
+
+    Ok(match rg.get_column_reader(idx)? {
+        ColumnReader::Int32ColumnReader(r) if unsigned => {
+            read_chunk!(r, rows, max_def, |v: i32| v as u32 as f64)
+        }
+        ColumnReader::Int32ColumnReader(r) => read_chunk!(r, rows, max_def, |v: i32| v as f64),
+        ColumnReader::Int64ColumnReader(r) => read_chunk!(r, rows, max_def, |v: i64| v as f64),
+        ColumnReader::FloatColumnReader(r) => read_chunk!(r, rows, max_def, |v: f32| v as f64),
+        ColumnReader::DoubleColumnReader(r) => read_chunk!(r, rows, max_def, |v: f64| v),
+        _ => {
+            return Err(ParquetError::General(format!(
+                "column `{}` is not numeric",
+                descr.name()
+            )))
+        }
+    })
+}
+
+/// Decode an entire string column chunk. Null values are returned as `None`
+pub(crate) fn read_strings(
+    rg: &dyn RowGroupReader,
+    idx: usize,
+) -> parquet::errors::Result<Vec<Option<String>>> {
+    let rows = rg.metadata().num_rows() as usize;
+    let descr = rg.metadata().column(idx).column_descr_ptr();
+    let max_def = descr.max_def_level();
+
+    Ok(match rg.get_column_reader(idx)? {
+        ColumnReader::ByteArrayColumnReader(r) => {
+            read_chunk!(r, rows, max_def, |v: ByteArray| String::from_utf8_lossy(
+                v.data()
+            )
+            .into_owned())
+        }
+        _ => {
+            return Err(ParquetError::General(format!(
+                "column `{}` is not a string",
+                descr.name()
+            )))
+        }
+    })
+}
+
+/// Decode a required numeric column chunk
+pub(crate) fn read_required(
+    rg: &dyn RowGroupReader,
+    idx: usize,
+) -> parquet::errors::Result<Vec<f64>> {
+    read_column(rg, idx)?
+        .into_iter()
+        .map(|v| {
+            v.ok_or_else(|| ParquetError::General("unexpected null in required column".into()))
+        })
+        .collect()
+}
+
+/// Extract an ion chromatogram: the summed intensity of all ions within
+/// tolerance of the target m/z, for each scan at the requested MS level.
+/// Scans without any matching ions are not reported. Files holding several
+/// runs (with a `file_id` column) get a point per run and scan.
+pub fn xic<R: 'static + ChunkReader>(
+    r: R,
+    query: &XicQuery,
+) -> parquet::errors::Result<Vec<XicPoint>> {
+    let reader = SerializedFileReader::new(r)?;
+    xic_from_reader(&reader, query)
+}
+
+pub(crate) fn xic_from_reader(
+    reader: &dyn FileReader,
+    query: &XicQuery,
+) -> parquet::errors::Result<Vec<XicPoint>> {
+    let scan_idx = column_index(reader, "scan")?;
+    let level_idx = column_index(reader, "level")?;
+    let rt_idx = column_index(reader, "rt")?;
+    let mz_idx = column_index(reader, "mz")?;
+    let int_idx = column_index(reader, "intensity")?;
+    // Merged runs share scan numbers
+    let file_id_idx = column_index(reader, "file_id").ok();
+
+    let (lo, hi) = query.tolerance.bounds(query.mz);
+    let (rt_lo, rt_hi) = rt_bounds(reader, query.rt);
+    let level = query.ms_level as f64;
+
+    let mut points: Vec<XicPoint> = Vec::new();
+    for i in 0..reader.num_row_groups() {
+        let meta = reader.metadata().row_group(i);
+        if !may_contain(meta, mz_idx, lo, hi)
+            || !may_contain(meta, rt_idx, rt_lo, rt_hi)
+            || !may_contain(meta, level_idx, level, level)
+        {
+            continue;
+        }
+
+        let rg = reader.get_row_group(i)?;
+        let scan = read_required(rg.as_ref(), scan_idx)?;
+        let levels = read_required(rg.as_ref(), level_idx)?;
+        let rt = read_required(rg.as_ref(), rt_idx)?;
+        let mz = read_required(rg.as_ref(), mz_idx)?;
+        let intensity = read_required(rg.as_ref(), int_idx)?;
+        let file_id = match file_id_idx {
+            Some(idx) => read_strings(rg.as_ref(), idx)?,
+            None => vec![None; mz.len()],
+        };
+
+        let mut scans = BTreeMap::new();
+        for row in 0..mz.len() {
+            if levels[row] != level
+                || mz[row] < lo
+                || mz[row] > hi
+                || rt[row] < rt_lo
+                || rt[row] > rt_hi
+            {
+                continue;
+            }
+            let scan = scan[row] as u32;
+            scans
+                .entry((file_id[row].as_deref(), scan))
+                .or_insert_with(|| XicPoint {
+                    file_id: file_id[row].clone(),
+                    scan,
+                    rt: rt[row] as f32,
+                    intensity: 0.0,
+                })
+                .intensity += intensity[row] as f32;
+        }
+        points.extend(scans.into_values());
+    }
+
+    Ok(points)
+}
+
+/// Parameters for finding MS2 spectra containing a product ion
+#[derive(Clone, Debug, PartialEq)]
+pub struct FragmentQuery {
+    /// Product ion m/z
+    pub mz: f64,
+    pub tolerance: Tolerance,
+    /// Optionally require the precursor m/z to be within tolerance
+    pub precursor: Option<(f64, Tolerance)>,
+    /// Optional (inclusive) retention time window, in seconds
+    pub rt: Option<(f32, f32)>,
+}
+
+/// An MS2 spectrum containing a matching product ion
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FragmentMatch {
+    pub scan: u32,
+    pub rt: f32,
+    pub precursor_mz: Option<f32>,
+    /// m/z of the most intense matching peak
+    pub mz: f32,
+    /// Intensity of the most intense matching peak
+    pub intensity: f32,
+}
+
+impl From<&[FragmentMatch]> for Table {
+    fn from(matches: &[FragmentMatch]) -> Self {
+        Table::default()
+            .with_column(
+                "scan",
+                Column::UInt(matches.iter().map(|m| m.scan).collect()),
+            )
+            .with_column("rt", Column::Float(matches.iter().map(|m| m.rt).collect()))
+            .with_column(
+                "precursor_mz",
+                Column::OptionalFloat(matches.iter().map(|m| m.precursor_mz).collect()),
+            )
+            .with_column("mz", Column::Float(matches.iter().map(|m| m.mz).collect()))
+            .with_column(
+                "intensity",
+                Column::Float(matches.iter().map(|m| m.intensity).collect()),
+            )
+    }
+}
+
+/// Find all MS2 spectra containing a product ion within tolerance of the
+/// target m/z. If several peaks in a spectrum match, the most intense one is
+/// reported.
+pub fn fragment<R: 'static + ChunkReader>(
+    r: R,
+    query: &FragmentQuery,
+) -> parquet::errors::Result<Vec<FragmentMatch>> {
+    let reader = SerializedFileReader::new(r)?;
+    fragment_from_reader(&reader, query)
+}
+
+pub(crate) fn fragment_from_reader(
+    reader: &dyn FileReader,
+    query: &FragmentQuery,
+) -> parquet::errors::Result<Vec<FragmentMatch>> {
+    let scan_idx = column_index(reader, "scan")?;
+    let level_idx = column_index(reader, "level")?;
+    let rt_idx = column_index(reader, "rt")?;
+    let mz_idx = column_index(reader, "mz")?;
+    let int_idx = column_index(reader, "intensity")?;
+    let pmz_idx = column_index(reader, "precursor_mz")?;
+
+    let (lo, hi) = query.tolerance.bounds(query.mz);
+    let (rt_lo, rt_hi) = rt_bounds(reader, query.rt);
+    let (p_lo, p_hi) = query
+        .precursor
+        .map(|(mz, tol)| tol.bounds(mz))
+        .unwrap_or((f64::MIN, f64::MAX));
+
+    let mut matches: Vec<FragmentMatch> = Vec::new();
+    for i in 0..reader.num_row_groups() {
+        let meta = reader.metadata().row_group(i);
+        if !may_contain(meta, mz_idx, lo, hi)
+            || !may_contain(meta, rt_idx, rt_lo, rt_hi)
+            || !may_contain(meta, level_idx, 2.0, 2.0)
+            || (query.precursor.is_some() && !may_contain(meta, pmz_idx, p_lo, p_hi))
+        {
+            continue;
+        }
+
+        let rg = reader.get_row_group(i)?;
+        let scan = read_required(rg.as_ref(), scan_idx)?;
+        let levels = read_required(rg.as_ref(), level_idx)?;
+        let rt = read_required(rg.as_ref(), rt_idx)?;
+        let mz = read_required(rg.as_ref(), mz_idx)?;
+        let intensity = read_required(rg.as_ref(), int_idx)?;
+        let precursor_mz = read_column(rg.as_ref(), pmz_idx)?;
+
+        let mut scans = BTreeMap::<u32, FragmentMatch>::new();
+        for row in 0..mz.len() {
+            if levels[row] != 2.0
+                || mz[row] < lo
+                || mz[row] > hi
+                || rt[row] < rt_lo
+                || rt[row] > rt_hi
+            {
+                continue;
+            }
+            if query.precursor.is_some()
+                && !precursor_mz[row].is_some_and(|pmz| pmz >= p_lo && pmz <= p_hi)
+            {
+                continue;
+            }
+
+            let found = FragmentMatch {
+                scan: scan[row] as u32,
+                rt: rt[row] as f32,
+                precursor_mz: precursor_mz[row].map(|mz| mz as f32),
+                mz: mz[row] as f32,
+                intensity: intensity[row] as f32,
+            };
+            match scans.get_mut(&found.scan) {
+                Some(best) if best.intensity >= found.intensity => {}
+                Some(best) => *best = found,
+                None => {
+                    scans.insert(found.scan, found);
+                }
+            }
+        }
+        matches.extend(scans.into_values());
+    }
+
+    Ok(matches)
+}
+
+/// Parameters for finding MS2 spectra by precursor m/z
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrecursorQuery {
+    /// Precursor m/z
+    pub mz: f64,
+    pub tolerance: Tolerance,
+    /// Also match spectra whose isolation window contains the target m/z,
+    /// even if the selected ion is outside of tolerance (e.g. for DIA data)
+    pub isolation_window: bool,
+    /// Optional (inclusive) retention time window, in seconds
+    pub rt: Option<(f32, f32)>,
+}
+
+/// An MS2 spectrum with a matching precursor
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PrecursorMatch {
+    pub scan: u32,
+    pub rt: f32,
+    pub precursor_mz: Option<f32>,
+    pub precursor_charge: Option<u32>,
+    pub isolation_lower: Option<f32>,
+    pub isolation_upper: Option<f32>,
+}
+
+impl From<&[PrecursorMatch]> for Table {
+    fn from(matches: &[PrecursorMatch]) -> Self {
+        Table::default()
+            .with_column(
+                "scan",
+                Column::UInt(matches.iter().map(|m| m.scan).collect()),
+            )
+            .with_column("rt", Column::Float(matches.iter().map(|m| m.rt).collect()))
+            .with_column(
+                "precursor_mz",
+                Column::OptionalFloat(matches.iter().map(|m| m.precursor_mz).collect()),
+            )
+            .with_column(
+                "precursor_charge",
+                Column::OptionalUInt(matches.iter().map(|m| m.precursor_charge).collect()),
+            )
+            .with_column(
+                "isolation_lower",
+                Column::OptionalFloat(matches.iter().map(|m| m.isolation_lower).collect()),
+            )
+            .with_column(
+                "isolation_upper",
+                Column::OptionalFloat(matches.iter().map(|m| m.isolation_upper).collect()),
+            )
+    }
+}
+
+/// Find all MS2 spectra whose precursor m/z is within tolerance of the
+/// target (or, optionally, whose isolation window contains the target)
+pub fn precursor<R: 'static + ChunkReader>(
+    r: R,
+    query: &PrecursorQuery,
+) -> parquet::errors::Result<Vec<PrecursorMatch>> {
+    let reader = SerializedFileReader::new(r)?;
+    precursor_from_reader(&reader, query)
+}
+
+pub(crate) fn precursor_from_reader(
+    reader: &dyn FileReader,
+    query: &PrecursorQuery,
+) -> parquet::errors::Result<Vec<PrecursorMatch>> {
+    let scan_idx = column_index(reader, "scan")?;
+    let level_idx = column_index(reader, "level")?;
+    let rt_idx = column_index(reader, "rt")?;
+    let pmz_idx = column_index(reader, "precursor_mz")?;
+    let pz_idx = column_index(reader, "precursor_charge")?;
+    let lo_idx = column_index(reader, "isolation_lower")?;
+    let hi_idx = column_index(reader, "isolation_upper")?;
+
+    let (lo, hi) = query.tolerance.bounds(query.mz);
+    let (rt_lo, rt_hi) = rt_bounds(reader, query.rt);
+
+    let mut matches: Vec<PrecursorMatch> = Vec::new();
+    for i in 0..reader.num_row_groups() {
+        let meta = reader.metadata().row_group(i);
+        let selected = may_contain(meta, pmz_idx, lo, hi);
+        let isolated = query.isolation_window
+            && may_contain(meta, lo_idx, f64::MIN, hi)
+            && may_contain(meta, hi_idx, lo, f64::MAX);
+        if !(selected || isolated)
+            || !may_contain(meta, rt_idx, rt_lo, rt_hi)
+            || !may_contain(meta, level_idx, 2.0, 2.0)
+        {
+            continue;
+        }
+
+        let rg = reader.get_row_group(i)?;
+        let scan = read_required(rg.as_ref(), scan_idx)?;
+        let levels = read_required(rg.as_ref(), level_idx)?;
+        let rt = read_required(rg.as_ref(), rt_idx)?;
+        let precursor_mz = read_column(rg.as_ref(), pmz_idx)?;
+        let precursor_charge = read_column(rg.as_ref(), pz_idx)?;
+        let isolation_lower = read_column(rg.as_ref(), lo_idx)?;
+        let isolation_upper = read_column(rg.as_ref(), hi_idx)?;
+
+        let mut scans = BTreeMap::new();
+        for row in 0..scan.len() {
+            let scan = scan[row] as u32;
+            // Precursor information is repeated for every ion in a scan
+            if scans.contains_key(&scan) || levels[row] != 2.0 || rt[row] < rt_lo || rt[row] > rt_hi
+            {
+                continue;
+            }
+
+            let selected = precursor_mz[row].is_some_and(|mz| mz >= lo && mz <= hi);
+            let isolated = query.isolation_window
+                && matches!(
+                    (isolation_lower[row], isolation_upper[row]),
+                    (Some(l), Some(h)) if l <= hi && h >= lo
+                );
+            if selected || isolated {
+                scans.insert(
+                    scan,
+                    PrecursorMatch {
+                        scan,
+                        rt: rt[row] as f32,
+                        precursor_mz: precursor_mz[row].map(|v| v as f32),
+                        precursor_charge: precursor_charge[row].map(|v| v as u32),
+                        isolation_lower: isolation_lower[row].map(|v| v as f32),
+                        isolation_upper: isolation_upper[row].map(|v| v as f32),
+                    },
+                );
+            }
+        }
+        matches.extend(scans.into_values());
+    }
+
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::RawSpectrum;
+    use crate::write_long::serialize_to_parquet;
+
+    #[test]
+    fn extract_ion_chromatogram() -> anyhow::Result<()> {
+        let spectrum = |ms_level, rt, mz: Vec<f64>| RawSpectrum {
+            ms_level,
+            scan_start_time: rt,
+            intensity: vec![100.0; mz.len()],
+            mz,
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum(1, 1.0, vec![499.999, 500.0, 500.001, 600.0]),
+            spectrum(2, 1.1, vec![500.0]),
+            spectrum(1, 2.0, vec![400.0]),
+            spectrum(1, 3.0, vec![500.002]),
+        ];
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+
+        let mut query = XicQuery {
+            mz: 500.0,
+            tolerance: Tolerance::Ppm(5.0),
+            rt: None,
+            ms_level: 1,
+        };
+        assert_eq!(
+            xic(buf.clone(), &query)?,
+            vec![
+                XicPoint {
+                    file_id: None,
+                    scan: 0,
+                    rt: 1.0,
+                    intensity: 300.0
+                },
+                XicPoint {
+                    file_id: None,
+                    scan: 3,
+                    rt: 3.0,
+                    intensity: 100.0
+                }
+            ]
+        );
+
+        query.rt = Some((2.5, 3.5));
+        assert_eq!(xic(buf, &query)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn xic_of_merged_runs() -> anyhow::Result<()> {
+        use crate::targets::{extract, Target, TargetPoint};
+
+        let spectrum = |rt, intensity| RawSpectrum {
+            ms_level: 1,
+            scan_start_time: rt,
+            mz: vec![500.0],
+            intensity: vec![intensity],
+            ..Default::default()
+        };
+        // Two runs with the same scans and retention times, differing only in
+        // intensity
+        let run = |intensity| -> anyhow::Result<bytes::Bytes> {
+            let spectra = vec![spectrum(1.0, intensity), spectrum(2.0, intensity)];
+            Ok(bytes::Bytes::from(serialize_to_parquet(
+                Vec::new(),
+                &spectra,
+            )?))
+        };
+        let source = |file_id: &str| crate::write_long::Source {
+            file_id: file_id.into(),
+            path: None,
+        };
+        let inputs = vec![Ok((source("a"), run(10.0)?)), Ok((source("b"), run(20.0)?))];
+        let (buf, _) = crate::rewrite::merge(Vec::new(), inputs, &Default::default())?;
+        let buf = bytes::Bytes::from(buf);
+
+        let query = XicQuery {
+            mz: 500.0,
+            tolerance: Tolerance::Ppm(5.0),
+            rt: None,
+            ms_level: 1,
+        };
+        let points = xic(buf.clone(), &query)?;
+        let keys = points
+            .iter()
+            .map(|p| (p.file_id.as_deref(), p.scan, p.intensity))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                (Some("a"), 0, 10.0),
+                (Some("a"), 1, 10.0),
+                (Some("b"), 0, 20.0),
+                (Some("b"), 1, 20.0)
+            ]
+        );
+        let mut csv = Vec::new();
+        Table::from(points.as_slice()).write_csv(&mut csv)?;
+        assert!(csv.starts_with(b"file_id,scan,rt,intensity\na,0,1,10\n"));
+
+        let target = Target {
+            name: "target".into(),
+            mz: 500.0,
+            rt: None,
+        };
+        let xics = extract(buf, &[target], Tolerance::Ppm(5.0), 1)?;
+        assert_eq!(xics[0].points, points);
+        let points = xics[0]
+            .points
+            .iter()
+            .map(|point| TargetPoint {
+                target: "target".into(),
+                point: point.clone(),
+            })
+            .collect::<Vec<_>>();
+        let mut csv = Vec::new();
+        Table::from(points.as_slice()).write_csv(&mut csv)?;
+        assert!(csv.starts_with(b"target,file_id,scan,rt,intensity\ntarget,a,0,"));
+        Ok(())
+    }
+
+    #[test]
+    fn rt_windows_in_seconds_for_files_in_minutes() -> anyhow::Result<()> {
+        use crate::mzml::Precursor;
+        use crate::targets::{extract, Target};
+
+        let ms1 = |rt| RawSpectrum {
+            ms_level: 1,
+            scan_start_time: rt,
+            mz: vec![500.0],
+            intensity: vec![100.0],
+            ..Default::default()
+        };
+        let ms2 = |rt| RawSpectrum {
+            ms_level: 2,
+            scan_start_time: rt,
+            precursors: vec![Precursor {
+                mz: 500.0,
+                ..Default::default()
+            }],
+            mz: vec![200.0],
+            intensity: vec![10.0],
+            ..Default::default()
+        };
+        // Scans at 1, 2 and 3 minutes, with an MS2 spectrum following each
+        let spectra = vec![
+            ms1(60.0),
+            ms2(61.0),
+            ms1(120.0),
+            ms2(121.0),
+            ms1(180.0),
+            ms2(181.0),
+        ];
+        let mut options = crate::write_long::WriterOptions::default();
+        options.set_rt_unit(RtUnit::Minutes);
+        let file = crate::rewrite::SpectrumFile {
+            format: crate::Format::Long,
+            sources: Vec::new(),
+            metadata: Default::default(),
+            spectra,
+        };
+        let buf = bytes::Bytes::from(file.write(Vec::new(), &options)?.0);
+
+        // A window of 100 - 150 seconds only holds the scans around 2 minutes
+        let rt = Some((100.0, 150.0));
+        let tolerance = Tolerance::Ppm(10.0);
+        let query = XicQuery {
+            mz: 500.0,
+            tolerance,
+            rt,
+            ms_level: 1,
+        };
+        let points = xic(buf.clone(), &query)?;
+        assert_eq!(points.iter().map(|p| p.scan).collect::<Vec<_>>(), [2]);
+        assert_eq!(points[0].rt, 2.0);
+
+        let query = FragmentQuery {
+            mz: 200.0,
+            tolerance,
+            precursor: None,
+            rt,
+        };
+        let matches = fragment(buf.clone(), &query)?;
+        assert_eq!(matches.iter().map(|m| m.scan).collect::<Vec<_>>(), [3]);
+
+        let query = PrecursorQuery {
+            mz: 500.0,
+            tolerance,
+            isolation_window: false,
+            rt,
+        };
+        let matches = precursor(buf.clone(), &query)?;
+        assert_eq!(matches.iter().map(|m| m.scan).collect::<Vec<_>>(), [3]);
+
+        let target = Target {
+            name: "target".into(),
+            mz: 500.0,
+            rt,
+        };
+        let xics = extract(buf, &[target], tolerance, 1)?;
+        let scans = xics[0].points.iter().map(|p| p.scan).collect::<Vec<_>>();
+        assert_eq!(scans, [2]);
+        Ok(())
+    }
+
+    #[test]
+    fn fragment_tolerances() -> anyhow::Result<()> {
+        use crate::mzml::Precursor;
+
+        let spectrum = |ms_level, precursor_mz: Option<f64>, mz: Vec<f64>, intensity| RawSpectrum {
+            ms_level,
+            scan_start_time: 10.0,
+            precursors: precursor_mz
+                .map(|mz| Precursor {
+                    mz,
+                    ..Default::default()
+                })
+                .into_iter()
+                .collect(),
+            mz,
+            intensity,
+            ..Default::default()
+        };
+        let spectra = vec![
+            // MS1 peaks are never product ions
+            spectrum(1, None, vec![300.0], vec![1000.0]),
+            // Several matching peaks: the most intense is reported
+            spectrum(
+                2,
+                Some(500.0),
+                vec![299.9995, 300.0004, 450.0],
+                vec![5.0, 50.0, 1.0],
+            ),
+            // 10 ppm away
+            spectrum(2, Some(600.0), vec![300.003], vec![20.0]),
+            spectrum(2, Some(700.0), vec![350.0], vec![30.0]),
+        ];
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+
+        let mut query = FragmentQuery {
+            mz: 300.0,
+            tolerance: Tolerance::Ppm(5.0),
+            precursor: None,
+            rt: None,
+        };
+        let found = fragment(buf.clone(), &query)?;
+        assert_eq!(
+            found,
+            vec![FragmentMatch {
+                scan: 1,
+                rt: 10.0,
+                precursor_mz: Some(500.0),
+                mz: 300.0004,
+                intensity: 50.0,
+            }]
+        );
+
+        query.tolerance = Tolerance::Ppm(20.0);
+        let scans = |found: Vec<FragmentMatch>| found.iter().map(|m| m.scan).collect::<Vec<_>>();
+        assert_eq!(scans(fragment(buf.clone(), &query)?), [1, 2]);
+        query.tolerance = Tolerance::Da(0.01);
+        assert_eq!(scans(fragment(buf.clone(), &query)?), [1, 2]);
+
+        // Only spectra of a precursor within its own tolerance
+        query.precursor = Some((600.001, Tolerance::Da(0.002)));
+        assert_eq!(scans(fragment(buf.clone(), &query)?), [2]);
+        query.precursor = Some((600.001, Tolerance::Ppm(1.0)));
+        assert!(fragment(buf.clone(), &query)?.is_empty());
+
+        query.precursor = None;
+        query.rt = Some((20.0, 30.0));
+        assert!(fragment(buf, &query)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn precursor_windows() -> anyhow::Result<()> {
+        use crate::mzml::Precursor;
+
+        let spectrum = |ms_level, precursor: Option<Precursor>| RawSpectrum {
+            ms_level,
+            scan_start_time: 10.0,
+            precursors: precursor.into_iter().collect(),
+            mz: vec![150.0, 250.0],
+            intensity: vec![1.0, 2.0],
+            ..Default::default()
+        };
+        let selected = |mz, charge| Precursor {
+            mz,
+            charge: Some(charge),
+            ..Default::default()
+        };
+        // An isolation window of 500 - 525, with the selected ion at its center
+        let dia = Precursor {
+            mz: 512.5,
+            isolation_window_target: Some(512.5),
+            isolation_window_lower: Some(12.5),
+            isolation_window_upper: Some(12.5),
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum(1, None),
+            spectrum(2, Some(selected(500.002, 2))),
+            spectrum(2, Some(selected(500.02, 3))),
+            spectrum(2, Some(dia)),
+        ];
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+
+        let mut query = PrecursorQuery {
+            mz: 500.0,
+            tolerance: Tolerance::Ppm(10.0),
+            isolation_window: false,
+            rt: None,
+        };
+        // One match per scan, even though precursors are repeated for every ion
+        let found = precursor(buf.clone(), &query)?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].scan, 1);
+        assert_eq!(found[0].precursor_charge, Some(2));
+        assert_eq!(found[0].precursor_mz, Some(500.002));
+
+        let scans = |found: Vec<PrecursorMatch>| found.iter().map(|m| m.scan).collect::<Vec<_>>();
+        query.tolerance = Tolerance::Da(0.05);
+        assert_eq!(scans(precursor(buf.clone(), &query)?), [1, 2]);
+
+        // Windows containing the target match, whatever their selected ion
+        query.isolation_window = true;
+        let found = precursor(buf.clone(), &query)?;
+        assert_eq!(scans(found.clone()), [1, 2, 3]);
+        assert_eq!(found[2].isolation_lower, Some(500.0));
+        assert_eq!(found[2].isolation_upper, Some(525.0));
+
+        query.mz = 530.0;
+        assert!(precursor(buf.clone(), &query)?.is_empty());
+        query.mz = 520.0;
+        assert_eq!(scans(precursor(buf.clone(), &query)?), [3]);
+        query.rt = Some((0.0, 5.0));
+        assert!(precursor(buf, &query)?.is_empty());
+        Ok(())
+    }
+}
@@ -1,12 +1,19 @@
-use crate::mzml::{Precursor, RawSpectrum};
+use crate::mzml::{Pixel, Precursor, RawSpectrum};
+use crate::progress::progress_bar;
+use crate::write_long::{
+    RtUnit, CHARGE_COLUMN, EXTRA_PARAMS_COLUMN, NOISE_COLUMNS, PIXEL_COLUMNS,
+    SPECTRUM_INDEX_COLUMN, SPS_MZ_COLUMN,
+};
 use parquet::{
     errors::ParquetError,
     file::{
+        metadata::ParquetMetaData,
         reader::{ChunkReader, FileReader},
         serialized_reader::SerializedFileReader,
     },
     record::{Field, RowColumnIter},
 };
+use std::collections::{btree_map::Entry, BTreeMap, HashMap};
 
 trait ExtractFromField: Sized {
     fn extract(field: &Field) -> parquet::errors::Result<Self>;
@@ -34,6 +41,20 @@ impl ExtractFromField for f32 {
     }
 }
 
+impl ExtractFromField for f64 {
+    fn extract(field: &Field) -> parquet::errors::Result<Self> {
+        match field {
+            Field::Double(f) => Ok(*f),
+            Field::Float(f) => Ok(*f as f64),
+            Field::UInt(f) => Ok(*f as f64),
+            Field::Int(f) => Ok(*f as f64),
+            _ => Err(ParquetError::General(
+                "failed to extract field as a `f64`".into(),
+            )),
+        }
+    }
+}
+
 impl ExtractFromField for u8 {
     fn extract(field: &Field) -> parquet::errors::Result<Self> {
         match field {
@@ -45,6 +66,18 @@ impl ExtractFromField for u8 {
     }
 }
 
+impl ExtractFromField for u32 {
+    fn extract(field: &Field) -> parquet::errors::Result<Self> {
+        match field {
+            Field::UInt(f) => Ok(*f),
+            Field::Int(f) => Ok(*f as u32),
+            _ => Err(ParquetError::General(
+                "failed to extract field as a `u32`".into(),
+            )),
+        }
+    }
+}
+
 impl ExtractFromField for bool {
     fn extract(field: &Field) -> parquet::errors::Result<Self> {
         match field {
@@ -131,20 +164,46 @@ fn get_from_column_iter<T: ExtractFromField>(
     }
 }
 
+/// Like [`get_from_column_iter`], for trailing columns that were added to
+/// the schema later, and are missing from older files
+fn get_trailing_from_column_iter<T: ExtractFromField>(
+    name: &'static str,
+    iter: &mut RowColumnIter<'_>,
+) -> parquet::errors::Result<Option<T>> {
+    match iter.next() {
+        None => Ok(None),
+        Some((header, field)) if header == name => Option::<T>::extract(field),
+        Some((header, _)) => Err(ParquetError::General(format!(
+            "tried to extract field {}, but got {} instead",
+            name, header
+        ))),
+    }
+}
+
+/// Decode the JSON object stored in the `extra_params` column
+fn parse_extra_params(json: &str) -> parquet::errors::Result<Vec<(String, String)>> {
+    let map: BTreeMap<String, String> = serde_json::from_str(json).map_err(|e| {
+        ParquetError::General(format!("invalid `{}` value: {}", EXTRA_PARAMS_COLUMN, e))
+    })?;
+    Ok(map.into_iter().collect())
+}
+
+/// Read all spectra from a wide format mzparquet file, where each spectrum
+/// is stored as a single row with nested m/z and intensity lists
 pub fn deserialize_from_parquet<R: 'static + ChunkReader>(
     r: R,
+) -> parquet::errors::Result<Vec<RawSpectrum>> {
+    read_wide(&SerializedFileReader::new(r)?)
+}
+
+fn read_wide<R: 'static + ChunkReader>(
+    reader: &SerializedFileReader<R>,
 ) -> parquet::errors::Result<Vec<RawSpectrum>> {
     let mut spectra = Vec::new();
-    let reader = SerializedFileReader::new(r)?;
     let nrows = reader.metadata().file_metadata().num_rows();
+    let rt_unit = RtUnit::from_metadata(reader.metadata());
 
-    let pb = indicatif::ProgressBar::new(nrows as u64)
-        .with_message("verifying mzparquet")
-        .with_style(
-            indicatif::ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-                .unwrap(),
-        );
+    let pb = progress_bar(nrows as u64, "verifying mzparquet");
 
     for row in reader.get_row_iter(None)? {
         let row = row?;
@@ -152,17 +211,31 @@ pub fn deserialize_from_parquet<R: 'static + ChunkReader>(
 
         let spectrum = RawSpectrum {
             id: get_from_column_iter::<String>("id", &mut iter)?.into_bytes(),
+            scan_number: None,
             ms_level: get_from_column_iter("ms_level", &mut iter)?,
             centroid: get_from_column_iter("centroid", &mut iter)?,
-            scan_start_time: get_from_column_iter("scan_start_time", &mut iter)?,
+            scan_start_time: rt_unit
+                .to_seconds(get_from_column_iter("scan_start_time", &mut iter)?),
             inverse_ion_mobility: get_from_column_iter("inverse_ion_mobility", &mut iter)?,
+            pixel: None,
+            polarity: None,
             ion_injection_time: get_from_column_iter("ion_injection_time", &mut iter)?,
             total_ion_current: get_from_column_iter("total_ion_current", &mut iter)?,
             precursors: get_from_column_iter::<Option<Vec<Precursor>>>("precursors", &mut iter)?
                 .unwrap_or_default(),
             mz: get_from_column_iter("mz", &mut iter)?,
             intensity: get_from_column_iter("intensity", &mut iter)?,
-            noise: Vec::new(),
+            filter_string: get_trailing_from_column_iter("filter_string", &mut iter)?,
+            ion_mobility: get_trailing_from_column_iter("ion_mobility", &mut iter)?
+                .unwrap_or_default(),
+            noise: get_trailing_from_column_iter("noise", &mut iter)?.unwrap_or_default(),
+            baseline: get_trailing_from_column_iter("baseline", &mut iter)?.unwrap_or_default(),
+            charge: get_trailing_from_column_iter::<Vec<u32>>("charge", &mut iter)?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|z| z as u8)
+                .collect(),
+            extra_params: Vec::new(),
         };
         spectra.push(spectrum);
         pb.inc(1);
@@ -170,3 +243,417 @@ pub fn deserialize_from_parquet<R: 'static + ChunkReader>(
 
     Ok(spectra)
 }
+
+/// Read all spectra from a long format mzparquet file, where each ion is
+/// stored as a separate row.
+///
+/// Rows sharing the same `spectrum_index` value (or `scan`, for files written
+/// before that column was added) are grouped back into a single
+/// [`RawSpectrum`], identified by its `native_id`. Files written before the
+/// `native_id` column was added fall back to using the scan number as the
+/// spectrum `id`. Precursors linked to a parent scan are given the id of the
+/// closest preceding spectrum with that scan number as their `spectrum_ref`.
+/// Spectra without any peaks have no rows, and are therefore not returned.
+pub fn deserialize_long_from_parquet<R: 'static + ChunkReader>(
+    r: R,
+) -> parquet::errors::Result<Vec<RawSpectrum>> {
+    read_long(&SerializedFileReader::new(r)?)
+}
+
+fn read_long<R: 'static + ChunkReader>(
+    reader: &SerializedFileReader<R>,
+) -> parquet::errors::Result<Vec<RawSpectrum>> {
+    // Ions are usually stored in acquisition order, but may be sorted by m/z
+    // within each row group, so spectra are collected by spectrum index
+    let mut spectra: BTreeMap<u32, RawSpectrum> = BTreeMap::new();
+    let mut scans: BTreeMap<u32, u32> = BTreeMap::new();
+    let nrows = reader.metadata().file_metadata().num_rows();
+    let rt_unit = RtUnit::from_metadata(reader.metadata());
+
+    let pb = progress_bar(nrows as u64, "reading mzparquet");
+
+    for row in reader.get_row_iter(None)? {
+        let row = row?;
+        let mut iter = row.get_column_iter();
+
+        let scan: u32 = get_from_column_iter("scan", &mut iter)?;
+        let level: u32 = get_from_column_iter("level", &mut iter)?;
+        let rt = get_from_column_iter("rt", &mut iter)?;
+        let mz = get_from_column_iter("mz", &mut iter)?;
+        let intensity: f64 = get_from_column_iter("intensity", &mut iter)?;
+        let ion_mobility: Option<f32> = get_from_column_iter("ion_mobility", &mut iter)?;
+        // Columns that are looked up by name, as they are missing from older
+        // files or only written if enabled
+        let lookup = |name: &str| row.get_column_iter().find(|(header, _)| *header == name);
+        let spectrum_index = lookup(SPECTRUM_INDEX_COLUMN)
+            .map(|(_, field)| u32::extract(field))
+            .transpose()?;
+        let [noise, baseline] = NOISE_COLUMNS.map(|name| {
+            lookup(name)
+                .map(|(_, field)| Option::<f32>::extract(field))
+                .transpose()
+                .map(Option::flatten)
+        });
+        let charge = lookup(CHARGE_COLUMN)
+            .map(|(_, field)| Option::<u32>::extract(field))
+            .transpose()?
+            .flatten();
+
+        let spectrum = match spectra.entry(spectrum_index.unwrap_or(scan)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                scans.insert(*entry.key(), scan);
+                let lo: Option<f32> = get_from_column_iter("isolation_lower", &mut iter)?;
+                let hi: Option<f32> = get_from_column_iter("isolation_upper", &mut iter)?;
+                let precursor_scan: Option<u32> =
+                    get_from_column_iter("precursor_scan", &mut iter)?;
+                let precursor_mz: Option<f64> = get_from_column_iter("precursor_mz", &mut iter)?;
+                let precursor_charge: Option<u32> =
+                    get_from_column_iter("precursor_charge", &mut iter)?;
+                let total_ion_current =
+                    get_trailing_from_column_iter("total_ion_current", &mut iter)?;
+                let ion_injection_time =
+                    get_trailing_from_column_iter("ion_injection_time", &mut iter)?;
+                let filter_string = get_trailing_from_column_iter("filter_string", &mut iter)?;
+                let native_id: Option<String> =
+                    get_trailing_from_column_iter("native_id", &mut iter)?;
+                // Optional columns, which may follow `file_id`
+                let mut pixel = [None; 3];
+                let mut extra_params = Vec::new();
+                let mut sps_mz = Vec::new();
+                for (header, field) in iter.by_ref() {
+                    if let Some(idx) = PIXEL_COLUMNS.iter().position(|c| c == header) {
+                        pixel[idx] = Option::<u32>::extract(field)?;
+                    } else if header == SPS_MZ_COLUMN {
+                        sps_mz = Option::<Vec<f64>>::extract(field)?.unwrap_or_default();
+                    } else if header == EXTRA_PARAMS_COLUMN {
+                        if let Some(json) = Option::<String>::extract(field)? {
+                            extra_params = parse_extra_params(&json)?;
+                        }
+                    }
+                }
+
+                let precursor = precursor_mz.map(|mz| Precursor {
+                    mz,
+                    charge: precursor_charge.map(|z| z as u8),
+                    spectrum_ref: precursor_scan.map(|scan| scan.to_string().into_bytes()),
+                    isolation_window_lower: lo.map(|lo| (mz - lo as f64) as f32),
+                    isolation_window_upper: hi.map(|hi| (hi as f64 - mz) as f32),
+                    ..Default::default()
+                });
+                // Only the m/z of the other precursors isolated alongside
+                // `precursor_mz` is stored
+                let precursors = match (
+                    precursor,
+                    sps_mz.iter().position(|&mz| Some(mz) == precursor_mz),
+                ) {
+                    (Some(precursor), Some(idx)) => sps_mz
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &mz)| match i == idx {
+                            true => precursor.clone(),
+                            false => Precursor {
+                                mz,
+                                spectrum_ref: precursor.spectrum_ref.clone(),
+                                ..Default::default()
+                            },
+                        })
+                        .collect(),
+                    (precursor, _) => precursor.into_iter().collect(),
+                };
+
+                entry.insert(RawSpectrum {
+                    id: native_id
+                        .map(String::into_bytes)
+                        .unwrap_or_else(|| scan.to_string().into_bytes()),
+                    // Older files hold a synthetic counter in `scan`
+                    scan_number: spectrum_index.map(|_| scan),
+                    ms_level: level as u8,
+                    scan_start_time: rt_unit.to_seconds(rt),
+                    inverse_ion_mobility: ion_mobility,
+                    pixel: match pixel {
+                        [Some(x), Some(y), z] => Some(Pixel { x, y, z }),
+                        _ => None,
+                    },
+                    total_ion_current: total_ion_current.unwrap_or_default(),
+                    ion_injection_time: ion_injection_time.unwrap_or_default(),
+                    filter_string,
+                    precursors,
+                    extra_params,
+                    ..Default::default()
+                })
+            }
+        };
+
+        spectrum.mz.push(mz);
+        spectrum.intensity.push(intensity);
+        spectrum.ion_mobility.push(ion_mobility.unwrap_or_default());
+        if let Some(noise) = noise? {
+            spectrum.noise.push(noise);
+        }
+        if let Some(baseline) = baseline? {
+            spectrum.baseline.push(baseline);
+        }
+        if let Some(charge) = charge {
+            spectrum.charge.push(charge as u8);
+        }
+        pb.inc(1);
+    }
+
+    // Mobilities are only kept per ion if they vary within the spectrum, in
+    // which case the spectrum has no single mobility
+    for spectrum in spectra.values_mut() {
+        let uniform = spectrum
+            .ion_mobility
+            .iter()
+            .all(|&im| Some(im) == spectrum.inverse_ion_mobility);
+        if uniform || spectrum.inverse_ion_mobility.is_none() {
+            spectrum.ion_mobility.clear();
+        } else {
+            spectrum.inverse_ion_mobility = None;
+        }
+        if spectrum.noise.len() != spectrum.mz.len() {
+            spectrum.noise.clear();
+        }
+        if spectrum.baseline.len() != spectrum.mz.len() {
+            spectrum.baseline.clear();
+        }
+        if spectrum.charge.len() != spectrum.mz.len() {
+            spectrum.charge.clear();
+        }
+    }
+
+    // Precursor references were read as parent scan numbers - replace them
+    // with the parent's native id, if the parent has any peaks. Scan numbers
+    // repeat when several runs are merged, so the most recent parent wins
+    let mut ids: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    for (idx, spectrum) in spectra.iter_mut() {
+        for precursor in &mut spectrum.precursors {
+            if let Some(id) = precursor.spectrum_ref.as_ref().and_then(|r| ids.get(r)) {
+                precursor.spectrum_ref = Some(id.clone());
+            }
+        }
+        ids.insert(scans[idx].to_string().into_bytes(), spectrum.id.clone());
+    }
+
+    Ok(spectra.into_values().collect())
+}
+
+/// Layout of the spectra stored in an mzparquet file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// One row per ion, see [`crate::write_long`]
+    Long,
+    /// One row per spectrum, with nested peak lists
+    Wide,
+}
+
+impl Format {
+    /// Determine the layout of an mzparquet file, using the `format` key in
+    /// the footer metadata if present, and otherwise falling back to
+    /// inspecting the columns in the schema
+    pub fn detect(metadata: &ParquetMetaData) -> parquet::errors::Result<Self> {
+        let file = metadata.file_metadata();
+        let tagged = file
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+            .find(|kv| kv.key == "format")
+            .and_then(|kv| kv.value.as_deref());
+
+        match tagged {
+            Some("long") => return Ok(Format::Long),
+            Some("wide") => return Ok(Format::Wide),
+            _ => {}
+        }
+
+        let schema = file.schema();
+        let has_column = |name: &str| schema.get_fields().iter().any(|f| f.name() == name);
+        if has_column("scan") && has_column("mz") {
+            Ok(Format::Long)
+        } else if has_column("id") && has_column("precursors") {
+            Ok(Format::Wide)
+        } else {
+            Err(ParquetError::General(
+                "unable to determine mzparquet format from schema".into(),
+            ))
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "long" => Ok(Format::Long),
+            "wide" => Ok(Format::Wide),
+            _ => Err(format!(
+                "unknown mzparquet format `{}`, expected long or wide",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Long => f.write_str("long"),
+            Format::Wide => f.write_str("wide"),
+        }
+    }
+}
+
+/// Read all spectra from an mzparquet file, regardless of whether it was
+/// written in the long or wide format. Retention times are converted back to
+/// seconds, from the unit recorded in the file (see [`RtUnit::from_metadata`])
+pub fn read_spectra<R: 'static + ChunkReader>(
+    r: R,
+) -> parquet::errors::Result<(Format, Vec<RawSpectrum>)> {
+    read_spectra_from(&SerializedFileReader::new(r)?)
+}
+
+/// Like [`read_spectra`], for a file that has already been opened
+pub(crate) fn read_spectra_from<R: 'static + ChunkReader>(
+    reader: &SerializedFileReader<R>,
+) -> parquet::errors::Result<(Format, Vec<RawSpectrum>)> {
+    let format = Format::detect(reader.metadata())?;
+    let spectra = match format {
+        Format::Long => read_long(reader)?,
+        Format::Wide => read_wide(reader)?,
+    };
+    Ok((format, spectra))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{deserialize_long_from_parquet, read_spectra, Format};
+    use crate::mzml::{Precursor, RawSpectrum};
+    use crate::write_long::serialize_to_parquet;
+    use crate::write_wide;
+
+    #[test]
+    fn wide_format_round_trip() -> anyhow::Result<()> {
+        let spectra = vec![
+            RawSpectrum {
+                id: b"scan=1".to_vec(),
+                ms_level: 1,
+                centroid: true,
+                scan_start_time: 10.0,
+                ion_injection_time: 25.0,
+                total_ion_current: 600.0,
+                filter_string: Some("FTMS + p NSI Full ms [350.00-1600.00]".into()),
+                mz: vec![400.0, 500.0, 600.0],
+                intensity: vec![100.0, 200.0, 300.0],
+                ion_mobility: vec![0.8, 0.9, 1.0],
+                noise: vec![10.0, 12.0, 11.0],
+                baseline: vec![2.0, 2.5, 2.0],
+                charge: vec![2, 0, 3],
+                ..Default::default()
+            },
+            RawSpectrum {
+                id: b"scan=2".to_vec(),
+                ms_level: 2,
+                inverse_ion_mobility: Some(0.9),
+                ..Default::default()
+            },
+            RawSpectrum {
+                id: b"scan=3".to_vec(),
+                ms_level: 3,
+                precursors: vec![
+                    Precursor {
+                        mz: 500.0,
+                        charge: Some(2),
+                        spectrum_ref: Some(b"scan=1".to_vec()),
+                        isolation_window_target: Some(500.0),
+                        ..Default::default()
+                    },
+                    Precursor {
+                        mz: 250.0,
+                        intensity: Some(1e4),
+                        isolation_window_lower: Some(1.0),
+                        isolation_window_upper: Some(1.5),
+                        ..Default::default()
+                    },
+                ],
+                mz: vec![150.0],
+                intensity: vec![10.0],
+                ..Default::default()
+            },
+        ];
+
+        let buf = bytes::Bytes::from(write_wide::serialize_to_parquet(Vec::new(), &spectra)?);
+        assert_eq!(read_spectra(buf)?, (Format::Wide, spectra));
+        Ok(())
+    }
+
+    #[test]
+    fn long_format_round_trip() -> anyhow::Result<()> {
+        let ms1 = RawSpectrum {
+            id: b"scan=1".to_vec(),
+            ms_level: 1,
+            scan_start_time: 10.0,
+            ion_injection_time: 25.0,
+            total_ion_current: 600.0,
+            mz: vec![400.0, 500.0, 600.0],
+            intensity: vec![100.0, 200.0, 300.0],
+            ion_mobility: vec![0.8, 0.9, 1.0],
+            ..Default::default()
+        };
+        let ms2 = RawSpectrum {
+            id: b"scan=2".to_vec(),
+            ms_level: 2,
+            scan_start_time: 10.5,
+            filter_string: Some("ITMS + c NSI d Full ms2 500.00@cid35.00".into()),
+            precursors: vec![Precursor {
+                mz: 500.0,
+                charge: Some(2),
+                spectrum_ref: Some(b"scan=1".to_vec()),
+                isolation_window_lower: Some(1.0),
+                isolation_window_upper: Some(1.5),
+                ..Default::default()
+            }],
+            mz: vec![150.0, 250.0],
+            intensity: vec![10.0, 20.0],
+            ..Default::default()
+        };
+
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &[ms1, ms2])?);
+        let spectra = deserialize_long_from_parquet(buf.clone())?;
+        assert_eq!(read_spectra(buf)?, (Format::Long, spectra.clone()));
+
+        assert_eq!(spectra.len(), 2);
+        assert_eq!(spectra[0].id, b"scan=1");
+        assert_eq!(spectra[0].ms_level, 1);
+        assert_eq!(spectra[0].mz, vec![400.0, 500.0, 600.0]);
+        assert_eq!(spectra[0].intensity, vec![100.0, 200.0, 300.0]);
+        assert_eq!(spectra[0].ion_injection_time, 25.0);
+        assert_eq!(spectra[0].total_ion_current, 600.0);
+        assert_eq!(spectra[0].filter_string, None);
+        assert_eq!(spectra[0].ion_mobility, vec![0.8, 0.9, 1.0]);
+        assert_eq!(spectra[0].inverse_ion_mobility, None);
+        assert!(spectra[0].precursors.is_empty());
+
+        assert_eq!(spectra[1].ms_level, 2);
+        assert_eq!(spectra[1].scan_start_time, 10.5);
+        assert_eq!(
+            spectra[1].filter_string.as_deref(),
+            Some("ITMS + c NSI d Full ms2 500.00@cid35.00")
+        );
+        assert_eq!(spectra[1].mz, vec![150.0, 250.0]);
+        assert!(spectra[1].ion_mobility.is_empty());
+        assert_eq!(
+            spectra[1].precursors,
+            vec![Precursor {
+                mz: 500.0,
+                charge: Some(2),
+                spectrum_ref: Some(b"scan=1".to_vec()),
+                isolation_window_lower: Some(1.0),
+                isolation_window_upper: Some(1.5),
+                ..Default::default()
+            }]
+        );
+        Ok(())
+    }
+}
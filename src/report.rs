@@ -0,0 +1,150 @@
+//! Machine readable summary of a conversion, written with `--report` so that
+//! workflow managers (e.g. Nextflow or Snakemake) can check which files were
+//! converted without parsing the log.
+//!
+//! The report is a JSON object with the counts of converted, skipped and
+//! failed files, and an entry per file with its outputs, the number of
+//! spectra and peaks written, input and output sizes in bytes, and how long
+//! the file took to convert.
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Outcome of converting a file
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    #[default]
+    Converted,
+    Skipped,
+    Failed,
+}
+
+/// A file written while converting an input
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Output {
+    pub path: String,
+    pub bytes: u64,
+}
+
+/// A converted (or skipped, or failed) input file
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileReport {
+    pub input: String,
+    pub status: Status,
+    pub outputs: Vec<Output>,
+    pub spectra: usize,
+    pub peaks: usize,
+    /// Size of the input, if it could be determined
+    pub input_bytes: Option<u64>,
+    pub output_bytes: u64,
+    pub seconds: f64,
+    pub warnings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FileReport {
+    pub fn new<S: Into<String>>(input: S) -> Self {
+        FileReport {
+            input: input.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Record a file written for this input
+    pub fn push_output<S: Into<String>>(&mut self, path: S, bytes: u64) {
+        self.output_bytes += bytes;
+        self.outputs.push(Output {
+            path: path.into(),
+            bytes,
+        });
+    }
+}
+
+/// Summary of every file handled by an invocation
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub version: String,
+    /// When the invocation started, in seconds since the epoch
+    pub started: u64,
+    pub seconds: f64,
+    pub converted: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub files: Vec<FileReport>,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Report {
+            version: env!("CARGO_PKG_VERSION").into(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            seconds: 0.0,
+            converted: 0,
+            skipped: 0,
+            failed: 0,
+            files: Vec::new(),
+        }
+    }
+}
+
+impl Report {
+    /// Add a file to the report
+    pub fn push(&mut self, file: FileReport) {
+        match file.status {
+            Status::Converted => self.converted += 1,
+            Status::Skipped => self.skipped += 1,
+            Status::Failed => self.failed += 1,
+        }
+        self.files.push(file);
+    }
+
+    /// Record how long the invocation took, and sort the files by input path
+    pub fn finish(&mut self, elapsed: Duration) {
+        self.seconds = elapsed.as_secs_f64();
+        self.files.sort_by(|a, b| a.input.cmp(&b.input));
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec_pretty(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summarize_files() -> anyhow::Result<()> {
+        let mut report = Report::default();
+        let mut file = FileReport::new("b.mzML");
+        file.spectra = 10;
+        file.peaks = 1000;
+        file.push_output("out/b.pos.mzparquet", 300);
+        file.push_output("out/b.neg.mzparquet", 200);
+        report.push(file);
+        report.push(FileReport {
+            status: Status::Failed,
+            error: Some("unexpected end of file".into()),
+            ..FileReport::new("a.mzML")
+        });
+        report.finish(Duration::from_millis(1500));
+
+        assert_eq!((report.converted, report.skipped, report.failed), (1, 0, 1));
+        assert_eq!(report.files[1].output_bytes, 500);
+
+        let json: serde_json::Value = serde_json::from_slice(&report.to_json()?)?;
+        assert_eq!(json["seconds"], 1.5);
+        assert_eq!(json["files"][0]["input"], "a.mzML");
+        assert_eq!(json["files"][0]["status"], "failed");
+        assert_eq!(
+            json["files"][1]["outputs"][1]["path"],
+            "out/b.neg.mzparquet"
+        );
+        assert!(json["files"][1].get("error").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+//! Rewrite existing mzparquet files, without going back to mzML.
+//!
+//! Files are read back into [`RawSpectrum`]s and written out again, so the
+//! same writer options are available as when converting from mzML.
+use crate::metadata::RunMetadata;
+use crate::mzml::RawSpectrum;
+use crate::reader::{read_spectra_from, Format};
+use crate::write_long::{build_schema, writer_properties, ChunkWriter, Source, WriterOptions};
+use crate::write_wide;
+use parquet::{
+    file::{
+        reader::{ChunkReader, FileReader},
+        serialized_reader::SerializedFileReader,
+        writer::SerializedFileWriter,
+    },
+    schema::types::SchemaDescriptor,
+};
+use std::{collections::BTreeSet, io::Write};
+
+/// The spectra of an existing mzparquet file, loaded so that they can be
+/// written out again
+pub struct SpectrumFile {
+    pub format: Format,
+    /// Runs recorded in the footer metadata, see [`Source`]
+    pub sources: Vec<Source>,
+    /// Run metadata recorded in the footer, see [`RunMetadata`]
+    pub metadata: RunMetadata,
+    pub spectra: Vec<RawSpectrum>,
+}
+
+impl SpectrumFile {
+    pub fn read<R: 'static + ChunkReader>(r: R) -> anyhow::Result<Self> {
+        Self::read_from(&SerializedFileReader::new(r)?)
+    }
+
+    /// Like [`SpectrumFile::read`], for a file that has already been opened
+    pub(crate) fn read_from<R: 'static + ChunkReader>(
+        reader: &SerializedFileReader<R>,
+    ) -> anyhow::Result<Self> {
+        let sources = Source::from_metadata(reader.metadata())?;
+        let metadata = RunMetadata::from_metadata(reader.metadata())?;
+        let (format, spectra) = read_spectra_from(reader)?;
+        Ok(SpectrumFile {
+            format,
+            sources,
+            metadata,
+            spectra,
+        })
+    }
+
+    /// Distinct MS levels, in ascending order
+    pub fn ms_levels(&self) -> Vec<u8> {
+        let levels = self.spectra.iter().map(|s| s.ms_level);
+        levels.collect::<BTreeSet<_>>().into_iter().collect()
+    }
+
+    /// Write every spectrum, in the same format as the original file, with
+    /// new writer options. Returns the writer and the number of spectra
+    /// written
+    pub fn write<W: Write + Send>(
+        &self,
+        w: W,
+        options: &WriterOptions,
+    ) -> anyhow::Result<(W, usize)> {
+        self.write_filtered(w, options, |_| true)
+    }
+
+    /// Write the spectra at MS level `level`, in the same format as the
+    /// original file, returning the writer and the number of spectra written.
+    ///
+    /// Long format scans are numbered as if every spectrum were written, so
+    /// `precursor_scan` still refers to the parent scan in the file holding
+    /// the other level
+    pub fn write_ms_level<W: Write + Send>(
+        &self,
+        level: u8,
+        w: W,
+        options: &WriterOptions,
+    ) -> anyhow::Result<(W, usize)> {
+        self.write_filtered(w, options, |spectrum| spectrum.ms_level == level)
+    }
+
+    fn write_filtered<W, F>(
+        &self,
+        w: W,
+        options: &WriterOptions,
+        keep: F,
+    ) -> anyhow::Result<(W, usize)>
+    where
+        W: Write + Send,
+        F: Fn(&RawSpectrum) -> bool,
+    {
+        match self.format {
+            Format::Long => {
+                let mut options = options.clone();
+                match self.sources.as_slice() {
+                    [] => {}
+                    [source] => {
+                        options.set_source(Some(source.clone()));
+                    }
+                    _ => anyhow::bail!(
+                        "file contains several merged runs, which cannot be rewritten"
+                    ),
+                }
+                if self.spectra.iter().any(|s| s.pixel.is_some()) {
+                    options.set_pixel_columns(true);
+                }
+                if self.spectra.iter().any(|s| !s.extra_params.is_empty()) {
+                    options.set_extra_params(true);
+                }
+                if self.spectra.iter().any(|s| !s.noise.is_empty()) {
+                    options.set_noise_columns(true);
+                }
+                if self.spectra.iter().any(|s| !s.charge.is_empty()) {
+                    options.set_charge_column(true);
+                }
+
+                let schema = build_schema(&options)?;
+                let sd = SchemaDescriptor::new(schema.clone().into());
+                let properties = writer_properties("long", &options)?;
+                let mut writer = SerializedFileWriter::new(w, schema.into(), properties.clone())?;
+                let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, properties);
+                chunk_writer
+                    .set_row_group_size(options.row_group_size)
+                    .set_progress(options.progress.clone());
+                if let Some(source) = &options.source {
+                    chunk_writer.set_source(source.clone());
+                }
+
+                let mut count = 0;
+                for spectrum in &self.spectra {
+                    if keep(spectrum) {
+                        chunk_writer.write_spectrum(spectrum)?;
+                        count += 1;
+                    } else {
+                        chunk_writer.skip_spectrum(spectrum);
+                    }
+                }
+                chunk_writer.finish()?;
+                for kv in self.metadata.to_key_value()? {
+                    writer.append_key_value_metadata(kv);
+                }
+                Ok((writer.into_inner()?, count))
+            }
+            Format::Wide => {
+                let schema = write_wide::build_schema()?;
+                let sd = SchemaDescriptor::new(schema.clone().into());
+                let properties = writer_properties("wide", options)?;
+                let mut writer = SerializedFileWriter::new(w, schema.into(), properties.clone())?;
+                let mut chunk_writer = write_wide::ChunkWriter::new(&mut writer, &sd, properties);
+                chunk_writer
+                    .set_row_group_size(options.row_group_size)
+                    .set_progress(options.progress.clone());
+
+                let mut count = 0;
+                for spectrum in &self.spectra {
+                    if keep(spectrum) {
+                        chunk_writer.write_spectrum(spectrum)?;
+                        count += 1;
+                    } else {
+                        chunk_writer.skip_spectrum(spectrum);
+                    }
+                }
+                chunk_writer.finish()?;
+                for kv in self.metadata.to_key_value()? {
+                    writer.append_key_value_metadata(kv);
+                }
+                Ok((writer.into_inner()?, count))
+            }
+        }
+    }
+}
+
+/// Read the spectra of an input file, along with the run they came from.
+/// Files converted with a [`Source`] keep it, while `fallback` is used for
+/// everything else
+fn read_source<R: 'static + ChunkReader>(
+    fallback: Source,
+    r: R,
+) -> anyhow::Result<(Source, Vec<RawSpectrum>)> {
+    let file = SpectrumFile::read(r)?;
+    let source = match file.sources.as_slice() {
+        [] => fallback,
+        [source] => source.clone(),
+        _ => anyhow::bail!(
+            "`{}` already contains several runs, merge the original files instead",
+            fallback.file_id
+        ),
+    };
+    Ok((source, file.spectra))
+}
+
+/// Concatenate several mzparquet files (in either format) into a single long
+/// format file, returning the writer and the number of spectra written.
+///
+/// Inputs are read one at a time, in order. Scans are renumbered
+/// sequentially across all inputs, and every row is tagged with the
+/// `file_id` of the run it came from, with the runs recorded in the footer
+/// metadata
+pub fn merge<W, R, I>(w: W, inputs: I, options: &WriterOptions) -> anyhow::Result<(W, usize)>
+where
+    W: Write + Send,
+    R: 'static + ChunkReader,
+    I: IntoIterator<Item = anyhow::Result<(Source, R)>>,
+{
+    let mut inputs = inputs
+        .into_iter()
+        .map(|input| input.and_then(|(source, r)| read_source(source, r)));
+    let first = inputs
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no input files to merge"))??;
+
+    let mut options = options.clone();
+    options.set_source(Some(first.0.clone()));
+    // Imaging runs keep their pixel columns
+    if first.1.iter().any(|s| s.pixel.is_some()) {
+        options.set_pixel_columns(true);
+    }
+    if first.1.iter().any(|s| !s.extra_params.is_empty()) {
+        options.set_extra_params(true);
+    }
+    if first.1.iter().any(|s| !s.noise.is_empty()) {
+        options.set_noise_columns(true);
+    }
+    if first.1.iter().any(|s| !s.charge.is_empty()) {
+        options.set_charge_column(true);
+    }
+    let schema = build_schema(&options)?;
+    let sd = SchemaDescriptor::new(schema.clone().into());
+    let properties = writer_properties("long", &options)?;
+
+    let mut writer = SerializedFileWriter::new(w, schema.into(), properties.clone())?;
+    let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, properties);
+    chunk_writer
+        .set_row_group_size(options.row_group_size)
+        .set_progress(options.progress.clone());
+
+    let mut count = 0;
+    for input in std::iter::once(Ok(first)).chain(inputs) {
+        let (source, spectra) = input?;
+        chunk_writer.set_source(source);
+        for spectrum in &spectra {
+            chunk_writer.write_spectrum(spectrum)?;
+        }
+        count += spectra.len();
+    }
+    chunk_writer.finish()?;
+    Ok((writer.into_inner()?, count))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::Precursor;
+    use crate::write_long::serialize_to_parquet;
+
+    #[test]
+    fn merge_runs() -> anyhow::Result<()> {
+        let run = |rt: f32| {
+            vec![
+                RawSpectrum {
+                    id: b"scan=1".to_vec(),
+                    ms_level: 1,
+                    scan_start_time: rt,
+                    mz: vec![400.0, 500.0],
+                    intensity: vec![1.0, 2.0],
+                    ..Default::default()
+                },
+                RawSpectrum {
+                    id: b"scan=2".to_vec(),
+                    ms_level: 2,
+                    scan_start_time: rt + 1.0,
+                    precursors: vec![Precursor {
+                        mz: 500.0,
+                        spectrum_ref: Some(b"scan=1".to_vec()),
+                        ..Default::default()
+                    }],
+                    mz: vec![150.0],
+                    intensity: vec![3.0],
+                    ..Default::default()
+                },
+            ]
+        };
+        let source = |file_id: &str| Source {
+            file_id: file_id.into(),
+            path: None,
+        };
+
+        let a = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &run(1.0))?);
+        let b = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &run(5.0))?);
+        let inputs = vec![Ok((source("a"), a)), Ok((source("b"), b))];
+        let (buf, count) = merge(Vec::new(), inputs, &WriterOptions::default())?;
+        assert_eq!(count, 4);
+
+        let reader = SerializedFileReader::new(bytes::Bytes::from(buf))?;
+        assert_eq!(
+            Source::from_metadata(reader.metadata())?,
+            vec![source("a"), source("b")]
+        );
+
+        let (_, spectra) = read_spectra_from(&reader)?;
+        let rts = spectra
+            .iter()
+            .map(|s| s.scan_start_time)
+            .collect::<Vec<_>>();
+        assert_eq!(rts, vec![1.0, 2.0, 5.0, 6.0]);
+        assert_eq!(
+            spectra[3].precursors[0].spectrum_ref,
+            Some(b"scan=1".to_vec())
+        );
+
+        // Each run keeps its own scan numbers, and precursors link to the
+        // parent scan within the same run
+        let row_group = reader.get_row_group(0)?;
+        let scan = crate::query::read_column(row_group.as_ref(), 0)?;
+        assert_eq!(
+            scan,
+            vec![
+                Some(0.0),
+                Some(0.0),
+                Some(1.0),
+                Some(0.0),
+                Some(0.0),
+                Some(1.0)
+            ]
+        );
+        let precursor_scan = crate::query::read_column(row_group.as_ref(), 8)?;
+        assert_eq!(
+            precursor_scan,
+            vec![None, None, Some(0.0), None, None, Some(0.0)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn split_by_ms_level() -> anyhow::Result<()> {
+        let spectra = (0..4)
+            .map(|scan| RawSpectrum {
+                id: format!("scan={}", scan).into_bytes(),
+                ms_level: if scan % 2 == 0 { 1 } else { 2 },
+                precursors: (scan % 2 == 1)
+                    .then(|| Precursor {
+                        mz: 500.0,
+                        spectrum_ref: Some(format!("scan={}", scan - 1).into_bytes()),
+                        ..Default::default()
+                    })
+                    .into_iter()
+                    .collect(),
+                mz: vec![100.0 * scan as f64 + 100.0],
+                intensity: vec![1.0],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+
+        let file = SpectrumFile::read(buf)?;
+        assert_eq!(file.ms_levels(), vec![1, 2]);
+
+        let options = WriterOptions::default();
+        let (ms1, count) = file.write_ms_level(1, Vec::new(), &options)?;
+        assert_eq!(count, 2);
+        let (ms2, count) = file.write_ms_level(2, Vec::new(), &options)?;
+        assert_eq!(count, 2);
+
+        let ms1 = SerializedFileReader::new(bytes::Bytes::from(ms1))?;
+        let ms2 = SerializedFileReader::new(bytes::Bytes::from(ms2))?;
+        let scans = |reader: &SerializedFileReader<bytes::Bytes>, idx| {
+            crate::query::read_column(reader.get_row_group(0)?.as_ref(), idx)
+        };
+        assert_eq!(scans(&ms1, 0)?, vec![Some(0.0), Some(2.0)]);
+        assert_eq!(scans(&ms2, 0)?, vec![Some(1.0), Some(3.0)]);
+        // MS2 precursors still point at the MS1 scans
+        assert_eq!(scans(&ms2, 8)?, vec![Some(0.0), Some(2.0)]);
+        Ok(())
+    }
+}
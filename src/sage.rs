@@ -0,0 +1,88 @@
+//! Read mzparquet files directly into [`sage_core`] spectra, so that Sage can
+//! search them in-process instead of converting back through mzML.
+//!
+//! Spectra are read with [`crate::reader::read_spectra`], so both long and
+//! wide format files are supported, and retention times are in seconds
+//! whatever unit the file was written with. Sage works in single precision:
+//! m/z values are downcast to `f32`, and spectrum ids are decoded as UTF-8
+//! (lossily, for the rare vendor ids that are not).
+//!
+//! MS1 spectra are returned as well, as Sage uses them for LFQ and
+//! chimeric searches; drop them with [`sage_core::spectrum::RawSpectrum::ms_level`]
+//! if only MS2 spectra are needed.
+use crate::mzml::{Precursor, RawSpectrum};
+use crate::reader::read_spectra;
+use parquet::file::reader::ChunkReader;
+use sage_core::mass::Tolerance;
+use sage_core::spectrum::{
+    self as sage, Peak, ProcessedSpectrum, Representation, SpectrumProcessor,
+};
+
+/// Convert a precursor to Sage's representation, with the isolation window
+/// as a tolerance around the precursor m/z
+pub fn convert_precursor(precursor: &Precursor) -> sage::Precursor {
+    let isolation_window = match (
+        precursor.isolation_window_lower,
+        precursor.isolation_window_upper,
+    ) {
+        (Some(lo), Some(hi)) => Some(Tolerance::Da(-lo, hi)),
+        _ => None,
+    };
+    sage::Precursor {
+        mz: precursor.mz as f32,
+        intensity: precursor.intensity,
+        charge: precursor.charge,
+        spectrum_ref: precursor
+            .spectrum_ref
+            .as_deref()
+            .map(|r| String::from_utf8_lossy(r).into_owned()),
+        isolation_window,
+    }
+}
+
+/// Convert a spectrum to Sage's representation. `file_id` identifies the
+/// file in Sage's results, and is the index of the file in the search
+pub fn convert_spectrum(spectrum: &RawSpectrum, file_id: usize) -> sage::RawSpectrum {
+    sage::RawSpectrum {
+        file_id,
+        ms_level: spectrum.ms_level,
+        id: String::from_utf8_lossy(&spectrum.id).into_owned(),
+        precursors: spectrum.precursors.iter().map(convert_precursor).collect(),
+        representation: match spectrum.centroid {
+            true => Representation::Centroid,
+            false => Representation::Profile,
+        },
+        scan_start_time: spectrum.scan_start_time,
+        ion_injection_time: spectrum.ion_injection_time,
+        total_ion_current: spectrum.total_ion_current,
+        mz: spectrum.mz.iter().map(|&mz| mz as f32).collect(),
+        intensity: spectrum.intensity.iter().map(|&i| i as f32).collect(),
+        ..Default::default()
+    }
+}
+
+/// Read all spectra of an mzparquet file as Sage raw spectra
+pub fn read_raw_spectra<R: 'static + ChunkReader>(
+    r: R,
+    file_id: usize,
+) -> parquet::errors::Result<Vec<sage::RawSpectrum>> {
+    let (_, spectra) = read_spectra(r)?;
+    Ok(spectra
+        .iter()
+        .map(|spectrum| convert_spectrum(spectrum, file_id))
+        .collect())
+}
+
+/// Read all spectra of an mzparquet file and process them with `processor`,
+/// ready to be searched by Sage
+pub fn read_processed_spectra<R: 'static + ChunkReader>(
+    r: R,
+    file_id: usize,
+    processor: &SpectrumProcessor,
+) -> parquet::errors::Result<Vec<ProcessedSpectrum<Peak>>> {
+    let (_, spectra) = read_spectra(r)?;
+    Ok(spectra
+        .iter()
+        .map(|spectrum| processor.process(convert_spectrum(spectrum, file_id)))
+        .collect())
+}
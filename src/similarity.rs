@@ -0,0 +1,341 @@
+//! Spectral similarity search, scoring query spectra against the MS2 spectra
+//! of long format files.
+//!
+//! Spectra are compared with the greedy cosine score used by molecular
+//! networking tools: peak pairs within tolerance are ranked by the product
+//! of their intensities, each peak is matched at most once, and the summed
+//! products are normalized by the norms of both spectra. The modified cosine
+//! also pairs peaks shifted by the difference between the precursor m/z of
+//! the two spectra, matching analogues that differ by a modification.
+//!
+//! Query spectra are read from MGF files, or from an inline `mz:intensity`
+//! peak list. Only MS2 row groups are decoded, one at a time.
+use crate::output::{Column, Table};
+use crate::query::{column_index, may_contain, read_column, read_required, Tolerance};
+use parquet::file::{
+    reader::{ChunkReader, FileReader},
+    serialized_reader::SerializedFileReader,
+};
+use std::collections::BTreeMap;
+
+/// A spectrum to search for
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QuerySpectrum {
+    /// Name reported with each match, the `TITLE` of MGF spectra
+    pub name: String,
+    /// Required for the modified cosine
+    pub precursor_mz: Option<f64>,
+    /// (m/z, intensity) pairs
+    pub peaks: Vec<(f64, f32)>,
+}
+
+impl QuerySpectrum {
+    /// Parse a comma or whitespace separated list of `mz:intensity` peaks
+    pub fn from_peak_list(name: &str, peaks: &str) -> anyhow::Result<Self> {
+        let peaks = peaks
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|peak| !peak.is_empty())
+            .map(|peak| {
+                let (mz, intensity) = peak
+                    .split_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("expected `mz:intensity`, got `{}`", peak))?;
+                Ok((mz.parse()?, intensity.parse()?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(QuerySpectrum {
+            name: name.into(),
+            precursor_mz: None,
+            peaks,
+        })
+    }
+}
+
+/// Parse the spectra of an MGF file. `TITLE` and `PEPMASS` are read from
+/// each spectrum, and other parameters are ignored
+pub fn parse_mgf(text: &str) -> anyhow::Result<Vec<QuerySpectrum>> {
+    let mut spectra = Vec::new();
+    let mut current: Option<QuerySpectrum> = None;
+    for (line, text) in text.lines().enumerate().map(|(n, l)| (n + 1, l.trim())) {
+        if text.is_empty() || text.starts_with(['#', ';', '!', '/']) {
+            continue;
+        }
+        let spectrum = match (text, current.as_mut()) {
+            ("BEGIN IONS", None) => {
+                current = Some(QuerySpectrum {
+                    name: format!("spectrum {}", spectra.len() + 1),
+                    ..Default::default()
+                });
+                continue;
+            }
+            ("END IONS", Some(_)) => {
+                spectra.extend(current.take());
+                continue;
+            }
+            (_, Some(spectrum)) => spectrum,
+            (_, None) => continue,
+        };
+        match text.split_once('=') {
+            Some(("TITLE", title)) => spectrum.name = title.into(),
+            Some(("PEPMASS", mass)) => {
+                let mz = mass.split_whitespace().next().unwrap_or_default();
+                spectrum.precursor_mz = Some(
+                    mz.parse()
+                        .map_err(|e| anyhow::anyhow!("line {}: invalid PEPMASS: {}", line, e))?,
+                );
+            }
+            Some(_) => {}
+            None => {
+                let mut fields = text.split_whitespace();
+                let peak = fields
+                    .next()
+                    .zip(fields.next())
+                    .and_then(|(mz, int)| Some((mz.parse().ok()?, int.parse().ok()?)))
+                    .ok_or_else(|| anyhow::anyhow!("line {}: invalid peak `{}`", line, text))?;
+                spectrum.peaks.push(peak);
+            }
+        }
+    }
+    Ok(spectra)
+}
+
+/// Parameters for a similarity search
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimilarityQuery {
+    pub spectra: Vec<QuerySpectrum>,
+    /// Tolerance for matching fragment peaks
+    pub tolerance: Tolerance,
+    /// Use the modified cosine, which needs precursor m/z values
+    pub modified: bool,
+    /// Number of matches reported for each query spectrum
+    pub top_k: usize,
+}
+
+/// An MS2 spectrum similar to a query spectrum
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimilarityMatch {
+    pub query: String,
+    pub scan: u32,
+    pub rt: f32,
+    pub precursor_mz: Option<f32>,
+    pub score: f32,
+    /// Number of peak pairs contributing to the score
+    pub matched_peaks: u32,
+}
+
+impl From<&[SimilarityMatch]> for Table {
+    fn from(matches: &[SimilarityMatch]) -> Self {
+        Table::default()
+            .with_column(
+                "query",
+                Column::Str(matches.iter().map(|m| m.query.clone()).collect()),
+            )
+            .with_column(
+                "scan",
+                Column::UInt(matches.iter().map(|m| m.scan).collect()),
+            )
+            .with_column("rt", Column::Float(matches.iter().map(|m| m.rt).collect()))
+            .with_column(
+                "precursor_mz",
+                Column::OptionalFloat(matches.iter().map(|m| m.precursor_mz).collect()),
+            )
+            .with_column(
+                "score",
+                Column::Float(matches.iter().map(|m| m.score).collect()),
+            )
+            .with_column(
+                "matched_peaks",
+                Column::UInt(matches.iter().map(|m| m.matched_peaks).collect()),
+            )
+    }
+}
+
+/// Greedy (modified) cosine score of two spectra, with their peaks sorted by
+/// m/z, and the number of matched peak pairs. `shift` is the precursor m/z of
+/// `a` minus that of `b`, for the modified cosine
+pub fn cosine(
+    a: &[(f64, f32)],
+    b: &[(f64, f32)],
+    tolerance: Tolerance,
+    shift: Option<f64>,
+) -> (f32, u32) {
+    let norm = |peaks: &[(f64, f32)]| {
+        peaks
+            .iter()
+            .map(|&(_, int)| (int as f64).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    };
+    let (norm_a, norm_b) = (norm(a), norm(b));
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return (0.0, 0);
+    }
+
+    // Candidate pairs, as (product of intensities, peak of a, peak of b)
+    let mut pairs = Vec::new();
+    for offset in [Some(0.0), shift.filter(|&s| s != 0.0)]
+        .into_iter()
+        .flatten()
+    {
+        for (i, &(mz, int)) in a.iter().enumerate() {
+            let (lo, hi) = tolerance.bounds(mz - offset);
+            let start = b.partition_point(|p| p.0 < lo);
+            let matching = b[start..].iter().take_while(|p| p.0 <= hi);
+            for (j, &(_, other)) in matching.enumerate() {
+                pairs.push((int as f64 * other as f64, i, start + j));
+            }
+        }
+    }
+    pairs.sort_by(|x, y| y.0.total_cmp(&x.0));
+
+    let (mut used_a, mut used_b) = (vec![false; a.len()], vec![false; b.len()]);
+    let (mut score, mut matched) = (0.0, 0);
+    for (product, i, j) in pairs {
+        if !used_a[i] && !used_b[j] {
+            used_a[i] = true;
+            used_b[j] = true;
+            score += product;
+            matched += 1;
+        }
+    }
+    ((score / (norm_a * norm_b)) as f32, matched)
+}
+
+/// Score every MS2 spectrum against each query spectrum, returning the
+/// `top_k` best matches of each, best first
+pub fn search<R: 'static + ChunkReader>(
+    r: R,
+    query: &SimilarityQuery,
+) -> parquet::errors::Result<Vec<SimilarityMatch>> {
+    let reader = SerializedFileReader::new(r)?;
+    search_from_reader(&reader, query)
+}
+
+pub(crate) fn search_from_reader(
+    reader: &dyn FileReader,
+    query: &SimilarityQuery,
+) -> parquet::errors::Result<Vec<SimilarityMatch>> {
+    let scan_idx = column_index(reader, "scan")?;
+    let level_idx = column_index(reader, "level")?;
+    let rt_idx = column_index(reader, "rt")?;
+    let mz_idx = column_index(reader, "mz")?;
+    let int_idx = column_index(reader, "intensity")?;
+    let pmz_idx = column_index(reader, "precursor_mz")?;
+
+    let queries = query
+        .spectra
+        .iter()
+        .map(|spectrum| {
+            let mut peaks = spectrum.peaks.clone();
+            peaks.sort_by(|a, b| a.0.total_cmp(&b.0));
+            peaks
+        })
+        .collect::<Vec<_>>();
+    let mut best = vec![Vec::<SimilarityMatch>::new(); queries.len()];
+
+    for i in 0..reader.num_row_groups() {
+        let meta = reader.metadata().row_group(i);
+        if !may_contain(meta, level_idx, 2.0, 2.0) {
+            continue;
+        }
+
+        let rg = reader.get_row_group(i)?;
+        let scan = read_required(rg.as_ref(), scan_idx)?;
+        let levels = read_required(rg.as_ref(), level_idx)?;
+        let rt = read_required(rg.as_ref(), rt_idx)?;
+        let mz = read_required(rg.as_ref(), mz_idx)?;
+        let intensity = read_required(rg.as_ref(), int_idx)?;
+        let precursor_mz = read_column(rg.as_ref(), pmz_idx)?;
+
+        // Scans, with their retention time, precursor m/z and peaks
+        let mut scans = BTreeMap::<u32, (f32, Option<f64>, Vec<(f64, f32)>)>::new();
+        for row in 0..mz.len() {
+            if levels[row] != 2.0 {
+                continue;
+            }
+            scans
+                .entry(scan[row] as u32)
+                .or_insert_with(|| (rt[row] as f32, precursor_mz[row], Vec::new()))
+                .2
+                .push((mz[row], intensity[row] as f32));
+        }
+
+        for (scan, (rt, pmz, mut peaks)) in scans {
+            peaks.sort_by(|a, b| a.0.total_cmp(&b.0));
+            for ((spectrum, sorted), best) in query.spectra.iter().zip(&queries).zip(&mut best) {
+                let shift = match query.modified {
+                    true => spectrum.precursor_mz.zip(pmz).map(|(q, p)| q - p),
+                    false => None,
+                };
+                let (score, matched_peaks) = cosine(sorted, &peaks, query.tolerance, shift);
+                if best.len() == query.top_k && best.last().is_none_or(|m| m.score >= score) {
+                    continue;
+                }
+                let at = best.partition_point(|m| m.score >= score);
+                best.insert(
+                    at,
+                    SimilarityMatch {
+                        query: spectrum.name.clone(),
+                        scan,
+                        rt,
+                        precursor_mz: pmz.map(|mz| mz as f32),
+                        score,
+                        matched_peaks,
+                    },
+                );
+                best.truncate(query.top_k);
+            }
+        }
+    }
+
+    Ok(best.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::{Precursor, RawSpectrum};
+    use crate::write_long::serialize_to_parquet;
+
+    #[test]
+    fn cosine_search() -> anyhow::Result<()> {
+        let spectrum = |precursor: f64, mz: Vec<f64>| RawSpectrum {
+            ms_level: 2,
+            precursors: vec![Precursor {
+                mz: precursor,
+                ..Default::default()
+            }],
+            intensity: vec![100.0; mz.len()],
+            mz,
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum(500.0, vec![100.0, 200.0, 300.0]),
+            spectrum(500.0, vec![100.0, 250.0, 350.0]),
+            // An analogue, 16 Da heavier: its fragments with the modification
+            // are shifted
+            spectrum(516.0, vec![100.0, 216.0, 316.0]),
+        ];
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+
+        let mgf =
+            "BEGIN IONS\nTITLE=query\nPEPMASS=500.0\n100.0 1.0\n200.0 1.0\n300.0 1.0\nEND IONS\n";
+        let mut query = SimilarityQuery {
+            spectra: parse_mgf(mgf)?,
+            tolerance: Tolerance::Da(0.01),
+            modified: false,
+            top_k: 2,
+        };
+        let matches = search(buf.clone(), &query)?;
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].scan, 0);
+        assert!((matches[0].score - 1.0).abs() < 1E-6);
+        assert!((matches[1].score - 1.0 / 3.0).abs() < 1E-6);
+
+        query.modified = true;
+        let matches = search(buf, &query)?;
+        assert_eq!(matches[1].scan, 2);
+        assert!((matches[1].score - 1.0).abs() < 1E-6);
+        assert_eq!(matches[1].matched_peaks, 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! Read the rows of a long format file within scan, MS level, retention time
+//! and m/z ranges as Arrow record batches, for serving slices of files to
+//! remote clients.
+//!
+//! Row groups whose statistics rule out every row are skipped, as for
+//! [`crate::query`]. The remaining rows are filtered after decoding, so the
+//! batches only hold matching ions, with the columns of the file.
+use crate::query::may_contain;
+use arrow_array::{Array, BooleanArray, Float64Array, RecordBatch};
+use arrow_schema::{ArrowError, DataType};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::errors::ParquetError;
+use parquet::file::reader::ChunkReader;
+use serde::{Deserialize, Serialize};
+
+/// Inclusive ranges that rows must fall within. Unset ranges match all rows
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Slice {
+    pub scan: Option<(u32, u32)>,
+    pub level: Option<(u8, u8)>,
+    pub rt: Option<(f32, f32)>,
+    pub mz: Option<(f64, f64)>,
+}
+
+impl Slice {
+    /// Column names and bounds of the ranges that are set
+    fn ranges(&self) -> Vec<(&'static str, f64, f64)> {
+        let mut ranges = Vec::new();
+        if let Some((lo, hi)) = self.scan {
+            ranges.push(("scan", lo as f64, hi as f64));
+        }
+        if let Some((lo, hi)) = self.level {
+            ranges.push(("level", lo as f64, hi as f64));
+        }
+        if let Some((lo, hi)) = self.rt {
+            ranges.push(("rt", lo as f64, hi as f64));
+        }
+        if let Some((lo, hi)) = self.mz {
+            ranges.push(("mz", lo, hi));
+        }
+        ranges
+    }
+}
+
+/// Values of a numeric column, widened to `f64`, or `None` if the batch has
+/// no such column
+pub(crate) fn column_values(
+    batch: &RecordBatch,
+    name: &str,
+) -> Result<Option<Vec<Option<f64>>>, ArrowError> {
+    let Some(column) = batch.column_by_name(name) else {
+        return Ok(None);
+    };
+    let values = arrow_cast::cast(column, &DataType::Float64)?;
+    let values = values
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .expect("cast to Float64");
+    Ok(Some(values.iter().collect()))
+}
+
+/// Keep the rows of `batch` within every range
+fn filter(batch: &RecordBatch, ranges: &[(&str, f64, f64)]) -> Result<RecordBatch, ArrowError> {
+    if ranges.is_empty() {
+        return Ok(batch.clone());
+    }
+    let mut keep = vec![true; batch.num_rows()];
+    for &(name, lo, hi) in ranges {
+        let values = column_values(batch, name)?
+            .ok_or_else(|| ArrowError::SchemaError(format!("missing column `{}`", name)))?;
+        for (keep, value) in keep.iter_mut().zip(values) {
+            *keep &= value.is_some_and(|v| v >= lo && v <= hi);
+        }
+    }
+    arrow_select::filter::filter_record_batch(batch, &BooleanArray::from(keep))
+}
+
+/// Read the rows of a long format file within `slice`
+pub fn read_slice<R: 'static + ChunkReader>(
+    r: R,
+    slice: &Slice,
+) -> parquet::errors::Result<Vec<RecordBatch>> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(r)?;
+    let metadata = builder.metadata().clone();
+    let columns = metadata.file_metadata().schema_descr().columns();
+    let ranges = slice.ranges();
+    let indices = ranges
+        .iter()
+        .map(|&(name, lo, hi)| {
+            columns
+                .iter()
+                .position(|col| col.name() == name)
+                .map(|idx| (idx, lo, hi))
+                .ok_or_else(|| ParquetError::General(format!("missing column `{}`", name)))
+        })
+        .collect::<parquet::errors::Result<Vec<_>>>()?;
+
+    let row_groups = (0..metadata.num_row_groups())
+        .filter(|&i| {
+            let rg = metadata.row_group(i);
+            indices
+                .iter()
+                .all(|&(idx, lo, hi)| may_contain(rg, idx, lo, hi))
+        })
+        .collect::<Vec<_>>();
+
+    let mut batches = Vec::new();
+    for batch in builder.with_row_groups(row_groups).build()? {
+        let batch = filter(&batch?, &ranges)?;
+        if batch.num_rows() > 0 {
+            batches.push(batch);
+        }
+    }
+    Ok(batches)
+}
+
+/// Key local files by file name, for addressing them in requests to a server
+#[cfg(any(feature = "flight", feature = "http"))]
+pub(crate) fn files_by_name<I: IntoIterator<Item = std::path::PathBuf>>(
+    paths: I,
+) -> anyhow::Result<std::collections::BTreeMap<String, std::path::PathBuf>> {
+    let mut files = std::collections::BTreeMap::new();
+    for path in paths {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("{} is not a file", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+        if let Some(other) = files.insert(name.clone(), path) {
+            anyhow::bail!("two files are named {}: {}", name, other.display());
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::RawSpectrum;
+    use crate::write_long::serialize_to_parquet;
+
+    #[test]
+    fn slice_rows() -> anyhow::Result<()> {
+        let spectra = (0..4)
+            .map(|i| RawSpectrum {
+                ms_level: 1 + i % 2,
+                scan_start_time: i as f32,
+                mz: vec![100.0, 200.0, 300.0],
+                intensity: vec![1.0, 2.0, 3.0],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+
+        let rows = |slice: &Slice| -> anyhow::Result<usize> {
+            let batches = read_slice(buf.clone(), slice)?;
+            Ok(batches.iter().map(RecordBatch::num_rows).sum())
+        };
+        assert_eq!(rows(&Slice::default())?, 12);
+        let slice = Slice {
+            level: Some((2, 2)),
+            mz: Some((150.0, 350.0)),
+            ..Default::default()
+        };
+        assert_eq!(rows(&slice)?, 4);
+        let slice = Slice {
+            scan: Some((1, 2)),
+            rt: Some((2.0, 10.0)),
+            ..Default::default()
+        };
+        assert_eq!(rows(&slice)?, 3);
+        Ok(())
+    }
+}
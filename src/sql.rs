@@ -0,0 +1,170 @@
+//! Run SQL over mzparquet files using [DataFusion](https://datafusion.apache.org/).
+//!
+//! Each file (or directory of files) is registered as a table, so that
+//! arbitrary queries can be run without any external tooling:
+//!
+//! ```sql
+//! SELECT scan, rt, mz, intensity FROM run WHERE level = 2 AND mz BETWEEN 500.0 AND 500.01
+//! ```
+use datafusion::arrow::{csv, datatypes::SchemaRef, record_batch::RecordBatch};
+use datafusion::error::Result;
+use datafusion::parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use datafusion::prelude::{ParquetReadOptions, SessionContext};
+use std::{io::Write, str::FromStr, sync::Arc};
+
+/// A file, or a directory of files sharing a schema, exposed as a table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableSource {
+    pub name: String,
+    pub path: String,
+}
+
+impl FromStr for TableSource {
+    type Err = std::convert::Infallible;
+
+    /// Parse `name=path`, or a bare path. Bare paths are named after the
+    /// file or directory, with any extensions removed
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((name, path)) = s.split_once('=') {
+            return Ok(TableSource {
+                name: name.into(),
+                path: path.into(),
+            });
+        }
+
+        let name = s
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or(s)
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        Ok(TableSource {
+            name,
+            path: s.into(),
+        })
+    }
+}
+
+/// Register `tables` and run `sql` against them
+pub async fn query(sql: &str, tables: &[TableSource]) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    let ctx = SessionContext::new();
+    for table in tables {
+        // Accept both `.mzparquet` and `.parquet` files inside directories
+        let options = ParquetReadOptions {
+            file_extension: "",
+            ..Default::default()
+        };
+        ctx.register_parquet(table.name.as_str(), &table.path, options)
+            .await?;
+    }
+
+    let df = ctx.sql(sql).await?;
+    let schema = Arc::new(df.schema().as_arrow().clone());
+    Ok((schema, df.collect().await?))
+}
+
+/// Write query results as CSV, with a header row
+pub fn write_csv<W: Write>(batches: &[RecordBatch], w: W) -> Result<()> {
+    let mut writer = csv::Writer::new(w);
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    Ok(())
+}
+
+/// Write query results as a ZSTD compressed parquet file
+pub fn write_parquet<W: Write + Send>(
+    schema: SchemaRef,
+    batches: &[RecordBatch],
+    w: W,
+) -> Result<W> {
+    let options = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::try_new(3)?))
+        .build();
+    let mut writer = ArrowWriter::try_new(w, schema, Some(options))?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::RawSpectrum;
+    use crate::write_long::serialize_to_parquet;
+
+    #[test]
+    fn parse_table_sources() -> anyhow::Result<()> {
+        let source = |name: &str, path: &str| TableSource {
+            name: name.into(),
+            path: path.into(),
+        };
+        assert_eq!(
+            "run=/data/a.mzparquet".parse::<TableSource>()?,
+            source("run", "/data/a.mzparquet")
+        );
+        assert_eq!(
+            "s3://bucket/runs/qc-01.mzparquet".parse::<TableSource>()?,
+            source("qc_01", "s3://bucket/runs/qc-01.mzparquet")
+        );
+        assert_eq!(
+            "/data/runs/".parse::<TableSource>()?,
+            source("runs", "/data/runs/")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_files_and_directories() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mz_parquet-sql-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("runs"))?;
+        let result = async {
+            let spectrum = |ms_level, mz: f64| RawSpectrum {
+                ms_level,
+                mz: vec![mz, mz + 100.0],
+                intensity: vec![1.0, 2.0],
+                ..Default::default()
+            };
+            let spectra = vec![
+                spectrum(1, 300.0),
+                spectrum(2, 300.0),
+                spectrum(2, 400.0),
+                spectrum(2, 200.0),
+            ];
+            // Directories may hold `.parquet` as well as `.mzparquet` files
+            for name in ["a.mzparquet", "b.parquet"] {
+                let file = std::fs::File::create(dir.join("runs").join(name))?;
+                serialize_to_parquet(file, &spectra)?;
+            }
+            let path = |name: &str| dir.join(name).display().to_string();
+
+            let csv = |batches: Vec<RecordBatch>| -> anyhow::Result<String> {
+                let mut buf = Vec::new();
+                write_csv(&batches, &mut buf)?;
+                Ok(String::from_utf8(buf)?)
+            };
+            let tables = [format!("run={}", path("runs/a.mzparquet")).parse::<TableSource>()?];
+            let sql = "SELECT scan FROM run WHERE level = 2 AND mz BETWEEN 399.999 AND 400.001 \
+                       ORDER BY scan";
+            let (_, batches) = query(sql, &tables).await?;
+            assert_eq!(csv(batches)?, "scan\n1\n2\n");
+
+            let tables = [path("runs").parse::<TableSource>()?];
+            let sql = "SELECT count(*) AS ions FROM runs";
+            let (_, batches) = query(sql, &tables).await?;
+            assert_eq!(csv(batches)?, "ions\n16\n");
+            anyhow::Ok(())
+        }
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        result
+    }
+}
@@ -0,0 +1,268 @@
+//! Per-run quality control metrics, computed from the spectra in an
+//! mzparquet file
+use crate::mzml::RawSpectrum;
+use crate::output::{Column, Table};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A point on the total ion current chromatogram
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct TicPoint {
+    pub rt: f32,
+    pub tic: f32,
+}
+
+/// Number of spectra with between `lo` (inclusive) and `hi` (exclusive) peaks
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct HistogramBin {
+    pub lo: usize,
+    pub hi: usize,
+    pub count: usize,
+}
+
+/// QC metrics for a single run
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RunStats {
+    /// Number of spectra, by MS level
+    pub spectra: BTreeMap<u8, usize>,
+    /// Total ion current of each MS1 spectrum, in acquisition order
+    pub tic: Vec<TicPoint>,
+    /// Median ion injection time, by MS level. Levels without any recorded
+    /// injection times are omitted
+    pub median_injection_time: BTreeMap<u8, f32>,
+    /// Number of MSn spectra with each precursor charge state
+    pub precursor_charge: BTreeMap<u8, usize>,
+    /// Number of MSn spectra whose precursor charge is not known
+    pub unknown_charge: usize,
+    /// Distribution of peaks per spectrum, by MS level. Bins double in width:
+    /// `[0, 1)`, `[1, 2)`, `[2, 4)`, `[4, 8)`, ...
+    pub peaks_per_spectrum: BTreeMap<u8, Vec<HistogramBin>>,
+}
+
+impl RunStats {
+    pub fn new(spectra: &[RawSpectrum]) -> Self {
+        let mut stats = RunStats::default();
+        let mut injection_times = BTreeMap::<u8, Vec<f32>>::new();
+        let mut peaks = BTreeMap::<u8, Vec<usize>>::new();
+
+        for spectrum in spectra {
+            *stats.spectra.entry(spectrum.ms_level).or_default() += 1;
+
+            if spectrum.ms_level == 1 {
+                // Files written before the `total_ion_current` column was
+                // added read back a TIC of zero, so sum the peaks instead
+                let tic = match spectrum.total_ion_current > 0.0 {
+                    true => spectrum.total_ion_current,
+                    false => spectrum.intensity.iter().sum::<f64>() as f32,
+                };
+                stats.tic.push(TicPoint {
+                    rt: spectrum.scan_start_time,
+                    tic,
+                });
+            }
+
+            if spectrum.ion_injection_time > 0.0 {
+                injection_times
+                    .entry(spectrum.ms_level)
+                    .or_default()
+                    .push(spectrum.ion_injection_time);
+            }
+
+            if let Some(precursor) = spectrum.precursors.first() {
+                match precursor.charge {
+                    Some(z) => *stats.precursor_charge.entry(z).or_default() += 1,
+                    None => stats.unknown_charge += 1,
+                }
+            }
+
+            let bin = match spectrum.mz.len() {
+                0 => 0,
+                n => n.ilog2() as usize + 1,
+            };
+            let bins = peaks.entry(spectrum.ms_level).or_default();
+            if bins.len() <= bin {
+                bins.resize(bin + 1, 0);
+            }
+            bins[bin] += 1;
+        }
+
+        stats.median_injection_time = injection_times
+            .into_iter()
+            .map(|(level, mut times)| {
+                times.sort_by(f32::total_cmp);
+                let mid = times.len() / 2;
+                let median = match times.len() % 2 {
+                    0 => (times[mid - 1] + times[mid]) / 2.0,
+                    _ => times[mid],
+                };
+                (level, median)
+            })
+            .collect();
+
+        stats.peaks_per_spectrum = peaks
+            .into_iter()
+            .map(|(level, counts)| {
+                let bins = counts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(bin, count)| HistogramBin {
+                        lo: match bin {
+                            0 => 0,
+                            _ => 1 << (bin - 1),
+                        },
+                        hi: 1 << bin,
+                        count,
+                    })
+                    .collect();
+                (level, bins)
+            })
+            .collect();
+
+        stats
+    }
+}
+
+/// Flatten the metrics into a single tidy table, with one row per value:
+///
+/// | metric                  | ms_level | x            | value         |
+/// |-------------------------|----------|--------------|---------------|
+/// | `spectra`               | level    |              | count         |
+/// | `tic`                   | 1        | rt           | TIC           |
+/// | `median_injection_time` | level    |              | time          |
+/// | `precursor_charge`      |          | charge       | count         |
+/// | `peaks_per_spectrum`    | level    | bin start    | count         |
+///
+/// Unknown precursor charges have an empty `x`
+impl From<&RunStats> for Table {
+    fn from(stats: &RunStats) -> Self {
+        let mut metric = Vec::new();
+        let mut ms_level = Vec::new();
+        let mut x = Vec::new();
+        let mut value = Vec::new();
+        let mut push = |name: &str, level: Option<u8>, at: Option<f32>, v: f32| {
+            metric.push(name.to_string());
+            ms_level.push(level.map(u32::from));
+            x.push(at);
+            value.push(v);
+        };
+
+        for (level, count) in &stats.spectra {
+            push("spectra", Some(*level), None, *count as f32);
+        }
+        for point in &stats.tic {
+            push("tic", Some(1), Some(point.rt), point.tic);
+        }
+        for (level, time) in &stats.median_injection_time {
+            push("median_injection_time", Some(*level), None, *time);
+        }
+        for (charge, count) in &stats.precursor_charge {
+            push(
+                "precursor_charge",
+                None,
+                Some(*charge as f32),
+                *count as f32,
+            );
+        }
+        if stats.unknown_charge > 0 {
+            push("precursor_charge", None, None, stats.unknown_charge as f32);
+        }
+        for (level, bins) in &stats.peaks_per_spectrum {
+            for bin in bins {
+                push(
+                    "peaks_per_spectrum",
+                    Some(*level),
+                    Some(bin.lo as f32),
+                    bin.count as f32,
+                );
+            }
+        }
+
+        Table::default()
+            .with_column("metric", Column::Str(metric))
+            .with_column("ms_level", Column::OptionalUInt(ms_level))
+            .with_column("x", Column::OptionalFloat(x))
+            .with_column("value", Column::Float(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::Precursor;
+
+    #[test]
+    fn run_metrics() {
+        let ms1 = |rt: f32, tic: f32, peaks: usize| RawSpectrum {
+            ms_level: 1,
+            scan_start_time: rt,
+            total_ion_current: tic,
+            ion_injection_time: 10.0,
+            mz: vec![500.0; peaks],
+            intensity: vec![2.0; peaks],
+            ..Default::default()
+        };
+        let ms2 = |charge: Option<u8>, iit: f32| RawSpectrum {
+            ms_level: 2,
+            ion_injection_time: iit,
+            precursors: vec![Precursor {
+                mz: 500.0,
+                charge,
+                ..Default::default()
+            }],
+            mz: vec![100.0; 5],
+            intensity: vec![1.0; 5],
+            ..Default::default()
+        };
+        let spectra = vec![
+            ms1(1.0, 100.0, 3),
+            ms2(Some(2), 20.0),
+            ms2(Some(2), 30.0),
+            ms1(2.0, 0.0, 0),
+            ms2(Some(3), 50.0),
+            ms2(None, 60.0),
+        ];
+
+        let stats = RunStats::new(&spectra);
+        assert_eq!(stats.spectra, BTreeMap::from([(1, 2), (2, 4)]));
+        assert_eq!(
+            stats.tic,
+            vec![
+                TicPoint {
+                    rt: 1.0,
+                    tic: 100.0
+                },
+                TicPoint { rt: 2.0, tic: 0.0 }
+            ]
+        );
+        assert_eq!(
+            stats.median_injection_time,
+            BTreeMap::from([(1, 10.0), (2, 40.0)])
+        );
+        assert_eq!(stats.precursor_charge, BTreeMap::from([(2, 2), (3, 1)]));
+        assert_eq!(stats.unknown_charge, 1);
+        assert_eq!(
+            stats.peaks_per_spectrum[&1],
+            vec![
+                HistogramBin {
+                    lo: 0,
+                    hi: 1,
+                    count: 1
+                },
+                HistogramBin {
+                    lo: 1,
+                    hi: 2,
+                    count: 0
+                },
+                HistogramBin {
+                    lo: 2,
+                    hi: 4,
+                    count: 1
+                },
+            ]
+        );
+        assert_eq!(stats.peaks_per_spectrum[&2].last().unwrap().count, 4);
+
+        let table = Table::from(&stats);
+        assert_eq!(table.num_rows(), 2 + 2 + 2 + 3 + 3 + 4);
+    }
+}
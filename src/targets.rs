@@ -0,0 +1,313 @@
+//! Targeted extraction of ion chromatograms and peak areas.
+//!
+//! A target list gives the m/z (and optionally a retention time window) of
+//! each analyte. Every file is read once: row groups that cannot contain any
+//! target are skipped using their `mz` and `rt` statistics, and the ions of
+//! the others are matched against all targets at the same time. Each XIC is
+//! integrated with the trapezoidal rule over retention time, so peak areas
+//! are in intensity x the `rt` unit of the file.
+use crate::output::{Column, Table};
+use crate::query::{
+    column_index, may_contain, read_required, read_strings, rt_bounds, Tolerance, XicPoint,
+};
+use parquet::file::{
+    reader::{ChunkReader, FileReader},
+    serialized_reader::SerializedFileReader,
+};
+use std::collections::BTreeMap;
+
+/// An analyte to extract
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    pub name: String,
+    pub mz: f64,
+    /// Optional (inclusive) retention time window, in seconds
+    pub rt: Option<(f32, f32)>,
+}
+
+/// Parse a CSV or tab separated target list, with a header naming the `name`
+/// and `mz` columns, and optionally `rt_min` and `rt_max` columns, in seconds
+/// (which may be left empty)
+pub fn parse_targets(text: &str) -> anyhow::Result<Vec<Target>> {
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let header = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("empty target list"))?;
+    let sep = match header.contains('\t') {
+        true => '\t',
+        false => ',',
+    };
+    let columns = header.split(sep).map(str::trim).collect::<Vec<_>>();
+    let column = |name: &str| columns.iter().position(|&c| c.eq_ignore_ascii_case(name));
+    let (name, mz) = match (column("name"), column("mz")) {
+        (Some(name), Some(mz)) => (name, mz),
+        _ => anyhow::bail!("target list must have `name` and `mz` columns"),
+    };
+    let (rt_min, rt_max) = (column("rt_min"), column("rt_max"));
+
+    lines
+        .enumerate()
+        .map(|(line, text)| {
+            let fields = text.split(sep).map(str::trim).collect::<Vec<_>>();
+            let field = |idx: Option<usize>| {
+                idx.and_then(|idx| fields.get(idx))
+                    .copied()
+                    .filter(|f| !f.is_empty())
+            };
+            let rt = |idx| {
+                field(idx)
+                    .map(|f| f.parse::<f32>())
+                    .transpose()
+                    .map_err(|e| {
+                        anyhow::anyhow!("line {}: invalid retention time: {}", line + 2, e)
+                    })
+            };
+            let (lo, hi) = (rt(rt_min)?, rt(rt_max)?);
+            Ok(Target {
+                name: field(Some(name)).unwrap_or_default().to_string(),
+                mz: field(Some(mz))
+                    .ok_or_else(|| anyhow::anyhow!("line {}: missing m/z", line + 2))?
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("line {}: invalid m/z: {}", line + 2, e))?,
+                rt: match (lo, hi) {
+                    (None, None) => None,
+                    (lo, hi) => Some((lo.unwrap_or(f32::MIN), hi.unwrap_or(f32::MAX))),
+                },
+            })
+        })
+        .collect()
+}
+
+/// The extracted ion chromatogram of a target in one file
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetXic {
+    pub target: Target,
+    /// One point per scan with a matching ion, in scan order
+    pub points: Vec<XicPoint>,
+}
+
+impl TargetXic {
+    /// Peak area, integrated over retention time with the trapezoidal rule.
+    /// Chromatograms with fewer than two points have no area
+    pub fn area(&self) -> f32 {
+        self.points
+            .windows(2)
+            .map(|w| (w[1].rt - w[0].rt) * (w[0].intensity + w[1].intensity) / 2.0)
+            .fold(0.0, |area, trapezoid| area + trapezoid)
+    }
+
+    /// The most intense point of the chromatogram
+    pub fn apex(&self) -> Option<&XicPoint> {
+        self.points
+            .iter()
+            .max_by(|a, b| a.intensity.total_cmp(&b.intensity))
+    }
+}
+
+impl From<&[TargetXic]> for Table {
+    fn from(xics: &[TargetXic]) -> Self {
+        let apex = xics.iter().map(TargetXic::apex).collect::<Vec<_>>();
+        Table::default()
+            .with_column(
+                "target",
+                Column::Str(xics.iter().map(|x| x.target.name.clone()).collect()),
+            )
+            .with_column(
+                "mz",
+                Column::Float(xics.iter().map(|x| x.target.mz as f32).collect()),
+            )
+            .with_column(
+                "area",
+                Column::Float(xics.iter().map(TargetXic::area).collect()),
+            )
+            .with_column(
+                "apex_rt",
+                Column::OptionalFloat(apex.iter().map(|p| p.map(|p| p.rt)).collect()),
+            )
+            .with_column(
+                "apex_intensity",
+                Column::OptionalFloat(apex.iter().map(|p| p.map(|p| p.intensity)).collect()),
+            )
+            .with_column(
+                "points",
+                Column::UInt(xics.iter().map(|x| x.points.len() as u32).collect()),
+            )
+    }
+}
+
+/// A single point of a target's chromatogram, for writing whole XICs
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetPoint {
+    pub target: String,
+    pub point: XicPoint,
+}
+
+impl From<&[TargetPoint]> for Table {
+    fn from(points: &[TargetPoint]) -> Self {
+        let mut table = Table::default().with_column(
+            "target",
+            Column::Str(points.iter().map(|p| p.target.clone()).collect()),
+        );
+        // Files holding several runs
+        if points.iter().any(|p| p.point.file_id.is_some()) {
+            let file_ids = points
+                .iter()
+                .map(|p| p.point.file_id.clone().unwrap_or_default())
+                .collect();
+            table = table.with_column("file_id", Column::Str(file_ids));
+        }
+        table
+            .with_column(
+                "scan",
+                Column::UInt(points.iter().map(|p| p.point.scan).collect()),
+            )
+            .with_column(
+                "rt",
+                Column::Float(points.iter().map(|p| p.point.rt).collect()),
+            )
+            .with_column(
+                "intensity",
+                Column::Float(points.iter().map(|p| p.point.intensity).collect()),
+            )
+    }
+}
+
+/// Extract the chromatogram of every target from the MS1 (or `ms_level`)
+/// scans of a file, in target list order. Targets without any matching ions
+/// are still reported, with an empty chromatogram
+pub fn extract<R: 'static + ChunkReader>(
+    r: R,
+    targets: &[Target],
+    tolerance: Tolerance,
+    ms_level: u8,
+) -> parquet::errors::Result<Vec<TargetXic>> {
+    let reader = SerializedFileReader::new(r)?;
+    extract_from_reader(&reader, targets, tolerance, ms_level)
+}
+
+pub(crate) fn extract_from_reader(
+    reader: &dyn FileReader,
+    targets: &[Target],
+    tolerance: Tolerance,
+    ms_level: u8,
+) -> parquet::errors::Result<Vec<TargetXic>> {
+    let scan_idx = column_index(reader, "scan")?;
+    let level_idx = column_index(reader, "level")?;
+    let rt_idx = column_index(reader, "rt")?;
+    let mz_idx = column_index(reader, "mz")?;
+    let int_idx = column_index(reader, "intensity")?;
+    let file_id_idx = column_index(reader, "file_id").ok();
+
+    // m/z and retention time bounds of each target, sorted by lower m/z
+    // bound so that the targets matching an ion can be found by bisection
+    let mut bounds = targets
+        .iter()
+        .enumerate()
+        .map(|(idx, target)| {
+            let (lo, hi) = tolerance.bounds(target.mz);
+            let (rt_lo, rt_hi) = rt_bounds(reader, target.rt);
+            (lo, hi, rt_lo, rt_hi, idx)
+        })
+        .collect::<Vec<_>>();
+    bounds.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let max_width = bounds.iter().map(|b| b.1 - b.0).fold(0.0, f64::max);
+    let level = ms_level as f64;
+
+    // Points of each target by run (in files holding several) and scan
+    let mut scans = vec![BTreeMap::<(Option<String>, u32), XicPoint>::new(); targets.len()];
+    for i in 0..reader.num_row_groups() {
+        let meta = reader.metadata().row_group(i);
+        let any_target = bounds.iter().any(|&(lo, hi, rt_lo, rt_hi, _)| {
+            may_contain(meta, mz_idx, lo, hi) && may_contain(meta, rt_idx, rt_lo, rt_hi)
+        });
+        if !any_target || !may_contain(meta, level_idx, level, level) {
+            continue;
+        }
+
+        let rg = reader.get_row_group(i)?;
+        let scan = read_required(rg.as_ref(), scan_idx)?;
+        let levels = read_required(rg.as_ref(), level_idx)?;
+        let rt = read_required(rg.as_ref(), rt_idx)?;
+        let mz = read_required(rg.as_ref(), mz_idx)?;
+        let intensity = read_required(rg.as_ref(), int_idx)?;
+        let file_id = match file_id_idx {
+            Some(idx) => read_strings(rg.as_ref(), idx)?,
+            None => vec![None; mz.len()],
+        };
+
+        for row in 0..mz.len() {
+            if levels[row] != level {
+                continue;
+            }
+            let end = bounds.partition_point(|b| b.0 <= mz[row]);
+            for &(_, hi, rt_lo, rt_hi, idx) in bounds[..end]
+                .iter()
+                .rev()
+                .take_while(|b| b.0 >= mz[row] - max_width)
+            {
+                if mz[row] > hi || rt[row] < rt_lo || rt[row] > rt_hi {
+                    continue;
+                }
+                let scan = scan[row] as u32;
+                scans[idx]
+                    .entry((file_id[row].clone(), scan))
+                    .or_insert_with(|| XicPoint {
+                        file_id: file_id[row].clone(),
+                        scan,
+                        rt: rt[row] as f32,
+                        intensity: 0.0,
+                    })
+                    .intensity += intensity[row] as f32;
+            }
+        }
+    }
+
+    Ok(targets
+        .iter()
+        .zip(scans)
+        .map(|(target, scans)| TargetXic {
+            target: target.clone(),
+            points: scans.into_values().collect(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::RawSpectrum;
+    use crate::write_long::serialize_to_parquet;
+
+    #[test]
+    fn extract_targets() -> anyhow::Result<()> {
+        let targets = parse_targets(
+            "name\tmz\trt_min\trt_max\n\
+             caffeine\t195.0877\t\t\n\
+             late\t195.0877\t2.5\t\n\
+             missing\t300.0\t\t\n",
+        )?;
+        assert_eq!(targets[1].rt, Some((2.5, f32::MAX)));
+
+        let spectrum = |rt, intensity| RawSpectrum {
+            ms_level: 1,
+            scan_start_time: rt,
+            mz: vec![100.0, 195.0877],
+            intensity: vec![1000.0, intensity],
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum(1.0, 0.0),
+            spectrum(2.0, 100.0),
+            spectrum(3.0, 50.0),
+        ];
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+
+        let xics = extract(buf, &targets, Tolerance::Ppm(10.0), 1)?;
+        assert_eq!(xics[0].points.len(), 3);
+        assert_eq!(xics[0].area(), 50.0 + 75.0);
+        assert_eq!(xics[0].apex().map(|p| p.rt), Some(2.0));
+        assert_eq!(xics[1].points.len(), 1);
+        assert!(xics[2].points.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+//! Read Bruker timsTOF `.d` directories directly, with [`timsrust`].
+//!
+//! Each frame is converted to a single spectrum, keeping the frame structure
+//! that is lost when converting through mzML. Ions are written in scan (ion
+//! mobility) order, with the inverse ion mobility of each ion stored in
+//! [`RawSpectrum::ion_mobility`], and so in the `ion_mobility` column of both
+//! long and wide format files. Intensities are the raw detector counts.
+//!
+//! MS2 frames list one precursor per quadrupole window: for DDA-PASEF these
+//! are the isolated precursors, and for DIA-PASEF the isolation windows of
+//! the frame's window group.
+use crate::mzml::{MzMLError, Precursor, RawSpectrum, SpectrumStream};
+use std::path::Path;
+use timsrust::{
+    converters::ConvertableDomain,
+    readers::{FrameReader, MetadataReader},
+    Frame, MSLevel, Metadata,
+};
+
+/// Spectra (frames) of a timsTOF `.d` directory, read one at a time
+pub struct TdfStream {
+    frames: FrameReader,
+    metadata: Metadata,
+    index: usize,
+}
+
+impl TdfStream {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        Ok(TdfStream {
+            frames: FrameReader::new(path)?,
+            metadata: MetadataReader::new(path.join("analysis.tdf"))?,
+            index: 0,
+        })
+    }
+
+    /// Number of frames in the acquisition
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.len() == 0
+    }
+}
+
+/// Convert a frame to a spectrum, calibrating the m/z and inverse ion
+/// mobility of each ion with `metadata`. The ions of each scan (a window of
+/// `tof_indices` between consecutive `scan_offsets`) share its mobility
+fn frame_to_spectrum(frame: Frame, metadata: &Metadata) -> RawSpectrum {
+    let mut mz = Vec::with_capacity(frame.tof_indices.len());
+    let mut ion_mobility = Vec::with_capacity(frame.tof_indices.len());
+    for (scan, window) in frame.scan_offsets.windows(2).enumerate() {
+        let im = metadata.im_converter.convert(scan as f64) as f32;
+        for &tof in &frame.tof_indices[window[0]..window[1]] {
+            mz.push(metadata.mz_converter.convert(tof as f64));
+            ion_mobility.push(im);
+        }
+    }
+    let intensity = frame
+        .intensities
+        .iter()
+        .map(|&i| i as f64)
+        .collect::<Vec<_>>();
+
+    let ms_level = match frame.ms_level {
+        MSLevel::MS1 => 1,
+        MSLevel::MS2 => 2,
+        MSLevel::Unknown => 0,
+    };
+    let quad = &frame.quadrupole_settings;
+    let precursors = match ms_level {
+        2 => quad
+            .isolation_mz
+            .iter()
+            .zip(&quad.isolation_width)
+            .map(|(&mz, &width)| Precursor {
+                mz,
+                isolation_window_target: Some(mz as f32),
+                isolation_window_lower: Some(width as f32 / 2.0),
+                isolation_window_upper: Some(width as f32 / 2.0),
+                ..Default::default()
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    RawSpectrum {
+        ms_level,
+        // Frame ids start from 1
+        id: format!("frame={}", frame.index + 1).into_bytes(),
+        scan_number: Some(frame.index as u32 + 1),
+        precursors,
+        centroid: true,
+        scan_start_time: frame.rt_in_seconds as f32,
+        total_ion_current: intensity.iter().sum::<f64>() as f32,
+        mz,
+        intensity,
+        ion_mobility,
+        ..Default::default()
+    }
+}
+
+impl SpectrumStream for TdfStream {
+    async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+        while self.index < self.frames.len() {
+            let frame = self
+                .frames
+                .get(self.index)
+                .map_err(|err| MzMLError::InputError(err.to_string()))?;
+            self.index += 1;
+            let spectrum = frame_to_spectrum(frame, &self.metadata);
+            // Calibration frames and other unknown acquisitions are skipped,
+            // as they are by the mzML parser
+            if spectrum.ms_level != 0 {
+                return Ok(Some(spectrum));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use timsrust::{
+        converters::{Scan2ImConverter, Tof2MzConverter},
+        QuadrupoleSettings,
+    };
+
+    #[test]
+    fn convert_frame() {
+        let metadata = Metadata {
+            im_converter: Scan2ImConverter::from_boundaries(1.6, 0.6, 4),
+            mz_converter: Tof2MzConverter::from_boundaries(100.0, 1700.0, 1000),
+            ..Default::default()
+        };
+        // Two ions in the first scan, none in the second and one in the third
+        let frame = Frame {
+            scan_offsets: vec![0, 2, 2, 3],
+            tof_indices: vec![10, 20, 30],
+            intensities: vec![5, 6, 7],
+            index: 4,
+            rt_in_seconds: 12.5,
+            ms_level: MSLevel::MS2,
+            quadrupole_settings: Arc::new(QuadrupoleSettings {
+                isolation_mz: vec![500.0],
+                isolation_width: vec![2.0],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let spectrum = frame_to_spectrum(frame, &metadata);
+        let im = |scan: f64| metadata.im_converter.convert(scan) as f32;
+        let mz = |tof: f64| metadata.mz_converter.convert(tof);
+        assert_eq!(spectrum.ion_mobility, vec![im(0.0), im(0.0), im(2.0)]);
+        assert_eq!(spectrum.mz, vec![mz(10.0), mz(20.0), mz(30.0)]);
+        assert_eq!(spectrum.intensity, vec![5.0, 6.0, 7.0]);
+        assert_eq!(spectrum.total_ion_current, 18.0);
+        assert_eq!(spectrum.id, b"frame=5");
+        assert_eq!(spectrum.scan_number, Some(5));
+        assert_eq!(spectrum.scan_start_time, 12.5);
+
+        assert_eq!(spectrum.ms_level, 2);
+        assert_eq!(spectrum.precursors.len(), 1);
+        assert_eq!(spectrum.precursors[0].mz, 500.0);
+        assert_eq!(spectrum.precursors[0].isolation_window_lower, Some(1.0));
+        assert_eq!(spectrum.precursors[0].isolation_window_upper, Some(1.0));
+    }
+}
@@ -0,0 +1,146 @@
+//! Fan out one stream of spectra to several writers, so that an input can be
+//! converted to several outputs (such as one file per polarity) while only
+//! being read once.
+//!
+//! The input is read on a blocking thread, and every spectrum is sent to each
+//! output through a bounded channel, so that reading waits for the slowest
+//! writer rather than buffering the whole run.
+use crate::metadata::RunMetadata;
+use crate::mzml::{MzMLError, RawSpectrum, SpectrumStream};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Spectra queued for each output before reading waits for it
+const BUFFER: usize = 64;
+
+/// One of the outputs of [`tee`], receiving every spectrum of the input
+pub struct TeeStream {
+    rx: mpsc::Receiver<Result<RawSpectrum, MzMLError>>,
+    metadata: Arc<Mutex<RunMetadata>>,
+}
+
+impl SpectrumStream for TeeStream {
+    async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+        self.rx.recv().await.transpose()
+    }
+
+    fn run_metadata(&self) -> RunMetadata {
+        self.metadata
+            .lock()
+            .expect("metadata is not poisoned")
+            .clone()
+    }
+}
+
+/// Read `stream` on a blocking thread, sending every spectrum to each of `n`
+/// outputs. The returned task resolves to the input once it has been read, for
+/// its chromatograms.
+///
+/// An error reading the input is also sent to every output, so that none of
+/// them is finished as if the run were complete. Reading stops early if every
+/// output has been dropped (e.g. as its writer failed)
+pub fn tee<S>(mut stream: S, n: usize) -> (Vec<TeeStream>, JoinHandle<Result<S, MzMLError>>)
+where
+    S: SpectrumStream + Send + 'static,
+{
+    let metadata = Arc::new(Mutex::new(RunMetadata::default()));
+    let (senders, outputs): (Vec<_>, Vec<_>) = (0..n)
+        .map(|_| {
+            let (tx, rx) = mpsc::channel(BUFFER);
+            let output = TeeStream {
+                rx,
+                metadata: metadata.clone(),
+            };
+            (tx, output)
+        })
+        .unzip();
+
+    let handle = tokio::runtime::Handle::current();
+    let task = tokio::task::spawn_blocking(move || {
+        handle.block_on(async move {
+            let mut first = true;
+            loop {
+                let next = stream.next_spectrum().await;
+                // Run metadata is complete once the first spectrum has been
+                // read, apart from what is only known at the end (checksums)
+                if first || matches!(next, Ok(None)) {
+                    *metadata.lock().expect("metadata is not poisoned") = stream.run_metadata();
+                    first = false;
+                }
+                match next {
+                    Ok(Some(spectrum)) => {
+                        for tx in &senders {
+                            // A dropped output has failed, and reports why
+                            let _ = tx.send(Ok(spectrum.clone())).await;
+                        }
+                        if senders.iter().all(|tx| tx.is_closed()) {
+                            return Ok(stream);
+                        }
+                    }
+                    Ok(None) => return Ok(stream),
+                    Err(err) => {
+                        for tx in &senders {
+                            let _ = tx.send(Err(MzMLError::InputError(err.to_string()))).await;
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+        })
+    });
+    (outputs, task)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Spectra(std::vec::IntoIter<Result<RawSpectrum, MzMLError>>);
+
+    impl SpectrumStream for Spectra {
+        async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+            self.0.next().transpose()
+        }
+    }
+
+    async fn drain(mut stream: TeeStream) -> Result<Vec<Vec<u8>>, MzMLError> {
+        let mut ids = Vec::new();
+        while let Some(spectrum) = stream.next_spectrum().await? {
+            ids.push(spectrum.id);
+        }
+        Ok(ids)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_output_gets_every_spectrum() -> anyhow::Result<()> {
+        let spectra = (0..200)
+            .map(|scan| {
+                Ok(RawSpectrum {
+                    id: format!("scan={}", scan).into_bytes(),
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+        let (outputs, input) = tee(Spectra(spectra.into_iter()), 2);
+        let drained = futures::future::join_all(outputs.into_iter().map(drain)).await;
+        input.await??;
+        for ids in drained {
+            let ids = ids?;
+            assert_eq!(ids.len(), 200);
+            assert_eq!(ids[199], b"scan=199");
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn input_errors_reach_every_output() -> anyhow::Result<()> {
+        let spectra = vec![Ok(RawSpectrum::default()), Err(MzMLError::Malformed)];
+        let (outputs, input) = tee(Spectra(spectra.into_iter()), 2);
+        for output in outputs {
+            assert!(drain(output).await.is_err());
+        }
+        assert!(matches!(input.await?, Err(MzMLError::Malformed)));
+        Ok(())
+    }
+}
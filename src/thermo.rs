@@ -0,0 +1,124 @@
+//! Read Thermo RAW files directly, through Thermo's RawFileReader library
+//! (bundled by the [`thermorawfilereader`] crate, which requires a .NET 8
+//! runtime), skipping the intermediate mzML file.
+//!
+//! Spectra are converted to the same [`RawSpectrum`]s that the mzML parser
+//! produces, with native ids in the format written by msconvert
+//! (`controllerType=0 controllerNumber=1 scan=N`), so that files converted
+//! either way can be queried together.
+use crate::mzml::{MzMLError, Polarity, Precursor, RawSpectrum, SpectrumStream};
+use std::path::Path;
+use thermorawfilereader::{schema::SpectrumMode, RawFileReader};
+
+/// Native id of the spectrum at `index` (scan numbers start from 1)
+fn native_id(index: usize) -> Vec<u8> {
+    format!("controllerType=0 controllerNumber=1 scan={}", index + 1).into_bytes()
+}
+
+/// Polarity of a scan, from the `+` or `-` in its filter string (e.g. `FTMS +
+/// p NSI Full ms`)
+fn polarity(filter_string: &str) -> Option<Polarity> {
+    filter_string
+        .split_ascii_whitespace()
+        .find_map(|token| match token {
+            "+" => Some(Polarity::Positive),
+            "-" => Some(Polarity::Negative),
+            _ => None,
+        })
+}
+
+/// Spectra of a Thermo RAW file, read one at a time
+pub struct RawFileStream {
+    handle: RawFileReader,
+    index: usize,
+}
+
+impl RawFileStream {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(RawFileStream {
+            handle: RawFileReader::open(path)?,
+            index: 0,
+        })
+    }
+
+    /// Number of spectra in the file
+    pub fn len(&self) -> usize {
+        self.handle.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handle.len() == 0
+    }
+
+    /// Read the spectrum at `index`, or `None` if it is out of bounds
+    pub fn get(&self, index: usize) -> Option<RawSpectrum> {
+        let raw = self.handle.get(index)?;
+
+        let precursors = raw
+            .precursor()
+            .filter(|precursor| precursor.mz() > 0.0)
+            .map(|precursor| {
+                let window = precursor.isolation_window();
+                Precursor {
+                    mz: precursor.mz(),
+                    intensity: Some(precursor.intensity()).filter(|&i| i > 0.0),
+                    charge: u8::try_from(precursor.charge()).ok().filter(|&z| z > 0),
+                    spectrum_ref: usize::try_from(precursor.parent_index())
+                        .ok()
+                        .map(native_id),
+                    isolation_window_target: Some(window.target() as f32),
+                    // mzML (and so the rest of mz_parquet) stores the window
+                    // as offsets from the target
+                    isolation_window_lower: Some((window.target() - window.lower()) as f32),
+                    isolation_window_upper: Some((window.upper() - window.target()) as f32),
+                }
+            })
+            .into_iter()
+            .collect();
+
+        let (mz, intensity): (Vec<f64>, Vec<f64>) = raw
+            .data()
+            .map(|data| {
+                (
+                    data.mz().to_vec(),
+                    data.intensity().iter().map(|&i| i as f64).collect(),
+                )
+            })
+            .unwrap_or_default();
+        let total_ion_current = intensity.iter().sum::<f64>() as f32;
+
+        Some(RawSpectrum {
+            ms_level: raw.ms_level(),
+            id: native_id(index),
+            scan_number: Some(index as u32 + 1),
+            precursors,
+            centroid: matches!(raw.mode(), SpectrumMode::Centroid),
+            // Thermo reports retention times in minutes
+            scan_start_time: raw.time() as f32 * 60.0,
+            ion_injection_time: raw
+                .acquisition()
+                .map(|acquisition| acquisition.injection_time())
+                .unwrap_or_default(),
+            total_ion_current,
+            inverse_ion_mobility: None,
+            pixel: None,
+            polarity: raw.filter_string().and_then(polarity),
+            ion_mobility: Vec::new(),
+            filter_string: raw.filter_string().map(str::to_string),
+            mz,
+            intensity,
+            noise: Vec::new(),
+            baseline: Vec::new(),
+            charge: Vec::new(),
+            extra_params: Vec::new(),
+        })
+    }
+}
+
+impl SpectrumStream for RawFileStream {
+    async fn next_spectrum(&mut self) -> Result<Option<RawSpectrum>, MzMLError> {
+        let spectrum = self.get(self.index);
+        self.index += 1;
+        Ok(spectrum)
+    }
+}
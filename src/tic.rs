@@ -0,0 +1,151 @@
+//! Total ion current (TIC) and base peak chromatograms (BPC) of long format
+//! mzparquet files.
+//!
+//! Only the `scan`, `level`, `rt`, `intensity` and `total_ion_current`
+//! columns are decoded, and row groups whose `level` statistics rule out the
+//! requested MS levels are skipped. The TIC of each scan is the
+//! `total_ion_current` reported by the instrument, falling back to the sum of
+//! its intensities where it is missing (null or zero). Scans without any
+//! ions are not reported.
+use crate::filter::MsLevels;
+use crate::output::{Column, Table};
+use crate::query::{column_index, may_contain, read_column, read_required};
+use parquet::file::{
+    reader::{ChunkReader, FileReader},
+    serialized_reader::SerializedFileReader,
+};
+use std::collections::BTreeMap;
+
+/// A single scan of a TIC and base peak chromatogram
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TracePoint {
+    pub scan: u32,
+    pub level: u8,
+    pub rt: f32,
+    /// Total ion current of the scan
+    pub tic: f32,
+    /// Intensity of the most intense ion of the scan
+    pub base_peak: f32,
+}
+
+impl From<&[TracePoint]> for Table {
+    fn from(points: &[TracePoint]) -> Self {
+        Table::default()
+            .with_column(
+                "scan",
+                Column::UInt(points.iter().map(|p| p.scan).collect()),
+            )
+            .with_column(
+                "level",
+                Column::UInt(points.iter().map(|p| p.level as u32).collect()),
+            )
+            .with_column("rt", Column::Float(points.iter().map(|p| p.rt).collect()))
+            .with_column("tic", Column::Float(points.iter().map(|p| p.tic).collect()))
+            .with_column(
+                "base_peak",
+                Column::Float(points.iter().map(|p| p.base_peak).collect()),
+            )
+    }
+}
+
+/// TIC and base peak intensity of every scan at `levels` (all levels if
+/// `None`), ordered by scan
+pub fn chromatogram<R: 'static + ChunkReader>(
+    r: R,
+    levels: Option<MsLevels>,
+) -> parquet::errors::Result<Vec<TracePoint>> {
+    let reader = SerializedFileReader::new(r)?;
+    chromatogram_from_reader(&reader, levels)
+}
+
+pub(crate) fn chromatogram_from_reader(
+    reader: &dyn FileReader,
+    levels: Option<MsLevels>,
+) -> parquet::errors::Result<Vec<TracePoint>> {
+    let scan_idx = column_index(reader, "scan")?;
+    let level_idx = column_index(reader, "level")?;
+    let rt_idx = column_index(reader, "rt")?;
+    let int_idx = column_index(reader, "intensity")?;
+    let tic_idx = column_index(reader, "total_ion_current")?;
+    let levels = levels.unwrap_or(MsLevels {
+        min: 0,
+        max: u8::MAX,
+    });
+
+    let mut points = Vec::new();
+    for i in 0..reader.num_row_groups() {
+        let meta = reader.metadata().row_group(i);
+        if !may_contain(meta, level_idx, levels.min as f64, levels.max as f64) {
+            continue;
+        }
+
+        let rg = reader.get_row_group(i)?;
+        let scan = read_required(rg.as_ref(), scan_idx)?;
+        let level = read_required(rg.as_ref(), level_idx)?;
+        let rt = read_required(rg.as_ref(), rt_idx)?;
+        let intensity = read_required(rg.as_ref(), int_idx)?;
+        let tic = read_column(rg.as_ref(), tic_idx)?;
+
+        // Scans, with the TIC reported by the instrument and the summed
+        // intensity of their ions
+        let mut scans: BTreeMap<u32, (TracePoint, Option<f64>, f64)> = BTreeMap::new();
+        for row in 0..scan.len() {
+            if !levels.contains(level[row] as u8) {
+                continue;
+            }
+            let (point, _, sum) = scans.entry(scan[row] as u32).or_insert((
+                TracePoint {
+                    scan: scan[row] as u32,
+                    level: level[row] as u8,
+                    rt: rt[row] as f32,
+                    tic: 0.0,
+                    base_peak: 0.0,
+                },
+                tic[row].filter(|&tic| tic > 0.0),
+                0.0,
+            ));
+            point.base_peak = point.base_peak.max(intensity[row] as f32);
+            *sum += intensity[row];
+        }
+        points.extend(scans.into_values().map(|(point, tic, sum)| TracePoint {
+            tic: tic.unwrap_or(sum) as f32,
+            ..point
+        }));
+    }
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::RawSpectrum;
+    use crate::write_long::serialize_to_parquet;
+
+    #[test]
+    fn trace_per_level() -> anyhow::Result<()> {
+        let spectra = [(1, Some(500.0)), (2, None), (1, None)]
+            .into_iter()
+            .map(|(ms_level, tic)| RawSpectrum {
+                ms_level,
+                total_ion_current: tic.unwrap_or_default(),
+                mz: vec![100.0, 200.0],
+                intensity: vec![10.0, 30.0],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+
+        let points = chromatogram(buf.clone(), None)?;
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[0].tic, 500.0);
+        assert_eq!(points[0].base_peak, 30.0);
+        // Missing TICs are summed from the intensities
+        assert_eq!(points[1].tic, 40.0);
+
+        let ms1 = MsLevels { min: 1, max: 1 };
+        let points = chromatogram(buf, Some(ms1))?;
+        assert_eq!(points.iter().map(|p| p.scan).collect::<Vec<_>>(), [0, 2]);
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+//! Convert vendor formats that have no native reader (Sciex WIFF/WIFF2,
+//! Waters MassLynx `.raw` and Agilent MassHunter `.d` directories) with
+//! ProteoWizard's `msconvert`, so that they can still be converted to
+//! mzparquet in a single step.
+//!
+//! The drift scans of Waters ion mobility acquisitions are combined into one
+//! spectrum per scan (`--combineIonMobilitySpectra`), with the drift time of
+//! each ion, in milliseconds, written to the `ion_mobility` column.
+//!
+//! `msconvert` writes an mzML file to a temporary directory, which is parsed
+//! as usual and deleted once it is dropped. The vendor libraries used by
+//! `msconvert` are Windows only, so on other platforms `MSCONVERT` usually
+//! points to a wrapper script, e.g. around the `proteowizard/pwiz-skyline`
+//! docker image.
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Environment variable holding the `msconvert` executable to run. Defaults
+/// to `msconvert`, found on the `PATH`
+pub const MSCONVERT_VAR: &str = "MSCONVERT";
+
+/// Vendor formats read through `msconvert`, by (lowercase) file extension
+pub const EXTENSIONS: [&str; 2] = ["wiff", "wiff2"];
+
+/// How to run `msconvert`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Msconvert {
+    program: OsString,
+    args: Vec<OsString>,
+}
+
+impl Default for Msconvert {
+    fn default() -> Self {
+        Msconvert {
+            program: std::env::var_os(MSCONVERT_VAR).unwrap_or_else(|| "msconvert".into()),
+            // Keep full precision, the writer options decide what is stored
+            args: vec!["--mzML".into(), "--64".into(), "--zlib".into()],
+        }
+    }
+}
+
+impl Msconvert {
+    /// Settings for Waters MassLynx `.raw` directories
+    pub fn waters() -> Self {
+        let mut msconvert = Self::default();
+        msconvert.arg("--combineIonMobilitySpectra");
+        msconvert
+    }
+
+    /// Settings for Agilent MassHunter `.d` directories: spectra are
+    /// centroided by the vendor library, as profile QTOF data is rarely
+    /// wanted and many times larger
+    pub fn agilent() -> Self {
+        let mut msconvert = Self::default();
+        msconvert
+            .arg("--filter")
+            .arg("peakPicking vendor msLevel=1-");
+        msconvert
+    }
+
+    /// Pass an extra argument to `msconvert`, e.g. `--filter "peakPicking
+    /// vendor"`
+    pub fn arg<S: Into<OsString>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Convert `input` to mzML in a new temporary directory, unique to the
+    /// call, as inputs converted at once may share a file stem
+    pub fn convert(&self, input: &Path) -> anyhow::Result<TempMzML> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let stem = input
+            .file_stem()
+            .ok_or_else(|| anyhow::anyhow!("{} has no file name", input.display()))?;
+        let dir = std::env::temp_dir().join(format!(
+            "mz_parquet-{}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed),
+            stem.to_string_lossy()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        // Removes the directory again if conversion fails
+        let mzml = TempMzML {
+            path: dir.join(stem).with_extension("mzML"),
+            dir,
+        };
+
+        log::info!(
+            file:% = input.display(),
+            stage = "msconvert";
+            "converting {} with msconvert", input.display()
+        );
+        let output = Command::new(&self.program)
+            .arg(input)
+            .args(&self.args)
+            .arg("-o")
+            .arg(&mzml.dir)
+            .arg("--outfile")
+            .arg(mzml.path.file_name().expect("file name"))
+            .output()
+            .map_err(|err| {
+                anyhow::anyhow!(
+                    "failed to run `{}` (set {} to the msconvert executable): {}",
+                    self.program.to_string_lossy(),
+                    MSCONVERT_VAR,
+                    err
+                )
+            })?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "msconvert failed to convert {}: {}",
+                input.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        if !mzml.path.exists() {
+            anyhow::bail!("msconvert did not write {}", mzml.path.display());
+        }
+        Ok(mzml)
+    }
+}
+
+/// Whether `path` is a Waters MassLynx `.raw` directory (Thermo RAW files
+/// share the extension, but are single files)
+pub fn is_waters_raw(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("raw"))
+        && path.is_dir()
+}
+
+/// Whether `path` is an Agilent MassHunter `.d` directory (Bruker `.d`
+/// directories have no `AcqData` directory)
+pub fn is_agilent_d(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("d"))
+        && path.join("AcqData").is_dir()
+}
+
+/// An mzML file written by `msconvert`, deleted (along with its directory)
+/// when dropped
+#[derive(Debug)]
+pub struct TempMzML {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl TempMzML {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempMzML {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            log::warn!("failed to remove {}: {}", self.dir.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report_missing_msconvert() {
+        let msconvert = Msconvert {
+            program: "/nonexistent/msconvert".into(),
+            args: Vec::new(),
+        };
+        let err = msconvert
+            .convert(Path::new("run.wiff"))
+            .expect_err("msconvert does not exist");
+        assert!(err.to_string().contains(MSCONVERT_VAR));
+        // The temporary directory is cleaned up
+        let prefix = format!("mz_parquet-{}-", std::process::id());
+        let left = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_name().to_string_lossy().starts_with(&prefix));
+        assert!(!left);
+    }
+
+    #[test]
+    fn detect_agilent_d() -> std::io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("vendor-{}", std::process::id()));
+        let agilent = dir.join("agilent.d");
+        let bruker = dir.join("bruker.d");
+        std::fs::create_dir_all(agilent.join("AcqData"))?;
+        std::fs::create_dir_all(&bruker)?;
+        std::fs::write(bruker.join("analysis.tdf"), b"")?;
+
+        let detected = (is_agilent_d(&agilent), is_agilent_d(&bruker));
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(detected, (true, false));
+
+        // Spectra are centroided by the vendor library
+        let args = Msconvert::agilent().args;
+        assert!(args.contains(&"peakPicking vendor msLevel=1-".into()));
+        Ok(())
+    }
+}
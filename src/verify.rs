@@ -0,0 +1,339 @@
+//! Audit the fidelity of a conversion by writing spectra to mzparquet,
+//! reading them back, and comparing the result against the source.
+//!
+//! Some loss is expected, depending on the writer options: m/z and intensity
+//! values written as FLOAT are rounded to the nearest `f32`, and `u32`
+//! intensities are rounded towards zero. [`Tolerances::new`] documents the
+//! error allowed for each setting, and anything beyond that is reported as a
+//! [`Discrepancy`].
+use crate::mzml::RawSpectrum;
+use crate::reader::{read_spectra, Format};
+use crate::rewrite::SpectrumFile;
+use crate::write_long::{IntensityType, MzPrecision, WriterOptions};
+use std::collections::HashMap;
+
+/// Maximum error allowed between a source value and the value read back
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tolerances {
+    /// Relative m/z error, in ppm
+    pub mz_ppm: f64,
+    /// Relative intensity error
+    pub intensity_relative: f64,
+    /// Absolute intensity error, on top of the relative error
+    pub intensity_absolute: f64,
+}
+
+impl Tolerances {
+    /// Tolerances implied by the physical types used for a file:
+    ///
+    /// * FLOAT values are within half a unit in the last place of the
+    ///   original, a relative error of 2^-24 (~0.06 ppm)
+    /// * DOUBLE values are exact
+    /// * `u32` intensities are truncated, so may be up to 1 lower
+    ///
+    /// Wide format files always store m/z and intensity as FLOAT
+    pub fn new(format: Format, options: &WriterOptions) -> Self {
+        const F32_ERROR: f64 = f32::EPSILON as f64 / 2.0;
+        let (mz, intensity) = match format {
+            Format::Long => (options.mz_precision, options.intensity_type),
+            Format::Wide => (MzPrecision::F32, IntensityType::F32),
+        };
+        let mz_ppm = match mz {
+            MzPrecision::F32 => F32_ERROR * 1e6,
+            MzPrecision::F64 => 0.0,
+        };
+        let (intensity_relative, intensity_absolute) = match intensity {
+            IntensityType::F32 => (F32_ERROR, 0.0),
+            IntensityType::F64 => (0.0, 0.0),
+            IntensityType::U32 => (0.0, 1.0),
+        };
+        Tolerances {
+            mz_ppm,
+            intensity_relative,
+            intensity_absolute,
+        }
+    }
+}
+
+/// A difference between the source spectra and those read back from
+/// mzparquet, beyond the allowed [`Tolerances`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Discrepancy {
+    /// The spectrum was not present in the mzparquet file. Long format files
+    /// have no rows for spectra without peaks
+    MissingSpectrum { id: String },
+    PeakCount {
+        id: String,
+        expected: usize,
+        found: usize,
+    },
+    /// `peak` is the index of the peak, in m/z order
+    Mz {
+        id: String,
+        peak: usize,
+        expected: f64,
+        found: f64,
+    },
+    Intensity {
+        id: String,
+        peak: usize,
+        expected: f64,
+        found: f64,
+    },
+}
+
+impl std::fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Discrepancy::MissingSpectrum { id } => write!(f, "{}: spectrum is missing", id),
+            Discrepancy::PeakCount {
+                id,
+                expected,
+                found,
+            } => write!(f, "{}: expected {} peaks, found {}", id, expected, found),
+            Discrepancy::Mz {
+                id,
+                peak,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: peak {} has m/z {}, expected {} ({:.3} ppm)",
+                id,
+                peak,
+                found,
+                expected,
+                ppm(*expected, *found)
+            ),
+            Discrepancy::Intensity {
+                id,
+                peak,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: peak {} has intensity {}, expected {}",
+                id, peak, found, expected
+            ),
+        }
+    }
+}
+
+/// The outcome of comparing source spectra against those read back from
+/// mzparquet
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub tolerances: Tolerances,
+    pub expected_spectra: usize,
+    pub found_spectra: usize,
+    pub expected_peaks: usize,
+    pub found_peaks: usize,
+    /// Largest m/z error observed, in ppm
+    pub max_mz_error_ppm: f64,
+    /// Largest relative intensity error observed
+    pub max_intensity_error: f64,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl Report {
+    /// True if every spectrum was read back within tolerance
+    pub fn is_ok(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "spectra:   {} expected, {} found",
+            self.expected_spectra, self.found_spectra
+        )?;
+        writeln!(
+            f,
+            "peaks:     {} expected, {} found",
+            self.expected_peaks, self.found_peaks
+        )?;
+        writeln!(
+            f,
+            "m/z:       max error {:.4} ppm (tolerance {:.4} ppm)",
+            self.max_mz_error_ppm, self.tolerances.mz_ppm
+        )?;
+        writeln!(
+            f,
+            "intensity: max relative error {:.3e} (tolerance {:.3e} + {})",
+            self.max_intensity_error,
+            self.tolerances.intensity_relative,
+            self.tolerances.intensity_absolute
+        )?;
+        write!(f, "discrepancies: {}", self.discrepancies.len())
+    }
+}
+
+fn ppm(expected: f64, found: f64) -> f64 {
+    match expected == found {
+        true => 0.0,
+        false => (found - expected).abs() / expected.abs() * 1e6,
+    }
+}
+
+/// Peaks of a spectrum, in m/z order. The long writer may sort ions, so
+/// peaks are compared by m/z rather than by their position in the source
+fn sorted_peaks(spectrum: &RawSpectrum) -> Vec<(f64, f64)> {
+    let mut peaks = spectrum
+        .mz
+        .iter()
+        .copied()
+        .zip(spectrum.intensity.iter().copied())
+        .collect::<Vec<_>>();
+    peaks.sort_by(|a, b| a.0.total_cmp(&b.0));
+    peaks
+}
+
+/// Compare `expected` spectra against those read back from an mzparquet
+/// file. Spectra are matched by id
+pub fn compare(expected: &[RawSpectrum], found: &[RawSpectrum], tolerances: Tolerances) -> Report {
+    let by_id = found
+        .iter()
+        .map(|s| (s.id.as_slice(), s))
+        .collect::<HashMap<_, _>>();
+
+    let mut report = Report {
+        tolerances,
+        expected_spectra: expected.len(),
+        found_spectra: found.len(),
+        expected_peaks: expected.iter().map(|s| s.mz.len()).sum(),
+        found_peaks: found.iter().map(|s| s.mz.len()).sum(),
+        max_mz_error_ppm: 0.0,
+        max_intensity_error: 0.0,
+        discrepancies: Vec::new(),
+    };
+
+    for spectrum in expected {
+        let id = || String::from_utf8_lossy(&spectrum.id).into_owned();
+        let Some(read) = by_id.get(spectrum.id.as_slice()) else {
+            report
+                .discrepancies
+                .push(Discrepancy::MissingSpectrum { id: id() });
+            continue;
+        };
+        if spectrum.mz.len() != read.mz.len() {
+            report.discrepancies.push(Discrepancy::PeakCount {
+                id: id(),
+                expected: spectrum.mz.len(),
+                found: read.mz.len(),
+            });
+            continue;
+        }
+
+        let peaks = sorted_peaks(spectrum).into_iter().zip(sorted_peaks(read));
+        for (peak, ((mz, int), (read_mz, read_int))) in peaks.enumerate() {
+            let mz_error = ppm(mz, read_mz);
+            report.max_mz_error_ppm = report.max_mz_error_ppm.max(mz_error);
+            if mz_error > tolerances.mz_ppm {
+                report.discrepancies.push(Discrepancy::Mz {
+                    id: id(),
+                    peak,
+                    expected: mz,
+                    found: read_mz,
+                });
+            }
+
+            let int_error = (read_int - int).abs();
+            if int != 0.0 {
+                report.max_intensity_error = report.max_intensity_error.max(int_error / int.abs());
+            }
+            if int_error > tolerances.intensity_absolute + tolerances.intensity_relative * int.abs()
+            {
+                report.discrepancies.push(Discrepancy::Intensity {
+                    id: id(),
+                    peak,
+                    expected: int,
+                    found: read_int,
+                });
+            }
+        }
+    }
+    report
+}
+
+/// Write `spectra` to an in-memory mzparquet file, read it back, and compare
+/// the result against the originals
+pub fn round_trip(
+    spectra: Vec<RawSpectrum>,
+    format: Format,
+    options: &WriterOptions,
+) -> anyhow::Result<Report> {
+    let file = SpectrumFile {
+        format,
+        sources: Vec::new(),
+        metadata: Default::default(),
+        spectra,
+    };
+    let (buf, _) = file.write(Vec::new(), options)?;
+    let (_, found) = read_spectra(bytes::Bytes::from(buf))?;
+    Ok(compare(
+        &file.spectra,
+        &found,
+        Tolerances::new(format, options),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spectra() -> Vec<RawSpectrum> {
+        vec![
+            RawSpectrum {
+                id: b"scan=1".to_vec(),
+                ms_level: 1,
+                mz: vec![400.123456789, 1200.987654321],
+                intensity: vec![1234.5678, 1e12],
+                ..Default::default()
+            },
+            RawSpectrum {
+                id: b"scan=2".to_vec(),
+                ms_level: 2,
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn lossy_casts_within_tolerance() -> anyhow::Result<()> {
+        let report = round_trip(spectra(), Format::Wide, &WriterOptions::default())?;
+        assert!(report.is_ok(), "{:?}", report.discrepancies);
+        assert_eq!(report.found_spectra, 2);
+        assert!(report.max_mz_error_ppm > 0.0);
+
+        let mut options = WriterOptions::default();
+        options.set_mz_precision(MzPrecision::F64);
+        options.set_intensity_type(IntensityType::F64);
+        let report = round_trip(spectra(), Format::Long, &options)?;
+        assert_eq!(report.max_mz_error_ppm, 0.0);
+        assert_eq!(report.max_intensity_error, 0.0);
+        // Spectra without peaks have no rows in the long format
+        assert_eq!(
+            report.discrepancies,
+            vec![Discrepancy::MissingSpectrum {
+                id: "scan=2".into()
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_clamped_intensities() -> anyhow::Result<()> {
+        let mut options = WriterOptions::default();
+        options.set_intensity_type(IntensityType::U32);
+        let report = round_trip(spectra(), Format::Long, &options)?;
+        assert!(report.discrepancies.contains(&Discrepancy::Intensity {
+            id: "scan=1".into(),
+            peak: 1,
+            expected: 1e12,
+            found: u32::MAX as f64,
+        }));
+        Ok(())
+    }
+}
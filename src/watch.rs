@@ -0,0 +1,292 @@
+//! Watch a directory (e.g. an instrument PC share) for mzML files, and pick
+//! out the ones that are ready to convert.
+//!
+//! Acquisition software writes mzML files gradually, so a file is only
+//! considered complete once its size and modification time have stopped
+//! changing for a settling period and, for uncompressed mzML, it ends with the
+//! closing `</mzML>` or `</indexedmzML>` tag. The directory is polled rather
+//! than watched through OS notifications, which are unreliable on network
+//! shares.
+//!
+//! Converted files, and failed attempts, are recorded in a JSON state file so
+//! that restarting the watcher does not convert files again. Files that are
+//! modified after they were converted are converted again.
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Name of the state file, placed in the watched directory by default
+pub const STATE_FILE: &str = ".mz_parquet_watch.json";
+
+/// Whether `path` names an mzML file, optionally gzipped
+pub fn is_mzml(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    name.ends_with(".mzml") || name.ends_with(".mzml.gz")
+}
+
+/// Whether an uncompressed mzML file has been written to the end. Gzipped
+/// files cannot be checked without decompressing them, and are assumed to be
+/// complete
+fn is_complete(path: &Path) -> std::io::Result<bool> {
+    if !path.to_string_lossy().to_lowercase().ends_with(".mzml") {
+        return Ok(true);
+    }
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(256)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    let tail = String::from_utf8_lossy(&tail);
+    let tail = tail.trim_end();
+    Ok(tail.ends_with("</mzML>") || tail.ends_with("</indexedmzML>"))
+}
+
+/// Size and modification time of a file, in milliseconds since the epoch
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub modified: u64,
+}
+
+impl Fingerprint {
+    fn read(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Fingerprint {
+            size: metadata.len(),
+            modified: modified.as_millis() as u64,
+        })
+    }
+}
+
+/// Outcome of converting a file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Outcome {
+    Converted,
+    Failed { attempts: u32, error: String },
+}
+
+/// A file recorded in the state file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    #[serde(flatten)]
+    pub fingerprint: Fingerprint,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    /// When the file was last attempted, in seconds since the epoch
+    pub timestamp: u64,
+}
+
+/// Files converted (or attempted), keyed by their path relative to the
+/// watched directory
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchState {
+    pub files: BTreeMap<String, FileState>,
+}
+
+impl WatchState {
+    /// Read a state file, starting afresh if it does not exist yet
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read(path) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the state file, replacing the previous one only once the new
+    /// one is complete
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Polls a directory for mzML files that are ready to convert
+pub struct Watcher {
+    dir: PathBuf,
+    state_path: PathBuf,
+    state: WatchState,
+    settle: Duration,
+    retries: u32,
+    /// Files that are not ready yet, with when they were last seen changing
+    pending: HashMap<PathBuf, (Fingerprint, Instant)>,
+}
+
+impl Watcher {
+    /// Watch `dir`, recording converted files in the state file at
+    /// `state_path`
+    pub fn new<P: Into<PathBuf>, S: Into<PathBuf>>(dir: P, state_path: S) -> anyhow::Result<Self> {
+        let state_path = state_path.into();
+        Ok(Watcher {
+            dir: dir.into(),
+            state: WatchState::load(&state_path)?,
+            state_path,
+            settle: Duration::from_secs(30),
+            retries: 3,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// How long a file must be left unchanged before it is converted.
+    /// Defaults to 30 seconds
+    pub fn set_settle(&mut self, settle: Duration) -> &mut Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Number of times a failing file is attempted before it is skipped, until
+    /// it is modified. Defaults to 3
+    pub fn set_retries(&mut self, retries: u32) -> &mut Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn state(&self) -> &WatchState {
+        &self.state
+    }
+
+    fn key(&self, path: &Path) -> String {
+        path.strip_prefix(&self.dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Whether a file with this fingerprint still needs to be converted
+    fn wanted(&self, key: &str, fingerprint: Fingerprint) -> bool {
+        match self.state.files.get(key) {
+            Some(file) if file.fingerprint == fingerprint => match &file.outcome {
+                Outcome::Converted => false,
+                Outcome::Failed { attempts, .. } => *attempts < self.retries,
+            },
+            _ => true,
+        }
+    }
+
+    /// mzML files in the directory that are complete and have not been
+    /// converted yet, in name order
+    pub fn poll(&mut self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = std::fs::read_dir(&self.dir)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        paths.retain(|path| path.is_file() && is_mzml(path));
+        paths.sort();
+
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        let mut pending = HashMap::new();
+        for path in paths {
+            // Files can be moved or deleted while they are being looked at
+            let Ok(fingerprint) = Fingerprint::read(&path) else {
+                continue;
+            };
+            if !self.wanted(&self.key(&path), fingerprint) {
+                continue;
+            }
+            let since = match self.pending.get(&path) {
+                Some(&(seen, since)) if seen == fingerprint => since,
+                _ => now,
+            };
+            if now.duration_since(since) >= self.settle && is_complete(&path).unwrap_or(false) {
+                ready.push(path);
+            } else {
+                pending.insert(path, (fingerprint, since));
+            }
+        }
+        self.pending = pending;
+        Ok(ready)
+    }
+
+    /// Record the outcome of converting `path`, and save the state file
+    pub fn record(&mut self, path: &Path, result: Result<(), String>) -> anyhow::Result<()> {
+        let key = self.key(path);
+        let fingerprint = Fingerprint::read(path)?;
+        let outcome = match result {
+            Ok(()) => Outcome::Converted,
+            Err(error) => {
+                let attempts = match self.state.files.get(&key) {
+                    Some(FileState {
+                        fingerprint: previous,
+                        outcome: Outcome::Failed { attempts, .. },
+                        ..
+                    }) if *previous == fingerprint => attempts + 1,
+                    _ => 1,
+                };
+                Outcome::Failed { attempts, error }
+            }
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.state.files.insert(
+            key,
+            FileState {
+                fingerprint,
+                outcome,
+                timestamp,
+            },
+        );
+        self.state.save(&self.state_path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn watch_directory() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mz_parquet_watch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let state = dir.join(STATE_FILE);
+        let complete = dir.join("a.mzML");
+        let partial = dir.join("b.mzML");
+        std::fs::write(&complete, "<indexedmzML>\n</indexedmzML>\n")?;
+        std::fs::write(&partial, "<mzML>\n<run>")?;
+        std::fs::write(dir.join("notes.txt"), "")?;
+
+        let mut watcher = Watcher::new(&dir, &state)?;
+        watcher.set_settle(Duration::ZERO).set_retries(2);
+        assert_eq!(watcher.poll()?, vec![complete.clone()]);
+
+        watcher.record(&complete, Err("disk full".into()))?;
+        assert_eq!(watcher.poll()?, vec![complete.clone()]);
+        watcher.record(&complete, Err("disk full".into()))?;
+        assert!(watcher.poll()?.is_empty());
+
+        std::fs::write(&partial, "<mzML>\n<run></run>\n</mzML>")?;
+        assert_eq!(watcher.poll()?, vec![partial.clone()]);
+        watcher.record(&partial, Ok(()))?;
+
+        // Restarting picks up where the last watcher left off
+        let mut watcher = Watcher::new(&dir, &state)?;
+        watcher.set_settle(Duration::ZERO).set_retries(2);
+        assert!(watcher.poll()?.is_empty());
+        assert_eq!(
+            watcher.state().files["a.mzML"].outcome,
+            Outcome::Failed {
+                attempts: 2,
+                error: "disk full".into()
+            }
+        );
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! Write the long format table as Arrow IPC, for tooling (Arrow Flight,
+//! memory-mapped analytics) that prefers IPC over parquet.
+//!
+//! Spectra are first written to an in-memory long format parquet file, and
+//! then decoded into record batches, so the IPC output has exactly the same
+//! columns and types as the parquet table, including the `--mz-precision`,
+//! `--intensity-type` and `--file-id` options.
+use crate::index;
+use crate::mzml::SpectrumStream;
+use crate::write_long::{self, WriterOptions, Written};
+use arrow_array::RecordBatch;
+use arrow_ipc::writer::{FileWriter, StreamWriter};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::{arrow::arrow_reader::ParquetRecordBatchReaderBuilder, file::reader::ChunkReader};
+use std::{io::Write, sync::Arc};
+
+/// Arrow IPC flavour to write
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum IpcFormat {
+    /// Random access file format (also known as Feather v2), which can be
+    /// memory-mapped
+    #[default]
+    File,
+    /// Streaming format, as used by Arrow Flight
+    Stream,
+}
+
+/// Copy the record batches of a long format mzparquet file to Arrow IPC,
+/// returning the underlying writer.
+///
+/// File level metadata (`version`, `format`, ...) is kept in the Arrow
+/// schema metadata, except for the scan index, as row groups have no
+/// equivalent in IPC
+pub fn parquet_to_ipc<R, W>(r: R, w: W, format: IpcFormat) -> anyhow::Result<W>
+where
+    R: 'static + ChunkReader,
+    W: Write,
+{
+    let builder = ParquetRecordBatchReaderBuilder::try_new(r)?;
+    let mut metadata = builder.schema().metadata().clone();
+    metadata.remove(index::KEY);
+    let schema = Arc::new(Schema::new_with_metadata(
+        builder.schema().fields().clone(),
+        metadata,
+    ));
+    let batches = builder.build()?.map(|batch| {
+        let batch = batch?;
+        RecordBatch::try_new(schema.clone(), batch.columns().to_vec())
+    });
+
+    match format {
+        IpcFormat::File => {
+            let mut writer = FileWriter::try_new(w, &schema)?;
+            for batch in batches {
+                writer.write(&batch?)?;
+            }
+            writer.finish()?;
+            Ok(writer.into_inner()?)
+        }
+        IpcFormat::Stream => {
+            let mut writer = StreamWriter::try_new(w, &schema)?;
+            for batch in batches {
+                writer.write(&batch?)?;
+            }
+            writer.finish()?;
+            Ok(writer.into_inner()?)
+        }
+    }
+}
+
+/// Decode a long format mzparquet file into record batches using only
+/// signed integer types, for table formats without unsigned integers (Delta
+/// Lake, Iceberg). The unsigned columns (`scan`, `level`, `precursor_scan`,
+/// ...) are widened to signed 64-bit integers.
+///
+/// Footer metadata, such as the scan index, describes a single file and is
+/// not carried over
+pub fn signed_record_batches<R>(r: R) -> anyhow::Result<Vec<RecordBatch>>
+where
+    R: 'static + ChunkReader,
+{
+    let builder = ParquetRecordBatchReaderBuilder::try_new(r)?;
+    let schema = Arc::new(Schema::new(
+        builder
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| match field.data_type() {
+                DataType::UInt32 => Arc::new(Field::new(
+                    field.name(),
+                    DataType::Int64,
+                    field.is_nullable(),
+                )),
+                _ => field.clone(),
+            })
+            .collect::<Vec<_>>(),
+    ));
+
+    builder
+        .build()?
+        .map(|batch| {
+            let batch = batch?;
+            let columns = batch
+                .columns()
+                .iter()
+                .zip(schema.fields())
+                .map(|(column, field)| arrow_cast::cast(column, field.data_type()))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(RecordBatch::try_new(schema.clone(), columns)?)
+        })
+        .collect()
+}
+
+/// Serialize spectra into an Arrow IPC file holding the long format table.
+/// Returns the underlying writer and the number of spectra and peaks that
+/// were written.
+///
+/// Unlike the parquet writers, the whole (compressed) table is held in
+/// memory until all spectra have been parsed
+pub async fn serialize_stream_to_ipc<W, S>(
+    w: W,
+    spectra: &mut S,
+    options: &WriterOptions,
+    format: IpcFormat,
+) -> anyhow::Result<(W, Written)>
+where
+    W: Write + Send,
+    S: SpectrumStream,
+{
+    let (buf, count) =
+        write_long::serialize_stream_to_parquet(Vec::new(), spectra, options).await?;
+    let w = parquet_to_ipc(bytes::Bytes::from(buf), w, format)?;
+    Ok((w, count))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::RawSpectrum;
+    use crate::write_long::{IntensityType, MzPrecision};
+    use arrow_array::{cast::AsArray, types::Float64Type};
+    use arrow_ipc::reader::{FileReader, StreamReader};
+    use arrow_schema::DataType;
+
+    #[test]
+    fn same_table_as_parquet() -> anyhow::Result<()> {
+        let spectra = vec![RawSpectrum {
+            id: b"scan=1".to_vec(),
+            ms_level: 1,
+            mz: vec![400.0, 500.000001],
+            intensity: vec![10.0, 20.0],
+            ..Default::default()
+        }];
+
+        let mut options = WriterOptions::default();
+        options
+            .set_mz_precision(MzPrecision::F64)
+            .set_intensity_type(IntensityType::U32);
+        let file = crate::rewrite::SpectrumFile {
+            format: crate::Format::Long,
+            sources: Vec::new(),
+            metadata: Default::default(),
+            spectra,
+        };
+        let (buf, _) = file.write(Vec::new(), &options)?;
+        let buf = bytes::Bytes::from(buf);
+
+        let ipc = parquet_to_ipc(buf.clone(), Vec::new(), IpcFormat::File)?;
+        let reader = FileReader::try_new(std::io::Cursor::new(ipc), None)?;
+        let schema = reader.schema();
+        assert_eq!(schema.field(0).name(), "scan");
+        assert_eq!(schema.field(3).data_type(), &DataType::Float64);
+        assert_eq!(schema.field(4).data_type(), &DataType::UInt32);
+        assert_eq!(
+            schema.metadata().get("format").map(String::as_str),
+            Some("long")
+        );
+        assert!(!schema.metadata().contains_key(index::KEY));
+
+        let batches = reader.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(batches.len(), 1);
+        let mz = batches[0].column(3).as_primitive::<Float64Type>();
+        assert_eq!(mz.values().to_vec(), vec![400.0, 500.000001]);
+
+        let ipc = parquet_to_ipc(buf, Vec::new(), IpcFormat::Stream)?;
+        let rows = StreamReader::try_new(std::io::Cursor::new(ipc), None)?
+            .map(|batch| batch.map(|b| b.num_rows()))
+            .sum::<Result<usize, _>>()?;
+        assert_eq!(rows, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! Write chromatograms (TIC, BPC, SRM/SIM transitions, ...) to a parquet
+//! file stored next to the spectra, with one row per point.
+//!
+//! Each point carries the id and type of its chromatogram, along with the
+//! precursor and product m/z of SRM transitions, so that a transition can be
+//! selected with a simple filter (e.g. `WHERE product_mz = 508.2`).
+//!
+//! Non-MS traces recorded alongside the run (UV/PDA absorbance, pump pressure,
+//! flow rate) are stored in the same table, with their values in the
+//! `intensity` column and a `type` of e.g. `absorption` or `pressure`.
+use crate::mzml::Chromatogram;
+use crate::write_long::{writer_properties, ColumnWriter, WriterOptions};
+use parquet::{
+    data_type::{ByteArray, ByteArrayType, DoubleType, FloatType},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::types::{SchemaDescriptor, Type},
+};
+use std::{io::Write, sync::Arc};
+
+/// Build the parquet schema for chromatogram files
+pub fn build_schema() -> parquet::errors::Result<Type> {
+    use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+
+    let chromatogram = Type::primitive_type_builder("chromatogram", PhysicalType::BYTE_ARRAY)
+        .with_repetition(Repetition::REQUIRED)
+        .with_logical_type(Some(LogicalType::String))
+        .build()?;
+
+    let kind = Type::primitive_type_builder("type", PhysicalType::BYTE_ARRAY)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_logical_type(Some(LogicalType::String))
+        .build()?;
+
+    let precursor_mz = Type::primitive_type_builder("precursor_mz", PhysicalType::DOUBLE)
+        .with_repetition(Repetition::OPTIONAL)
+        .build()?;
+
+    let product_mz = Type::primitive_type_builder("product_mz", PhysicalType::DOUBLE)
+        .with_repetition(Repetition::OPTIONAL)
+        .build()?;
+
+    let time = Type::primitive_type_builder("time", PhysicalType::FLOAT)
+        .with_repetition(Repetition::REQUIRED)
+        .build()?;
+
+    let intensity = Type::primitive_type_builder("intensity", PhysicalType::FLOAT)
+        .with_repetition(Repetition::REQUIRED)
+        .build()?;
+
+    Type::group_type_builder("schema")
+        .with_fields(vec![
+            Arc::new(chromatogram),
+            Arc::new(kind),
+            Arc::new(precursor_mz),
+            Arc::new(product_mz),
+            Arc::new(time),
+            Arc::new(intensity),
+        ])
+        .build()
+}
+
+/// Incrementally writes chromatograms into row groups of a chromatogram file
+pub struct ChunkWriter<'a, W>
+where
+    W: std::io::Write + Send,
+{
+    writer: &'a mut SerializedFileWriter<W>,
+    options: WriterOptions,
+    current_rows: usize,
+    current_chromatograms: usize,
+
+    chromatogram: ColumnWriter<ByteArrayType>,
+    kind: ColumnWriter<ByteArrayType, true>,
+    precursor_mz: ColumnWriter<DoubleType, true>,
+    product_mz: ColumnWriter<DoubleType, true>,
+    time: ColumnWriter<FloatType>,
+    intensity: ColumnWriter<FloatType>,
+}
+
+impl<'a, W> ChunkWriter<'a, W>
+where
+    W: std::io::Write + Send,
+{
+    pub fn new(
+        writer: &'a mut SerializedFileWriter<W>,
+        descr: &SchemaDescriptor,
+        properties: Arc<WriterProperties>,
+        options: &WriterOptions,
+    ) -> Self {
+        assert_eq!(descr.num_columns(), 6);
+
+        Self {
+            writer,
+            options: options.clone(),
+            current_rows: 0,
+            current_chromatograms: 0,
+            chromatogram: ColumnWriter::new(descr.column(0), properties.clone()),
+            kind: ColumnWriter::new(descr.column(1), properties.clone()),
+            precursor_mz: ColumnWriter::new(descr.column(2), properties.clone()),
+            product_mz: ColumnWriter::new(descr.column(3), properties.clone()),
+            time: ColumnWriter::new(descr.column(4), properties.clone()),
+            intensity: ColumnWriter::new(descr.column(5), properties),
+        }
+    }
+
+    pub fn write_chromatogram(&mut self, chromatogram: &Chromatogram) -> anyhow::Result<()> {
+        let n = chromatogram.time.len().min(chromatogram.intensity.len());
+        self.chromatogram.extend(std::iter::repeat_n(
+            ByteArray::from(chromatogram.id.clone()),
+            n,
+        ));
+        self.kind.extend(std::iter::repeat_n(
+            chromatogram.kind.map(ByteArray::from),
+            n,
+        ));
+        self.precursor_mz
+            .extend(std::iter::repeat_n(chromatogram.precursor_mz, n));
+        self.product_mz
+            .extend(std::iter::repeat_n(chromatogram.product_mz, n));
+        self.time.extend(
+            chromatogram.time[..n]
+                .iter()
+                .map(|&t| self.options.rt_unit.from_seconds(t as f32)),
+        );
+        self.intensity
+            .extend(chromatogram.intensity[..n].iter().map(|&i| i as f32));
+
+        self.current_rows += n;
+        self.current_chromatograms += 1;
+        // Around 32 bytes per point, with the (repeated) id
+        if self.options.row_group_size.is_full(
+            self.current_rows,
+            self.current_chromatograms,
+            self.current_rows * (32 + chromatogram.id.len()),
+        ) {
+            self.write_to_row_group()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        if self.current_rows > 0 {
+            self.write_to_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn write_to_row_group(&mut self) -> anyhow::Result<()> {
+        let mut rg = self.writer.next_row_group()?;
+        self.chromatogram.write_and_flush(&mut rg)?;
+        self.kind.write_and_flush(&mut rg)?;
+        self.precursor_mz.write_and_flush(&mut rg)?;
+        self.product_mz.write_and_flush(&mut rg)?;
+        self.time.write_and_flush(&mut rg)?;
+        self.intensity.write_and_flush(&mut rg)?;
+        rg.close()?;
+
+        self.current_rows = 0;
+        self.current_chromatograms = 0;
+        Ok(())
+    }
+}
+
+/// Serialize `chromatograms` into a chromatogram file, returning the
+/// underlying writer once the file footer has been written
+pub fn serialize_to_parquet<W: Write + Send>(
+    w: W,
+    chromatograms: &[Chromatogram],
+    options: &WriterOptions,
+) -> anyhow::Result<W> {
+    let schema = build_schema()?;
+    let sd = SchemaDescriptor::new(schema.clone().into());
+    let properties = writer_properties("chromatogram", options)?;
+
+    let mut writer = SerializedFileWriter::new(w, schema.into(), properties.clone())?;
+    let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, properties, options);
+    for chromatogram in chromatograms {
+        chunk_writer.write_chromatogram(chromatogram)?;
+    }
+    chunk_writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::MzMLReader;
+    use crate::write_long::RtUnit;
+    use parquet::{
+        file::{reader::FileReader, serialized_reader::SerializedFileReader},
+        record::Field,
+    };
+
+    #[tokio::test]
+    async fn write_srm_transitions() -> anyhow::Result<()> {
+        let document = r#"
+        <mzML>
+        <run id="run">
+        <spectrumList count="0"/>
+        <chromatogramList count="2">
+            <chromatogram index="0" id="TIC" defaultArrayLength="2">
+                <cvParam cvRef="MS" accession="MS:1000235" name="total ion current chromatogram"/>
+                <binaryDataArrayList count="2">
+                    <binaryDataArray encodedLength="24">
+                        <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float"/>
+                        <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                        <cvParam cvRef="MS" accession="MS:1000595" name="time array" unitAccession="UO:0000031" unitName="minute"/>
+                        <binary>AAAAAAAA4D8AAAAAAADwPw==</binary>
+                    </binaryDataArray>
+                    <binaryDataArray encodedLength="12">
+                        <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float"/>
+                        <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                        <cvParam cvRef="MS" accession="MS:1000515" name="intensity array"/>
+                        <binary>AACAPwAAAEA=</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+            </chromatogram>
+            <chromatogram index="1" id="SRM SIC Q1=654.3 Q3=508.2" defaultArrayLength="2">
+                <cvParam cvRef="MS" accession="MS:1001473" name="selected reaction monitoring chromatogram"/>
+                <precursor>
+                    <isolationWindow>
+                        <cvParam cvRef="MS" accession="MS:1000827" name="isolation window target m/z" value="654.3"/>
+                    </isolationWindow>
+                    <activation>
+                        <cvParam cvRef="MS" accession="MS:1000133" name="collision-induced dissociation"/>
+                    </activation>
+                </precursor>
+                <product>
+                    <isolationWindow>
+                        <cvParam cvRef="MS" accession="MS:1000827" name="isolation window target m/z" value="508.2"/>
+                    </isolationWindow>
+                </product>
+                <binaryDataArrayList count="2">
+                    <binaryDataArray encodedLength="24">
+                        <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float"/>
+                        <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                        <cvParam cvRef="MS" accession="MS:1000595" name="time array" unitAccession="UO:0000031" unitName="minute"/>
+                        <binary>AAAAAAAA4D8AAAAAAADwPw==</binary>
+                    </binaryDataArray>
+                    <binaryDataArray encodedLength="12">
+                        <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float"/>
+                        <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                        <cvParam cvRef="MS" accession="MS:1000515" name="intensity array"/>
+                        <binary>AACAPwAAAEA=</binary>
+                    </binaryDataArray>
+                </binaryDataArrayList>
+            </chromatogram>
+        </chromatogramList>
+        </run>
+        </mzML>
+        "#;
+
+        let mut stream = MzMLReader::default().stream(document.as_bytes());
+        assert!(stream.next_spectrum().await?.is_none());
+        let chromatograms = stream.take_chromatograms();
+        assert_eq!(chromatograms.len(), 2);
+        assert_eq!(chromatograms[0].kind, Some("TIC"));
+        assert_eq!(chromatograms[0].time, vec![30.0, 60.0]);
+        assert_eq!(chromatograms[0].intensity, vec![1.0, 2.0]);
+        assert_eq!(chromatograms[1].kind, Some("SRM"));
+        assert_eq!(chromatograms[1].precursor_mz, Some(654.3));
+        assert_eq!(chromatograms[1].product_mz, Some(508.2));
+
+        let mut options = WriterOptions::default();
+        options.set_rt_unit(RtUnit::Minutes);
+        let buf = serialize_to_parquet(Vec::new(), &chromatograms, &options)?;
+        let reader = SerializedFileReader::new(bytes::Bytes::from(buf))?;
+        let rows = reader.get_row_iter(None)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(rows.len(), 4);
+        let last = rows[3].get_column_iter().collect::<Vec<_>>();
+        assert_eq!(last[0].1, &Field::Str("SRM SIC Q1=654.3 Q3=508.2".into()));
+        assert_eq!(last[1].1, &Field::Str("SRM".into()));
+        assert_eq!(last[3].1, &Field::Double(508.2));
+        assert_eq!(last[4].1, &Field::Float(1.0));
+        assert_eq!(last[5].1, &Field::Float(2.0));
+        Ok(())
+    }
+}
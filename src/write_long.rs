@@ -1,20 +1,232 @@
-use crate::mzml::RawSpectrum;
+use crate::average::{AveragedStream, Ms1Averaging};
+use crate::binning::Binning;
+use crate::deisotope::Deisotope;
+use crate::demux::{Demultiplex, DemultiplexedStream};
+use crate::filter::{PeakFilter, SpectrumFilter};
+use crate::index::{RowGroupRange, ScanIndex};
+use crate::lock_mass::LockMass;
+use crate::mzml::{Precursor, RawSpectrum, SpectrumStream};
+use crate::progress::{Callback, Progress};
+use crate::purity::PrecursorPurity;
 use parquet::{
-    basic::ZstdLevel,
-    data_type::{FloatType, Int32Type},
+    basic::{Compression, Type as PhysicalType, ZstdLevel},
+    data_type::{ByteArray, ByteArrayType, DoubleType, FloatType, Int32Type, Int64Type},
     file::{
-        metadata::KeyValue,
-        properties::WriterProperties,
+        metadata::{KeyValue, ParquetMetaData},
+        properties::{EnabledStatistics, WriterProperties},
         writer::{
             SerializedFileWriter, SerializedPageWriter, SerializedRowGroupWriter, TrackedWrite,
         },
     },
-    schema::types::{ColumnDescriptor, SchemaDescriptor, Type},
+    format::SortingColumn,
+    schema::types::{ColumnDescriptor, ColumnPath, SchemaDescriptor, Type},
 };
-use std::{collections::HashMap, io::Write, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    sync::Arc,
+};
+
+/// Physical type used for the `mz` and `precursor_mz` columns
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MzPrecision {
+    /// FLOAT - smaller files, but loses ~1 ppm of accuracy at high m/z
+    #[default]
+    F32,
+    /// DOUBLE - preserves 64-bit m/z values, e.g. from Orbitrap/FTMS data
+    F64,
+}
+
+impl MzPrecision {
+    fn physical_type(self) -> PhysicalType {
+        match self {
+            MzPrecision::F32 => PhysicalType::FLOAT,
+            MzPrecision::F64 => PhysicalType::DOUBLE,
+        }
+    }
+}
+
+impl std::str::FromStr for MzPrecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(MzPrecision::F32),
+            "f64" => Ok(MzPrecision::F64),
+            _ => Err(format!(
+                "unknown m/z precision `{}`, expected `f32` or `f64`",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for MzPrecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MzPrecision::F32 => f.write_str("f32"),
+            MzPrecision::F64 => f.write_str("f64"),
+        }
+    }
+}
+
+/// Physical type used for the `intensity` column
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum IntensityType {
+    /// FLOAT
+    #[default]
+    F32,
+    /// DOUBLE
+    F64,
+    /// Unsigned INT32 - intensities are rounded towards zero and clamped
+    /// to `0..=u32::MAX`
+    U32,
+}
+
+impl std::str::FromStr for IntensityType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(IntensityType::F32),
+            "f64" => Ok(IntensityType::F64),
+            "u32" => Ok(IntensityType::U32),
+            _ => Err(format!(
+                "unknown intensity type `{}`, expected `f32`, `f64` or `u32`",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for IntensityType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntensityType::F32 => f.write_str("f32"),
+            IntensityType::F64 => f.write_str("f64"),
+            IntensityType::U32 => f.write_str("u32"),
+        }
+    }
+}
+
+/// Unit of the retention times stored in a file. Parsers always report scan
+/// start times in seconds; the unit they are written in is chosen with
+/// [`WriterOptions::set_rt_unit`], and recorded in the footer metadata
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RtUnit {
+    Minutes,
+    #[default]
+    Seconds,
+}
+
+impl RtUnit {
+    /// Convert a retention time in seconds to this unit
+    pub fn from_seconds(self, seconds: f32) -> f32 {
+        match self {
+            RtUnit::Minutes => seconds / 60.0,
+            RtUnit::Seconds => seconds,
+        }
+    }
 
-pub fn build_schema() -> parquet::errors::Result<Type> {
-    use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+    /// Convert a retention time in this unit to seconds
+    pub fn to_seconds(self, rt: f32) -> f32 {
+        match self {
+            RtUnit::Minutes => rt * 60.0,
+            RtUnit::Seconds => rt,
+        }
+    }
+
+    /// Unit of the retention times in a file. Files written before the unit
+    /// was recorded hold scan start times as found in the mzML file, which
+    /// usually means minutes
+    pub fn from_metadata(metadata: &ParquetMetaData) -> Self {
+        Self::from_key_value(metadata.file_metadata().key_value_metadata())
+            .unwrap_or(RtUnit::Minutes)
+    }
+
+    pub(crate) fn from_key_value(kv: Option<&Vec<KeyValue>>) -> Option<Self> {
+        kv.into_iter()
+            .flatten()
+            .find(|kv| kv.key == RT_UNIT_KEY)
+            .and_then(|kv| kv.value.as_deref())
+            .and_then(|value| value.parse().ok())
+    }
+}
+
+impl std::str::FromStr for RtUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minutes" | "min" => Ok(RtUnit::Minutes),
+            "seconds" | "sec" | "s" => Ok(RtUnit::Seconds),
+            _ => Err(format!(
+                "unknown retention time unit `{}`, expected `minutes` or `seconds`",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for RtUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RtUnit::Minutes => f.write_str("minutes"),
+            RtUnit::Seconds => f.write_str("seconds"),
+        }
+    }
+}
+
+/// Footer metadata key holding the [`RtUnit`] of the `rt` (long format) or
+/// `scan_start_time` (wide format) column
+pub const RT_UNIT_KEY: &str = "rt_unit";
+
+/// Column numbering spectra in acquisition order, alongside the vendor scan
+/// numbers in `scan`
+pub const SPECTRUM_INDEX_COLUMN: &str = "spectrum_index";
+
+/// List column holding the m/z of all precursors isolated together, such as
+/// SPS-MS3 notches
+pub const SPS_MZ_COLUMN: &str = "sps_mz";
+
+/// Optional columns holding the position of imaging spectra, see
+/// [`WriterOptions::set_pixel_columns`]
+pub const PIXEL_COLUMNS: [&str; 3] = ["pixel_x", "pixel_y", "pixel_z"];
+
+/// Optional columns holding the noise and baseline of each ion, see
+/// [`WriterOptions::set_noise_columns`]
+pub const NOISE_COLUMNS: [&str; 2] = ["noise", "baseline"];
+
+/// Optional column holding the charge state of each ion, see
+/// [`WriterOptions::set_charge_column`]
+pub const CHARGE_COLUMN: &str = "charge";
+
+/// Optional column holding the lock mass error removed from the m/z values of
+/// each spectrum, in ppm, see [`WriterOptions::set_lock_mass`]
+pub const LOCK_MASS_COLUMN: &str = "lock_mass_correction";
+
+/// Optional column holding the index of the m/z bin of each ion, see
+/// [`WriterOptions::set_binning`]
+pub const MZ_BIN_COLUMN: &str = "mz_bin";
+
+/// Optional column holding the precursor purity of each MS2 spectrum, see
+/// [`WriterOptions::set_precursor_purity`]
+pub const PRECURSOR_PURITY_COLUMN: &str = "precursor_purity";
+
+/// Optional column holding the wall-clock time of each spectrum, see
+/// [`WriterOptions::set_acquisition_time`]
+pub const ACQUISITION_TIME_COLUMN: &str = "acquisition_time";
+
+/// Optional column holding the unparsed cvParams and userParams of each
+/// spectrum as a JSON object, see [`WriterOptions::set_extra_params`]
+pub const EXTRA_PARAMS_COLUMN: &str = "extra_params";
+
+/// Build the parquet schema for the long format, where each individual ion
+/// in an acquisition has it's own row
+pub fn build_schema(options: &WriterOptions) -> parquet::errors::Result<Type> {
+    use parquet::basic::{LogicalType, Repetition};
     use parquet::schema::types::Type;
 
     let scan = Type::primitive_type_builder("scan", PhysicalType::INT32)
@@ -37,17 +249,21 @@ pub fn build_schema() -> parquet::errors::Result<Type> {
         .with_repetition(Repetition::REQUIRED)
         .build()?;
 
-    let mz = Type::primitive_type_builder("mz", PhysicalType::FLOAT)
+    let mz = Type::primitive_type_builder("mz", options.mz_precision.physical_type())
         .with_repetition(Repetition::REQUIRED)
         .build()?;
 
-    let intensity = Type::primitive_type_builder("intensity", PhysicalType::INT32)
-        .with_repetition(Repetition::REQUIRED)
-        .with_logical_type(Some(LogicalType::Integer {
-            bit_width: 32,
-            is_signed: false,
-        }))
-        .build()?;
+    let intensity = match options.intensity_type {
+        IntensityType::F32 => Type::primitive_type_builder("intensity", PhysicalType::FLOAT),
+        IntensityType::F64 => Type::primitive_type_builder("intensity", PhysicalType::DOUBLE),
+        IntensityType::U32 => Type::primitive_type_builder("intensity", PhysicalType::INT32)
+            .with_logical_type(Some(LogicalType::Integer {
+                bit_width: 32,
+                is_signed: false,
+            })),
+    }
+    .with_repetition(Repetition::REQUIRED)
+    .build()?;
     let ion_mobility = Type::primitive_type_builder("ion_mobility", PhysicalType::FLOAT)
         .with_repetition(Repetition::OPTIONAL)
         .build()?;
@@ -68,11 +284,59 @@ pub fn build_schema() -> parquet::errors::Result<Type> {
         }))
         .build()?;
 
-    let precursor_mz = Type::primitive_type_builder("precursor_mz", PhysicalType::FLOAT)
+    let precursor_mz =
+        Type::primitive_type_builder("precursor_mz", options.mz_precision.physical_type())
+            .with_repetition(Repetition::OPTIONAL)
+            .build()?;
+
+    let precursor_z = Type::primitive_type_builder("precursor_charge", PhysicalType::INT32)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_logical_type(Some(LogicalType::Integer {
+            bit_width: 32,
+            is_signed: false,
+        }))
+        .build()?;
+
+    // Spectrum-level values, repeated for each ion. These were added after the
+    // columns above, and are optional so that older files remain valid
+    let total_ion_current = Type::primitive_type_builder("total_ion_current", PhysicalType::FLOAT)
         .with_repetition(Repetition::OPTIONAL)
         .build()?;
 
-    let precursor_z = Type::primitive_type_builder("precursor_charge", PhysicalType::INT32)
+    let ion_injection_time =
+        Type::primitive_type_builder("ion_injection_time", PhysicalType::FLOAT)
+            .with_repetition(Repetition::OPTIONAL)
+            .build()?;
+
+    // Repeated for each ion, but dictionary encoded so that each distinct
+    // filter string is only stored once per row group
+    let filter_string = Type::primitive_type_builder("filter_string", PhysicalType::BYTE_ARRAY)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_logical_type(Some(LogicalType::String))
+        .build()?;
+
+    // The vendor/native spectrum identifier, e.g. `controllerType=0
+    // controllerNumber=1 scan=42`, needed to join against search engine
+    // results for formats without a scan number
+    let native_id = Type::primitive_type_builder("native_id", PhysicalType::BYTE_ARRAY)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_logical_type(Some(LogicalType::String))
+        .build()?;
+
+    // `scan` holds the vendor scan number where the native id has one, which
+    // is not unique when several runs are merged, so spectra are numbered by
+    // a separate counter in acquisition order
+    let spectrum_index = Type::primitive_type_builder(SPECTRUM_INDEX_COLUMN, PhysicalType::INT32)
+        .with_repetition(Repetition::REQUIRED)
+        .with_logical_type(Some(LogicalType::Integer {
+            bit_width: 32,
+            is_signed: false,
+        }))
+        .build()?;
+
+    // `precursor_scan` only links a spectrum to the previous MS stage, so the
+    // MS1 scan at the root of each MSn precursor chain is stored as well
+    let ms1_scan = Type::primitive_type_builder("ms1_scan", PhysicalType::INT32)
         .with_repetition(Repetition::OPTIONAL)
         .with_logical_type(Some(LogicalType::Integer {
             bit_width: 32,
@@ -80,23 +344,135 @@ pub fn build_schema() -> parquet::errors::Result<Type> {
         }))
         .build()?;
 
+    // The m/z of every precursor isolated from the same parent scan as
+    // `precursor_mz`, e.g. the SPS notches of TMT MS3 scans. Null for spectra
+    // with a single precursor
+    let sps_mz = crate::write_wide::list(
+        SPS_MZ_COLUMN,
+        Repetition::OPTIONAL,
+        Type::primitive_type_builder("element", options.mz_precision.physical_type())
+            .with_repetition(Repetition::REQUIRED)
+            .build()?,
+    )?;
+
+    let mut fields = vec![
+        Arc::new(scan),
+        Arc::new(level),
+        Arc::new(rt),
+        Arc::new(mz),
+        Arc::new(intensity),
+        Arc::new(ion_mobility),
+        Arc::new(isolation_lower),
+        Arc::new(isolation_upper),
+        Arc::new(precursor_scan),
+        Arc::new(precursor_mz),
+        Arc::new(precursor_z),
+        Arc::new(total_ion_current),
+        Arc::new(ion_injection_time),
+        Arc::new(filter_string),
+        Arc::new(native_id),
+        Arc::new(spectrum_index),
+        Arc::new(ms1_scan),
+        Arc::new(sps_mz),
+    ];
+
+    if options.source.is_some() {
+        fields.push(Arc::new(
+            Type::primitive_type_builder("file_id", PhysicalType::BYTE_ARRAY)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(Some(LogicalType::String))
+                .build()?,
+        ));
+    }
+
+    if options.pixel_columns {
+        for name in PIXEL_COLUMNS {
+            fields.push(Arc::new(
+                Type::primitive_type_builder(name, PhysicalType::INT32)
+                    .with_repetition(Repetition::OPTIONAL)
+                    .with_logical_type(Some(LogicalType::Integer {
+                        bit_width: 32,
+                        is_signed: false,
+                    }))
+                    .build()?,
+            ));
+        }
+    }
+
+    if options.noise_columns {
+        for name in NOISE_COLUMNS {
+            fields.push(Arc::new(
+                Type::primitive_type_builder(name, PhysicalType::FLOAT)
+                    .with_repetition(Repetition::OPTIONAL)
+                    .build()?,
+            ));
+        }
+    }
+
+    if options.charge_column {
+        fields.push(Arc::new(
+            Type::primitive_type_builder(CHARGE_COLUMN, PhysicalType::INT32)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(Some(LogicalType::Integer {
+                    bit_width: 32,
+                    is_signed: false,
+                }))
+                .build()?,
+        ));
+    }
+
+    if options.acquisition_time {
+        fields.push(Arc::new(
+            Type::primitive_type_builder(ACQUISITION_TIME_COLUMN, PhysicalType::INT64)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(Some(LogicalType::Timestamp {
+                    is_adjusted_to_u_t_c: true,
+                    unit: parquet::basic::TimeUnit::MILLIS(Default::default()),
+                }))
+                .build()?,
+        ));
+    }
+
+    if options.lock_mass.is_some() {
+        fields.push(Arc::new(
+            Type::primitive_type_builder(LOCK_MASS_COLUMN, PhysicalType::FLOAT)
+                .with_repetition(Repetition::OPTIONAL)
+                .build()?,
+        ));
+    }
+
+    if options.binning.is_some() {
+        fields.push(Arc::new(
+            Type::primitive_type_builder(MZ_BIN_COLUMN, PhysicalType::INT32)
+                .with_repetition(Repetition::OPTIONAL)
+                .build()?,
+        ));
+    }
+
+    if options.precursor_purity.is_some() {
+        fields.push(Arc::new(
+            Type::primitive_type_builder(PRECURSOR_PURITY_COLUMN, PhysicalType::FLOAT)
+                .with_repetition(Repetition::OPTIONAL)
+                .build()?,
+        ));
+    }
+
+    if options.extra_params {
+        fields.push(Arc::new(
+            Type::primitive_type_builder(EXTRA_PARAMS_COLUMN, PhysicalType::BYTE_ARRAY)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(Some(LogicalType::Json))
+                .build()?,
+        ));
+    }
+
     Type::group_type_builder("schema")
-        .with_fields(vec![
-            Arc::new(scan),
-            Arc::new(level),
-            Arc::new(rt),
-            Arc::new(mz),
-            Arc::new(intensity),
-            Arc::new(ion_mobility),
-            Arc::new(isolation_lower),
-            Arc::new(isolation_upper),
-            Arc::new(precursor_scan),
-            Arc::new(precursor_mz),
-            Arc::new(precursor_z),
-        ])
+        .with_fields(fields)
         .build()
 }
 
+/// Buffers the values (and definition/repetition levels) of a single column
+/// until the current row group is flushed
 pub struct ColumnWriter<T: parquet::data_type::DataType, const NULLABLE: bool = false> {
     values: Vec<T::T>,
     def_levels: Vec<i16>,
@@ -116,7 +492,42 @@ impl<T: parquet::data_type::DataType, const NULLABLE: bool> ColumnWriter<T, NULL
         }
     }
 
-    fn write_and_flush<W: std::io::Write + Send>(
+    /// Push a value nested inside of a repeated (list) column, along with its
+    /// definition and repetition levels. `None` is recorded as a null (or empty
+    /// list) at definition level `def`
+    pub fn push_nested(&mut self, value: Option<T::T>, def: i16, rep: i16) {
+        if let Some(value) = value {
+            self.values.push(value);
+        }
+        self.def_levels.push(def);
+        self.rep_levels.push(rep);
+    }
+
+    /// Reorder the buffered rows of a repeated column, so that row `i`
+    /// becomes `order[i]`. Values are only present at definition level
+    /// `max_def`
+    pub(crate) fn permute_nested(&mut self, order: &[usize], max_def: i16) {
+        let mut values = std::mem::take(&mut self.values).into_iter();
+        let def_levels = std::mem::take(&mut self.def_levels);
+        let rep_levels = std::mem::take(&mut self.rep_levels);
+        let mut starts = Vec::new();
+        let mut levels = Vec::with_capacity(def_levels.len());
+        for (def, rep) in def_levels.into_iter().zip(rep_levels) {
+            if rep == 0 {
+                starts.push(levels.len());
+            }
+            let value = if def == max_def { values.next() } else { None };
+            levels.push((value, def, rep));
+        }
+        starts.push(levels.len());
+        for &i in order {
+            for (value, def, rep) in levels[starts[i]..starts[i + 1]].iter().cloned() {
+                self.push_nested(value, def, rep);
+            }
+        }
+    }
+
+    pub(crate) fn write_and_flush<W: std::io::Write + Send>(
         &mut self,
         rg: &mut SerializedRowGroupWriter<'_, W>,
     ) -> anyhow::Result<()> {
@@ -128,11 +539,9 @@ impl<T: parquet::data_type::DataType, const NULLABLE: bool> ColumnWriter<T, NULL
             page_writer,
         );
 
-        if NULLABLE {
-            column.write_batch(&self.values, Some(&self.def_levels), None)?
-        } else {
-            column.write_batch(&self.values, None, None)?
-        };
+        let def_levels = (!self.def_levels.is_empty()).then_some(self.def_levels.as_slice());
+        let rep_levels = (!self.rep_levels.is_empty()).then_some(self.rep_levels.as_slice());
+        column.write_batch(&self.values, def_levels, rep_levels)?;
 
         let c = column.close().unwrap();
         buf.flush()?;
@@ -141,6 +550,7 @@ impl<T: parquet::data_type::DataType, const NULLABLE: bool> ColumnWriter<T, NULL
 
         self.values.clear();
         self.def_levels.clear();
+        self.rep_levels.clear();
 
         Ok(())
     }
@@ -150,6 +560,11 @@ impl<T: parquet::data_type::DataType> ColumnWriter<T, false> {
     pub fn extend<I: Iterator<Item = T::T>>(&mut self, iter: I) {
         self.values.extend(iter)
     }
+
+    /// Reorder the buffered rows, so that row `i` becomes `order[i]`
+    pub(crate) fn permute(&mut self, order: &[usize]) {
+        self.values = order.iter().map(|&i| self.values[i].clone()).collect();
+    }
 }
 
 impl<T: parquet::data_type::DataType> ColumnWriter<T, true> {
@@ -161,31 +576,187 @@ impl<T: parquet::data_type::DataType> ColumnWriter<T, true> {
             } else {
                 self.def_levels.push(0);
             }
-            // self.rep_levels.push(0);
+        }
+    }
+
+    /// Reorder the buffered rows, so that row `i` becomes `order[i]`
+    pub(crate) fn permute(&mut self, order: &[usize]) {
+        let mut values = std::mem::take(&mut self.values).into_iter();
+        let rows = std::mem::take(&mut self.def_levels)
+            .into_iter()
+            .map(|def| if def == 1 { values.next() } else { None })
+            .collect::<Vec<_>>();
+        self.extend(order.iter().map(|&i| rows[i].clone()));
+    }
+}
+
+/// A numeric column whose physical type is configurable (see [`MzPrecision`]
+/// and [`IntensityType`]), written as FLOAT, DOUBLE or unsigned INT32
+/// depending on the schema
+pub(crate) enum NumericColumn<const NULLABLE: bool = false> {
+    F32(ColumnWriter<FloatType, NULLABLE>),
+    F64(ColumnWriter<DoubleType, NULLABLE>),
+    U32(ColumnWriter<Int32Type, NULLABLE>),
+}
+
+impl<const NULLABLE: bool> NumericColumn<NULLABLE> {
+    pub(crate) fn new(column: Arc<ColumnDescriptor>, options: Arc<WriterProperties>) -> Self {
+        match column.physical_type() {
+            PhysicalType::DOUBLE => NumericColumn::F64(ColumnWriter::new(column, options)),
+            PhysicalType::INT32 => NumericColumn::U32(ColumnWriter::new(column, options)),
+            _ => NumericColumn::F32(ColumnWriter::new(column, options)),
+        }
+    }
+
+    pub(crate) fn write_and_flush<W: std::io::Write + Send>(
+        &mut self,
+        rg: &mut SerializedRowGroupWriter<'_, W>,
+    ) -> anyhow::Result<()> {
+        match self {
+            NumericColumn::F32(column) => column.write_and_flush(rg),
+            NumericColumn::F64(column) => column.write_and_flush(rg),
+            NumericColumn::U32(column) => column.write_and_flush(rg),
+        }
+    }
+}
+
+impl NumericColumn<false> {
+    pub(crate) fn extend<I: Iterator<Item = f64>>(&mut self, iter: I) {
+        match self {
+            NumericColumn::F32(column) => column.extend(iter.map(|v| v as f32)),
+            NumericColumn::F64(column) => column.extend(iter),
+            NumericColumn::U32(column) => column.extend(iter.map(|v| v as u32 as i32)),
+        }
+    }
+
+    pub(crate) fn permute(&mut self, order: &[usize]) {
+        match self {
+            NumericColumn::F32(column) => column.permute(order),
+            NumericColumn::F64(column) => column.permute(order),
+            NumericColumn::U32(column) => column.permute(order),
+        }
+    }
+
+    /// Push a value nested inside of a list, see [`ColumnWriter::push_nested`]
+    pub(crate) fn push_nested(&mut self, value: Option<f64>, def: i16, rep: i16) {
+        match self {
+            NumericColumn::F32(column) => column.push_nested(value.map(|v| v as f32), def, rep),
+            NumericColumn::F64(column) => column.push_nested(value, def, rep),
+            NumericColumn::U32(column) => {
+                column.push_nested(value.map(|v| v as u32 as i32), def, rep)
+            }
+        }
+    }
+
+    pub(crate) fn permute_nested(&mut self, order: &[usize], max_def: i16) {
+        match self {
+            NumericColumn::F32(column) => column.permute_nested(order, max_def),
+            NumericColumn::F64(column) => column.permute_nested(order, max_def),
+            NumericColumn::U32(column) => column.permute_nested(order, max_def),
+        }
+    }
+
+    /// Buffered values, widened to `f64`
+    fn values(&self) -> Vec<f64> {
+        match self {
+            NumericColumn::F32(column) => column.values.iter().map(|v| *v as f64).collect(),
+            NumericColumn::F64(column) => column.values.clone(),
+            NumericColumn::U32(column) => column.values.iter().map(|v| *v as u32 as f64).collect(),
+        }
+    }
+}
+
+impl NumericColumn<true> {
+    pub(crate) fn extend<I: Iterator<Item = Option<f64>>>(&mut self, iter: I) {
+        match self {
+            NumericColumn::F32(column) => column.extend(iter.map(|v| v.map(|v| v as f32))),
+            NumericColumn::F64(column) => column.extend(iter),
+            NumericColumn::U32(column) => column.extend(iter.map(|v| v.map(|v| v as u32 as i32))),
+        }
+    }
+
+    pub(crate) fn permute(&mut self, order: &[usize]) {
+        match self {
+            NumericColumn::F32(column) => column.permute(order),
+            NumericColumn::F64(column) => column.permute(order),
+            NumericColumn::U32(column) => column.permute(order),
         }
     }
 }
 
+/// A written (or skipped) spectrum that later precursors may refer to
+#[derive(Copy, Clone, Debug)]
+struct Parent {
+    scan: u32,
+    level: u8,
+    ms1_scan: Option<u32>,
+}
+
+/// Incrementally writes spectra into row groups of a long format mzparquet file
 pub struct ChunkWriter<'a, W>
 where
     W: std::io::Write + Send,
 {
     writer: &'a mut SerializedFileWriter<W>,
+    row_group_size: RowGroupSize,
     current_rows: usize,
+    current_spectra: usize,
+    current_bytes: usize,
     scans_written: usize,
-    spectrum_ref_to_scan: HashMap<Vec<u8>, u32>,
+    peaks_written: usize,
+    /// Sort the ions in each row group by `level` and then `mz`
+    sorted: bool,
+    /// Scans and retention times in the current and previous row groups
+    current_range: Option<RowGroupRange>,
+    index: ScanIndex,
+    spectrum_ref_to_scan: HashMap<Vec<u8>, Parent>,
 
     scan: ColumnWriter<Int32Type>,
     level: ColumnWriter<Int32Type>,
     rt: ColumnWriter<FloatType>,
-    mz: ColumnWriter<FloatType>,
-    int: ColumnWriter<Int32Type>,
+    mz: NumericColumn,
+    int: NumericColumn,
     ion_mobility: ColumnWriter<FloatType, true>,
     lo: ColumnWriter<FloatType, true>,
     hi: ColumnWriter<FloatType, true>,
     pscan: ColumnWriter<Int32Type, true>,
-    pmz: ColumnWriter<FloatType, true>,
+    pmz: NumericColumn<true>,
     pz: ColumnWriter<Int32Type, true>,
+    tic: ColumnWriter<FloatType, true>,
+    iit: ColumnWriter<FloatType, true>,
+    filter_string: ColumnWriter<ByteArrayType, true>,
+    native_id: ColumnWriter<ByteArrayType, true>,
+    spectrum_index: ColumnWriter<Int32Type>,
+    ms1_scan: ColumnWriter<Int32Type, true>,
+    sps_mz: NumericColumn,
+    /// Only present if the schema has a `file_id` column
+    file_id: Option<ColumnWriter<ByteArrayType, true>>,
+    /// Only present if the schema has pixel columns
+    pixel: Option<[ColumnWriter<Int32Type, true>; 3]>,
+    /// Only present if the schema has noise and baseline columns
+    noise: Option<[ColumnWriter<FloatType, true>; 2]>,
+    /// Only present if the schema has a `charge` column
+    charge: Option<ColumnWriter<Int32Type, true>>,
+    /// Only present if the schema has an `acquisition_time` column
+    acquisition_time: Option<ColumnWriter<Int64Type, true>>,
+    /// Only present if the schema has a `lock_mass_correction` column
+    lock_mass: Option<ColumnWriter<FloatType, true>>,
+    /// Only present if the schema has an `mz_bin` column
+    mz_bin: Option<ColumnWriter<Int32Type, true>>,
+    /// Only present if the schema has a `precursor_purity` column
+    purity: Option<ColumnWriter<FloatType, true>>,
+    /// Only present if the schema has an `extra_params` column
+    extra_params: Option<ColumnWriter<ByteArrayType, true>>,
+    /// Unit of the `rt` column, as recorded in the footer metadata
+    rt_unit: RtUnit,
+    /// Peak processing, as recorded in the footer metadata
+    processing: Processing,
+    /// Start of the run, in milliseconds since the Unix epoch, used to fill
+    /// `acquisition_time`
+    run_start: Option<i64>,
+    sources: Vec<Source>,
+    current_source: Option<usize>,
+    progress: Callback,
 }
 
 impl<'a, W> ChunkWriter<'a, W>
@@ -197,94 +768,437 @@ where
         descr: &SchemaDescriptor,
         options: Arc<WriterProperties>,
     ) -> Self {
-        assert_eq!(descr.num_columns(), 11);
+        assert!(descr.num_columns() >= 18);
+        let column = |name: &str| {
+            (18..descr.num_columns())
+                .find(|&idx| descr.column(idx).name() == name)
+                .map(|idx| descr.column(idx))
+        };
 
         Self {
+            row_group_size: RowGroupSize::default(),
             current_rows: 0,
+            current_spectra: 0,
+            current_bytes: 0,
             scans_written: 0,
+            peaks_written: 0,
+            sorted: options.sorting_columns().is_some(),
+            current_range: None,
+            index: ScanIndex::default(),
             writer,
             spectrum_ref_to_scan: Default::default(),
             scan: ColumnWriter::new(descr.column(0), options.clone()),
             level: ColumnWriter::new(descr.column(1), options.clone()),
             rt: ColumnWriter::new(descr.column(2), options.clone()),
-            mz: ColumnWriter::new(descr.column(3), options.clone()),
-            int: ColumnWriter::new(descr.column(4), options.clone()),
+            mz: NumericColumn::new(descr.column(3), options.clone()),
+            int: NumericColumn::new(descr.column(4), options.clone()),
             ion_mobility: ColumnWriter::new(descr.column(5), options.clone()),
             lo: ColumnWriter::new(descr.column(6), options.clone()),
             hi: ColumnWriter::new(descr.column(7), options.clone()),
             pscan: ColumnWriter::new(descr.column(8), options.clone()),
-            pmz: ColumnWriter::new(descr.column(9), options.clone()),
+            pmz: NumericColumn::new(descr.column(9), options.clone()),
             pz: ColumnWriter::new(descr.column(10), options.clone()),
+            tic: ColumnWriter::new(descr.column(11), options.clone()),
+            iit: ColumnWriter::new(descr.column(12), options.clone()),
+            filter_string: ColumnWriter::new(descr.column(13), options.clone()),
+            native_id: ColumnWriter::new(descr.column(14), options.clone()),
+            spectrum_index: ColumnWriter::new(descr.column(15), options.clone()),
+            ms1_scan: ColumnWriter::new(descr.column(16), options.clone()),
+            sps_mz: NumericColumn::new(descr.column(17), options.clone()),
+            file_id: column("file_id").map(|c| ColumnWriter::new(c, options.clone())),
+            pixel: match PIXEL_COLUMNS.map(column) {
+                [Some(x), Some(y), Some(z)] => {
+                    Some([x, y, z].map(|c| ColumnWriter::new(c, options.clone())))
+                }
+                _ => None,
+            },
+            noise: match NOISE_COLUMNS.map(column) {
+                [Some(noise), Some(baseline)] => {
+                    Some([noise, baseline].map(|c| ColumnWriter::new(c, options.clone())))
+                }
+                _ => None,
+            },
+            charge: column(CHARGE_COLUMN).map(|c| ColumnWriter::new(c, options.clone())),
+            acquisition_time: column(ACQUISITION_TIME_COLUMN)
+                .map(|c| ColumnWriter::new(c, options.clone())),
+            lock_mass: column(LOCK_MASS_COLUMN).map(|c| ColumnWriter::new(c, options.clone())),
+            mz_bin: column(MZ_BIN_COLUMN).map(|c| ColumnWriter::new(c, options.clone())),
+            purity: column(PRECURSOR_PURITY_COLUMN).map(|c| ColumnWriter::new(c, options.clone())),
+            extra_params: column(EXTRA_PARAMS_COLUMN)
+                .map(|c| ColumnWriter::new(c, options.clone())),
+            rt_unit: RtUnit::from_key_value(options.key_value_metadata()).unwrap_or_default(),
+            processing: Processing::from_key_value(options.key_value_metadata()),
+            run_start: None,
+            sources: Vec::new(),
+            current_source: None,
+            progress: Callback::default(),
+        }
+    }
+
+    pub fn set_row_group_size(&mut self, row_group_size: RowGroupSize) -> &mut Self {
+        self.row_group_size = row_group_size;
+        self
+    }
+
+    pub(crate) fn set_progress(&mut self, progress: Callback) -> &mut Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Tag all subsequently written spectra with `source.file_id`. Requires a
+    /// schema built with [`WriterOptions::set_source`].
+    ///
+    /// Precursor references are only resolved against spectra from the same
+    /// source, as native ids are not unique across runs
+    pub fn set_source(&mut self, source: Source) -> &mut Self {
+        let idx = match self.sources.iter().position(|s| s == &source) {
+            Some(idx) => idx,
+            None => {
+                self.sources.push(source);
+                self.sources.len() - 1
+            }
+        };
+        if self.current_source != Some(idx) {
+            self.spectrum_ref_to_scan.clear();
         }
+        self.current_source = Some(idx);
+        self
+    }
+
+    /// Fill the `acquisition_time` column of subsequently written spectra
+    /// with `start` (in milliseconds since the Unix epoch) plus their
+    /// retention time. Without a start time, the column is null
+    pub fn set_run_start(&mut self, start: Option<i64>) -> &mut Self {
+        self.run_start = start;
+        self
+    }
+
+    /// Reserve a spectrum index for `spectrum` without writing any of its
+    /// ions, so that spectra (and precursor references) are numbered the same
+    /// as in a file holding every spectrum
+    pub fn skip_spectrum(&mut self, spectrum: &RawSpectrum) {
+        // MS1 spectra still provide the lock mass correction and precursor
+        // purity of the MSn spectra that follow them
+        if spectrum.ms_level == 1 {
+            self.processing.process(spectrum);
+        }
+        let (_, parent) = self.immediate_precursor(spectrum);
+        self.insert_parent(spectrum, parent);
+        self.scans_written += 1;
+    }
+
+    /// The vendor scan number of `spectrum`, falling back to its index
+    fn scan_number(&self, spectrum: &RawSpectrum) -> u32 {
+        spectrum.scan_number.unwrap_or(self.scans_written as u32)
+    }
+
+    /// The precursor isolated at the previous MS stage, and the spectrum it
+    /// was isolated from. MSn spectra may also list the precursors of earlier
+    /// stages, so the precursor whose parent has the highest MS level is
+    /// used, falling back to the first precursor if no parent was written
+    fn immediate_precursor<'s>(
+        &self,
+        spectrum: &'s RawSpectrum,
+    ) -> (Option<&'s Precursor>, Option<Parent>) {
+        spectrum
+            .precursors
+            .iter()
+            .rev()
+            .filter_map(|precursor| {
+                let parent = self
+                    .spectrum_ref_to_scan
+                    .get(precursor.spectrum_ref.as_ref()?)?;
+                Some((precursor, *parent))
+            })
+            .max_by_key(|(_, parent)| parent.level)
+            .map(|(precursor, parent)| (Some(precursor), Some(parent)))
+            .unwrap_or((spectrum.precursors.first(), None))
+    }
+
+    /// Record `spectrum` so that later precursors can refer to it, returning
+    /// its scan number and the MS1 scan at the root of its precursor chain
+    fn insert_parent(&mut self, spectrum: &RawSpectrum, parent: Option<Parent>) -> Parent {
+        let scan = self.scan_number(spectrum);
+        let entry = Parent {
+            scan,
+            level: spectrum.ms_level,
+            ms1_scan: match spectrum.ms_level {
+                1 => Some(scan),
+                _ => parent.and_then(|p| p.ms1_scan),
+            },
+        };
+        self.spectrum_ref_to_scan.insert(spectrum.id.clone(), entry);
+        entry
+    }
+
+    /// Number of peaks written so far, after peak processing
+    pub fn peaks_written(&self) -> usize {
+        self.peaks_written
     }
 
     /// Write a spectrum to an mzparquet file. This function may have IO operations,
     /// if writing this spectrum would fill up the current row group.
     pub fn write_spectrum(&mut self, spectrum: &RawSpectrum) -> anyhow::Result<()> {
+        let spectrum = &*self.processing.process(spectrum);
         let n = spectrum.mz.len();
-        self.spectrum_ref_to_scan
-            .insert(spectrum.id.clone(), self.scans_written as u32);
+        if let Some(file_id) = &mut self.file_id {
+            let source = self
+                .current_source
+                .map(|idx| &self.sources[idx])
+                .ok_or_else(|| {
+                    anyhow::anyhow!("schema has a `file_id` column, but no source was set")
+                })?;
+            file_id.extend(std::iter::repeat_n(
+                Some(ByteArray::from(source.file_id.as_str())),
+                n,
+            ));
+        }
+        let (precursor, parent) = self.immediate_precursor(spectrum);
+        let Parent { scan, ms1_scan, .. } = self.insert_parent(spectrum, parent);
 
-        self.scan
-            .extend(std::iter::repeat(self.scans_written as u32 as i32).take(n));
+        self.scan.extend(std::iter::repeat_n(scan as i32, n));
         self.level
-            .extend(std::iter::repeat(spectrum.ms_level as u32 as i32).take(n));
-        self.rt
-            .extend(std::iter::repeat(spectrum.scan_start_time).take(n));
+            .extend(std::iter::repeat_n(spectrum.ms_level as u32 as i32, n));
+        let rt = self.rt_unit.from_seconds(spectrum.scan_start_time);
+        self.rt.extend(std::iter::repeat_n(rt, n));
         self.mz.extend(spectrum.mz.iter().copied());
-        self.int
-            .extend(spectrum.intensity.iter().map(|n| *n as u32 as i32));
-        self.ion_mobility
-            .extend(std::iter::repeat(spectrum.inverse_ion_mobility).take(n));
-
-        if let Some(precursor) = spectrum.precursors.get(0) {
-            let precursor_scan = precursor
-                .spectrum_ref
-                .as_ref()
-                .map(|s| self.spectrum_ref_to_scan.get(s))
-                .flatten();
-
-            let lo = precursor.isolation_window_lower.map(|w| precursor.mz - w);
-            let hi = precursor.isolation_window_upper.map(|w| precursor.mz + w);
-
-            self.lo.extend(std::iter::repeat(lo).take(n));
-            self.hi.extend(std::iter::repeat(hi).take(n));
-
-            self.pmz
-                .extend(std::iter::repeat(Some(precursor.mz)).take(n));
+        self.int.extend(spectrum.intensity.iter().copied());
+        match spectrum.ion_mobility.len() {
+            0 => self
+                .ion_mobility
+                .extend(std::iter::repeat_n(spectrum.inverse_ion_mobility, n)),
+            _ => self
+                .ion_mobility
+                .extend(spectrum.ion_mobility.iter().copied().map(Some)),
+        }
+
+        if let Some(precursor) = precursor {
+            let lo = precursor
+                .isolation_window_lower
+                .map(|w| (precursor.mz - w as f64) as f32);
+            let hi = precursor
+                .isolation_window_upper
+                .map(|w| (precursor.mz + w as f64) as f32);
+
+            self.lo.extend(std::iter::repeat_n(lo, n));
+            self.hi.extend(std::iter::repeat_n(hi, n));
+
+            self.pmz.extend(std::iter::repeat_n(Some(precursor.mz), n));
             self.pz
-                .extend(std::iter::repeat(precursor.charge.map(|z| z as i32)).take(n));
+                .extend(std::iter::repeat_n(precursor.charge.map(|z| z as i32), n));
             self.pscan
-                .extend(std::iter::repeat(precursor_scan.map(|z| *z as i32)).take(n));
+                .extend(std::iter::repeat_n(parent.map(|p| p.scan as i32), n));
         } else {
-            self.lo.extend(std::iter::repeat(None).take(n));
-            self.hi.extend(std::iter::repeat(None).take(n));
-            self.pmz.extend(std::iter::repeat(None).take(n));
-            self.pz.extend(std::iter::repeat(None).take(n));
-            self.pscan.extend(std::iter::repeat(None).take(n));
+            self.lo.extend(std::iter::repeat_n(None, n));
+            self.hi.extend(std::iter::repeat_n(None, n));
+            self.pmz.extend(std::iter::repeat_n(None, n));
+            self.pz.extend(std::iter::repeat_n(None, n));
+            self.pscan.extend(std::iter::repeat_n(None, n));
+        }
+
+        self.tic
+            .extend(std::iter::repeat_n(Some(spectrum.total_ion_current), n));
+        self.iit
+            .extend(std::iter::repeat_n(Some(spectrum.ion_injection_time), n));
+        self.filter_string.extend(std::iter::repeat_n(
+            spectrum.filter_string.as_deref().map(ByteArray::from),
+            n,
+        ));
+        self.native_id.extend(std::iter::repeat_n(
+            Some(ByteArray::from(spectrum.id.clone())),
+            n,
+        ));
+        self.spectrum_index
+            .extend(std::iter::repeat_n(self.scans_written as u32 as i32, n));
+        self.ms1_scan
+            .extend(std::iter::repeat_n(ms1_scan.map(|s| s as i32), n));
+        // The list is null (definition level 0) unless there are several
+        // precursors, in which case each m/z is defined at level 2
+        let sps_mz = precursor
+            .filter(|p| p.spectrum_ref.is_some())
+            .map(|p| {
+                spectrum
+                    .precursors
+                    .iter()
+                    .filter(|s| s.spectrum_ref == p.spectrum_ref)
+                    .map(|s| s.mz)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|mz| mz.len() > 1)
+            .unwrap_or_default();
+        for _ in 0..n {
+            if sps_mz.is_empty() {
+                self.sps_mz.push_nested(None, 0, 0);
+            }
+            for (idx, mz) in sps_mz.iter().enumerate() {
+                self.sps_mz.push_nested(Some(*mz), 2, (idx > 0) as i16);
+            }
+        }
+        if let Some([x, y, z]) = &mut self.pixel {
+            let pixel = spectrum.pixel;
+            x.extend(std::iter::repeat_n(pixel.map(|p| p.x as i32), n));
+            y.extend(std::iter::repeat_n(pixel.map(|p| p.y as i32), n));
+            z.extend(std::iter::repeat_n(
+                pixel.and_then(|p| p.z).map(|z| z as i32),
+                n,
+            ));
+        }
+        if let Some([noise, baseline]) = &mut self.noise {
+            for (column, values) in [(noise, &spectrum.noise), (baseline, &spectrum.baseline)] {
+                match values.len() == n {
+                    true => column.extend(values.iter().copied().map(Some)),
+                    false => column.extend(std::iter::repeat_n(None, n)),
+                }
+            }
+        }
+        if let Some(charge) = &mut self.charge {
+            match spectrum.charge.len() == n {
+                true => charge.extend(spectrum.charge.iter().map(|&z| Some(z as i32))),
+                false => charge.extend(std::iter::repeat_n(None, n)),
+            }
+        }
+        if let Some(acquisition_time) = &mut self.acquisition_time {
+            let time = self
+                .run_start
+                .map(|start| start + (spectrum.scan_start_time as f64 * 1000.0).round() as i64);
+            acquisition_time.extend(std::iter::repeat_n(time, n));
+        }
+        if let Some(lock_mass) = &mut self.lock_mass {
+            lock_mass.extend(std::iter::repeat_n(self.processing.lock_mass_error(), n));
+        }
+        if let (Some(mz_bin), Some(binning)) = (&mut self.mz_bin, self.processing.binning()) {
+            mz_bin.extend(spectrum.mz.iter().map(|&mz| Some(binning.index(mz))));
+        }
+        if let Some(purity) = &mut self.purity {
+            purity.extend(std::iter::repeat_n(self.processing.precursor_purity(), n));
+        }
+        let mut extra_params_len = 0;
+        if let Some(extra_params) = &mut self.extra_params {
+            let json = match spectrum.extra_params.is_empty() {
+                true => None,
+                false => Some(extra_params_json(&spectrum.extra_params)?),
+            };
+            extra_params_len = json.as_ref().map_or(0, String::len);
+            extra_params.extend(std::iter::repeat_n(
+                json.map(|json| ByteArray::from(json.into_bytes())),
+                n,
+            ));
+        }
+
+        if n > 0 {
+            match &mut self.current_range {
+                Some(range) => range.extend(scan, rt),
+                None => self.current_range = Some(RowGroupRange::new(scan, rt)),
+            }
         }
 
         self.scans_written += 1;
+        self.peaks_written += n;
         self.current_rows += n;
+        self.current_spectra += 1;
+        // 15 four byte columns per ion, plus the (dictionary encoded) filter
+        // string and native id
+        self.current_bytes += n * 60
+            + spectrum.filter_string.as_ref().map_or(0, String::len)
+            + spectrum.id.len()
+            + n * sps_mz.len() * 8
+            + self.noise.as_ref().map_or(0, |_| n * 8)
+            + self.charge.as_ref().map_or(0, |_| n * 4)
+            + self.mz_bin.as_ref().map_or(0, |_| n * 4)
+            + self.purity.as_ref().map_or(0, |_| n * 4)
+            + extra_params_len;
 
-        // If we have more than 2^18 ions in this row group, write it to buffer
-        // and reset all of the columns
-        if n >= 2usize.pow(18) {
+        // If this row group is full, write it to buffer and reset all of the
+        // columns
+        if self.current_rows > 0
+            && self.row_group_size.is_full(
+                self.current_rows,
+                self.current_spectra,
+                self.current_bytes,
+            )
+        {
             self.write_to_row_group()?;
         }
 
         Ok(())
     }
 
-    #[must_use]
+    /// Flush the final row group, and record the scan index in the footer
+    /// metadata
     pub fn finish(mut self) -> anyhow::Result<()> {
         if self.current_rows > 0 {
             self.write_to_row_group()?;
         }
+        self.writer
+            .append_key_value_metadata(self.index.to_key_value()?);
+        if !self.sources.is_empty() {
+            self.writer.append_key_value_metadata(KeyValue {
+                key: SOURCES_KEY.into(),
+                value: Some(serde_json::to_string(&self.sources)?),
+            });
+        }
         Ok(())
     }
 
+    /// Reorder the buffered ions by `level` and then `mz`, keeping
+    /// acquisition order for ties
+    fn sort_rows(&mut self) {
+        let (level, mz) = (&self.level.values, self.mz.values());
+        let mut order = (0..mz.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| level[a].cmp(&level[b]).then(mz[a].total_cmp(&mz[b])));
+
+        self.scan.permute(&order);
+        self.level.permute(&order);
+        self.rt.permute(&order);
+        self.mz.permute(&order);
+        self.int.permute(&order);
+        self.ion_mobility.permute(&order);
+        self.lo.permute(&order);
+        self.hi.permute(&order);
+        self.pscan.permute(&order);
+        self.pmz.permute(&order);
+        self.pz.permute(&order);
+        self.tic.permute(&order);
+        self.iit.permute(&order);
+        self.filter_string.permute(&order);
+        self.native_id.permute(&order);
+        self.spectrum_index.permute(&order);
+        self.ms1_scan.permute(&order);
+        self.sps_mz.permute_nested(&order, 2);
+        if let Some(file_id) = &mut self.file_id {
+            file_id.permute(&order);
+        }
+        for column in self.pixel.iter_mut().flatten() {
+            column.permute(&order);
+        }
+        for column in self.noise.iter_mut().flatten() {
+            column.permute(&order);
+        }
+        if let Some(charge) = &mut self.charge {
+            charge.permute(&order);
+        }
+        if let Some(acquisition_time) = &mut self.acquisition_time {
+            acquisition_time.permute(&order);
+        }
+        if let Some(lock_mass) = &mut self.lock_mass {
+            lock_mass.permute(&order);
+        }
+        if let Some(mz_bin) = &mut self.mz_bin {
+            mz_bin.permute(&order);
+        }
+        if let Some(purity) = &mut self.purity {
+            purity.permute(&order);
+        }
+        if let Some(extra_params) = &mut self.extra_params {
+            extra_params.permute(&order);
+        }
+    }
+
     fn write_to_row_group(&mut self) -> anyhow::Result<()> {
+        if self.sorted {
+            self.sort_rows();
+        }
+
         let mut rg = self.writer.next_row_group()?;
 
         self.scan.write_and_flush(&mut rg)?;
@@ -298,44 +1212,1577 @@ where
         self.pscan.write_and_flush(&mut rg)?;
         self.pmz.write_and_flush(&mut rg)?;
         self.pz.write_and_flush(&mut rg)?;
+        self.tic.write_and_flush(&mut rg)?;
+        self.iit.write_and_flush(&mut rg)?;
+        self.filter_string.write_and_flush(&mut rg)?;
+        self.native_id.write_and_flush(&mut rg)?;
+        self.spectrum_index.write_and_flush(&mut rg)?;
+        self.ms1_scan.write_and_flush(&mut rg)?;
+        self.sps_mz.write_and_flush(&mut rg)?;
+        if let Some(file_id) = &mut self.file_id {
+            file_id.write_and_flush(&mut rg)?;
+        }
+        for column in self.pixel.iter_mut().flatten() {
+            column.write_and_flush(&mut rg)?;
+        }
+        for column in self.noise.iter_mut().flatten() {
+            column.write_and_flush(&mut rg)?;
+        }
+        if let Some(charge) = &mut self.charge {
+            charge.write_and_flush(&mut rg)?;
+        }
+        if let Some(acquisition_time) = &mut self.acquisition_time {
+            acquisition_time.write_and_flush(&mut rg)?;
+        }
+        if let Some(lock_mass) = &mut self.lock_mass {
+            lock_mass.write_and_flush(&mut rg)?;
+        }
+        if let Some(mz_bin) = &mut self.mz_bin {
+            mz_bin.write_and_flush(&mut rg)?;
+        }
+        if let Some(purity) = &mut self.purity {
+            purity.write_and_flush(&mut rg)?;
+        }
+        if let Some(extra_params) = &mut self.extra_params {
+            extra_params.write_and_flush(&mut rg)?;
+        }
 
         rg.close()?;
 
+        self.progress.rows_written(self.current_rows);
         // We have written and cleared all buffers, reset number of written rows
         self.current_rows = 0;
+        self.current_spectra = 0;
+        self.current_bytes = 0;
+        self.index.row_groups.extend(self.current_range.take());
 
         Ok(())
     }
 }
 
-pub fn serialize_to_parquet<W: Write + Send>(w: W, spectra: &[RawSpectrum]) -> anyhow::Result<W> {
-    let schema = build_schema()?;
-    let sd = parquet::schema::types::SchemaDescriptor::new(schema.clone().into());
-
-    let options = Arc::new(
-        WriterProperties::builder()
-            .set_compression(parquet::basic::Compression::ZSTD(ZstdLevel::try_new(3)?))
-            .set_dictionary_enabled(false)
-            .set_key_value_metadata(Some(vec![
-                KeyValue {
-                    key: "version".into(),
-                    value: Some("0.2".into()),
-                },
-                KeyValue {
-                    key: "writer".into(),
-                    value: Some("github.com/lazear/mz_parquet".into()),
-                },
-            ]))
-            .build(),
-    );
+/// Thresholds at which a row group is flushed. A row group is written as soon
+/// as any of the limits is reached, and spectra are never split across row
+/// groups
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RowGroupSize {
+    /// Number of ions (peaks)
+    pub ions: Option<usize>,
+    /// Number of spectra
+    pub spectra: Option<usize>,
+    /// Estimated uncompressed size, in bytes
+    pub bytes: Option<usize>,
+}
 
-    let mut writer = SerializedFileWriter::new(w, schema.into(), options.clone())?;
+impl Default for RowGroupSize {
+    fn default() -> Self {
+        Self {
+            ions: Some(2usize.pow(18)),
+            spectra: None,
+            bytes: None,
+        }
+    }
+}
 
-    let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, options);
+impl RowGroupSize {
+    pub(crate) fn is_full(&self, ions: usize, spectra: usize, bytes: usize) -> bool {
+        self.ions.is_some_and(|n| ions >= n)
+            || self.spectra.is_some_and(|n| spectra >= n)
+            || self.bytes.is_some_and(|n| bytes >= n)
+    }
+}
 
-    for spectrum in spectra {
-        chunk_writer.write_spectrum(spectrum)?;
+/// Split block bloom filter parameters. Bloom filters only help exact value
+/// lookups (e.g. `WHERE precursor_mz = 512.2713`), but allow readers to skip
+/// row groups without decoding any pages
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BloomFilter {
+    /// Target false positive probability
+    pub fpp: f64,
+    /// Expected number of distinct values per row group
+    pub ndv: u64,
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self {
+            fpp: 0.05,
+            ndv: 2u64.pow(18),
+        }
+    }
+}
+
+/// Schema version recorded under the `version` footer key. Files written
+/// before the `total_ion_current`, `ion_injection_time`, `filter_string` and
+/// `native_id` columns were added are version 0.2, files with a synthetic
+/// counter in the `scan` column and no `spectrum_index` are 0.3, files
+/// without `ms1_scan` are 0.4, and files without `sps_mz` are 0.5
+pub const SCHEMA_VERSION: &str = "0.6";
+
+/// Footer metadata key listing the [`Source`]s of a long format file
+pub const SOURCES_KEY: &str = "sources";
+
+/// A run that spectra were read from. When writing a long format file with a
+/// source, each row is tagged with its `file_id`, so that several runs can be
+/// concatenated into a single dataset without losing provenance
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    /// Identifier stored in the `file_id` column
+    pub file_id: String,
+    /// Original location of the run, e.g. the mzML file it was converted from
+    pub path: Option<String>,
+}
+
+impl Source {
+    /// Load the sources recorded in a file's footer metadata
+    pub fn from_metadata(metadata: &ParquetMetaData) -> serde_json::Result<Vec<Self>> {
+        metadata
+            .file_metadata()
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+            .find(|kv| kv.key == SOURCES_KEY)
+            .and_then(|kv| kv.value.as_deref())
+            .map(serde_json::from_str)
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+}
+
+/// Processing applied to the peaks of each spectrum before it is written,
+/// read back from the footer metadata of the file being written
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Processing {
+    peak_pick: bool,
+    lock_mass: Option<LockMass>,
+    deisotope: Option<Deisotope>,
+    peak_filter: PeakFilter,
+    binning: Option<Binning>,
+    purity: Option<PrecursorPurity>,
+    /// Lock mass error of the last MS1 spectrum it was found in
+    lock_mass_error: Option<f32>,
+    /// Last MS1 spectrum, centroided, kept to compute precursor purity
+    last_ms1: Option<RawSpectrum>,
+    /// Precursor purity of the last MS2 spectrum
+    precursor_purity: Option<f32>,
+}
+
+impl Processing {
+    pub(crate) fn from_key_value(kv: Option<&Vec<KeyValue>>) -> Self {
+        Self {
+            peak_pick: crate::centroid::from_key_value(kv),
+            lock_mass: LockMass::from_key_value(kv).ok().flatten(),
+            deisotope: Deisotope::from_key_value(kv).ok().flatten(),
+            peak_filter: PeakFilter::from_key_value(kv)
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+            binning: Binning::from_key_value(kv).ok().flatten(),
+            purity: PrecursorPurity::from_key_value(kv).ok().flatten(),
+            lock_mass_error: None,
+            last_ms1: None,
+            precursor_purity: None,
+        }
+    }
+
+    /// Precursor purity of the last processed spectrum, or of the MS2
+    /// spectrum it was acquired from for MS3 spectra
+    pub(crate) fn precursor_purity(&self) -> Option<f32> {
+        self.precursor_purity
+    }
+
+    /// Grid the peaks are binned on, if any
+    pub(crate) fn binning(&self) -> Option<Binning> {
+        self.binning
+    }
+
+    /// Lock mass error removed from the last processed spectrum, in ppm
+    pub(crate) fn lock_mass_error(&self) -> Option<f32> {
+        self.lock_mass_error
+    }
+
+    /// Centroid profile spectra, recalibrate them, then deisotope, filter and
+    /// bin the peaks. Spectra must be processed in acquisition order
+    pub(crate) fn process<'s>(&mut self, spectrum: &'s RawSpectrum) -> Cow<'s, RawSpectrum> {
+        let mut spectrum = Cow::Borrowed(spectrum);
+        if self.peak_pick && !spectrum.centroid {
+            spectrum = Cow::Owned(crate::centroid::centroid(&spectrum));
+        }
+        if let Some(lock_mass) = self.lock_mass {
+            if spectrum.ms_level == 1 {
+                if let Some(error) = lock_mass.error(&spectrum) {
+                    self.lock_mass_error = Some(error);
+                }
+            }
+            if let Some(error) = self.lock_mass_error {
+                spectrum = Cow::Owned(crate::lock_mass::recalibrate(&spectrum, error));
+            }
+        }
+        if let Some(deisotoped) = self.deisotope.and_then(|d| d.deisotope(&spectrum)) {
+            spectrum = Cow::Owned(deisotoped);
+        }
+        if let Some(filtered) = self.peak_filter.filter(&spectrum) {
+            spectrum = Cow::Owned(filtered);
+        }
+        if let Some(purity) = self.purity {
+            match spectrum.ms_level {
+                1 => {
+                    self.precursor_purity = None;
+                    self.last_ms1 = Some(match spectrum.centroid {
+                        true => spectrum.clone().into_owned(),
+                        false => crate::centroid::centroid(&spectrum),
+                    });
+                }
+                2 => {
+                    self.precursor_purity = self
+                        .last_ms1
+                        .as_ref()
+                        .zip(spectrum.precursors.first())
+                        .and_then(|(ms1, precursor)| purity.purity(ms1, precursor));
+                }
+                _ => {}
+            }
+        }
+        if let Some(binning) = self.binning {
+            spectrum = Cow::Owned(binning.bin(&spectrum));
+        }
+        spectrum
+    }
+}
+
+/// Number of spectra, and of peaks, written to a file
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Written {
+    pub spectra: usize,
+    pub peaks: usize,
+}
+
+/// Options controlling how mzparquet files are written
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriterOptions {
+    pub(crate) row_group_size: RowGroupSize,
+    pub(crate) mz_precision: MzPrecision,
+    pub(crate) intensity_type: IntensityType,
+    compression: Compression,
+    bloom_filter: Option<BloomFilter>,
+    sort_ions: bool,
+    page_index: bool,
+    pub(crate) source: Option<Source>,
+    pub(crate) pixel_columns: bool,
+    pub(crate) noise_columns: bool,
+    pub(crate) charge_column: bool,
+    pub(crate) centroid: bool,
+    pub(crate) deisotope: Option<Deisotope>,
+    pub(crate) lock_mass: Option<LockMass>,
+    pub(crate) ms1_averaging: Option<Ms1Averaging>,
+    pub(crate) demultiplex: Option<Demultiplex>,
+    pub(crate) spectrum_filter: SpectrumFilter,
+    pub(crate) peak_filter: PeakFilter,
+    pub(crate) binning: Option<Binning>,
+    pub(crate) precursor_purity: Option<PrecursorPurity>,
+    pub(crate) rt_unit: RtUnit,
+    pub(crate) acquisition_time: bool,
+    pub(crate) extra_params: bool,
+    metadata: BTreeMap<String, String>,
+    pub(crate) progress: Callback,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            row_group_size: RowGroupSize::default(),
+            mz_precision: MzPrecision::default(),
+            intensity_type: IntensityType::default(),
+            compression: Compression::ZSTD(ZstdLevel::try_new(3).expect("valid zstd level")),
+            bloom_filter: None,
+            sort_ions: false,
+            page_index: true,
+            source: None,
+            pixel_columns: false,
+            noise_columns: false,
+            charge_column: false,
+            centroid: false,
+            deisotope: None,
+            lock_mass: None,
+            ms1_averaging: None,
+            demultiplex: None,
+            spectrum_filter: SpectrumFilter::default(),
+            peak_filter: PeakFilter::default(),
+            binning: None,
+            precursor_purity: None,
+            rt_unit: RtUnit::default(),
+            acquisition_time: false,
+            extra_params: false,
+            metadata: BTreeMap::new(),
+            progress: Callback::default(),
+        }
+    }
+}
+
+impl WriterOptions {
+    /// Control when row groups are flushed. Larger row groups compress better
+    /// and suit engines like DuckDB, while smaller ones allow finer grained
+    /// pruning and parallelism
+    pub fn set_row_group_size(&mut self, row_group_size: RowGroupSize) -> &mut Self {
+        self.row_group_size = row_group_size;
+        self
+    }
+
+    /// Physical type of the `mz` and `precursor_mz` columns. Only applies to
+    /// the long format
+    pub fn set_mz_precision(&mut self, mz_precision: MzPrecision) -> &mut Self {
+        self.mz_precision = mz_precision;
+        self
+    }
+
+    /// Physical type of the `intensity` column. Only applies to the long
+    /// format
+    pub fn set_intensity_type(&mut self, intensity_type: IntensityType) -> &mut Self {
+        self.intensity_type = intensity_type;
+        self
+    }
+
+    /// Add a `file_id` column identifying the run each ion came from, and
+    /// record the run in the footer metadata. Only applies to the long format
+    pub fn set_source(&mut self, source: Option<Source>) -> &mut Self {
+        self.source = source;
+        self
+    }
+
+    /// Add `pixel_x`, `pixel_y` and `pixel_z` columns holding the position of
+    /// imaging spectra (see [`RawSpectrum::pixel`]). Only applies to the long
+    /// format
+    pub fn set_pixel_columns(&mut self, pixel_columns: bool) -> &mut Self {
+        self.pixel_columns = pixel_columns;
+        self
+    }
+
+    /// Add `noise` and `baseline` columns holding the per-ion noise levels of
+    /// spectra (see [`RawSpectrum::noise`]). Only applies to the long format,
+    /// as wide format files always have (possibly null) `noise` and
+    /// `baseline` lists
+    pub fn set_noise_columns(&mut self, noise_columns: bool) -> &mut Self {
+        self.noise_columns = noise_columns;
+        self
+    }
+
+    /// Add a `charge` column holding the charge state that the instrument
+    /// assigned to each ion (see [`RawSpectrum::charge`]). Only applies to
+    /// the long format, as wide format files always have a (possibly null)
+    /// `charge` list
+    pub fn set_charge_column(&mut self, charge_column: bool) -> &mut Self {
+        self.charge_column = charge_column;
+        self
+    }
+
+    /// Peak pick profile spectra before writing them (see
+    /// [`crate::centroid`]), and record that in the footer metadata
+    pub fn set_centroid(&mut self, centroid: bool) -> &mut Self {
+        self.centroid = centroid;
+        self
+    }
+
+    /// Deisotope MSn spectra before writing them (see [`crate::deisotope`]),
+    /// and record the settings in the footer metadata
+    pub fn set_deisotope(&mut self, deisotope: Option<Deisotope>) -> &mut Self {
+        self.deisotope = deisotope;
+        self
+    }
+
+    /// Recalibrate m/z values against a lock mass (see
+    /// [`crate::lock_mass`]), and record the settings in the footer metadata.
+    /// Long format files also get a `lock_mass_correction` column holding the
+    /// correction applied to each spectrum
+    pub fn set_lock_mass(&mut self, lock_mass: Option<LockMass>) -> &mut Self {
+        self.lock_mass = lock_mass;
+        self
+    }
+
+    /// Average each MS1 spectrum with its neighbours (see
+    /// [`crate::average`]), and record the settings in the footer metadata.
+    /// Only applies to the streaming writers
+    pub fn set_ms1_averaging(&mut self, ms1_averaging: Option<Ms1Averaging>) -> &mut Self {
+        self.ms1_averaging = ms1_averaging;
+        self
+    }
+
+    /// Split the MS2 spectra of overlapping window DIA runs into the halves
+    /// of their isolation windows (see [`crate::demux`]), and record the
+    /// settings in the footer metadata. Only applies to the streaming writers
+    pub fn set_demultiplex(&mut self, demultiplex: Option<Demultiplex>) -> &mut Self {
+        self.demultiplex = demultiplex;
+        self
+    }
+
+    /// Only write the spectra that pass `spectrum_filter` (see
+    /// [`crate::filter`]), and record the filter in the footer metadata. In
+    /// the long format, scans are numbered as if every spectrum were written
+    pub fn set_spectrum_filter(&mut self, spectrum_filter: SpectrumFilter) -> &mut Self {
+        self.spectrum_filter = spectrum_filter;
+        self
+    }
+
+    /// Drop peaks below an intensity threshold before writing them (see
+    /// [`crate::filter`]), and record the filter in the footer metadata
+    pub fn set_peak_filter(&mut self, peak_filter: PeakFilter) -> &mut Self {
+        self.peak_filter = peak_filter;
+        self
+    }
+
+    /// Sum the peaks of each spectrum onto a fixed m/z grid (see
+    /// [`crate::binning`]) after any other processing, and record the grid
+    /// in the footer metadata. Long format files also get an `mz_bin` column
+    /// holding the bin index of each ion
+    pub fn set_binning(&mut self, binning: Option<Binning>) -> &mut Self {
+        self.binning = binning;
+        self
+    }
+
+    /// Compute the purity of the precursor of each MS2 spectrum in the MS1
+    /// spectrum before it (see [`crate::purity`]), and store it in a
+    /// `precursor_purity` column. Long format only
+    pub fn set_precursor_purity(&mut self, precursor_purity: Option<PrecursorPurity>) -> &mut Self {
+        self.precursor_purity = precursor_purity;
+        self
+    }
+
+    /// Unit of the retention times written to the `rt` (long format) and
+    /// `scan_start_time` (wide format) columns, and of chromatogram times.
+    /// Defaults to seconds
+    pub fn set_rt_unit(&mut self, rt_unit: RtUnit) -> &mut Self {
+        self.rt_unit = rt_unit;
+        self
+    }
+
+    /// Add an `acquisition_time` column holding the wall-clock time of each
+    /// spectrum: the start timestamp of the run plus its retention time. Only
+    /// applies to the long format
+    pub fn set_acquisition_time(&mut self, acquisition_time: bool) -> &mut Self {
+        self.acquisition_time = acquisition_time;
+        self
+    }
+
+    /// Add an `extra_params` column holding the cvParams and userParams of
+    /// each spectrum that have no column of their own (see
+    /// [`RawSpectrum::extra_params`]), as a JSON object. Only applies to the
+    /// long format
+    pub fn set_extra_params(&mut self, extra_params: bool) -> &mut Self {
+        self.extra_params = extra_params;
+        self
+    }
+
+    /// Compression codec for all columns. Defaults to ZSTD level 3
+    pub fn set_compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write bloom filters for the `mz` and `precursor_mz` columns
+    pub fn set_bloom_filter(&mut self, bloom_filter: Option<BloomFilter>) -> &mut Self {
+        self.bloom_filter = bloom_filter;
+        self
+    }
+
+    /// Sort the ions in each row group by `level` and then `mz`, rather than
+    /// in acquisition order, so that product ion range queries only touch a
+    /// few pages. Spectra are never split across row groups. Only applies to
+    /// the long format
+    pub fn set_sort_ions(&mut self, sort_ions: bool) -> &mut Self {
+        self.sort_ions = sort_ions;
+        self
+    }
+
+    /// Write page-level min/max statistics, and the column index, for the
+    /// `scan`, `rt`, `mz` and `precursor_mz` columns, so that query engines
+    /// can skip individual pages. Column chunk statistics are always written.
+    /// Enabled by default
+    pub fn set_page_index(&mut self, page_index: bool) -> &mut Self {
+        self.page_index = page_index;
+        self
+    }
+
+    /// Extra key-value pairs stored in the footer metadata, e.g. the sample
+    /// a run was acquired from. Keys may not clash with the ones written by
+    /// mz_parquet
+    pub fn set_metadata(&mut self, metadata: BTreeMap<String, String>) -> &mut Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Report rows to `progress` as row groups are written
+    pub fn set_progress(&mut self, progress: Option<Arc<dyn Progress>>) -> &mut Self {
+        self.progress = Callback::new(progress);
+        self
+    }
+}
+
+/// Encode the extra params of a spectrum as a JSON object. If a param is
+/// repeated, the first value is kept
+fn extra_params_json(params: &[(String, String)]) -> serde_json::Result<String> {
+    let mut map = serde_json::Map::new();
+    for (key, value) in params {
+        map.entry(key.as_str())
+            .or_insert_with(|| serde_json::Value::String(value.clone()));
+    }
+    serde_json::to_string(&map)
+}
+
+pub(crate) fn writer_properties(
+    format: &str,
+    options: &WriterOptions,
+) -> anyhow::Result<Arc<WriterProperties>> {
+    let mut key_value = vec![
+        KeyValue {
+            key: "version".into(),
+            value: Some(SCHEMA_VERSION.into()),
+        },
+        KeyValue {
+            key: "format".into(),
+            value: Some(format.into()),
+        },
+        KeyValue {
+            key: "writer".into(),
+            value: Some("github.com/lazear/mz_parquet".into()),
+        },
+        KeyValue {
+            key: "mz_precision".into(),
+            value: Some(options.mz_precision.to_string()),
+        },
+        KeyValue {
+            key: "intensity_type".into(),
+            value: Some(options.intensity_type.to_string()),
+        },
+        KeyValue {
+            key: RT_UNIT_KEY.into(),
+            value: Some(options.rt_unit.to_string()),
+        },
+    ];
+    if options.centroid {
+        key_value.push(KeyValue {
+            key: crate::centroid::CENTROIDED_KEY.into(),
+            value: Some("true".into()),
+        });
+    }
+    if let Some(ms1_averaging) = options.ms1_averaging {
+        key_value.push(ms1_averaging.to_key_value()?);
+    }
+    if let Some(demultiplex) = options.demultiplex {
+        key_value.push(demultiplex.to_key_value()?);
+    }
+    if let Some(lock_mass) = options.lock_mass {
+        key_value.push(lock_mass.to_key_value()?);
+    }
+    if let Some(deisotope) = options.deisotope {
+        key_value.push(deisotope.to_key_value()?);
+    }
+    if !options.spectrum_filter.is_empty() {
+        key_value.push(options.spectrum_filter.to_key_value()?);
+    }
+    if !options.peak_filter.is_empty() {
+        key_value.push(options.peak_filter.to_key_value()?);
+    }
+    if let Some(binning) = options.binning {
+        key_value.push(binning.to_key_value()?);
+    }
+    if let Some(precursor_purity) = options.precursor_purity {
+        key_value.push(precursor_purity.to_key_value()?);
+    }
+    // Keys appended once the file has been written
+    let appended = [
+        SOURCES_KEY,
+        crate::index::KEY,
+        crate::metadata::INSTRUMENTS_KEY,
+        crate::metadata::SOURCE_FILES_KEY,
+        crate::metadata::INPUT_KEY,
+        crate::metadata::START_TIMESTAMP_KEY,
+        "ARROW:schema",
+    ];
+    for (key, value) in &options.metadata {
+        if key_value.iter().any(|kv| &kv.key == key) || appended.contains(&key.as_str()) {
+            anyhow::bail!("metadata key `{}` is reserved", key);
+        }
+        key_value.push(KeyValue {
+            key: key.clone(),
+            value: Some(value.clone()),
+        });
+    }
+    let mut builder = WriterProperties::builder()
+        .set_compression(options.compression)
+        .set_dictionary_enabled(false)
+        .set_key_value_metadata(Some(key_value));
+
+    let path = |column: &[&str]| ColumnPath::new(column.iter().map(|s| s.to_string()).collect());
+    // Runs only have a handful of distinct filter strings, and the native id
+    // of a spectrum is repeated for each of its ions in the long format
+    for column in ["filter_string", "native_id"] {
+        builder = builder.set_column_dictionary_enabled(path(&[column]), true);
+    }
+    let (mz, precursor_mz, indexed): (_, _, &[&[&str]]) = match format {
+        "wide" => (
+            path(&["mz", "list", "element"]),
+            path(&["precursors", "list", "element", "selected_ion_mz"]),
+            &[&["scan_start_time"]],
+        ),
+        _ => (
+            path(&["mz"]),
+            path(&["precursor_mz"]),
+            &[&["scan"], &["rt"]],
+        ),
+    };
+
+    let page_statistics = match options.page_index {
+        true => EnabledStatistics::Page,
+        false => EnabledStatistics::Chunk,
+    };
+    builder = builder.set_statistics_enabled(EnabledStatistics::Chunk);
+    for column in indexed
+        .iter()
+        .map(|c| path(c))
+        .chain([mz.clone(), precursor_mz.clone()])
+    {
+        builder = builder.set_column_statistics_enabled(column, page_statistics);
+    }
+
+    if let Some(bloom) = options.bloom_filter {
+        for path in [mz, precursor_mz] {
+            builder = builder
+                .set_column_bloom_filter_enabled(path.clone(), true)
+                .set_column_bloom_filter_fpp(path.clone(), bloom.fpp)
+                .set_column_bloom_filter_ndv(path, bloom.ndv);
+        }
+    }
+
+    if options.sort_ions && format == "long" {
+        builder = builder.set_sorting_columns(Some(vec![
+            SortingColumn::new(1, false, false),
+            SortingColumn::new(3, false, false),
+        ]));
+    }
+
+    Ok(Arc::new(builder.build()))
+}
+
+/// Serialize `spectra` into a long format mzparquet file, returning the
+/// underlying writer once the file footer has been written
+pub fn serialize_to_parquet<W: Write + Send>(w: W, spectra: &[RawSpectrum]) -> anyhow::Result<W> {
+    let schema = build_schema(&WriterOptions::default())?;
+    let sd = SchemaDescriptor::new(schema.clone().into());
+    let options = writer_properties("long", &WriterOptions::default())?;
+
+    let mut writer = SerializedFileWriter::new(w, schema.into(), options.clone())?;
+
+    let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, options);
+
+    for spectrum in spectra {
+        chunk_writer.write_spectrum(spectrum)?;
+    }
+    chunk_writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+/// Serialize spectra into a long format mzparquet file as they are parsed.
+///
+/// Row groups are flushed to `w` as they fill up, so only a single row group
+/// is ever held in memory. Returns the underlying writer and the number of
+/// spectra and peaks that were written.
+pub async fn serialize_stream_to_parquet<W, S>(
+    w: W,
+    spectra: &mut S,
+    options: &WriterOptions,
+) -> anyhow::Result<(W, Written)>
+where
+    W: Write + Send,
+    S: SpectrumStream,
+{
+    let schema = build_schema(options)?;
+    let sd = SchemaDescriptor::new(schema.clone().into());
+    let properties = writer_properties("long", options)?;
+
+    let mut writer = SerializedFileWriter::new(w, schema.into(), properties.clone())?;
+
+    let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, properties);
+    chunk_writer
+        .set_row_group_size(options.row_group_size)
+        .set_progress(options.progress.clone());
+    if let Some(source) = &options.source {
+        chunk_writer.set_source(source.clone());
+    }
+
+    let mut spectra = AveragedStream::new(spectra, options.ms1_averaging);
+    let mut spectra = DemultiplexedStream::new(&mut spectra, options.demultiplex);
+    let mut count = 0;
+    let mut index = 0;
+    while let Some(spectrum) = spectra.next_spectrum().await? {
+        // The run start is parsed from the header, before the first spectrum
+        if index == 0 && options.acquisition_time {
+            chunk_writer.set_run_start(spectra.run_metadata().start_time_millis());
+        }
+        let keep = options.spectrum_filter.keep(index, &spectrum);
+        index += 1;
+        if keep {
+            chunk_writer.write_spectrum(&spectrum)?;
+            count += 1;
+        } else {
+            chunk_writer.skip_spectrum(&spectrum);
+        }
+    }
+    let peaks = chunk_writer.peaks_written();
+    chunk_writer.finish()?;
+    for kv in spectra.run_metadata().to_key_value()? {
+        writer.append_key_value_metadata(kv);
+    }
+    let written = Written {
+        spectra: count,
+        peaks,
+    };
+    Ok((writer.into_inner()?, written))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mzml::Precursor;
+    use parquet::basic::Encoding;
+    use parquet::file::{
+        page_index::index::Index,
+        properties::ReaderProperties,
+        reader::FileReader,
+        serialized_reader::{ReadOptionsBuilder, SerializedFileReader},
+    };
+
+    /// Write a long format file with `options`, adding spectra (and anything
+    /// else) through the chunk writer given to `write`
+    fn write_with<F>(options: &WriterOptions, write: F) -> anyhow::Result<bytes::Bytes>
+    where
+        F: FnOnce(&mut ChunkWriter<'_, Vec<u8>>) -> anyhow::Result<()>,
+    {
+        let properties = writer_properties("long", options)?;
+        let schema = build_schema(options)?;
+        let sd = SchemaDescriptor::new(schema.clone().into());
+        let mut writer = SerializedFileWriter::new(Vec::new(), schema.into(), properties.clone())?;
+        let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, properties);
+        write(&mut chunk_writer)?;
+        chunk_writer.finish()?;
+        Ok(bytes::Bytes::from(writer.into_inner()?))
+    }
+
+    /// Write `spectra` to a long format file with `options`
+    fn write(spectra: &[RawSpectrum], options: &WriterOptions) -> anyhow::Result<bytes::Bytes> {
+        write_with(options, |chunk_writer| {
+            for spectrum in spectra {
+                chunk_writer.write_spectrum(spectrum)?;
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn write_bloom_filters() -> anyhow::Result<()> {
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            precursors: vec![Precursor {
+                mz: 512.25,
+                ..Default::default()
+            }],
+            mz: vec![100.5, 200.25],
+            intensity: vec![1.0, 2.0],
+            ..Default::default()
+        };
+
+        let mut options = WriterOptions::default();
+        options.set_bloom_filter(Some(BloomFilter::default()));
+        let buf = write(&[spectrum], &options)?;
+
+        let reader = SerializedFileReader::new_with_options(
+            buf,
+            ReadOptionsBuilder::new()
+                .with_reader_properties(
+                    ReaderProperties::builder()
+                        .set_read_bloom_filter(true)
+                        .build(),
+                )
+                .build(),
+        )?;
+        let rg = reader.get_row_group(0)?;
+
+        let mz = rg.get_column_bloom_filter(3).expect("mz bloom filter");
+        assert!(mz.check(&200.25f32));
+        assert!(!mz.check(&300.0f32));
+
+        let pmz = rg
+            .get_column_bloom_filter(9)
+            .expect("precursor_mz bloom filter");
+        assert!(pmz.check(&512.25f32));
+        assert!(rg.get_column_bloom_filter(2).is_none());
+        Ok(())
+    }
+
+    /// Whether the first column chunk of `column` is dictionary encoded
+    fn dictionary_encoded(buf: bytes::Bytes, column: &str) -> anyhow::Result<bool> {
+        let reader = SerializedFileReader::new(buf)?;
+        let idx = crate::query::column_index(&reader, column)?;
+        Ok(reader
+            .metadata()
+            .row_group(0)
+            .column(idx)
+            .encodings()
+            .iter()
+            .any(|e| matches!(e, Encoding::PLAIN_DICTIONARY | Encoding::RLE_DICTIONARY)))
+    }
+
+    #[test]
+    fn dictionary_encode_filter_strings() -> anyhow::Result<()> {
+        let spectrum = |filter: &str| RawSpectrum {
+            ms_level: 1,
+            filter_string: Some(filter.into()),
+            mz: vec![100.0, 200.0, 300.0],
+            intensity: vec![1.0, 2.0, 3.0],
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum("FTMS + p NSI Full ms [350.00-1500.00]"),
+            spectrum("FTMS + p NSI Full ms [350.00-1500.00]"),
+            spectrum("FTMS + p NSI d Full ms2 500.25@hcd30.00 [100.00-1000.00]"),
+        ];
+
+        let buf = write(&spectra, &WriterOptions::default())?;
+        assert!(dictionary_encoded(buf.clone(), "filter_string")?);
+        assert!(!dictionary_encoded(buf.clone(), "mz")?);
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(read[2].filter_string, spectra[2].filter_string);
+
+        let wide = crate::rewrite::SpectrumFile {
+            format: crate::Format::Wide,
+            sources: Vec::new(),
+            metadata: Default::default(),
+            spectra,
+        };
+        let (buf, _) = wide.write(Vec::new(), &WriterOptions::default())?;
+        assert!(dictionary_encoded(
+            bytes::Bytes::from(buf),
+            "filter_string"
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn dictionary_encode_native_ids() -> anyhow::Result<()> {
+        let spectra = (1..=3)
+            .map(|scan| RawSpectrum {
+                id: format!("controllerType=0 controllerNumber=1 scan={}", scan).into_bytes(),
+                ms_level: 1,
+                mz: vec![100.0, 200.0, 300.0],
+                intensity: vec![1.0, 2.0, 3.0],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let buf = write(&spectra, &WriterOptions::default())?;
+        assert!(dictionary_encoded(buf.clone(), "native_id")?);
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(
+            read.iter().map(|s| s.id.clone()).collect::<Vec<_>>(),
+            spectra.iter().map(|s| s.id.clone()).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_page_index() -> anyhow::Result<()> {
+        let spectrum = RawSpectrum {
+            ms_level: 1,
+            mz: vec![100.5, 200.25],
+            intensity: vec![1.0, 2.0],
+            ..Default::default()
+        };
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &[spectrum])?);
+        let reader = SerializedFileReader::new_with_options(
+            buf,
+            ReadOptionsBuilder::new().with_page_index().build(),
+        )?;
+
+        let index = &reader.metadata().column_index().expect("column index")[0];
+        assert!(matches!(index[3], Index::FLOAT(_)), "mz has a column index");
+        assert!(
+            matches!(index[4], Index::NONE),
+            "intensity has no column index"
+        );
+        assert!(reader
+            .metadata()
+            .row_group(0)
+            .column(4)
+            .statistics()
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn sort_ions_by_level_and_mz() -> anyhow::Result<()> {
+        let spectrum = |ms_level, mz: Vec<f64>, precursor: Option<f64>| RawSpectrum {
+            id: b"0".to_vec(),
+            ms_level,
+            intensity: mz.iter().map(|mz| mz.round()).collect(),
+            mz,
+            precursors: precursor
+                .map(|mz| Precursor {
+                    mz,
+                    ..Default::default()
+                })
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let mut spectra = vec![
+            spectrum(1, vec![300.0, 400.0, 500.0], None),
+            spectrum(2, vec![150.0, 450.0], Some(500.0)),
+            spectrum(1, vec![200.0, 350.0], None),
+        ];
+        for (scan, spectrum) in spectra.iter_mut().enumerate() {
+            spectrum.id = scan.to_string().into_bytes();
+        }
+
+        let mut options = WriterOptions::default();
+        options.set_sort_ions(true);
+        let buf = write(&spectra, &options)?;
+
+        let reader = SerializedFileReader::new(buf.clone())?;
+        let rg = reader.metadata().row_group(0);
+        assert_eq!(
+            rg.sorting_columns().map(|c| c.len()),
+            Some(2),
+            "sorting columns are recorded"
+        );
+
+        let mz = crate::query::read_required(reader.get_row_group(0)?.as_ref(), 3)?;
+        assert_eq!(mz, vec![200.0, 300.0, 350.0, 400.0, 500.0, 150.0, 450.0]);
+
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(read.len(), 3);
+        for (read, written) in read.iter().zip(&spectra) {
+            assert_eq!(read.id, written.id);
+            assert_eq!(read.mz, written.mz);
+            assert_eq!(read.intensity, written.intensity);
+            assert_eq!(read.precursors.len(), written.precursors.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mz_precision_f64() -> anyhow::Result<()> {
+        let spectrum = RawSpectrum {
+            id: b"0".to_vec(),
+            ms_level: 2,
+            mz: vec![1234.567891234, 1500.000001],
+            intensity: vec![1.0, 2.0],
+            precursors: vec![Precursor {
+                mz: 1999.99999912,
+                isolation_window_lower: Some(0.5),
+                isolation_window_upper: Some(0.5),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut options = WriterOptions::default();
+        options.set_mz_precision(MzPrecision::F64);
+        let buf = write(std::slice::from_ref(&spectrum), &options)?;
+
+        let reader = SerializedFileReader::new(buf.clone())?;
+        let columns = reader.metadata().file_metadata().schema_descr();
+        assert_eq!(columns.column(3).physical_type(), PhysicalType::DOUBLE);
+        assert_eq!(columns.column(9).physical_type(), PhysicalType::DOUBLE);
+        assert!(reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+            .any(|kv| kv.key == "mz_precision" && kv.value.as_deref() == Some("f64")));
+
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(read[0].mz, spectrum.mz);
+        assert_eq!(read[0].precursors[0].mz, spectrum.precursors[0].mz);
+        Ok(())
+    }
+
+    #[test]
+    fn tag_rows_with_file_id() -> anyhow::Result<()> {
+        let spectrum = RawSpectrum {
+            id: b"0".to_vec(),
+            ms_level: 1,
+            mz: vec![100.0, 200.0],
+            intensity: vec![1.0, 2.0],
+            ..Default::default()
+        };
+        let source = |file_id: &str| Source {
+            file_id: file_id.into(),
+            path: Some(format!("s3://bucket/{}.mzML", file_id)),
+        };
+
+        let mut options = WriterOptions::default();
+        options.set_source(Some(source("a")));
+        // The source is switched between spectra of the same file
+        let buf = write_with(&options, |chunk_writer| {
+            for file_id in ["a", "b"] {
+                chunk_writer.set_source(source(file_id));
+                chunk_writer.write_spectrum(&spectrum)?;
+            }
+            Ok(())
+        })?;
+
+        let reader = SerializedFileReader::new(buf.clone())?;
+        assert_eq!(
+            Source::from_metadata(reader.metadata())?,
+            vec![source("a"), source("b")]
+        );
+        use parquet::record::RowAccessor;
+        let file_ids = reader
+            .get_row_iter(None)?
+            .map(|row| Ok(row?.get_string(18)?.clone()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(file_ids, vec!["a", "a", "b", "b"]);
+
+        // The extra column is ignored when reading spectra back
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(read.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn write_pixel_columns() -> anyhow::Result<()> {
+        use crate::mzml::Pixel;
+
+        let spectrum = |x, y| RawSpectrum {
+            id: format!("Scan={}", x).into_bytes(),
+            ms_level: 1,
+            pixel: Some(Pixel { x, y, z: None }),
+            mz: vec![100.0, 200.0],
+            intensity: vec![1.0, 2.0],
+            ..Default::default()
+        };
+        let spectra = vec![spectrum(1, 5), spectrum(2, 5)];
+
+        let mut options = WriterOptions::default();
+        options
+            .set_source(Some(Source {
+                file_id: "slide".into(),
+                path: None,
+            }))
+            .set_pixel_columns(true);
+        let schema = build_schema(&options)?;
+        let names = schema
+            .get_fields()
+            .iter()
+            .skip(18)
+            .map(|f| f.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["file_id", "pixel_x", "pixel_y", "pixel_z"]);
+
+        let file = crate::rewrite::SpectrumFile {
+            format: crate::Format::Long,
+            sources: Vec::new(),
+            metadata: Default::default(),
+            spectra: spectra.clone(),
+        };
+        let (buf, _) = file.write(Vec::new(), &options)?;
+        let (_, read) = crate::reader::read_spectra(bytes::Bytes::from(buf))?;
+        assert_eq!(
+            read.iter().map(|s| s.pixel).collect::<Vec<_>>(),
+            spectra.iter().map(|s| s.pixel).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_extra_params() -> anyhow::Result<()> {
+        let document = r#"
+        <spectrum index="0" id="scan=1" defaultArrayLength="1">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1"/>
+            <cvParam cvRef="MS" accession="MS:1000130" name="positive scan"/>
+            <cvParam cvRef="MS" accession="MS:1000504" name="base peak m/z" value="100.0"/>
+            <userParam name="filter string" type="xsd:string" value="FTMS + p NSI Full ms"/>
+            <scanList count="1">
+                <scan>
+                    <userParam name="[Thermo Trailer Extra]Monoisotopic M/Z:" value="0"/>
+                </scan>
+            </scanList>
+            <binaryDataArrayList count="2">
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array"/>
+                    <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float"/>
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                    <binary>AAAAAAAAWUA=</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="8">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array"/>
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float"/>
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                    <binary>AACAPw==</binary>
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>
+        "#;
+
+        let mut reader = crate::MzMLReader::default();
+        reader.set_extra_params(true);
+        let mut stream = reader.stream(document.as_bytes());
+        let mut options = WriterOptions::default();
+        options.set_extra_params(true);
+        let (buf, count) = serialize_stream_to_parquet(Vec::new(), &mut stream, &options).await?;
+        assert_eq!(count.spectra, 1);
+
+        let (_, read) = crate::reader::read_spectra(bytes::Bytes::from(buf))?;
+        assert_eq!(
+            read[0].filter_string.as_deref(),
+            Some("FTMS + p NSI Full ms")
+        );
+        assert_eq!(
+            read[0].extra_params,
+            vec![
+                ("MS:1000504".into(), "100.0".into()),
+                ("[Thermo Trailer Extra]Monoisotopic M/Z:".into(), "0".into()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn vendor_scan_numbers() -> anyhow::Result<()> {
+        let spectrum = |scan: u32, level: u8, precursor: &str| {
+            format!(
+                r#"
+        <spectrum index="{index}" id="controllerType=0 controllerNumber=1 scan={scan}" defaultArrayLength="1">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="{level}"/>
+            {precursor}
+            <binaryDataArrayList count="2">
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array"/>
+                    <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float"/>
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                    <binary>AAAAAAAAWUA=</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="8">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array"/>
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float"/>
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                    <binary>AACAPw==</binary>
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>"#,
+                index = scan - 101,
+            )
+        };
+        let precursor = r#"
+            <precursorList count="1">
+                <precursor spectrumRef="controllerType=0 controllerNumber=1 scan=101">
+                    <selectedIonList count="1">
+                        <selectedIon>
+                            <cvParam cvRef="MS" accession="MS:1000744" name="selected ion m/z" value="100.0"/>
+                        </selectedIon>
+                    </selectedIonList>
+                </precursor>
+            </precursorList>"#;
+        let document = format!(
+            r#"
+        <fileDescription>
+            <sourceFileList count="1">
+                <sourceFile id="RAW1" name="run.raw" location="file:///C:/data">
+                    <cvParam cvRef="MS" accession="MS:1000768" name="Thermo nativeID format" value=""/>
+                </sourceFile>
+            </sourceFileList>
+        </fileDescription>
+        {}{}"#,
+            spectrum(101, 1, ""),
+            spectrum(102, 2, precursor)
+        );
+
+        let mut stream = crate::MzMLReader::default().stream(document.as_bytes());
+        let (buf, count) =
+            serialize_stream_to_parquet(Vec::new(), &mut stream, &WriterOptions::default()).await?;
+        assert_eq!(count.spectra, 2);
+        let buf = bytes::Bytes::from(buf);
+
+        use parquet::record::RowAccessor;
+        let reader = SerializedFileReader::new(buf.clone())?;
+        let scans = reader
+            .get_row_iter(None)?
+            .map(|row| {
+                let row = row?;
+                Ok((row.get_uint(0)?, row.get_uint(15)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(scans, vec![(101, 0), (102, 1)]);
+
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(read[0].scan_number, Some(101));
+        assert_eq!(read[1].scan_number, Some(102));
+        assert_eq!(read[1].precursors[0].spectrum_ref, Some(read[0].id.clone()));
+        Ok(())
+    }
+
+    #[test]
+    fn msn_precursor_chain() -> anyhow::Result<()> {
+        let precursor = |mz: f64, spectrum_ref: Option<&[u8]>| Precursor {
+            mz,
+            spectrum_ref: spectrum_ref.map(<[u8]>::to_vec),
+            ..Default::default()
+        };
+        let spectrum = |id: &[u8], ms_level, precursors| RawSpectrum {
+            id: id.to_vec(),
+            ms_level,
+            precursors,
+            mz: vec![100.0],
+            intensity: vec![1.0],
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum(b"scan=1", 1, vec![]),
+            spectrum(b"scan=2", 2, vec![precursor(500.0, Some(b"scan=1"))]),
+            // The MS2 stage is listed before the MS3 stage
+            spectrum(
+                b"scan=3",
+                3,
+                vec![precursor(500.0, None), precursor(300.0, Some(b"scan=2"))],
+            ),
+            spectrum(b"scan=4", 1, vec![]),
+            spectrum(b"scan=5", 3, vec![precursor(300.0, Some(b"scan=2"))]),
+        ];
+
+        let buf = bytes::Bytes::from(serialize_to_parquet(Vec::new(), &spectra)?);
+        let reader = SerializedFileReader::new(buf.clone())?;
+        let rg = reader.get_row_group(0)?;
+        let pmz = crate::query::read_column(rg.as_ref(), 9)?;
+        assert_eq!(pmz, vec![None, Some(500.0), Some(300.0), None, Some(300.0)]);
+        let pscan = crate::query::read_column(rg.as_ref(), 8)?;
+        assert_eq!(pscan, vec![None, Some(0.0), Some(1.0), None, Some(1.0)]);
+        // Later MS1 scans don't change the root of an existing chain
+        let ms1_scan = crate::query::read_column(rg.as_ref(), 16)?;
+        assert_eq!(
+            ms1_scan,
+            vec![Some(0.0), Some(0.0), Some(0.0), Some(3.0), Some(0.0)]
+        );
+
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(read[2].precursors.len(), 1);
+        assert_eq!(read[2].precursors[0].mz, 300.0);
+        assert_eq!(read[2].precursors[0].spectrum_ref, Some(b"scan=2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn sps_notches() -> anyhow::Result<()> {
+        let precursor = |mz: f64, spectrum_ref: &[u8]| Precursor {
+            mz,
+            spectrum_ref: Some(spectrum_ref.to_vec()),
+            ..Default::default()
+        };
+        let spectrum = |id: &[u8], ms_level, mz: Vec<f64>, precursors| RawSpectrum {
+            id: id.to_vec(),
+            ms_level,
+            precursors,
+            intensity: vec![1.0; mz.len()],
+            mz,
+            ..Default::default()
+        };
+        let notches = vec![
+            precursor(300.0, b"scan=2"),
+            precursor(350.0, b"scan=2"),
+            precursor(400.0, b"scan=2"),
+        ];
+        let spectra = vec![
+            spectrum(b"scan=1", 1, vec![100.0], vec![]),
+            spectrum(b"scan=2", 2, vec![150.0], vec![precursor(500.0, b"scan=1")]),
+            spectrum(b"scan=3", 3, vec![127.1, 126.1], notches.clone()),
+            spectrum(b"scan=4", 1, vec![200.0], vec![]),
+        ];
+
+        // Sorting moves the notches along with the rest of each row
+        let mut options = WriterOptions::default();
+        options.set_sort_ions(true);
+        let file = crate::rewrite::SpectrumFile {
+            format: crate::Format::Long,
+            sources: Vec::new(),
+            metadata: Default::default(),
+            spectra,
+        };
+        let (buf, _) = file.write(Vec::new(), &options)?;
+
+        let (_, read) = crate::reader::read_spectra(bytes::Bytes::from(buf))?;
+        assert_eq!(read[1].precursors.len(), 1);
+        let mz = read[2]
+            .precursors
+            .iter()
+            .map(|p| (p.mz, p.spectrum_ref.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mz,
+            notches
+                .iter()
+                .map(|p| (p.mz, p.spectrum_ref.clone()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(read[2].mz, vec![126.1f32 as f64, 127.1f32 as f64]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn per_ion_arrays() -> anyhow::Result<()> {
+        let array = |accession: &str, name: &str, binary: &str| {
+            format!(
+                r#"
+                <binaryDataArray encodedLength="8">
+                    <cvParam cvRef="MS" accession="{accession}" name="{name}"/>
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float"/>
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression"/>
+                    <binary>{binary}</binary>
+                </binaryDataArray>"#
+            )
+        };
+        let document = format!(
+            r#"
+        <spectrum index="0" id="scan=1" defaultArrayLength="1">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1"/>
+            <binaryDataArrayList count="5">{}{}{}{}{}
+            </binaryDataArrayList>
+        </spectrum>
+        "#,
+            array("MS:1000514", "m/z array", "AADIQg=="),
+            array("MS:1000515", "intensity array", "AACAPw=="),
+            array("MS:1002744", "sampled noise intensity array", "AAAAQA=="),
+            array("MS:1002745", "sampled noise baseline array", "AACAPw=="),
+            array("MS:1000516", "charge array", "AAAAQA=="),
+        );
+
+        let mut stream = crate::MzMLReader::default().stream(document.as_bytes());
+        let mut options = WriterOptions::default();
+        options.set_noise_columns(true).set_charge_column(true);
+        let (buf, count) = serialize_stream_to_parquet(Vec::new(), &mut stream, &options).await?;
+        assert_eq!(count.spectra, 1);
+
+        let (_, read) = crate::reader::read_spectra(bytes::Bytes::from(buf))?;
+        assert_eq!(read[0].mz, vec![100.0]);
+        assert_eq!(read[0].noise, vec![2.0]);
+        assert_eq!(read[0].baseline, vec![1.0]);
+        assert_eq!(read[0].charge, vec![2]);
+        Ok(())
+    }
+
+    #[test]
+    fn charge_column() -> anyhow::Result<()> {
+        let spectrum = |id: &[u8], charge: Vec<u8>| RawSpectrum {
+            id: id.to_vec(),
+            ms_level: 1,
+            mz: vec![100.0, 200.0],
+            intensity: vec![1.0, 2.0],
+            charge,
+            ..Default::default()
+        };
+        let spectra = vec![spectrum(b"0", vec![2, 0]), spectrum(b"1", Vec::new())];
+
+        // The column is only written when asked for
+        let buf = write(&spectra, &WriterOptions::default())?;
+        let reader = SerializedFileReader::new(buf)?;
+        assert!(crate::query::column_index(&reader, CHARGE_COLUMN).is_err());
+
+        let mut options = WriterOptions::default();
+        options.set_charge_column(true);
+        let buf = write(&spectra, &options)?;
+        let reader = SerializedFileReader::new(buf.clone())?;
+        let idx = crate::query::column_index(&reader, CHARGE_COLUMN)?;
+        let charge = crate::query::read_column(reader.get_row_group(0)?.as_ref(), idx)?;
+        // Ions of spectra without charges are null
+        assert_eq!(charge, vec![Some(2.0), Some(0.0), None, None]);
+
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(read[0].charge, vec![2, 0]);
+        assert!(read[1].charge.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rt_units() -> anyhow::Result<()> {
+        let spectrum = RawSpectrum {
+            id: b"0".to_vec(),
+            ms_level: 1,
+            scan_start_time: 90.0,
+            mz: vec![100.0],
+            intensity: vec![1.0],
+            ..Default::default()
+        };
+
+        let mut options = WriterOptions::default();
+        options.set_rt_unit(RtUnit::Minutes);
+        let file = crate::rewrite::SpectrumFile {
+            format: crate::Format::Long,
+            sources: Vec::new(),
+            metadata: Default::default(),
+            spectra: vec![spectrum],
+        };
+        let (buf, _) = file.write(Vec::new(), &options)?;
+        let buf = bytes::Bytes::from(buf);
+
+        let reader = SerializedFileReader::new(buf.clone())?;
+        assert_eq!(RtUnit::from_metadata(reader.metadata()), RtUnit::Minutes);
+        let rt = crate::query::read_column(reader.get_row_group(0)?.as_ref(), 2)?;
+        assert_eq!(rt, vec![Some(1.5)]);
+
+        // Spectra are read back in seconds
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(read[0].scan_start_time, 90.0);
+        Ok(())
+    }
+
+    #[test]
+    fn intensity_types() -> anyhow::Result<()> {
+        let spectrum = RawSpectrum {
+            id: b"0".to_vec(),
+            ms_level: 1,
+            mz: vec![100.0, 200.0],
+            intensity: vec![0.25, 5e9 + 0.5],
+            ..Default::default()
+        };
+
+        for (ty, physical, expected) in [
+            (IntensityType::F32, PhysicalType::FLOAT, vec![0.25, 5e9]),
+            (
+                IntensityType::F64,
+                PhysicalType::DOUBLE,
+                vec![0.25, 5e9 + 0.5],
+            ),
+            (
+                IntensityType::U32,
+                PhysicalType::INT32,
+                vec![0.0, u32::MAX as f64],
+            ),
+        ] {
+            let mut options = WriterOptions::default();
+            options.set_intensity_type(ty);
+            let buf = write(std::slice::from_ref(&spectrum), &options)?;
+
+            let reader = SerializedFileReader::new(buf.clone())?;
+            let columns = reader.metadata().file_metadata().schema_descr();
+            assert_eq!(columns.column(4).physical_type(), physical);
+
+            let (_, read) = crate::reader::read_spectra(buf)?;
+            assert_eq!(read[0].intensity, expected, "{}", ty);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn row_group_thresholds() -> anyhow::Result<()> {
+        let size = |ions, spectra, bytes| RowGroupSize {
+            ions,
+            spectra,
+            bytes,
+        };
+        assert!(!size(Some(10), None, None).is_full(9, 100, 1 << 30));
+        assert!(size(Some(10), None, None).is_full(10, 1, 0));
+        assert!(size(None, Some(3), None).is_full(0, 3, 0));
+        assert!(size(None, None, Some(1000)).is_full(0, 0, 1000));
+        // Any of the limits flushes the row group
+        assert!(size(Some(10), Some(3), Some(1000)).is_full(1, 1, 1000));
+        assert!(!size(None, None, None).is_full(usize::MAX, usize::MAX, usize::MAX));
+
+        let spectra = (0..10)
+            .map(|scan| RawSpectrum {
+                id: format!("scan={}", scan).into_bytes(),
+                ms_level: 1,
+                mz: vec![100.0, 200.0],
+                intensity: vec![1.0, 2.0],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let rows = |row_group_size: RowGroupSize| {
+            let buf = write_with(&WriterOptions::default(), |chunk_writer| {
+                chunk_writer.set_row_group_size(row_group_size);
+                for spectrum in &spectra {
+                    chunk_writer.write_spectrum(spectrum)?;
+                }
+                Ok(())
+            })?;
+            let reader = SerializedFileReader::new(buf)?;
+            let rows = reader.metadata().row_groups().iter();
+            anyhow::Ok(rows.map(|rg| rg.num_rows()).collect::<Vec<_>>())
+        };
+        // Spectra are never split, so 5 ions flush after the third spectrum
+        assert_eq!(rows(size(Some(5), None, None))?, vec![6, 6, 6, 2]);
+        assert_eq!(rows(size(None, Some(4), None))?, vec![8, 8, 4]);
+        assert_eq!(rows(size(None, None, Some(1)))?, vec![2; 10]);
+        assert_eq!(rows(RowGroupSize::default())?, vec![20]);
+        Ok(())
+    }
+
+    #[test]
+    fn compression_codecs() -> anyhow::Result<()> {
+        let spectrum = RawSpectrum {
+            id: b"0".to_vec(),
+            ms_level: 1,
+            mz: vec![100.0, 200.0],
+            intensity: vec![1.0, 2.0],
+            ..Default::default()
+        };
+        // The level is not recorded in the file, only the codec
+        for (compression, codec) in [
+            (
+                Compression::ZSTD(ZstdLevel::try_new(9)?),
+                Compression::ZSTD(ZstdLevel::default()),
+            ),
+            (Compression::SNAPPY, Compression::SNAPPY),
+            (Compression::UNCOMPRESSED, Compression::UNCOMPRESSED),
+        ] {
+            let mut options = WriterOptions::default();
+            options.set_compression(compression);
+            let buf = write(std::slice::from_ref(&spectrum), &options)?;
+            let reader = SerializedFileReader::new(buf.clone())?;
+            let columns = reader.metadata().row_group(0).columns();
+            assert!(columns.iter().all(|column| column.compression() == codec));
+
+            let (_, read) = crate::reader::read_spectra(buf)?;
+            assert_eq!(read[0].mz, spectrum.mz);
+            assert_eq!(read[0].intensity, spectrum.intensity);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn spectrum_level_columns() -> anyhow::Result<()> {
+        let spectrum = |id: &[u8], ms_level, tic, iit| RawSpectrum {
+            id: id.to_vec(),
+            ms_level,
+            total_ion_current: tic,
+            ion_injection_time: iit,
+            mz: vec![100.0, 200.0],
+            intensity: vec![1.0, 2.0],
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum(b"0", 1, 3000.0, 12.5),
+            spectrum(b"1", 2, 150.0, 50.0),
+        ];
+        let buf = write(&spectra, &WriterOptions::default())?;
+
+        // Repeated for every ion of a spectrum
+        let reader = SerializedFileReader::new(buf.clone())?;
+        let row_group = reader.get_row_group(0)?;
+        let column = |name| {
+            let idx = crate::query::column_index(&reader, name)?;
+            crate::query::read_column(row_group.as_ref(), idx)
+        };
+        assert_eq!(
+            column("total_ion_current")?,
+            vec![Some(3000.0), Some(3000.0), Some(150.0), Some(150.0)]
+        );
+        assert_eq!(
+            column("ion_injection_time")?,
+            vec![Some(12.5), Some(12.5), Some(50.0), Some(50.0)]
+        );
+
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        assert_eq!(read.len(), spectra.len());
+        for (read, written) in read.iter().zip(&spectra) {
+            assert_eq!(read.total_ion_current, written.total_ion_current);
+            assert_eq!(read.ion_injection_time, written.ion_injection_time);
+        }
+        Ok(())
     }
-    chunk_writer.finish()?;
-    Ok(writer.into_inner()?)
 }
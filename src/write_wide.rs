@@ -0,0 +1,634 @@
+use crate::average::AveragedStream;
+use crate::demux::DemultiplexedStream;
+use crate::mzml::{RawSpectrum, SpectrumStream};
+use crate::progress::Callback;
+use crate::write_long::{
+    writer_properties, ColumnWriter, Processing, RowGroupSize, RtUnit, WriterOptions, Written,
+};
+use parquet::{
+    data_type::{BoolType, ByteArray, ByteArrayType, DataType, FloatType, Int32Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::types::{SchemaDescriptor, Type},
+};
+use std::{io::Write, sync::Arc};
+
+/// Build the parquet schema for the wide format, where each spectrum is
+/// stored as a single row, with m/z and intensity values stored as lists
+pub fn build_schema() -> parquet::errors::Result<Type> {
+    use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+
+    let id = Type::primitive_type_builder("id", PhysicalType::BYTE_ARRAY)
+        .with_repetition(Repetition::REQUIRED)
+        .with_logical_type(Some(LogicalType::String))
+        .build()?;
+
+    let ms_level = Type::primitive_type_builder("ms_level", PhysicalType::INT32)
+        .with_repetition(Repetition::REQUIRED)
+        .build()?;
+
+    let centroid = Type::primitive_type_builder("centroid", PhysicalType::BOOLEAN)
+        .with_repetition(Repetition::REQUIRED)
+        .build()?;
+
+    let scan_start_time = Type::primitive_type_builder("scan_start_time", PhysicalType::FLOAT)
+        .with_repetition(Repetition::REQUIRED)
+        .build()?;
+
+    let inverse_ion_mobility =
+        Type::primitive_type_builder("inverse_ion_mobility", PhysicalType::FLOAT)
+            .with_repetition(Repetition::OPTIONAL)
+            .build()?;
+
+    let ion_injection_time =
+        Type::primitive_type_builder("ion_injection_time", PhysicalType::FLOAT)
+            .with_repetition(Repetition::REQUIRED)
+            .build()?;
+
+    let total_ion_current = Type::primitive_type_builder("total_ion_current", PhysicalType::FLOAT)
+        .with_repetition(Repetition::REQUIRED)
+        .build()?;
+
+    let selected_ion_mz = Type::primitive_type_builder("selected_ion_mz", PhysicalType::FLOAT)
+        .with_repetition(Repetition::REQUIRED)
+        .build()?;
+
+    let selected_ion_charge =
+        Type::primitive_type_builder("selected_ion_charge", PhysicalType::INT32)
+            .with_repetition(Repetition::OPTIONAL)
+            .build()?;
+
+    let selected_ion_intensity =
+        Type::primitive_type_builder("selected_ion_intensity", PhysicalType::FLOAT)
+            .with_repetition(Repetition::OPTIONAL)
+            .build()?;
+
+    let isolation_window_target =
+        Type::primitive_type_builder("isolation_window_target", PhysicalType::FLOAT)
+            .with_repetition(Repetition::OPTIONAL)
+            .build()?;
+
+    let isolation_window_lower =
+        Type::primitive_type_builder("isolation_window_lower", PhysicalType::FLOAT)
+            .with_repetition(Repetition::OPTIONAL)
+            .build()?;
+
+    let isolation_window_upper =
+        Type::primitive_type_builder("isolation_window_upper", PhysicalType::FLOAT)
+            .with_repetition(Repetition::OPTIONAL)
+            .build()?;
+
+    let spectrum_ref = Type::primitive_type_builder("spectrum_ref", PhysicalType::BYTE_ARRAY)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_logical_type(Some(LogicalType::String))
+        .build()?;
+
+    let precursor = Type::group_type_builder("element")
+        .with_repetition(Repetition::REQUIRED)
+        .with_fields(vec![
+            Arc::new(selected_ion_mz),
+            Arc::new(selected_ion_charge),
+            Arc::new(selected_ion_intensity),
+            Arc::new(isolation_window_target),
+            Arc::new(isolation_window_lower),
+            Arc::new(isolation_window_upper),
+            Arc::new(spectrum_ref),
+        ])
+        .build()?;
+
+    let precursors = list("precursors", Repetition::OPTIONAL, precursor)?;
+
+    let mz = list(
+        "mz",
+        Repetition::REQUIRED,
+        Type::primitive_type_builder("element", PhysicalType::FLOAT)
+            .with_repetition(Repetition::REQUIRED)
+            .build()?,
+    )?;
+
+    let intensity = list(
+        "intensity",
+        Repetition::REQUIRED,
+        Type::primitive_type_builder("element", PhysicalType::FLOAT)
+            .with_repetition(Repetition::REQUIRED)
+            .build()?,
+    )?;
+
+    let filter_string = Type::primitive_type_builder("filter_string", PhysicalType::BYTE_ARRAY)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_logical_type(Some(LogicalType::String))
+        .build()?;
+
+    // Per-ion mobilities, e.g. for timsTOF frames. Null if the spectrum has
+    // a single mobility (`inverse_ion_mobility`) or none at all
+    let ion_mobility = list(
+        "ion_mobility",
+        Repetition::OPTIONAL,
+        Type::primitive_type_builder("element", PhysicalType::FLOAT)
+            .with_repetition(Repetition::REQUIRED)
+            .build()?,
+    )?;
+
+    // Per-ion noise levels and baselines, null if not reported
+    let [noise, baseline] = ["noise", "baseline"].map(|name| {
+        list(
+            name,
+            Repetition::OPTIONAL,
+            Type::primitive_type_builder("element", PhysicalType::FLOAT)
+                .with_repetition(Repetition::REQUIRED)
+                .build()?,
+        )
+    });
+
+    // Per-ion charge states, null if not assigned by the instrument
+    let charge = list(
+        "charge",
+        Repetition::OPTIONAL,
+        Type::primitive_type_builder("element", PhysicalType::INT32)
+            .with_repetition(Repetition::REQUIRED)
+            .build()?,
+    )?;
+
+    Type::group_type_builder("schema")
+        .with_fields(vec![
+            Arc::new(id),
+            Arc::new(ms_level),
+            Arc::new(centroid),
+            Arc::new(scan_start_time),
+            Arc::new(inverse_ion_mobility),
+            Arc::new(ion_injection_time),
+            Arc::new(total_ion_current),
+            Arc::new(precursors),
+            Arc::new(mz),
+            Arc::new(intensity),
+            Arc::new(filter_string),
+            Arc::new(ion_mobility),
+            Arc::new(noise?),
+            Arc::new(baseline?),
+            Arc::new(charge),
+        ])
+        .build()
+}
+
+/// Wrap `element` in the standard 3-level parquet LIST structure
+pub(crate) fn list(
+    name: &str,
+    repetition: parquet::basic::Repetition,
+    element: Type,
+) -> parquet::errors::Result<Type> {
+    use parquet::basic::{LogicalType, Repetition};
+
+    let list = Type::group_type_builder("list")
+        .with_repetition(Repetition::REPEATED)
+        .with_fields(vec![Arc::new(element)])
+        .build()?;
+
+    Type::group_type_builder(name)
+        .with_repetition(repetition)
+        .with_logical_type(Some(LogicalType::List))
+        .with_fields(vec![Arc::new(list)])
+        .build()
+}
+
+/// Write a required list of required values. Empty lists are recorded at
+/// definition level 0
+fn extend_list<I: ExactSizeIterator<Item = f32>>(column: &mut ColumnWriter<FloatType>, values: I) {
+    if values.len() == 0 {
+        column.push_nested(None, 0, 0);
+    }
+    for (idx, value) in values.enumerate() {
+        column.push_nested(Some(value), 1, (idx > 0) as i16);
+    }
+}
+
+/// Write an optional list of required values, which is null (definition level
+/// 0) if there are no values, with each value defined at level 2
+fn extend_optional_list<T, I>(column: &mut ColumnWriter<T>, values: I)
+where
+    T: DataType,
+    I: ExactSizeIterator<Item = T::T>,
+{
+    if values.len() == 0 {
+        column.push_nested(None, 0, 0);
+    }
+    for (idx, value) in values.enumerate() {
+        column.push_nested(Some(value), 2, (idx > 0) as i16);
+    }
+}
+
+/// Incrementally writes spectra into row groups of a wide format mzparquet file
+pub struct ChunkWriter<'a, W>
+where
+    W: std::io::Write + Send,
+{
+    writer: &'a mut SerializedFileWriter<W>,
+    row_group_size: RowGroupSize,
+    current_rows: usize,
+    current_ions: usize,
+    current_bytes: usize,
+    peaks_written: usize,
+    progress: Callback,
+    /// Unit of the `scan_start_time` column, as recorded in the footer
+    /// metadata
+    rt_unit: RtUnit,
+    /// Peak processing, as recorded in the footer metadata
+    processing: Processing,
+
+    id: ColumnWriter<ByteArrayType>,
+    ms_level: ColumnWriter<Int32Type>,
+    centroid: ColumnWriter<BoolType>,
+    scan_start_time: ColumnWriter<FloatType>,
+    inverse_ion_mobility: ColumnWriter<FloatType, true>,
+    ion_injection_time: ColumnWriter<FloatType>,
+    total_ion_current: ColumnWriter<FloatType>,
+
+    selected_ion_mz: ColumnWriter<FloatType>,
+    selected_ion_charge: ColumnWriter<Int32Type>,
+    selected_ion_intensity: ColumnWriter<FloatType>,
+    isolation_window_target: ColumnWriter<FloatType>,
+    isolation_window_lower: ColumnWriter<FloatType>,
+    isolation_window_upper: ColumnWriter<FloatType>,
+    spectrum_ref: ColumnWriter<ByteArrayType>,
+
+    mz: ColumnWriter<FloatType>,
+    intensity: ColumnWriter<FloatType>,
+    filter_string: ColumnWriter<ByteArrayType, true>,
+    ion_mobility: ColumnWriter<FloatType>,
+    noise: ColumnWriter<FloatType>,
+    baseline: ColumnWriter<FloatType>,
+    charge: ColumnWriter<Int32Type>,
+}
+
+impl<'a, W> ChunkWriter<'a, W>
+where
+    W: std::io::Write + Send,
+{
+    pub fn new(
+        writer: &'a mut SerializedFileWriter<W>,
+        descr: &SchemaDescriptor,
+        options: Arc<WriterProperties>,
+    ) -> Self {
+        assert_eq!(descr.num_columns(), 21);
+
+        Self {
+            row_group_size: RowGroupSize::default(),
+            current_rows: 0,
+            current_ions: 0,
+            current_bytes: 0,
+            peaks_written: 0,
+            progress: Callback::default(),
+            rt_unit: RtUnit::from_key_value(options.key_value_metadata()).unwrap_or_default(),
+            processing: Processing::from_key_value(options.key_value_metadata()),
+            writer,
+            id: ColumnWriter::new(descr.column(0), options.clone()),
+            ms_level: ColumnWriter::new(descr.column(1), options.clone()),
+            centroid: ColumnWriter::new(descr.column(2), options.clone()),
+            scan_start_time: ColumnWriter::new(descr.column(3), options.clone()),
+            inverse_ion_mobility: ColumnWriter::new(descr.column(4), options.clone()),
+            ion_injection_time: ColumnWriter::new(descr.column(5), options.clone()),
+            total_ion_current: ColumnWriter::new(descr.column(6), options.clone()),
+            selected_ion_mz: ColumnWriter::new(descr.column(7), options.clone()),
+            selected_ion_charge: ColumnWriter::new(descr.column(8), options.clone()),
+            selected_ion_intensity: ColumnWriter::new(descr.column(9), options.clone()),
+            isolation_window_target: ColumnWriter::new(descr.column(10), options.clone()),
+            isolation_window_lower: ColumnWriter::new(descr.column(11), options.clone()),
+            isolation_window_upper: ColumnWriter::new(descr.column(12), options.clone()),
+            spectrum_ref: ColumnWriter::new(descr.column(13), options.clone()),
+            mz: ColumnWriter::new(descr.column(14), options.clone()),
+            intensity: ColumnWriter::new(descr.column(15), options.clone()),
+            filter_string: ColumnWriter::new(descr.column(16), options.clone()),
+            ion_mobility: ColumnWriter::new(descr.column(17), options.clone()),
+            noise: ColumnWriter::new(descr.column(18), options.clone()),
+            baseline: ColumnWriter::new(descr.column(19), options.clone()),
+            charge: ColumnWriter::new(descr.column(20), options.clone()),
+        }
+    }
+
+    pub fn set_row_group_size(&mut self, row_group_size: RowGroupSize) -> &mut Self {
+        self.row_group_size = row_group_size;
+        self
+    }
+
+    pub(crate) fn set_progress(&mut self, progress: Callback) -> &mut Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Number of peaks written so far, after peak processing
+    pub fn peaks_written(&self) -> usize {
+        self.peaks_written
+    }
+
+    /// Skip a spectrum that is left out of the file. MS1 spectra are still
+    /// processed, as they provide the lock mass correction of the MSn spectra
+    /// that follow them
+    pub fn skip_spectrum(&mut self, spectrum: &RawSpectrum) {
+        if spectrum.ms_level == 1 {
+            self.processing.process(spectrum);
+        }
+    }
+
+    /// Write a spectrum to an mzparquet file. This function may have IO operations,
+    /// if writing this spectrum would fill up the current row group.
+    pub fn write_spectrum(&mut self, spectrum: &RawSpectrum) -> anyhow::Result<()> {
+        let spectrum = &*self.processing.process(spectrum);
+        self.id
+            .extend(std::iter::once(ByteArray::from(spectrum.id.clone())));
+        self.ms_level
+            .extend(std::iter::once(spectrum.ms_level as i32));
+        self.centroid.extend(std::iter::once(spectrum.centroid));
+        self.scan_start_time.extend(std::iter::once(
+            self.rt_unit.from_seconds(spectrum.scan_start_time),
+        ));
+        self.inverse_ion_mobility
+            .extend(std::iter::once(spectrum.inverse_ion_mobility));
+        self.ion_injection_time
+            .extend(std::iter::once(spectrum.ion_injection_time));
+        self.total_ion_current
+            .extend(std::iter::once(spectrum.total_ion_current));
+
+        // `precursors` is an optional list of required structs: the list is
+        // null at definition level 0, present at 1, and each optional field
+        // within a precursor is defined at level 3
+        if spectrum.precursors.is_empty() {
+            self.selected_ion_mz.push_nested(None, 0, 0);
+            self.selected_ion_charge.push_nested(None, 0, 0);
+            self.selected_ion_intensity.push_nested(None, 0, 0);
+            self.isolation_window_target.push_nested(None, 0, 0);
+            self.isolation_window_lower.push_nested(None, 0, 0);
+            self.isolation_window_upper.push_nested(None, 0, 0);
+            self.spectrum_ref.push_nested(None, 0, 0);
+        }
+
+        for (idx, precursor) in spectrum.precursors.iter().enumerate() {
+            let rep = (idx > 0) as i16;
+            let def = |present: bool| if present { 3 } else { 2 };
+
+            self.selected_ion_mz
+                .push_nested(Some(precursor.mz as f32), 2, rep);
+            self.selected_ion_charge.push_nested(
+                precursor.charge.map(|z| z as i32),
+                def(precursor.charge.is_some()),
+                rep,
+            );
+            self.selected_ion_intensity.push_nested(
+                precursor.intensity,
+                def(precursor.intensity.is_some()),
+                rep,
+            );
+            self.isolation_window_target.push_nested(
+                precursor.isolation_window_target,
+                def(precursor.isolation_window_target.is_some()),
+                rep,
+            );
+            self.isolation_window_lower.push_nested(
+                precursor.isolation_window_lower,
+                def(precursor.isolation_window_lower.is_some()),
+                rep,
+            );
+            self.isolation_window_upper.push_nested(
+                precursor.isolation_window_upper,
+                def(precursor.isolation_window_upper.is_some()),
+                rep,
+            );
+            self.spectrum_ref.push_nested(
+                precursor.spectrum_ref.clone().map(ByteArray::from),
+                def(precursor.spectrum_ref.is_some()),
+                rep,
+            );
+        }
+
+        extend_list(&mut self.mz, spectrum.mz.iter().map(|mz| *mz as f32));
+        extend_list(
+            &mut self.intensity,
+            spectrum.intensity.iter().map(|i| *i as f32),
+        );
+        self.filter_string.extend(std::iter::once(
+            spectrum.filter_string.as_deref().map(ByteArray::from),
+        ));
+        extend_optional_list(
+            &mut self.ion_mobility,
+            spectrum.ion_mobility.iter().copied(),
+        );
+        extend_optional_list(&mut self.noise, spectrum.noise.iter().copied());
+        extend_optional_list(&mut self.baseline, spectrum.baseline.iter().copied());
+        extend_optional_list(&mut self.charge, spectrum.charge.iter().map(|&z| z as i32));
+
+        self.current_rows += 1;
+        self.current_ions += spectrum.mz.len();
+        self.peaks_written += spectrum.mz.len();
+        // Eight bytes per ion, plus the mobilities, noise levels and the
+        // scalar and precursor columns
+        self.current_bytes += spectrum.mz.len() * 8
+            + (spectrum.ion_mobility.len()
+                + spectrum.noise.len()
+                + spectrum.baseline.len()
+                + spectrum.charge.len())
+                * 4
+            + spectrum.id.len()
+            + 32
+            + spectrum.precursors.len() * 32
+            + spectrum.filter_string.as_ref().map_or(0, String::len);
+
+        // If this row group is full, write it to buffer and reset all of the
+        // columns
+        if self
+            .row_group_size
+            .is_full(self.current_ions, self.current_rows, self.current_bytes)
+        {
+            self.write_to_row_group()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        if self.current_rows > 0 {
+            self.write_to_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn write_to_row_group(&mut self) -> anyhow::Result<()> {
+        let mut rg = self.writer.next_row_group()?;
+
+        self.id.write_and_flush(&mut rg)?;
+        self.ms_level.write_and_flush(&mut rg)?;
+        self.centroid.write_and_flush(&mut rg)?;
+        self.scan_start_time.write_and_flush(&mut rg)?;
+        self.inverse_ion_mobility.write_and_flush(&mut rg)?;
+        self.ion_injection_time.write_and_flush(&mut rg)?;
+        self.total_ion_current.write_and_flush(&mut rg)?;
+        self.selected_ion_mz.write_and_flush(&mut rg)?;
+        self.selected_ion_charge.write_and_flush(&mut rg)?;
+        self.selected_ion_intensity.write_and_flush(&mut rg)?;
+        self.isolation_window_target.write_and_flush(&mut rg)?;
+        self.isolation_window_lower.write_and_flush(&mut rg)?;
+        self.isolation_window_upper.write_and_flush(&mut rg)?;
+        self.spectrum_ref.write_and_flush(&mut rg)?;
+        self.mz.write_and_flush(&mut rg)?;
+        self.intensity.write_and_flush(&mut rg)?;
+        self.filter_string.write_and_flush(&mut rg)?;
+        self.ion_mobility.write_and_flush(&mut rg)?;
+        self.noise.write_and_flush(&mut rg)?;
+        self.baseline.write_and_flush(&mut rg)?;
+        self.charge.write_and_flush(&mut rg)?;
+
+        rg.close()?;
+
+        self.progress.rows_written(self.current_rows);
+        // We have written and cleared all buffers, reset number of written rows
+        self.current_rows = 0;
+        self.current_ions = 0;
+        self.current_bytes = 0;
+
+        Ok(())
+    }
+}
+
+/// Serialize `spectra` into a wide format mzparquet file, returning the
+/// underlying writer once the file footer has been written
+pub fn serialize_to_parquet<W: Write + Send>(w: W, spectra: &[RawSpectrum]) -> anyhow::Result<W> {
+    let schema = build_schema()?;
+    let sd = SchemaDescriptor::new(schema.clone().into());
+    let options = writer_properties("wide", &WriterOptions::default())?;
+
+    let mut writer = SerializedFileWriter::new(w, schema.into(), options.clone())?;
+
+    let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, options);
+
+    for spectrum in spectra {
+        chunk_writer.write_spectrum(spectrum)?;
+    }
+    chunk_writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+/// Serialize spectra into a wide format mzparquet file as they are parsed,
+/// returning the underlying writer and the number of spectra and peaks that
+/// were written
+pub async fn serialize_stream_to_parquet<W, S>(
+    w: W,
+    spectra: &mut S,
+    options: &WriterOptions,
+) -> anyhow::Result<(W, Written)>
+where
+    W: Write + Send,
+    S: SpectrumStream,
+{
+    let schema = build_schema()?;
+    let sd = SchemaDescriptor::new(schema.clone().into());
+    let properties = writer_properties("wide", options)?;
+
+    let mut writer = SerializedFileWriter::new(w, schema.into(), properties.clone())?;
+
+    let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, properties);
+    chunk_writer
+        .set_row_group_size(options.row_group_size)
+        .set_progress(options.progress.clone());
+
+    let mut count = 0;
+    let mut spectra = AveragedStream::new(spectra, options.ms1_averaging);
+    let mut spectra = DemultiplexedStream::new(&mut spectra, options.demultiplex);
+    let mut index = 0;
+    while let Some(spectrum) = spectra.next_spectrum().await? {
+        let keep = options.spectrum_filter.keep(index, &spectrum);
+        index += 1;
+        if keep {
+            chunk_writer.write_spectrum(&spectrum)?;
+            count += 1;
+        } else {
+            chunk_writer.skip_spectrum(&spectrum);
+        }
+    }
+    let peaks = chunk_writer.peaks_written();
+    chunk_writer.finish()?;
+    for kv in spectra.run_metadata().to_key_value()? {
+        writer.append_key_value_metadata(kv);
+    }
+    let written = Written {
+        spectra: count,
+        peaks,
+    };
+    Ok((writer.into_inner()?, written))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parquet::column::reader::ColumnReader;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    #[test]
+    fn ion_mobility_levels() -> anyhow::Result<()> {
+        let spectrum = |mz: Vec<f64>, ion_mobility: Vec<f32>| RawSpectrum {
+            id: b"scan".to_vec(),
+            ms_level: 1,
+            intensity: vec![1.0; mz.len()],
+            mz,
+            ion_mobility,
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum(vec![100.0, 200.0], vec![0.8, 1.2]),
+            spectrum(vec![300.0], Vec::new()),
+            // An empty frame, with neither peaks nor mobilities
+            spectrum(Vec::new(), Vec::new()),
+            spectrum(vec![400.0], vec![1.0]),
+        ];
+
+        let schema = build_schema()?;
+        let sd = SchemaDescriptor::new(schema.clone().into());
+        let properties = writer_properties("wide", &WriterOptions::default())?;
+        let mut writer = SerializedFileWriter::new(Vec::new(), schema.into(), properties.clone())?;
+        let mut chunk_writer = ChunkWriter::new(&mut writer, &sd, properties);
+        for spectrum in &spectra {
+            chunk_writer.write_spectrum(spectrum)?;
+        }
+        chunk_writer.finish()?;
+        let buf = bytes::Bytes::from(writer.into_inner()?);
+
+        // Spectra without per-ion mobilities have a null list, and values are
+        // defined at level 2 (inside the optional list and its repeated group)
+        let reader = SerializedFileReader::new(buf.clone())?;
+        let idx = reader
+            .metadata()
+            .file_metadata()
+            .schema_descr()
+            .columns()
+            .iter()
+            .position(|col| col.path().string() == "ion_mobility.list.element")
+            .expect("ion_mobility column");
+        let ColumnReader::FloatColumnReader(mut column) =
+            reader.get_row_group(0)?.get_column_reader(idx)?
+        else {
+            panic!("ion_mobility is a float column");
+        };
+        let (mut values, mut def_levels, mut rep_levels) = (Vec::new(), Vec::new(), Vec::new());
+        let (rows, _, _) = column.read_records(
+            usize::MAX,
+            Some(&mut def_levels),
+            Some(&mut rep_levels),
+            &mut values,
+        )?;
+        assert_eq!(rows, 4);
+        assert_eq!(values, vec![0.8, 1.2, 1.0]);
+        assert_eq!(def_levels, vec![2, 2, 0, 0, 2]);
+        assert_eq!(rep_levels, vec![0, 1, 0, 0, 0]);
+
+        let (_, read) = crate::reader::read_spectra(buf)?;
+        let read = read
+            .iter()
+            .map(|s| (s.mz.len(), s.ion_mobility.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            read,
+            vec![
+                (2, vec![0.8, 1.2]),
+                (1, Vec::new()),
+                (0, Vec::new()),
+                (1, vec![1.0])
+            ]
+        );
+        Ok(())
+    }
+}